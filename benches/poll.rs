@@ -0,0 +1,54 @@
+use std::future::Future;
+use std::hint::black_box;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+/// A future that stays pending forever, like a task waiting on a socket.
+async fn pending() {
+    std::future::poll_fn(|cx| {
+        black_box(cx.waker().clone());
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+async fn nested() {
+    futures::join!(pending(), async { pending().await });
+}
+
+fn poll(c: &mut Criterion) {
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut group = c.benchmark_group("poll");
+
+    let mut fut = pin!(pending());
+    group.bench_function("untraced", |b| b.iter(|| fut.as_mut().poll(&mut cx)));
+
+    let (fut, _trace_handle) = tasktrace::traced(pending());
+    let mut fut = pin!(fut);
+    group.bench_function("traced", |b| b.iter(|| fut.as_mut().poll(&mut cx)));
+
+    tasktrace::set_enabled(false);
+    group.bench_function("traced disabled", |b| b.iter(|| fut.as_mut().poll(&mut cx)));
+    tasktrace::set_enabled(true);
+
+    group.finish();
+}
+
+fn capture(c: &mut Criterion) {
+    let mut cx = Context::from_waker(Waker::noop());
+    let (fut, trace_handle) = tasktrace::traced(nested());
+    let mut fut = pin!(fut);
+    c.bench_function("capture", |b| {
+        b.iter(|| {
+            trace_handle
+                .request_backtrace(|trace| drop(trace.unwrap()))
+                .unwrap();
+            fut.as_mut().poll(&mut cx)
+        })
+    });
+}
+
+criterion_group!(benches, poll, capture);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+use std::time::Duration;
+
+fn main() {
+    smol::block_on(async {
+        let (fut, trace_handle) = tasktrace::traced(foo());
+        let _task = smol::spawn(fut);
+
+        println!("{}", trace_handle.backtrace().await.unwrap());
+    });
+}
+
+async fn foo() {
+    futures::join!(sleep(), recv());
+}
+
+async fn sleep() {
+    smol::Timer::after(Duration::from_secs(3600)).await;
+}
+
+async fn recv() {
+    let (_tx, rx) = smol::channel::bounded::<()>(1);
+    let _ = rx.recv().await;
+}
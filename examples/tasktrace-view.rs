@@ -0,0 +1,38 @@
+//! Renders a snapshot file written by `TraceSnapshot::write_to`.
+//!
+//! ```text
+//! cargo run --example tasktrace-view -- dump.ttsnap
+//! ```
+
+use std::fs::File;
+use std::io::BufReader;
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+
+use tasktrace::TraceSnapshot;
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args_os().nth(1) else {
+        eprintln!("usage: tasktrace-view <snapshot>");
+        return ExitCode::FAILURE;
+    };
+    let snapshot =
+        match File::open(&path).and_then(|file| TraceSnapshot::read_from(BufReader::new(file))) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                eprintln!("{}: {err}", path.to_string_lossy());
+                return ExitCode::FAILURE;
+            }
+        };
+    let taken_at = snapshot
+        .taken_at()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    println!(
+        "{} tasks, taken at {} seconds since the epoch\n",
+        snapshot.tasks().len(),
+        taken_at.as_secs()
+    );
+    print!("{snapshot}");
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,241 @@
+//! Attribute macros for `tasktrace`. Use them through the `macros` feature of `tasktrace` rather
+//! than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    parse_macro_input, parse_quote, Error, Expr, FnArg, GenericParam, ItemFn, Lifetime,
+    LifetimeParam, LitStr, ReturnType,
+};
+
+/// Turns an `async fn` into a function that returns the traced future along with its handle.
+///
+/// ```rust,ignore
+/// #[tasktrace::traced]
+/// async fn serve(port: u16) -> std::io::Result<()> {
+///     // ...
+/// }
+///
+/// let (fut, trace_handle) = serve(8080);
+/// tokio::spawn(fut);
+/// ```
+///
+/// With the `registry` feature of `tasktrace` the task is also registered in
+/// `tasktrace::registry` under the path of the function, like `my_crate::server::serve`, which
+/// for methods leaves out the type.
+///
+/// The function keeps its arguments and generics, and the future resolves to its original return
+/// type. Methods are supported as long as they are inherent, since the rewritten signature no longer
+/// matches a trait declaration. Arguments of `impl Trait` type are not supported.
+#[proc_macro_attribute]
+pub fn traced(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = proc_macro2::TokenStream::from(args);
+    if !args.is_empty() {
+        return Error::new_spanned(args, "`#[tasktrace::traced]` takes no arguments")
+            .into_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as ItemFn);
+    expand(item)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut item: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let sig = &mut item.sig;
+    if sig.asyncness.take().is_none() {
+        return Err(Error::new_spanned(
+            sig.fn_token,
+            "`#[tasktrace::traced]` can only be applied to an `async fn`",
+        ));
+    }
+
+    // The returned future borrows from the arguments just like the one of an `async fn`, which
+    // requires all of their lifetimes to be named so that the future can capture them.
+    let mut lifetimes = NameElided::default();
+    for input in &mut sig.inputs {
+        match input {
+            FnArg::Receiver(receiver) if receiver.colon_token.is_none() => {
+                if let Some((_, lifetime @ None)) = &mut receiver.reference {
+                    *lifetime = Some(lifetimes.next());
+                }
+            }
+            FnArg::Receiver(receiver) => lifetimes.visit_type_mut(&mut receiver.ty),
+            FnArg::Typed(arg) => lifetimes.visit_type_mut(&mut arg.ty),
+        }
+    }
+    if let Some(err) = lifetimes.err {
+        return Err(err);
+    }
+    for lifetime in lifetimes.named.into_iter().rev() {
+        sig.generics
+            .params
+            .insert(0, GenericParam::Lifetime(LifetimeParam::new(lifetime)));
+    }
+
+    let captures = sig.generics.params.iter().map(|param| match param {
+        GenericParam::Lifetime(param) => {
+            let lifetime = &param.lifetime;
+            quote!(#lifetime)
+        }
+        GenericParam::Type(param) => {
+            let ident = &param.ident;
+            quote!(#ident)
+        }
+        GenericParam::Const(param) => {
+            let ident = &param.ident;
+            quote!(#ident)
+        }
+    });
+    let output = match &sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    sig.output = parse_quote! {
+        -> (
+            ::tasktrace::TracedTask<
+                impl ::core::future::Future<Output = #output> + use<#(#captures),*>
+            >,
+            ::tasktrace::TraceHandle,
+        )
+    };
+
+    let block = &item.block;
+    let name = LitStr::new(&format!("::{}", sig.ident), Span::call_site());
+    item.block = parse_quote! {{
+        ::tasktrace::__private::traced_fn(
+            async move {
+                let __ret: #output = #block;
+                __ret
+            },
+            ::core::concat!(::core::module_path!(), #name),
+        )
+    }};
+    Ok(quote!(#item))
+}
+
+/// Labels every `.await` of an `async fn` with an identifier of the await point, which traces show
+/// as a frame above the awaited future.
+///
+/// ```rust,ignore
+/// #[tasktrace::instrument]
+/// async fn handle(db: &Db, id: u64) -> Response {
+///     let user = db.user(id).await;
+///     let orders = db.orders(id).await;
+///     // ...
+/// }
+/// ```
+///
+/// The labels are the path of the function followed by the number of the await point in source
+/// order, like `server::handle await #1` for the second one above. They don't depend on debug
+/// info or on what the compiler inlined, and stay the same as long as the awaits before them do.
+/// Awaits inside nested async blocks are labelled as well, those of nested items aren't. To combine
+/// it with [`traced`](macro@traced), put `#[tasktrace::instrument]` above it.
+#[proc_macro_attribute]
+pub fn instrument(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = proc_macro2::TokenStream::from(args);
+    if !args.is_empty() {
+        return Error::new_spanned(args, "`#[tasktrace::instrument]` takes no arguments")
+            .into_compile_error()
+            .into();
+    }
+    let mut item = parse_macro_input!(item as ItemFn);
+    if item.sig.asyncness.is_none() {
+        return Error::new_spanned(
+            item.sig.fn_token,
+            "`#[tasktrace::instrument]` can only be applied to an `async fn`",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let mut awaits = LabelAwaits {
+        name: item.sig.ident.to_string(),
+        count: 0,
+    };
+    awaits.visit_block_mut(&mut item.block);
+    quote!(#item).into()
+}
+
+/// Wraps every `.await` in a labelled future.
+struct LabelAwaits {
+    name: String,
+    count: usize,
+}
+
+impl VisitMut for LabelAwaits {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let Expr::Await(await_expr) = expr else {
+            return visit_mut::visit_expr_mut(self, expr);
+        };
+        // The awaits of the awaited expression come first in source order
+        self.visit_expr_mut(&mut await_expr.base);
+        let label = LitStr::new(
+            &format!("::{} await #{}", self.name, self.count),
+            Span::call_site(),
+        );
+        self.count += 1;
+        let base = &await_expr.base;
+        *await_expr.base = parse_quote! {
+            ::tasktrace::labelled(
+                ::core::concat!(::core::module_path!(), #label),
+                ::core::future::IntoFuture::into_future(#base),
+            )
+        };
+    }
+
+    // The awaits of nested functions belong to them
+    fn visit_item_mut(&mut self, _: &mut syn::Item) {}
+}
+
+/// Gives a name to every elided lifetime in the argument types.
+#[derive(Default)]
+struct NameElided {
+    named: Vec<Lifetime>,
+    err: Option<Error>,
+}
+
+impl NameElided {
+    fn next(&mut self) -> Lifetime {
+        let lifetime = Lifetime::new(
+            &format!("'__tasktrace{}", self.named.len()),
+            Span::call_site(),
+        );
+        self.named.push(lifetime.clone());
+        lifetime
+    }
+}
+
+impl VisitMut for NameElided {
+    fn visit_type_reference_mut(&mut self, ty: &mut syn::TypeReference) {
+        if ty.lifetime.is_none() {
+            ty.lifetime = Some(self.next());
+        }
+        visit_mut::visit_type_reference_mut(self, ty);
+    }
+
+    fn visit_lifetime_mut(&mut self, lifetime: &mut Lifetime) {
+        if lifetime.ident == "_" {
+            *lifetime = self.next();
+        }
+    }
+
+    fn visit_type_impl_trait_mut(&mut self, ty: &mut syn::TypeImplTrait) {
+        self.err.get_or_insert_with(|| {
+            Error::new_spanned(
+                ty,
+                "`#[tasktrace::traced]` does not support `impl Trait` arguments, use a type parameter instead",
+            )
+        });
+    }
+
+    // Elided lifetimes of function pointers and `Fn` traits belong to them, not to the function
+    fn visit_type_bare_fn_mut(&mut self, _: &mut syn::TypeBareFn) {}
+
+    fn visit_parenthesized_generic_arguments_mut(
+        &mut self,
+        _: &mut syn::ParenthesizedGenericArguments,
+    ) {
+    }
+}
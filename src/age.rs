@@ -0,0 +1,114 @@
+//! Annotating the frames of traces with how long they have been part of them.
+//!
+//! The frames a task holds on to for many polls are the parts of its await tree that don't move,
+//! e.g. where it is stuck, while the ones that keep changing show where it makes progress. A task
+//! created through [`traced_with_frame_ages`] captures every poll and follows each frame from one
+//! poll to the next by its path from the root, like [`Branch::id`](crate::Branch::id) does for
+//! whole branches.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+
+use crate::{traced, Frame, Trace, TraceHandle, TracedTask};
+
+/// Like [`traced`] but captures every poll of the task and gives every frame of its traces the
+/// number of consecutive polls it has been part of them for, see [`Frame::age`]. Traces render it
+/// next to the frame, as `(new)` for frames that appeared in the poll the trace was captured from
+/// and as e.g. `(17 polls)` for older ones.
+///
+/// Every poll pays the cost of a capture and of symbolizing it, since frames are followed by
+/// their symbols and source locations. Polls made while tracing is
+/// [disabled](crate::set_enabled) aren't captured and so don't count.
+pub fn traced_with_frame_ages<F: Future>(fut: F) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.frame_ages = Some(FrameAges::default());
+    (task, handle)
+}
+
+/// The ages of the frames of the last poll of a task created with [`traced_with_frame_ages`], by
+/// the hash of their path from the root.
+#[derive(Default)]
+pub(crate) struct FrameAges {
+    last: HashMap<u64, usize>,
+}
+
+impl FrameAges {
+    /// Returns `trace`, the trace of the next poll of the task, with the ages of its frames.
+    pub(crate) fn observe(&mut self, trace: &Trace) -> Trace {
+        let mut ages = HashMap::new();
+        let mut roots = trace.roots().to_vec();
+        for root in &mut roots {
+            self.age(root, DefaultHasher::new(), &mut ages);
+        }
+        self.last = ages;
+        trace.with_roots(roots)
+    }
+
+    fn age(&self, frame: &mut Frame, mut path: DefaultHasher, ages: &mut HashMap<u64, usize>) {
+        (frame.symbol(), frame.file(), frame.line(), frame.col()).hash(&mut path);
+        let id = path.finish();
+        let age = self.last.get(&id).map_or(1, |age| age + 1);
+        ages.insert(id, age);
+        frame.set_age(age);
+        for child in frame.children_mut() {
+            self.age(child, path.clone(), ages);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::branches;
+    use crate::test_util::{pending, TestExecutor};
+
+    #[test]
+    fn frame_ages() {
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (fut, trace_handle) = traced_with_frame_ages(async { wait(rx).await });
+        let mut executor = TestExecutor::new(fut);
+        let backtrace = |executor: &mut TestExecutor<_>| {
+            let mut request = Box::pin(trace_handle.backtrace());
+            assert!((&mut request).now_or_never().is_none());
+            assert!(executor.poll_once().is_pending());
+            request.now_or_never().unwrap().unwrap()
+        };
+
+        let trace = backtrace(&mut executor);
+        assert!(branches(&trace)
+            .flat_map(|branch| branch.frames().collect::<Vec<_>>())
+            .all(|frame| frame.age() == Some(1)));
+        assert!(trace
+            .to_string()
+            .lines()
+            .all(|line| line.ends_with(" (new)")));
+
+        // Still parked at the receiver
+        let trace = backtrace(&mut executor);
+        assert!(trace.to_string().contains("oneshot::Receiver"));
+        assert!(trace
+            .to_string()
+            .lines()
+            .all(|line| line.ends_with(" (2 polls)")));
+
+        // The root stays where it was, `wait` moved on
+        tx.send(()).unwrap();
+        let trace = backtrace(&mut executor);
+        let branch = branches(&trace).next().unwrap();
+        assert_eq!(branch.frames().next().unwrap().age(), Some(3));
+        assert_eq!(branch.leaf().age(), Some(1));
+        let rendered = trace.to_string();
+        assert!(rendered.lines().next().unwrap().ends_with(" (3 polls)"));
+        assert!(rendered.contains("test_util::pending::{{closure}} at "));
+        assert!(!rendered.contains("oneshot::Receiver"));
+    }
+
+    async fn wait(rx: futures_channel::oneshot::Receiver<()>) {
+        let _ = rx.await;
+        pending().await;
+    }
+}
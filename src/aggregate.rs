@@ -0,0 +1,158 @@
+//! Collapsing of repeated subtrees.
+//!
+//! A task that waits on many equal futures, e.g. thousands of connections parked on the same read,
+//! produces a trace with as many identical subtrees. [`aggregate`] renders each distinct subtree
+//! once along with the number of branches it stands for.
+
+use std::fmt;
+
+use crate::trace::display_tree;
+use crate::{Frame, Trace};
+
+/// Merges the sibling subtrees of `trace` that consist of the same functions at the same lines.
+///
+/// Siblings are only merged when their whole subtrees match, and the merged subtree takes the place
+/// of the first one. In the rendered output every frame that is reached by a different number of
+/// branches than its parent is annotated with `(×N)`.
+pub fn aggregate(trace: &Trace) -> AggregatedTrace {
+    aggregate_with_precision(trace, Precision::SymbolLine)
+}
+
+/// Like [`aggregate`] but merges the subtrees whose frames match at `precision`. The merged frames
+/// show the location of the first subtree.
+pub fn aggregate_with_precision(trace: &Trace, precision: Precision) -> AggregatedTrace {
+    AggregatedTrace {
+        roots: merge(trace.roots(), precision),
+    }
+}
+
+/// How closely the frames of different branches or traces have to match to be merged, see
+/// [`aggregate_with_precision`] and [`Aggregator::precision`](crate::Aggregator::precision).
+///
+/// Traces of many samples have lots of frames that only differ in their columns or lines, e.g. the
+/// awaits of a loop body. Merging at a coarser precision counts them together, which makes for
+/// fewer and larger entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Precision {
+    /// Frames of the same function match wherever in the function they are.
+    Symbol,
+    /// Frames of the same function match if they are at the same line.
+    SymbolLine,
+    /// Frames of the same function match if they are at the same line and column.
+    #[default]
+    SymbolLineCol,
+}
+
+impl Precision {
+    /// Returns whether `frame` and `other` are at the same place at this precision.
+    pub(crate) fn same_point(self, frame: &Frame, other: &Frame) -> bool {
+        frame.symbol() == other.symbol()
+            && match self {
+                Precision::Symbol => true,
+                Precision::SymbolLine => {
+                    frame.file() == other.file() && frame.line() == other.line()
+                }
+                Precision::SymbolLineCol => {
+                    frame.file() == other.file()
+                        && frame.line() == other.line()
+                        && frame.col() == other.col()
+                }
+            }
+    }
+}
+
+/// A trace whose repeated subtrees have been merged. See [`aggregate`].
+#[derive(Debug, Clone)]
+pub struct AggregatedTrace {
+    roots: Vec<Frame>,
+}
+
+impl AggregatedTrace {
+    /// The outermost frames of the trace.
+    pub fn roots(&self) -> &[Frame] {
+        &self.roots
+    }
+}
+
+impl fmt::Display for AggregatedTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        display_tree(f, &self.roots, true, &|f, frame| write!(f, "{frame}"))
+    }
+}
+
+fn merge(frames: &[Frame], precision: Precision) -> Vec<Frame> {
+    let mut merged: Vec<Frame> = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let frame = frame.with_children(merge(frame.children(), precision));
+        match merged
+            .iter_mut()
+            .find(|other| other.same_shape(&frame, precision))
+        {
+            Some(other) => other.absorb(&frame),
+            None => merged.push(frame),
+        }
+    }
+    merged
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame_at, pending};
+    use crate::traced;
+
+    #[tokio::test]
+    async fn join_all() {
+        let (fut, trace_handle) = traced(async {
+            let parked = futures::future::join_all((0..5).map(|_| pending()));
+            tokio::join!(parked, pending());
+        });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let aggregated = aggregate(&trace).to_string();
+        let counted: Vec<_> = aggregated
+            .lines()
+            .filter(|line| line.contains("(×"))
+            .collect();
+        assert_eq!(counted.len(), 1);
+        assert!(counted[0].contains("tests::join_all::{{closure}}::{{closure}}::{{closure}}"));
+        assert!(counted[0].ends_with(" (×5)"));
+        assert_eq!(
+            aggregated
+                .matches("Waker as core::clone::Clone>::clone")
+                .count(),
+            2
+        );
+
+        // The plain rendering has the same shape, minus the counts
+        assert_eq!(aggregated.replace(" (×5)", ""), trace.to_string());
+    }
+
+    #[test]
+    fn precision() {
+        // Two awaits of the same function that only differ in their column, and a third one at
+        // another line
+        let trace = Trace::from_roots(vec![frame_at(
+            "app::serve",
+            1,
+            1,
+            vec![
+                frame_at("app::read", 10, 5, Vec::new()),
+                frame_at("app::read", 10, 9, Vec::new()),
+                frame_at("app::read", 12, 5, Vec::new()),
+            ],
+        )]);
+        let merged = |precision| {
+            let aggregated = aggregate_with_precision(&trace, precision);
+            aggregated.roots()[0]
+                .children()
+                .iter()
+                .map(Frame::branches)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(merged(Precision::SymbolLineCol), [1, 1, 1]);
+        assert_eq!(merged(Precision::SymbolLine), [2, 1]);
+        assert_eq!(merged(Precision::Symbol), [3]);
+    }
+}
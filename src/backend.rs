@@ -0,0 +1,220 @@
+//! Pluggable stack walking.
+//!
+//! Leaves walk the stack through the `backtrace` crate and symbolize it through its copy of the
+//! debug info. A task created with [`traced_with_backend`](crate::traced_with_backend) walks and
+//! symbolizes its stacks through a [`CaptureBackend`] instead, e.g. a frame pointer walker that is
+//! faster than unwinding, a platform specific unwinder, or a mock that returns the same frames
+//! every time for tests that assert on rendered traces.
+
+use std::ffi::c_void;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Walks and symbolizes the stacks of the leaves of a task.
+///
+/// ```rust
+/// use tasktrace::{CaptureBackend, ResolvedFrame, StackBounds};
+///
+/// /// Shows every leaf as the same two frames.
+/// struct Mock;
+///
+/// impl CaptureBackend for Mock {
+///     fn capture(&self, _bounds: &StackBounds) -> Vec<usize> {
+///         vec![2, 1]
+///     }
+///
+///     fn resolve(&self, ip: usize) -> Vec<ResolvedFrame> {
+///         let name = if ip == 1 { "app::serve" } else { "app::accept" };
+///         vec![ResolvedFrame::new(name).file("src/main.rs").line(ip as u32)]
+///     }
+/// }
+///
+/// # async fn serve() {}
+/// let (fut, trace_handle) = tasktrace::traced_with_backend(serve(), Mock);
+/// ```
+pub trait CaptureBackend: Send + Sync + 'static {
+    /// Records the frames of the current thread's stack that lie within `bounds`, innermost first,
+    /// as the instruction pointers that [`CaptureBackend::resolve`] is called with later on.
+    ///
+    /// Called from within the poll of the task, once for every leaf, so it should be quick.
+    fn capture(&self, bounds: &StackBounds) -> Vec<usize>;
+
+    /// Symbolizes the frame at `ip`, as returned by [`CaptureBackend::capture`], into the
+    /// functions that it is in, the functions inlined into the others first. Frames that can't be
+    /// symbolized resolve to a default [`ResolvedFrame`].
+    ///
+    /// Called when the trace is first inspected, possibly on another thread.
+    fn resolve(&self, ip: usize) -> Vec<ResolvedFrame>;
+}
+
+/// The part of the stack that [`CaptureBackend::capture`] records.
+#[derive(Debug, Clone, Copy)]
+pub struct StackBounds {
+    pub(crate) leaf: usize,
+    pub(crate) root: usize,
+}
+
+impl StackBounds {
+    /// The address of the function that records the leaf. Its frame and the frames it called are
+    /// left out.
+    pub fn leaf(&self) -> usize {
+        self.leaf
+    }
+
+    /// The address of the function that started the capture. Its frame and the frames that called
+    /// it are left out.
+    pub fn root(&self) -> usize {
+        self.root
+    }
+}
+
+/// A function that a recorded frame is in, as symbolized by [`CaptureBackend::resolve`].
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedFrame {
+    pub(crate) symbol: Option<String>,
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) line: Option<u32>,
+    pub(crate) col: Option<u32>,
+}
+
+impl ResolvedFrame {
+    /// Creates a frame of the function called `symbol`, demangled and without its hash.
+    pub fn new(symbol: impl Into<String>) -> Self {
+        ResolvedFrame {
+            symbol: Some(symbol.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Sets the source file of the frame.
+    pub fn file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// Sets the line number within the source file.
+    pub fn line(mut self, line: u32) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// Sets the column number within the line.
+    pub fn col(mut self, col: u32) -> Self {
+        self.col = Some(col);
+        self
+    }
+}
+
+/// The backend that walks and symbolizes stacks through the `backtrace` crate, like the tasks that
+/// don't have a backend of their own. Unlike theirs, its leaves are symbolized frame by frame.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BacktraceBackend;
+
+impl CaptureBackend for BacktraceBackend {
+    fn capture(&self, bounds: &StackBounds) -> Vec<usize> {
+        let mut ips = Vec::new();
+        let mut above_leaf = false;
+        backtrace::trace(|frame| {
+            let addr = frame.symbol_address() as usize;
+            if !above_leaf {
+                above_leaf = addr == bounds.leaf;
+                return true;
+            }
+            if addr == bounds.root {
+                return false;
+            }
+            ips.push(frame.ip() as usize);
+            true
+        });
+        ips
+    }
+
+    fn resolve(&self, ip: usize) -> Vec<ResolvedFrame> {
+        let mut frames = Vec::new();
+        backtrace::resolve(ip as *mut c_void, |symbol| {
+            frames.push(ResolvedFrame {
+                symbol: symbol.name().map(|name| format!("{name:#}")),
+                file: symbol.filename().map(Path::to_path_buf),
+                line: symbol.lineno(),
+                col: symbol.colno(),
+            })
+        });
+        frames
+    }
+}
+
+/// The backend of a task, see [`CaptureLimits`](crate::CaptureLimits).
+#[derive(Clone)]
+pub(crate) struct Backend(pub(crate) Arc<dyn CaptureBackend>);
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CaptureBackend")
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+    use crate::{traced_with_backend, TraceOptions};
+
+    struct Mock;
+
+    impl CaptureBackend for Mock {
+        fn capture(&self, _bounds: &StackBounds) -> Vec<usize> {
+            vec![3, 2, 1]
+        }
+
+        fn resolve(&self, ip: usize) -> Vec<ResolvedFrame> {
+            match ip {
+                1 => vec![ResolvedFrame::new("app::serve")
+                    .file("src/main.rs")
+                    .line(10)],
+                // The inlined function comes first
+                2 => vec![
+                    ResolvedFrame::new("app::read_frame"),
+                    ResolvedFrame::new("app::handle")
+                        .file("src/main.rs")
+                        .line(20),
+                ],
+                _ => vec![ResolvedFrame::default()],
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mock() {
+        let (fut, trace_handle) = traced_with_backend(pending(), Mock);
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        // Frames that can't be symbolized are left blank, like those of the built-in walk
+        assert_eq!(
+            trace.to_string(),
+            "╼ app::serve at src/main.rs:10\n  \
+             └╼ app::handle at src/main.rs:20\n     \
+             └╼ app::read_frame\n        \
+             └╼ "
+        );
+
+        let options = TraceOptions::new().expand_inlined(false);
+        let trace = trace_handle.backtrace_with(options).await.unwrap();
+        assert_eq!(
+            trace.to_string(),
+            "╼ app::serve at src/main.rs:10\n  \
+             └╼ app::handle at src/main.rs:20\n     \
+             └╼ "
+        );
+    }
+
+    #[tokio::test]
+    async fn backtrace() {
+        let (fut, trace_handle) = traced_with_backend(pending(), BacktraceBackend);
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert!(trace
+            .to_string()
+            .starts_with("╼ tasktrace::test_util::pending::{{closure}}"));
+    }
+}
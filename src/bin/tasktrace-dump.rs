@@ -0,0 +1,296 @@
+//! Inspects the traced tasks of a running process or of a saved snapshot.
+//!
+//! ```text
+//! tasktrace-dump list http://127.0.0.1:6060
+//! tasktrace-dump show tcp://10.0.0.7:7070 "http worker"
+//! tasktrace-dump diff before.ttsnap after.ttsnap
+//! tasktrace-dump flamegraph tcp://10.0.0.7:7070 | inferno-flamegraph > tasks.svg
+//! tasktrace-dump watch http://127.0.0.1:6060 --interval 5s
+//! ```
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use tasktrace::remote::RemoteRegistryClient;
+use tasktrace::{fold, Branch, Frame, FrameOrigin, TraceDiff, TraceSnapshot};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+const USAGE: &str = "\
+usage: tasktrace-dump <command>
+
+commands:
+  list <source>                             lists the traced tasks
+  show <source> <task>                      prints the trace of a task
+  diff <old> <new>                          compares two dumps of the same tasks
+  flamegraph <source>                       prints the folded stacks of all the tasks
+  watch <source> [--interval <duration>]    prints a dump every 5s, or every <duration>
+
+sources:
+  <path>            a snapshot file written by TraceSnapshot::write_to
+  tcp://<addr>      a tasktrace::remote::RemoteRegistryServer
+  http://<addr>     the endpoint of tasktrace::server::serve, which has no snapshots to diff or fold";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            eprintln!("tasktrace-dump: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(&runtime, &args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage) => {
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+        Err(Error::Failed(err)) => {
+            eprintln!("tasktrace-dump: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+enum Error {
+    Usage,
+    Failed(String),
+}
+
+impl From<String> for Error {
+    fn from(err: String) -> Self {
+        Error::Failed(err)
+    }
+}
+
+fn run(runtime: &Runtime, args: &[&str]) -> Result<(), Error> {
+    match args {
+        ["list", source] => {
+            for (name, _) in runtime.block_on(Source::parse(source).dump())? {
+                println!("{name}");
+            }
+        }
+        ["show", source, task] => {
+            let trace = runtime.block_on(Source::parse(source).trace(task))?;
+            println!("{trace}");
+        }
+        ["diff", old, new] => {
+            let old = runtime.block_on(Source::parse(old).snapshot())?;
+            let new = runtime.block_on(Source::parse(new).snapshot())?;
+            print!("{}", diff(&old, &new));
+        }
+        ["flamegraph", source] => {
+            let snapshot = runtime.block_on(Source::parse(source).snapshot())?;
+            for (name, trace) in snapshot.tasks() {
+                for stack in fold(trace).lines() {
+                    println!("{name};{stack}");
+                }
+            }
+        }
+        ["watch", source, rest @ ..] => {
+            let interval = match rest {
+                [] => Duration::from_secs(5),
+                ["--interval", interval] => parse_duration(interval)
+                    .ok_or_else(|| Error::Failed(format!("invalid interval {interval:?}")))?,
+                _ => return Err(Error::Usage),
+            };
+            let source = Source::parse(source);
+            let watch = async {
+                loop {
+                    for (name, trace) in source.dump().await? {
+                        println!("task {name}:\n{trace}\n");
+                    }
+                    println!("---");
+                    tokio::time::sleep(interval).await;
+                }
+            };
+            let result: Result<(), String> = runtime.block_on(watch);
+            result?;
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+/// Where the traces come from.
+enum Source<'a> {
+    File(PathBuf),
+    Remote(&'a str),
+    Http(&'a str),
+}
+
+impl<'a> Source<'a> {
+    fn parse(source: &'a str) -> Self {
+        if let Some(addr) = source.strip_prefix("tcp://") {
+            Source::Remote(addr)
+        } else if let Some(addr) = source.strip_prefix("http://") {
+            Source::Http(addr.trim_end_matches('/'))
+        } else {
+            Source::File(source.into())
+        }
+    }
+
+    async fn snapshot(&self) -> Result<TraceSnapshot, String> {
+        match self {
+            Source::File(path) => File::open(path)
+                .and_then(|file| TraceSnapshot::read_from(BufReader::new(file)))
+                .map_err(|err| format!("{}: {err}", path.display())),
+            Source::Remote(addr) => {
+                let dump = async {
+                    let conn = TcpStream::connect(addr).await?;
+                    RemoteRegistryClient::new(conn).dump().await
+                };
+                dump.await.map_err(|err| format!("{addr}: {err}"))
+            }
+            Source::Http(_) => Err(
+                "the HTTP endpoint serves rendered traces only, use a snapshot or a tcp:// source"
+                    .to_owned(),
+            ),
+        }
+    }
+
+    /// The names and rendered traces of all the tasks.
+    async fn dump(&self) -> Result<Vec<(String, String)>, String> {
+        let Source::Http(addr) = self else {
+            let snapshot = self.snapshot().await?;
+            return Ok(snapshot
+                .tasks()
+                .iter()
+                .map(|(name, trace)| (name.clone(), trace.to_string()))
+                .collect());
+        };
+        let names = http_get(addr, "/tasks").await?;
+        let mut dump = Vec::new();
+        for name in names.lines() {
+            // Tasks that can't be traced are shown with the reason
+            let trace = self.trace(name).await.unwrap_or_else(|err| err);
+            dump.push((name.to_owned(), trace));
+        }
+        Ok(dump)
+    }
+
+    /// The rendered trace of the task called `name`.
+    async fn trace(&self, name: &str) -> Result<String, String> {
+        if let Source::Http(addr) = self {
+            let trace = http_get(addr, &format!("/tasks/{}/trace", percent_encode(name))).await?;
+            return Ok(trace.trim_end().to_owned());
+        }
+        let snapshot = self.snapshot().await?;
+        match snapshot.tasks().iter().find(|(task, _)| task == name) {
+            Some((_, trace)) => Ok(trace.to_string()),
+            None => Err(format!("no task called {name:?}")),
+        }
+    }
+}
+
+/// Describes every task of `new` by how its branches compare to the ones it had in `old`.
+fn diff(old: &TraceSnapshot, new: &TraceSnapshot) -> String {
+    let mut diff = String::new();
+    for (name, trace) in new.tasks() {
+        let Some((_, old_trace)) = old.tasks().iter().find(|(task, _)| task == name) else {
+            diff.push_str(&format!("task {name}: new\n"));
+            continue;
+        };
+        let task_diff = TraceDiff::diff(old_trace, trace);
+        diff.push_str(&format!(
+            "task {name}: {} unchanged, {} changed\n",
+            task_diff.unchanged().len(),
+            task_diff.changed().len()
+        ));
+        for branch in task_diff.unchanged() {
+            diff.push_str(&format!("  = {}\n", innermost_user_frame(branch)));
+        }
+        for branch in task_diff.changed() {
+            diff.push_str(&format!("  + {}\n", innermost_user_frame(branch)));
+        }
+    }
+    for (name, _) in old.tasks() {
+        if !new.tasks().iter().any(|(task, _)| task == name) {
+            diff.push_str(&format!("task {name}: gone\n"));
+        }
+    }
+    diff
+}
+
+/// The frame of the application closest to the leaf of `branch`, which is where it is waiting.
+fn innermost_user_frame<'a>(branch: &Branch<'a>) -> &'a Frame {
+    branch
+        .frames()
+        .rev()
+        .find(|frame| frame.origin() == FrameOrigin::User && frame.file().is_some())
+        .unwrap_or(branch.leaf())
+}
+
+/// Requests `path` from the HTTP endpoint at `addr` and returns the body of a `200 OK` response.
+async fn http_get(addr: &str, path: &str) -> Result<String, String> {
+    let get = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = get.await.map_err(|err| format!("{addr}: {err}"))?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| format!("{addr}: malformed response"))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(body.trim_end().to_owned());
+    }
+    Ok(body.to_owned())
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Parses durations like `5s`, `250ms` or `2m`.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let n: u64 = s[..split].parse().ok()?;
+    match &s[split..] {
+        "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_secs(n * 60)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("5s"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("5"), None);
+        assert_eq!(parse_duration("s"), None);
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(percent_encode("http worker/1"), "http%20worker%2F1");
+    }
+}
@@ -0,0 +1,140 @@
+//! Showing the traces of the registered tasks alongside tokio-console.
+//!
+//! tokio-console shows the tokio tasks of a process by id, along with where they were spawned and
+//! how often they were polled, but not where they are parked. [`spawn`] spawns a registered task
+//! that keeps its latest trace, and [`latest_stacks`] returns those traces by tokio task id
+//! without waking any task, so that tooling reading the console's data can show them next to it
+//! as often as it refreshes.
+//!
+//! A task only records a trace when it is captured, so a task that is never asked for one has no
+//! stack to show. Dumping the registry every now and then keeps the stacks of idle tasks recent:
+//!
+//! ```rust,no_run
+//! # async fn example() {
+//! loop {
+//!     tasktrace::registry::dump_all().await;
+//!     for task in tasktrace::console::latest_stacks() {
+//!         if let Some(trace) = task.trace() {
+//!             println!("task {} ({}):\n{trace}", task.id(), task.name());
+//!         }
+//!     }
+//!     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+//! }
+//! # }
+//! ```
+
+use std::future::Future;
+
+use crate::registry::{self, register};
+use crate::{traced_cached, Trace, TraceHandle};
+
+/// Spawns `fut` on the current tokio runtime, registers it under `name` like
+/// [`traced_registered`](registry::traced_registered) and keeps its latest trace like
+/// [`traced_cached`], so that [`latest_stacks`] can show where it is parked by the id tokio-console
+/// knows it by.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, like `tokio::spawn`.
+pub fn spawn<F>(name: &str, fut: F) -> (tokio::task::JoinHandle<F::Output>, TraceHandle)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, handle) = register(traced_cached(fut), name);
+    let join_handle = tokio::spawn(task);
+    let _ = handle.state.tokio_id.set(join_handle.id());
+    (join_handle, handle)
+}
+
+/// The registered tasks whose tokio id is known, see [`TraceHandle::tokio_id`], along with their
+/// latest traces, sorted by name and then by registration. Only reads the traces the tasks already
+/// have, so it never waits and never wakes a task.
+pub fn latest_stacks() -> Vec<ConsoleTask> {
+    registry::tasks()
+        .into_iter()
+        .filter_map(|(name, handle)| {
+            Some(ConsoleTask {
+                id: handle.tokio_id()?,
+                trace: handle.last_trace(),
+                status: handle.status(),
+                name,
+            })
+        })
+        .collect()
+}
+
+/// The latest trace of the registered task that tokio runs under `id`, if it has one, see
+/// [`latest_stacks`].
+pub fn latest_stack(id: tokio::task::Id) -> Option<Trace> {
+    registry::tasks()
+        .into_iter()
+        .find(|(_, handle)| handle.tokio_id() == Some(id))?
+        .1
+        .last_trace()
+}
+
+/// A task of [`latest_stacks`].
+#[derive(Clone)]
+pub struct ConsoleTask {
+    id: tokio::task::Id,
+    name: String,
+    trace: Option<Trace>,
+    status: Option<String>,
+}
+
+impl ConsoleTask {
+    /// The id of the tokio task, as tokio-console shows it.
+    pub fn id(&self) -> tokio::task::Id {
+        self.id
+    }
+
+    /// The name the task is registered under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The latest trace of the task, see [`TraceHandle::last_trace`], `None` if it was never
+    /// captured or the task keeps no traces.
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    /// The status of the task, see [`TraceHandle::set_status`].
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_deref()
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+
+    #[tokio::test]
+    async fn latest_stacks() {
+        let (join_handle, trace_handle) = spawn("console worker", pending());
+        let id = join_handle.id();
+        trace_handle.set_status("idle");
+        let find = || {
+            super::latest_stacks()
+                .into_iter()
+                .find(|task| task.id() == id)
+                .unwrap()
+        };
+        assert!(find().trace().is_none());
+        assert!(latest_stack(id).is_none());
+
+        trace_handle.backtrace().await.unwrap();
+        let task = find();
+        assert_eq!(task.name(), "console worker");
+        assert_eq!(task.status(), Some("idle"));
+        assert!(task
+            .trace()
+            .unwrap()
+            .to_string()
+            .contains("test_util::pending"));
+        assert!(latest_stack(id).is_some());
+        join_handle.abort();
+    }
+}
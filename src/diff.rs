@@ -0,0 +1,109 @@
+//! Comparison of two traces of the same task.
+//!
+//! A task that is stuck keeps reporting the same branches no matter how long apart its traces are
+//! captured, while a task that makes progress moves on to other lines. [`TraceDiff`] tells the
+//! two kinds of branches apart, so that only the ones that haven't moved need attention.
+
+use crate::{branches, Branch, Frame, Trace};
+
+/// The branches of a trace split by whether an earlier trace of the same task had them too.
+///
+/// ```rust
+/// # async fn check(trace_handle: tasktrace::TraceHandle) {
+/// let old = trace_handle.backtrace().await.unwrap();
+/// // ... some time later
+/// let new = trace_handle.backtrace().await.unwrap();
+/// for branch in tasktrace::TraceDiff::diff(&old, &new).unchanged() {
+///     eprintln!("possibly stuck at {}", branch.leaf());
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TraceDiff<'a> {
+    unchanged: Vec<Branch<'a>>,
+    changed: Vec<Branch<'a>>,
+}
+
+impl<'a> TraceDiff<'a> {
+    /// Compares the branches of `new` to the ones of `old`. Branches are the same if they go
+    /// through the same functions at the same locations, regardless of how many leaves they have.
+    pub fn diff(old: &Trace, new: &'a Trace) -> Self {
+        let old: Vec<_> = branches(old).collect();
+        let (unchanged, changed) =
+            branches(new).partition(|branch| old.iter().any(|other| same_path(branch, other)));
+        TraceDiff { unchanged, changed }
+    }
+
+    /// The branches of the new trace that the old trace had as well, i.e. the ones that may be
+    /// stuck.
+    pub fn unchanged(&self) -> &[Branch<'a>] {
+        &self.unchanged
+    }
+
+    /// The branches of the new trace that the old trace didn't have, i.e. the ones that moved.
+    pub fn changed(&self) -> &[Branch<'a>] {
+        &self.changed
+    }
+}
+
+fn same_path(branch: &Branch<'_>, other: &Branch<'_>) -> bool {
+    fn location(frame: &Frame) -> impl PartialEq + '_ {
+        (frame.symbol(), frame.file(), frame.line(), frame.col())
+    }
+
+    branch
+        .frames()
+        .map(location)
+        .eq(other.frames().map(location))
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::test_util::pending;
+    use crate::traced;
+
+    #[tokio::test]
+    async fn stuck_branch() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let (fut, trace_handle) = traced(async move {
+            futures::join!(pending(), async {
+                loop {
+                    first(&mut rx).await;
+                    second(&mut rx).await;
+                }
+            })
+        });
+        tokio::spawn(fut);
+
+        let old = trace_handle.backtrace().await.unwrap();
+        tx.unbounded_send(()).unwrap();
+        let new = trace_handle.backtrace().await.unwrap();
+
+        let diff = TraceDiff::diff(&old, &new);
+        assert_eq!(diff.unchanged().len(), 1);
+        assert!(diff.unchanged()[0]
+            .frames()
+            .any(|frame| frame.symbol() == Some("tasktrace::test_util::pending::{{closure}}")));
+        assert_eq!(diff.changed().len(), 1);
+        assert!(diff.changed()[0]
+            .frames()
+            .any(|frame| frame.symbol() == Some("tasktrace::diff::tests::second::{{closure}}")));
+
+        // Nothing moved between a trace and itself
+        let diff = TraceDiff::diff(&new, &new);
+        assert_eq!(diff.unchanged().len(), 2);
+        assert!(diff.changed().is_empty());
+    }
+
+    async fn first(rx: &mut mpsc::UnboundedReceiver<()>) {
+        rx.next().await;
+    }
+
+    async fn second(rx: &mut mpsc::UnboundedReceiver<()>) {
+        rx.next().await;
+    }
+}
@@ -0,0 +1,217 @@
+//! Emitting traces as `tracing` events.
+//!
+//! Applications that already ship their logs through `tracing` can have the traces end up there
+//! as well, either on demand through [`TraceHandle::emit_to_tracing`] or periodically by passing
+//! [`tracing_sink`] to one of the sampling constructors. [`TraceHandle::backtrace_record_span`]
+//! records a summary of the trace on the current span instead.
+
+use tracing::{Level, Span};
+
+use crate::{branches, leaf_count, Trace, TraceError, TraceHandle};
+
+impl TraceHandle {
+    /// Captures a backtrace of the task during its next poll and emits it as a `tracing` event at
+    /// `level`, see [`tracing_sink`] for its fields.
+    pub async fn emit_to_tracing(&self, level: Level) -> Result<(), TraceError> {
+        let trace = self.backtrace().await?;
+        emit(&trace, level);
+        Ok(())
+    }
+
+    /// Captures a backtrace of the task during its next poll and records a summary of it as fields
+    /// of the span that is current when this is called, then returns the trace.
+    ///
+    /// The fields are `tasktrace.leaf` with the symbol of the frame the task is parked in, i.e. the
+    /// innermost frame of the deepest branch outside of the standard library, `tasktrace.depth` with
+    /// the number of frames of that branch and `tasktrace.branches` with the number of leaves. Spans
+    /// only record the fields they were created with, so declare them as
+    /// [`Empty`](tracing::field::Empty):
+    ///
+    /// ```rust
+    /// # async fn example(trace_handle: tasktrace::TraceHandle) {
+    /// use tracing::field::Empty;
+    ///
+    /// let span = tracing::info_span!(
+    ///     "inspect",
+    ///     tasktrace.leaf = Empty,
+    ///     tasktrace.depth = Empty,
+    ///     tasktrace.branches = Empty,
+    /// );
+    /// let _enter = span.enter();
+    /// let trace = trace_handle.backtrace_record_span().await;
+    /// # }
+    /// ```
+    ///
+    /// Nothing is recorded if the capture fails.
+    pub async fn backtrace_record_span(&self) -> Result<Trace, TraceError> {
+        let span = Span::current();
+        let trace = self.backtrace().await?;
+        if let Some(leaf) = trace.parked_at() {
+            span.record("tasktrace.leaf", leaf.symbol().unwrap_or("??"));
+        }
+        if let Some(depth) = branches(&trace).map(|branch| branch.frames().count()).max() {
+            span.record("tasktrace.depth", depth as u64);
+        }
+        span.record("tasktrace.branches", leaf_count(&trace) as u64);
+        Ok(trace)
+    }
+}
+
+/// Returns a sink that emits every trace it is given as a `tracing` event at `level`, e.g. for
+/// [`traced_every_n_polls`](crate::traced_every_n_polls).
+///
+/// The event has a `task` field with the name of the task, if it has one, a `frames` field with
+/// the symbols of every branch from the root down to the leaf, and a `trace` field with the
+/// rendered trace.
+pub fn tracing_sink(level: Level) -> impl Fn(Trace) + Send + 'static {
+    move |trace| emit(&trace, level)
+}
+
+fn emit(trace: &Trace, level: Level) {
+    let task = trace.name().unwrap_or_default();
+    let frames: Vec<Vec<_>> = branches(trace)
+        .map(|branch| {
+            branch
+                .frames()
+                .map(|frame| frame.symbol().unwrap_or("??"))
+                .collect()
+        })
+        .collect();
+    // The level of an event has to be known at compile time
+    match level {
+        Level::ERROR => tracing::error!(task, ?frames, %trace, "task trace"),
+        Level::WARN => tracing::warn!(task, ?frames, %trace, "task trace"),
+        Level::INFO => tracing::info!(task, ?frames, %trace, "task trace"),
+        Level::DEBUG => tracing::debug!(task, ?frames, %trace, "task trace"),
+        Level::TRACE => tracing::trace!(task, ?frames, %trace, "task trace"),
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::Empty;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Id, Record};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+    use super::*;
+    use crate::test_util::pending;
+    use crate::traced_named;
+
+    /// The names and values of the fields of an event.
+    type Fields = Vec<(String, String)>;
+
+    /// Records the fields of every event.
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<Vec<(Level, Fields)>>>);
+
+    struct Recorder(Fields);
+
+    impl Visit for Recorder {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push((field.name().to_owned(), format!("{value:?}")));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push((field.name().to_owned(), value.to_owned()));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for Events {
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            // With tokio_unstable, tokio emits events of its own
+            if event.metadata().target().starts_with("tokio::") {
+                return;
+            }
+
+            let mut fields = Recorder(Vec::new());
+            event.record(&mut fields);
+            let level = *event.metadata().level();
+            self.0.lock().unwrap().push((level, fields.0));
+        }
+    }
+
+    /// Records the values recorded on spans after they were created.
+    #[derive(Clone, Default)]
+    struct Records(Arc<Mutex<Fields>>);
+
+    impl<S: Subscriber> Layer<S> for Records {
+        fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            let mut fields = Recorder(Vec::new());
+            values.record(&mut fields);
+            self.0.lock().unwrap().extend(fields.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn emit_to_tracing() {
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        let (fut, trace_handle) = traced_named(pending(), "worker");
+        tokio::spawn(fut);
+        trace_handle.emit_to_tracing(Level::WARN).await.unwrap();
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let (level, fields) = &events[0];
+        assert_eq!(*level, Level::WARN);
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap()
+        };
+        assert_eq!(field("message"), "task trace");
+        assert_eq!(field("task"), "worker");
+        assert!(field("frames").starts_with("[[\"tasktrace::test_util::pending::{{closure}}\""));
+        assert!(field("trace").starts_with("Task \"worker\" (busy "));
+    }
+
+    #[tokio::test]
+    async fn backtrace_record_span() {
+        let records = Records::default();
+        let subscriber = tracing_subscriber::registry().with(records.clone());
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        let (fut, trace_handle) = traced_named(pending(), "worker");
+        tokio::spawn(fut);
+        let span = tracing::info_span!(
+            "inspect",
+            tasktrace.leaf = Empty,
+            tasktrace.depth = Empty,
+            tasktrace.branches = Empty,
+        );
+        let _enter = span.enter();
+        let trace = trace_handle.backtrace_record_span().await.unwrap();
+
+        let depth = branches(&trace)
+            .next()
+            .unwrap()
+            .frames()
+            .count()
+            .to_string();
+        let records = records.0.lock().unwrap();
+        let records: Vec<_> = records
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (
+                    "tasktrace.leaf",
+                    "tasktrace::test_util::pending::{{closure}}::{{closure}}"
+                ),
+                ("tasktrace.depth", depth.as_str()),
+                ("tasktrace.branches", "1"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,588 @@
+//! Hiding uninteresting frames from traces.
+//!
+//! Traces tend to be dominated by the frames of the runtime and of the standard library, which are
+//! the same for every task. A [`TraceFilter`] replaces them with `…` placeholders so that the frames
+//! of the application stand out. It can also rewrite the paths of the source locations, which
+//! otherwise give away where the binary was built and differ between machines.
+
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{Frame, FrameOrigin, Trace};
+
+/// Removes the frames of selected crates from a trace, and trims the ones that remain.
+///
+/// Every run of consecutive hidden frames is collapsed into a single `…` frame. The branches below
+/// a hidden frame are kept, so a hidden `join!` still shows one child per joined future.
+///
+/// ```rust
+/// # async fn task() {}
+/// # async fn example() {
+/// let (fut, trace_handle) = tasktrace::traced(task());
+/// tokio::spawn(fut);
+/// let trace = trace_handle.backtrace().await.unwrap();
+/// let filter = tasktrace::TraceFilter::new()
+///     .hide_crate("tokio")
+///     .hide_crate("core");
+/// println!("{}", filter.apply(&trace));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    hidden_crates: Vec<String>,
+    hidden_origins: Vec<FrameOrigin>,
+    count_hidden: bool,
+    hidden_locations: Vec<String>,
+    strip_closures: bool,
+    remapped_paths: Vec<(PathBuf, PathBuf)>,
+    remap_default_paths: bool,
+    sort_branches: bool,
+    max_symbol_len: Option<usize>,
+    anonymize_generics: bool,
+}
+
+impl TraceFilter {
+    /// Creates a filter that hides nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hides the frames of the crate called `name`, e.g. `"tokio"` or `"core"`.
+    pub fn hide_crate(mut self, name: &str) -> Self {
+        self.hidden_crates.push(name.to_owned());
+        self
+    }
+
+    /// Hides the frames of the given origin, see [`Frame::origin`].
+    pub fn hide_origin(mut self, origin: FrameOrigin) -> Self {
+        self.hidden_origins.push(origin);
+        self
+    }
+
+    /// Creates a filter that hides every frame but the ones of the application, see
+    /// [`Frame::origin`], and counts the hidden ones like [`TraceFilter::count_hidden`].
+    pub fn user_frames_only() -> Self {
+        Self::new()
+            .hide_origin(FrameOrigin::Runtime)
+            .hide_origin(FrameOrigin::Std)
+            .hide_origin(FrameOrigin::Dependency)
+            .count_hidden()
+    }
+
+    /// Creates a filter that hides the frames every task goes through: those of the runtime, of the
+    /// standard library and of the futures-rs combinators like `ForEach` or `Fold`, which thread
+    /// the task's waker through to the futures they drive. Unlike
+    /// [`TraceFilter::user_frames_only`] it keeps the frames of the other dependencies. The hidden
+    /// frames are counted like [`TraceFilter::count_hidden`].
+    pub fn runtime_noise() -> Self {
+        Self::new()
+            .hide_origin(FrameOrigin::Runtime)
+            .hide_origin(FrameOrigin::Std)
+            .hide_crate("futures_util")
+            .hide_crate("futures_core")
+            .count_hidden()
+    }
+
+    /// Labels every `…` frame with the number of hidden frames it stands for, along with their
+    /// origin if they share it, e.g. `… 5 runtime frames …`.
+    pub fn count_hidden(mut self) -> Self {
+        self.count_hidden = true;
+        self
+    }
+
+    /// Leaves out the source location of the frames whose path contains `pattern`, e.g.
+    /// `".cargo/registry"` or `"/rustc/"`. The frames themselves are kept.
+    pub fn hide_locations_containing(mut self, pattern: &str) -> Self {
+        self.hidden_locations.push(pattern.to_owned());
+        self
+    }
+
+    /// Removes the `::{{closure}}` suffixes that the symbols of `async` blocks and functions end
+    /// with, e.g. turning `app::serve::{{closure}}` into `app::serve`.
+    pub fn strip_closure_suffixes(mut self) -> Self {
+        self.strip_closures = true;
+        self
+    }
+
+    /// Replaces the generic arguments in the symbols by `_`, e.g. turning
+    /// `app::Repository<app::model::User>::query` into `app::Repository<_>::query`, so that traces
+    /// can be shared without giving away the types of the application. Nested arguments are part of
+    /// the replaced ones, while the `<T as Trait>` of trait methods is kept apart from their
+    /// arguments.
+    pub fn anonymize_generics(mut self) -> Self {
+        self.anonymize_generics = true;
+        self
+    }
+
+    /// Shortens the symbols longer than `max` characters by replacing their middle with `…`, which
+    /// keeps the crate they come from and the function they end with. The length counts
+    /// characters, not bytes, and includes the `…`. A `max` below 2 counts as 2, which keeps the
+    /// first character of every shortened symbol.
+    pub fn max_symbol_len(mut self, max: usize) -> Self {
+        self.max_symbol_len = Some(max);
+        self
+    }
+
+    /// Replaces the `prefix` of the paths of the source locations that start with it by
+    /// `replacement`, e.g. the checkout directory of the application by `.`. The prefix is
+    /// matched by whole path components. The first matching prefix applies, in the order in which
+    /// they were added.
+    pub fn remap_path_prefix(
+        mut self,
+        prefix: impl Into<PathBuf>,
+        replacement: impl Into<PathBuf>,
+    ) -> Self {
+        self.remapped_paths
+            .push((prefix.into(), replacement.into()));
+        self
+    }
+
+    /// Shortens the paths of the dependencies and the standard library that weren't remapped by
+    /// [`TraceFilter::remap_path_prefix`]: sources of the cargo registry get a `<crate-version>`
+    /// prefix in place of everything up to the crate's directory, e.g. `<tokio-1.38.0>/src/...`,
+    /// and `/rustc/<commit>` becomes `<std>`.
+    pub fn remap_default_paths(mut self) -> Self {
+        self.remap_default_paths = true;
+        self
+    }
+
+    /// Orders the children of every frame by the path down to their first leaf instead of the order
+    /// in which the poll reached them, which varies between polls, e.g. with `tokio::join!`. Two
+    /// captures of a task in the same state then render identically and can be diffed as text.
+    pub fn sort_branches(mut self) -> Self {
+        self.sort_branches = true;
+        self
+    }
+
+    /// Returns a copy of `trace` without the hidden frames. The name of the task and the duration
+    /// of the poll are kept.
+    pub fn apply(&self, trace: &Trace) -> Trace {
+        trace.with_roots(self.filter(trace.roots()))
+    }
+
+    fn is_hidden(&self, frame: &Frame) -> bool {
+        frame
+            .crate_name()
+            .is_some_and(|name| self.hidden_crates.iter().any(|hidden| hidden == name))
+            || (!self.hidden_origins.is_empty() && self.hidden_origins.contains(&frame.origin()))
+    }
+
+    fn filter(&self, frames: &[Frame]) -> Vec<Frame> {
+        let frames = frames
+            .iter()
+            .map(|frame| {
+                if self.is_hidden(frame) {
+                    let mut visible = Vec::new();
+                    let mut hidden = vec![frame.origin()];
+                    self.visible_below(frame, &mut visible, &mut hidden);
+                    let visible = self.sorted(visible);
+                    Frame::placeholder(self.label(&hidden), frame.branches(), visible)
+                } else {
+                    self.keep(frame)
+                }
+            })
+            .collect();
+        self.sorted(frames)
+    }
+
+    /// Sorts the already filtered sibling `frames` if [`TraceFilter::sort_branches`] is set.
+    fn sorted(&self, mut frames: Vec<Frame>) -> Vec<Frame> {
+        if self.sort_branches {
+            frames.sort_by_cached_key(first_path);
+        }
+        frames
+    }
+
+    /// Collects the filtered trees of the first visible frames below the hidden `frame`, and the
+    /// origins of the hidden frames on the way to them.
+    fn visible_below(
+        &self,
+        frame: &Frame,
+        visible: &mut Vec<Frame>,
+        hidden: &mut Vec<FrameOrigin>,
+    ) {
+        for child in frame.children() {
+            if self.is_hidden(child) {
+                hidden.push(child.origin());
+                self.visible_below(child, visible, hidden);
+            } else {
+                visible.push(self.keep(child));
+            }
+        }
+    }
+
+    /// The label of the frame that takes the place of frames of the given origins.
+    fn label(&self, hidden: &[FrameOrigin]) -> String {
+        if !self.count_hidden {
+            return "…".to_owned();
+        }
+        let count = hidden.len();
+        let frames = if count == 1 { "frame" } else { "frames" };
+        if hidden.iter().all(|origin| *origin == hidden[0]) {
+            format!("… {count} {} {frames} …", hidden[0])
+        } else {
+            format!("… {count} {frames} …")
+        }
+    }
+
+    /// Returns the trimmed copy of the visible `frame` along with its filtered children.
+    fn keep(&self, frame: &Frame) -> Frame {
+        let mut kept = frame.with_children(self.filter(frame.children()));
+        if let Some(symbol) = frame.symbol() {
+            let mut rewritten = Cow::Borrowed(symbol);
+            if self.strip_closures {
+                let mut stripped = symbol;
+                while let Some(rest) = stripped.strip_suffix("::{{closure}}") {
+                    stripped = rest;
+                }
+                rewritten = Cow::Borrowed(stripped);
+            }
+            if self.anonymize_generics {
+                rewritten = Cow::Owned(anonymize(&rewritten));
+            }
+            if let Some(max) = self.max_symbol_len {
+                if let Some(shortened) = shorten(&rewritten, max) {
+                    rewritten = Cow::Owned(shortened);
+                }
+            }
+            if rewritten != symbol {
+                kept = kept.with_symbol(rewritten.into_owned());
+            }
+        }
+        let hidden = frame.file().is_some_and(|file| {
+            let file = file.to_string_lossy();
+            self.hidden_locations
+                .iter()
+                .any(|pattern| file.contains(pattern.as_str()))
+        });
+        if hidden {
+            kept = kept.without_location();
+        } else if let Some(file) = frame.file().and_then(|file| self.remap(file)) {
+            kept = kept.with_file(file);
+        }
+        kept
+    }
+
+    /// Returns the remapped path of `file`, or `None` if it is kept as is.
+    fn remap(&self, file: &Path) -> Option<PathBuf> {
+        for (prefix, replacement) in &self.remapped_paths {
+            if let Ok(rest) = file.strip_prefix(prefix) {
+                return Some(replacement.join(rest));
+            }
+        }
+        if !self.remap_default_paths {
+            return None;
+        }
+        if let Ok(rest) = file.strip_prefix("/rustc") {
+            let mut rest = rest.components();
+            // The commit of the compiler
+            rest.next()?;
+            return Some(Path::new("<std>").join(rest.as_path()));
+        }
+        // .cargo/registry/src/<index>/<crate-version>/...
+        let components: Vec<_> = file.components().collect();
+        let registry = components.windows(2).position(|pair| {
+            pair[0] == Component::Normal(".cargo".as_ref())
+                && pair[1] == Component::Normal("registry".as_ref())
+        })?;
+        let crate_dir = components.get(registry + 4)?.as_os_str().to_str()?;
+        let rest: PathBuf = components.get(registry + 5..)?.iter().collect();
+        Some(Path::new(&format!("<{crate_dir}>")).join(rest))
+    }
+}
+
+/// Returns `symbol` with every list of generic arguments replaced by `<_>`. A `<` starts one if it
+/// follows a name, otherwise it opens a `<T as Trait>` whose names are anonymized in turn.
+fn anonymize(symbol: &str) -> String {
+    let mut anonymized = String::with_capacity(symbol.len());
+    let mut chars = symbol.chars();
+    let mut prev = None;
+    while let Some(c) = chars.next() {
+        if c == '<' && prev.is_some_and(|prev: char| prev.is_alphanumeric() || prev == '_') {
+            let mut depth = 1;
+            let mut last = c;
+            for c in chars.by_ref() {
+                match c {
+                    '<' => depth += 1,
+                    // The arrow of a `fn() -> T` argument
+                    '>' if last == '-' => {}
+                    '>' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+                last = c;
+            }
+            anonymized.push_str("<_>");
+            prev = Some('>');
+        } else {
+            anonymized.push(c);
+            prev = Some(c);
+        }
+    }
+    anonymized
+}
+
+/// Returns `symbol` with its middle replaced by `…` so that it is `max` characters long, but at
+/// least 2, or `None` if it is short enough already.
+fn shorten(symbol: &str, max: usize) -> Option<String> {
+    let max = max.max(2);
+    let len = symbol.chars().count();
+    if len <= max {
+        return None;
+    }
+    let kept = max.saturating_sub(1);
+    let tail = kept / 2;
+    let head: String = symbol.chars().take(kept - tail).collect();
+    let tail: String = symbol.chars().skip(len - tail).collect();
+    Some(format!("{head}…{tail}"))
+}
+
+/// The frames from `frame` down to its first leaf, rendered, which orders it among its siblings.
+fn first_path(frame: &Frame) -> String {
+    let mut path = frame.to_string();
+    let mut frame = frame;
+    while let Some(child) = frame.children().first() {
+        path.push('\n');
+        path.push_str(&child.to_string());
+        frame = child;
+    }
+    path
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::normalize;
+    use crate::test_util::pending;
+    use crate::traced;
+
+    #[tokio::test]
+    async fn collapse_hidden_frames() {
+        let (fut, trace_handle) = traced(async { tokio::join!(fiz(), buz()) });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let filter = TraceFilter::new().hide_crate("tokio").hide_crate("core");
+        let expected = "\
+╼ tasktrace::filter::tests::collapse_hidden_frames::{{closure}}::{{closure}}
+  └╼ …
+     ├╼ tasktrace::filter::tests::collapse_hidden_frames::{{closure}}::{{closure}}::{{closure}}
+     │  └╼ …
+     │     └╼ tasktrace::filter::tests::fiz::{{closure}}
+     │        └╼ tasktrace::test_util::pending::{{closure}}
+     │           └╼ …
+     │              └╼ tasktrace::test_util::pending::{{closure}}::{{closure}}
+     │                 └╼ …
+     └╼ tasktrace::filter::tests::collapse_hidden_frames::{{closure}}::{{closure}}::{{closure}}
+        └╼ …
+           └╼ tasktrace::filter::tests::buz::{{closure}}
+              └╼ tasktrace::test_util::pending::{{closure}}
+                 └╼ …
+                    └╼ tasktrace::test_util::pending::{{closure}}::{{closure}}
+                       └╼ …
+";
+        assert_eq!(normalize(&filter.apply(&trace)), expected);
+    }
+
+    #[tokio::test]
+    async fn trim_frames() {
+        let (fut, trace_handle) = traced(fiz());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let filter = TraceFilter::new()
+            .strip_closure_suffixes()
+            .hide_locations_containing("/rustc/");
+        let rendered = filter.apply(&trace).to_string();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert!(lines[0].starts_with("╼ tasktrace::filter::tests::fiz at "));
+        assert!(lines[0].contains("src/filter.rs:"));
+        assert!(lines[1].starts_with("  └╼ tasktrace::test_util::pending at "));
+        assert!(!rendered.contains("{{closure}}"));
+        // The frames of the standard library are kept, just without their location
+        assert!(lines.iter().any(|line| line.ends_with(
+            "└╼ <core::future::poll_fn::PollFn<F> as core::future::future::Future>::poll"
+        )));
+        assert!(!rendered.contains("/rustc/"));
+    }
+
+    #[tokio::test]
+    async fn anonymize_generics() {
+        let (fut, trace_handle) = traced(fiz());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let rendered = TraceFilter::new()
+            .anonymize_generics()
+            .apply(&trace)
+            .to_string();
+        assert!(rendered.contains(
+            "╼ <core::future::poll_fn::PollFn<_> as core::future::future::Future>::poll"
+        ));
+        assert!(!rendered.contains("PollFn<F>"));
+
+        assert_eq!(
+            anonymize("app::Repository<app::Secret>::query"),
+            "app::Repository<_>::query"
+        );
+        assert_eq!(
+            anonymize("<app::Cache<std::HashMap<String, Vec<u8>>> as app::Store<fn() -> u8>>::get"),
+            "<app::Cache<_> as app::Store<_>>::get"
+        );
+        assert_eq!(
+            anonymize("app::serve::{{closure}}"),
+            "app::serve::{{closure}}"
+        );
+    }
+
+    #[tokio::test]
+    async fn max_symbol_len() {
+        let (fut, trace_handle) = traced(fiz());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let filter = TraceFilter::new().max_symbol_len(24);
+        let shortened = filter.apply(&trace);
+        let leaf = crate::branches(&shortened).next().unwrap();
+        let symbols: Vec<_> = leaf.frames().map(|frame| frame.symbol().unwrap()).collect();
+        assert_eq!(symbols[0], "tasktrace::f…{{closure}}");
+        assert!(symbols.iter().all(|symbol| symbol.chars().count() <= 24));
+
+        // Multi-byte characters are never split
+        assert_eq!(shorten("ünïcödé::sym", 5).unwrap(), "ün…ym");
+        assert_eq!(shorten("short", 5), None);
+        // Every shortened symbol keeps its first character
+        assert_eq!(shorten("symbol", 0).unwrap(), "s…");
+        assert_eq!(shorten("symbol", 1).unwrap(), "s…");
+        assert_eq!(shorten("symbol", 2).unwrap(), "s…");
+        assert_eq!(shorten("symbol", 3).unwrap(), "s…l");
+        assert_eq!(shorten("ab", 0), None);
+    }
+
+    #[tokio::test]
+    async fn remap_paths() {
+        let (fut, trace_handle) = traced(fiz());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let filter = TraceFilter::new()
+            .remap_path_prefix(env!("CARGO_MANIFEST_DIR"), "tasktrace")
+            .remap_default_paths();
+        let remapped = filter.apply(&trace);
+        let files: Vec<_> = crate::branches(&remapped)
+            .next()
+            .unwrap()
+            .frames()
+            .map(|frame| frame.file().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        assert!(files[0].starts_with("tasktrace/src/filter.rs"));
+        assert!(files[2].starts_with("<std>/library/core/src/future/"));
+
+        let (fut, trace_handle) = traced(async { tokio::join!(fiz(), buz()) });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        let rendered = TraceFilter::new()
+            .remap_default_paths()
+            .apply(&trace)
+            .to_string();
+        assert!(rendered.contains(" at <tokio-"));
+        assert!(!rendered.contains(".cargo"));
+        assert!(!rendered.contains("/rustc/"));
+    }
+
+    #[tokio::test]
+    async fn user_frames_only() {
+        let (fut, trace_handle) = traced(async { tokio::join!(fiz(), buz()) });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let origins: Vec<_> = crate::branches(&trace)
+            .next()
+            .unwrap()
+            .frames()
+            .map(Frame::origin)
+            .collect();
+        use FrameOrigin::*;
+        assert_eq!(
+            origins,
+            [User, Std, Dependency, Runtime, User, User, Std, User, Std]
+        );
+
+        let expected = "\
+╼ tasktrace::filter::tests::user_frames_only::{{closure}}::{{closure}}
+  └╼ … 5 frames …
+     ├╼ tasktrace::filter::tests::fiz::{{closure}}
+     │  └╼ tasktrace::test_util::pending::{{closure}}
+     │     └╼ … 1 std frame …
+     │        └╼ tasktrace::test_util::pending::{{closure}}::{{closure}}
+     │           └╼ … 1 std frame …
+     └╼ tasktrace::filter::tests::buz::{{closure}}
+        └╼ tasktrace::test_util::pending::{{closure}}
+           └╼ … 1 std frame …
+              └╼ tasktrace::test_util::pending::{{closure}}::{{closure}}
+                 └╼ … 1 std frame …
+";
+        let filter = TraceFilter::user_frames_only();
+        assert_eq!(normalize(&filter.apply(&trace)), expected);
+    }
+
+    #[tokio::test]
+    async fn runtime_noise() {
+        use futures::stream::{self, StreamExt};
+
+        let (fut, trace_handle) = traced(stream::iter(0..3).for_each(|_| fiz()));
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert!(trace.to_string().contains("ForEach<St,Fut,F>"));
+
+        let expected = "\
+╼ … 1 dependency frame …
+  └╼ tasktrace::filter::tests::fiz::{{closure}}
+     └╼ tasktrace::test_util::pending::{{closure}}
+        └╼ … 1 std frame …
+           └╼ tasktrace::test_util::pending::{{closure}}::{{closure}}
+              └╼ … 1 std frame …
+";
+        let filter = TraceFilter::runtime_noise();
+        assert_eq!(normalize(&filter.apply(&trace)), expected);
+    }
+
+    #[tokio::test]
+    async fn sort_branches() {
+        let (fut, trace_handle) = traced(async { tokio::join!(buz(), fiz()) });
+        tokio::spawn(fut);
+        let first = trace_handle.backtrace().await.unwrap();
+        let second = trace_handle.backtrace().await.unwrap();
+        // tokio::join! takes turns at which future it polls first
+        assert_ne!(normalize(&first), normalize(&second));
+
+        let filter = TraceFilter::new().sort_branches();
+        let sorted = normalize(&filter.apply(&first));
+        assert_eq!(sorted, normalize(&filter.apply(&second)));
+    }
+
+    #[tokio::test]
+    async fn keep_metadata() {
+        let (fut, trace_handle) = crate::traced_named(fiz(), "fizzer");
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let filtered = TraceFilter::user_frames_only().apply(&trace);
+        assert_eq!(filtered.name(), Some("fizzer"));
+        assert_eq!(filtered.poll_duration(), trace.poll_duration());
+        assert_eq!(filtered.busy_ratio(), trace.busy_ratio());
+        assert!(filtered.to_string().starts_with("Task \"fizzer\" (busy "));
+    }
+
+    async fn fiz() {
+        pending().await;
+    }
+
+    async fn buz() {
+        pending().await;
+    }
+}
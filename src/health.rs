@@ -0,0 +1,187 @@
+//! A single number summarizing how worried to be about a task.
+//!
+//! [`TraceHandle::health`] combines what the poll counters tell about a task with where its trace
+//! shows it parked, so that a dashboard can sort its tasks by the score and show the worst ones
+//! first. The score is a heuristic: a task that waits on a socket for minutes is as stale as one
+//! that is deadlocked, and only the trace tells them apart.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::{TraceError, TraceHandle};
+
+/// How long a task may go without being polled before it counts as stuck.
+const STUCK_AFTER: Duration = Duration::from_secs(60);
+/// The busy ratio from which a task counts as spinning.
+const SPINNING_BUSY_RATIO: f64 = 0.9;
+/// The polls per second from which a task counts as spinning.
+const SPINNING_POLL_RATE: f64 = 1000.0;
+
+impl TraceHandle {
+    /// Captures a backtrace of the task and scores its health from 1.0, nothing to worry about, to
+    /// 0.0, stuck or spinning, see [`HealthReport`].
+    ///
+    /// The score is 1.0 minus the worst of three signals, each between 0.0 and 1.0:
+    ///
+    /// - the staleness of the leaf, the time since the task was last polled over 60 seconds, as it
+    ///   sits at the same leaf until it is polled again,
+    /// - the busy ratio of the task, see [`TraceHandle::busy_ratio`],
+    /// - the poll rate, the polls per second of the task's lifetime over 1000.
+    ///
+    /// The task counts as stuck once it went unpolled for 60 seconds, and as spinning from a busy
+    /// ratio of 0.9 or 1000 polls per second on. The signals are read before the capture, whose
+    /// poll would reset the staleness.
+    pub async fn health(&self) -> Result<HealthReport, TraceError> {
+        let stats = self.stats();
+        let idle = match self.last_polled_at() {
+            Some(at) => self.state.since(at),
+            None => stats.age(),
+        };
+        let busy_ratio = self.busy_ratio();
+        let age = stats.age().as_secs_f64();
+        let poll_rate = if age > 0.0 {
+            stats.polls() as f64 / age
+        } else {
+            0.0
+        };
+        let trace = self.backtrace().await?;
+
+        let staleness = (idle.as_secs_f64() / STUCK_AFTER.as_secs_f64()).min(1.0);
+        let pressure = (poll_rate / SPINNING_POLL_RATE).min(1.0);
+        let worst = staleness.max(busy_ratio).max(pressure);
+        let leaf = trace
+            .parked_at()
+            .and_then(|frame| frame.symbol())
+            .unwrap_or("??");
+        Ok(HealthReport {
+            score: (1.0 - worst) as f32,
+            stuck: idle >= STUCK_AFTER,
+            spinning: busy_ratio >= SPINNING_BUSY_RATIO || poll_rate >= SPINNING_POLL_RATE,
+            leaf: leaf.to_owned(),
+        })
+    }
+}
+
+/// The health of a task, see [`TraceHandle::health`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    score: f32,
+    stuck: bool,
+    spinning: bool,
+    leaf: String,
+}
+
+impl HealthReport {
+    /// The score, from 1.0 for a healthy task to 0.0 for a stuck or spinning one.
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+
+    /// Whether the task went unpolled for so long that it may never be woken again.
+    pub fn stuck(&self) -> bool {
+        self.stuck
+    }
+
+    /// Whether the task is polled so much that it may be busy looping.
+    pub fn spinning(&self) -> bool {
+        self.spinning
+    }
+
+    /// The symbol of the function the task is parked in, `??` if it is unknown.
+    pub fn leaf(&self) -> &str {
+        &self.leaf
+    }
+}
+
+/// Renders the report as `0.42 parked in app::serve` with ` (stuck)` or ` (spinning)` appended
+/// where that applies.
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2} parked in {}", self.score, self.leaf)?;
+        if self.stuck {
+            f.write_str(" (stuck)")?;
+        }
+        if self.spinning {
+            f.write_str(" (spinning)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use super::*;
+    use crate::test_util::MockClock;
+    use crate::traced_with_clock;
+
+    /// Parks, taking `busy` of the clock's time for every poll.
+    struct Parked {
+        clock: MockClock,
+        busy: Duration,
+    }
+
+    impl Future for Parked {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.clock.advance(self.busy);
+            drop(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn health() {
+        let clock = MockClock::new();
+        let parked = Parked {
+            clock: clock.clone(),
+            busy: Duration::ZERO,
+        };
+        let (fut, trace_handle) = traced_with_clock(parked, clock.clone());
+        tokio::spawn(fut);
+        trace_handle.backtrace().await.unwrap();
+
+        clock.advance(Duration::from_secs(15));
+        let health = trace_handle.health().await.unwrap();
+        assert_eq!(health.score(), 0.75);
+        assert!(!health.stuck());
+        assert!(!health.spinning());
+        assert_eq!(
+            health.leaf(),
+            "<tasktrace::health::tests::Parked as core::future::future::Future>::poll"
+        );
+        assert_eq!(
+            health.to_string(),
+            format!("0.75 parked in {}", health.leaf())
+        );
+
+        clock.advance(Duration::from_secs(60));
+        let health = trace_handle.health().await.unwrap();
+        assert_eq!(health.score(), 0.0);
+        assert!(health.stuck());
+    }
+
+    #[tokio::test]
+    async fn spinning() {
+        let clock = MockClock::new();
+        let parked = Parked {
+            clock: clock.clone(),
+            busy: Duration::from_secs(9),
+        };
+        let (fut, trace_handle) = traced_with_clock(parked, clock.clone());
+        tokio::spawn(fut);
+        trace_handle.backtrace().await.unwrap();
+
+        // Busy for 9s of every 10s or more
+        clock.advance(Duration::from_secs(1));
+        let health = trace_handle.health().await.unwrap();
+        assert!(health.score() <= 0.1 + 1e-6);
+        assert!(!health.stuck());
+        assert!(health.spinning());
+        assert!(health.to_string().ends_with(" (spinning)"));
+    }
+}
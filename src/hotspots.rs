@@ -0,0 +1,304 @@
+//! Where many samples of tasks are waiting.
+//!
+//! A single trace tells where a task is parked right now. Traces sampled over time, e.g. through
+//! [`traced_every_n_polls`](crate::traced_every_n_polls), or captured from many tasks at once tell
+//! where the tasks spend their time waiting. [`Aggregator`] counts the await points of the leaves
+//! across such samples and ranks them, and sums up their [folded](crate::fold) stacks for
+//! flamegraphs.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::render::is_dependency;
+use crate::{branches, fold, Frame, Precision, Trace};
+
+/// Counts the await points that the leaves of many traces wait at.
+///
+/// ```rust
+/// # fn report(traces: Vec<tasktrace::Trace>) {
+/// let mut aggregator = tasktrace::Aggregator::new();
+/// for trace in &traces {
+///     aggregator.add(trace);
+/// }
+/// eprintln!("{aggregator}");
+/// # }
+/// ```
+///
+/// The await point of a leaf is the innermost frame of the application on its branch, since the
+/// frames below it belong to the runtime or the libraries that implement the leaf future. Which
+/// frames belong to dependencies is decided as for [`render_colored`](crate::render::render_colored).
+/// Await points are told apart by their function, line and column, unless
+/// [`Aggregator::precision`] says otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct Aggregator {
+    hot_spots: Vec<HotSpot>,
+    samples: usize,
+    leaves: usize,
+    precision: Precision,
+    /// The folded stacks of the samples along with how many samples had them.
+    stacks: BTreeMap<String, usize>,
+}
+
+/// An await point along with how many leaves waited at it, see [`Aggregator`].
+#[derive(Debug, Clone)]
+pub struct HotSpot {
+    frame: Frame,
+    count: usize,
+    total: usize,
+}
+
+impl Aggregator {
+    /// Creates an aggregator that has seen no traces.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the await points that match at `precision` together, e.g. all the awaits of a
+    /// function with [`Precision::Symbol`]. A merged await point shows the location at which it
+    /// was first seen.
+    pub fn precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Counts the leaves of `trace`. Identical leaves, e.g. of a `join_all` over equal futures,
+    /// count individually.
+    pub fn add(&mut self, trace: &Trace) {
+        self.samples += 1;
+        for line in fold(trace).lines() {
+            let stack = line.strip_suffix(" 1").unwrap_or(line);
+            *self.stacks.entry(stack.to_owned()).or_default() += 1;
+        }
+        for branch in branches(trace) {
+            let point = branch
+                .frames()
+                .rev()
+                .find(|frame| frame.file().is_some_and(|file| !is_dependency(file)))
+                .unwrap_or_else(|| branch.leaf());
+            let count = branch.leaf().branches();
+            self.leaves += count;
+            match self
+                .hot_spots
+                .iter_mut()
+                .find(|hot_spot| self.precision.same_point(&hot_spot.frame, point))
+            {
+                Some(hot_spot) => hot_spot.count += count,
+                None => self.hot_spots.push(HotSpot {
+                    frame: point.with_children(Vec::new()),
+                    count,
+                    total: 0,
+                }),
+            }
+        }
+    }
+
+    /// The number of traces added so far.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// The await points, the one with the most leaves first.
+    pub fn hot_spots(&self) -> Vec<HotSpot> {
+        let mut hot_spots = self.hot_spots.clone();
+        for hot_spot in &mut hot_spots {
+            hot_spot.total = self.leaves;
+        }
+        // Stable, so await points with the same count keep the order in which they were first seen
+        hot_spots.sort_by_key(|hot_spot| std::cmp::Reverse(hot_spot.count));
+        hot_spots
+    }
+
+    /// Renders the samples in the folded stack format, like [`fold`] does for a single trace, with
+    /// the number of samples that had each stack as its count. The stacks are sorted.
+    pub fn fold(&self) -> String {
+        let mut folded = String::new();
+        for (stack, count) in &self.stacks {
+            folded.push_str(stack);
+            folded.push_str(&format!(
+                " {count}
+"
+            ));
+        }
+        folded
+    }
+
+    /// Renders the samples as a flamegraph through `inferno`, from the stacks of
+    /// [`Aggregator::fold`], so that collected samples become a picture without running
+    /// `flamegraph.pl` on them. An aggregator without samples renders a flamegraph that says so.
+    /// Requires the `flamegraph` feature.
+    #[cfg(feature = "flamegraph")]
+    pub fn to_flamegraph_svg(&self) -> String {
+        let mut options = inferno::flamegraph::Options::default();
+        options.title = "Await points".to_owned();
+        options.count_name = "samples".to_owned();
+        let mut svg = Vec::new();
+        let folded = self.fold();
+        // Only fails on empty input, after rendering an SVG with the error message
+        let _ = inferno::flamegraph::from_lines(&mut options, folded.lines(), &mut svg);
+        String::from_utf8(svg).expect("inferno renders UTF-8")
+    }
+}
+
+impl HotSpot {
+    /// The frame of the await point, without its children.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// The number of leaves that waited at the await point.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The share of all the leaves counted by the aggregator that waited at the await point, in
+    /// percent.
+    pub fn percent(&self) -> f64 {
+        100.0 * self.count as f64 / self.total.max(1) as f64
+    }
+}
+
+/// Renders a `N samples, M leaves` line followed by one `  42.0%  120  frame` line per await
+/// point.
+impl fmt::Display for Aggregator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} samples, {} leaves", self.samples, self.leaves)?;
+        for hot_spot in self.hot_spots() {
+            writeln!(
+                f,
+                "{:>6.1}% {:>6}  {}",
+                hot_spot.percent(),
+                hot_spot.count,
+                hot_spot.frame
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::frame_at;
+    use crate::traced;
+
+    #[tokio::test]
+    async fn hot_spots() {
+        let mut aggregator = Aggregator::new();
+        for _ in 0..3 {
+            let (fut, trace_handle) = traced(async { futures::join!(first(), first(), second()) });
+            tokio::spawn(fut);
+            aggregator.add(&trace_handle.backtrace().await.unwrap());
+        }
+        assert_eq!(aggregator.samples(), 3);
+
+        let hot_spots = aggregator.hot_spots();
+        assert_eq!(hot_spots.len(), 2);
+        assert!(hot_spots[0]
+            .frame()
+            .symbol()
+            .unwrap()
+            .starts_with("tasktrace::hotspots::tests::first::{{closure}}"));
+        assert_eq!(hot_spots[0].count(), 6);
+        assert_eq!(hot_spots[1].count(), 3);
+        assert!((hot_spots[0].percent() - 200.0 / 3.0).abs() < 1e-9);
+
+        let rendered = aggregator.to_string();
+        assert!(rendered.starts_with("3 samples, 9 leaves\n  66.7%      6  tasktrace::hotspots::"));
+    }
+
+    #[tokio::test]
+    async fn backtrace_into_aggregator() {
+        let mut aggregator = Aggregator::new();
+        let (fut, trace_handle) = traced(async { futures::join!(first(), second()) });
+        tokio::spawn(fut);
+        for _ in 0..2 {
+            trace_handle
+                .backtrace_into_aggregator(&mut aggregator)
+                .await
+                .unwrap();
+        }
+        assert_eq!(aggregator.samples(), 2);
+        let counts: Vec<_> = aggregator.hot_spots().iter().map(HotSpot::count).collect();
+        assert_eq!(counts, [2, 2]);
+    }
+
+    #[test]
+    fn fold() {
+        let sample = |leaf| {
+            Trace::from_roots(vec![frame_at(
+                "app::serve",
+                1,
+                1,
+                vec![frame_at(leaf, 10, 5, Vec::new())],
+            )])
+        };
+        let mut aggregator = Aggregator::new();
+        for leaf in ["app::write", "app::read", "app::read"] {
+            aggregator.add(&sample(leaf));
+        }
+        assert_eq!(
+            aggregator.fold(),
+            "app::serve;app::read 2\napp::serve;app::write 1\n"
+        );
+
+        #[cfg(feature = "flamegraph")]
+        {
+            let svg = aggregator.to_flamegraph_svg();
+            assert!(svg.starts_with("<?xml"));
+            assert!(svg.contains("app::read (2 samples, 66.67%)"));
+            assert!(Aggregator::new().to_flamegraph_svg().contains("ERROR"));
+        }
+    }
+
+    #[test]
+    fn precision() {
+        // The same function waits at two columns of one line in one sample, and at another line in
+        // the other
+        let samples = [
+            Trace::from_roots(vec![frame_at(
+                "app::serve",
+                1,
+                1,
+                vec![
+                    frame_at("app::read", 10, 5, Vec::new()),
+                    frame_at("app::read", 10, 9, Vec::new()),
+                ],
+            )]),
+            Trace::from_roots(vec![frame_at(
+                "app::serve",
+                1,
+                1,
+                vec![frame_at("app::read", 12, 5, Vec::new())],
+            )]),
+        ];
+        let counts = |precision| {
+            let mut aggregator = Aggregator::new().precision(precision);
+            for trace in &samples {
+                aggregator.add(trace);
+            }
+            let hot_spots = aggregator.hot_spots();
+            hot_spots.iter().map(HotSpot::count).collect::<Vec<_>>()
+        };
+        assert_eq!(counts(Precision::default()), [1, 1, 1]);
+        assert_eq!(counts(Precision::SymbolLine), [2, 1]);
+        assert_eq!(counts(Precision::Symbol), [3]);
+    }
+
+    async fn first() {
+        let mut waker = None;
+        std::future::poll_fn(|cx| {
+            waker = Some(cx.waker().clone());
+            std::task::Poll::Pending
+        })
+        .await
+    }
+
+    async fn second() {
+        let mut waker = None;
+        std::future::poll_fn(|cx| {
+            waker = Some(cx.waker().clone());
+            std::task::Poll::Pending
+        })
+        .await
+    }
+}
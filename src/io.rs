@@ -0,0 +1,186 @@
+//! Tracing I/O objects.
+//!
+//! A task blocked on a socket is traced like any other, but when it is the socket that looks
+//! stuck it is more convenient to ask the socket. [`traced_io`] wraps an I/O object so that its
+//! handle captures a trace of whatever the object is waiting on the next time a read or write on
+//! it has to wait.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use pin_project_lite::pin_project;
+
+use crate::{
+    traced_with_state, CaptureLimits, SystemClock, Trace, TraceHandle, TracedTask, TracedWaker,
+};
+
+pin_project! {
+    /// An I/O object traced with [`traced_io`].
+    pub struct TracedIo<T> {
+        #[pin]
+        task: TracedTask<T>,
+    }
+}
+
+/// Like [`traced`](crate::traced) but for an I/O object, implementing the `AsyncRead` and
+/// `AsyncWrite` traits of tokio with the `tokio` feature and the ones of `futures-io` with the
+/// `futures-io` feature, as long as the wrapped object implements them.
+///
+/// ```rust
+/// # async fn example() {
+/// use tokio::io::AsyncReadExt;
+///
+/// let (_client, server) = tokio::io::duplex(64);
+/// let (mut server, trace_handle) = tasktrace::traced_io(server);
+/// tokio::spawn(async move { server.read_u8().await });
+/// println!("{}", trace_handle.backtrace().await.unwrap());
+/// # }
+/// ```
+///
+/// Requests are answered during the next read or write that returns `Poll::Pending`, with a trace
+/// of the leaves of the object that registered the waker. Reads and writes that are ready right
+/// away leave the requests waiting. The object is traced from whichever task polls it, and the
+/// handle keeps working for as long as the object is alive.
+pub fn traced_io<T>(io: T) -> (TracedIo<T>, TraceHandle) {
+    let (task, handle) = traced_with_state(io, None, false, Arc::new(SystemClock));
+    (TracedIo { task }, handle)
+}
+
+impl<T> TracedIo<T> {
+    /// Returns another handle to the object, like the one returned along with it.
+    pub fn handle(&self) -> TraceHandle {
+        self.task.handle()
+    }
+
+    /// Returns a reference to the wrapped object.
+    pub fn get_ref(&self) -> &T {
+        &self.task.fut
+    }
+
+    /// Returns the wrapped object, which is no longer traced, see [`TracedTask::into_inner`].
+    pub fn into_inner(self) -> T {
+        self.task.into_inner()
+    }
+
+    /// Polls the wrapped object with `poll`, capturing a trace of the poll when one is due.
+    fn poll_io<A, R>(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        arg: A,
+        poll: impl Fn(Pin<&mut T>, &mut Context<'_>, A) -> Poll<R>,
+    ) -> Poll<R> {
+        self.project().task.poll_with(
+            cx,
+            arg,
+            &poll,
+            |io, waker, limits, arg| capture_io(io, waker, limits, |io, cx| poll(io, cx, arg)),
+            |_| false,
+        )
+    }
+}
+
+/// Like [`capture`](crate::capture) but polls `io` with `poll`.
+fn capture_io<T, R>(
+    io: Pin<&mut T>,
+    waker: &Waker,
+    limits: &CaptureLimits,
+    poll: impl FnOnce(Pin<&mut T>, &mut Context<'_>) -> Poll<R>,
+) -> (Poll<R>, Trace) {
+    let trace_waker = TracedWaker(waker);
+    let waker = trace_waker.waker();
+    let mut traced_cx = Context::from_waker(&waker);
+    Trace::root(|| poll(io, &mut traced_cx), limits)
+}
+
+#[cfg(feature = "tokio")]
+impl<T: tokio::io::AsyncRead> tokio::io::AsyncRead for TracedIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_io(cx, buf, |io, cx, buf| io.poll_read(cx, buf))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: tokio::io::AsyncWrite> tokio::io::AsyncWrite for TracedIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_io(cx, buf, |io, cx, buf| io.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_io(cx, (), |io, cx, ()| io.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_io(cx, (), |io, cx, ()| io.poll_shutdown(cx))
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: futures_io::AsyncRead> futures_io::AsyncRead for TracedIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_io(cx, buf, |io, cx, buf| io.poll_read(cx, buf))
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: futures_io::AsyncWrite> futures_io::AsyncWrite for TracedIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_io(cx, buf, |io, cx, buf| io.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_io(cx, (), |io, cx, ()| io.poll_flush(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_io(cx, (), |io, cx, ()| io.poll_close(cx))
+    }
+}
+
+#[cfg(all(test, feature = "enabled", feature = "tokio"))]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn read() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let (mut server, trace_handle) = traced_io(server);
+        let read = tokio::spawn(async move {
+            let first = server.read_u8().await.unwrap();
+            (first, server.read_u8().await.unwrap())
+        });
+
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.starts_with(
+            "╼ <tokio::io::util::mem::DuplexStream as tokio::io::async_read::AsyncRead>::poll_read"
+        ));
+        // Reads that don't have to wait don't answer the request
+        let next = tokio::spawn({
+            let trace_handle = trace_handle.clone();
+            async move { trace_handle.backtrace().await }
+        });
+        client.write_all(&[1, 2]).await.unwrap();
+        assert_eq!(read.await.unwrap(), (1, 2));
+        // The object was dropped without having to wait again
+        assert!(next.await.unwrap().is_err());
+    }
+}
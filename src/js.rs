@@ -0,0 +1,217 @@
+//! Capturing stacks through JavaScript on wasm32.
+//!
+//! `backtrace` can't walk the stack of a WebAssembly module, since the stack is the engine's. The
+//! engine describes it in the `stack` property of every `Error` though, naming the functions of
+//! the module after its name section, which is kept unless the module is stripped. With the `wasm`
+//! feature, leaves parse it instead.
+//!
+//! Functions are told apart by their names, so the frames of a trace carry no addresses and the
+//! frames of WebAssembly functions no source locations.
+
+/// A frame of the stack as `Error.stack` describes it, innermost first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StackFrame {
+    /// The demangled name of the function, without its hash.
+    pub(crate) symbol: Option<String>,
+    /// The URL of the script, for frames of JavaScript functions.
+    pub(crate) file: Option<String>,
+    pub(crate) line: Option<u32>,
+    pub(crate) col: Option<u32>,
+}
+
+const LEAF: &str = "tasktrace::trace::Trace::leaf";
+const HERE: &str = "tasktrace::trace::Trace::here";
+const ROOT: &str = "tasktrace::trace::Trace::root_inner";
+
+/// The frames between `Trace::leaf` and the roots of `captures` nested
+/// captures along with where each root is, like the walk of the native `Trace::leaf`, or `None`
+/// if this is the call of an outer capture's waker that the inner one already recorded.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn walk_leaf(captures: usize) -> Option<(Vec<StackFrame>, Vec<usize>)> {
+    let stack = stack();
+    let mut frames = Vec::new();
+    let mut ends = Vec::with_capacity(captures);
+    let mut above = stack.into_iter().skip_while(|frame| !is_fn(frame, LEAF));
+    above.next()?;
+    let mut caller = None;
+    for frame in above {
+        match &caller {
+            None => caller = Some(frame.symbol.clone()),
+            // The waker of an inner capture wraps the one of an outer capture and calls into it
+            Some(caller) if ends.is_empty() && caller.is_some() && *caller == frame.symbol => {
+                return None
+            }
+            Some(_) => {}
+        }
+        if is_fn(&frame, ROOT) {
+            ends.push(frames.len());
+            if ends.len() == captures {
+                break;
+            }
+            continue;
+        }
+        frames.push(frame);
+    }
+    Some((frames, ends))
+}
+
+/// The frames above `Trace::here`, innermost first.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn walk_here() -> Vec<StackFrame> {
+    stack()
+        .into_iter()
+        .skip_while(|frame| !is_fn(frame, HERE))
+        .skip(1)
+        .collect()
+}
+
+/// Reads the stack of the current thread out of a new `Error`.
+#[cfg(target_arch = "wasm32")]
+fn stack() -> Vec<StackFrame> {
+    use js_sys::{Error, Reflect};
+    use wasm_bindgen::JsValue;
+
+    // V8 only describes the innermost 10 frames by default, far fewer than a trace spans
+    let constructor = JsValue::from(Error::new("").constructor());
+    let limit = JsValue::from_str("stackTraceLimit");
+    let previous = Reflect::get(&constructor, &limit).unwrap_or(JsValue::UNDEFINED);
+    let _ = Reflect::set(&constructor, &limit, &JsValue::from_f64(f64::INFINITY));
+    let error = Error::new("");
+    let _ = Reflect::set(&constructor, &limit, &previous);
+    Reflect::get(&error, &JsValue::from_str("stack"))
+        .ok()
+        .and_then(|stack| stack.as_string())
+        .map_or_else(Vec::new, |stack| parse(&stack))
+}
+
+/// Returns whether `frame` belongs to the function at `path`, whether or not the engine shows
+/// its generic arguments.
+fn is_fn(frame: &StackFrame, path: &str) -> bool {
+    frame.symbol.as_deref().is_some_and(|symbol| {
+        symbol
+            .strip_prefix(path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::<"))
+    })
+}
+
+/// Parses the value of `Error.stack`, in the format of V8 (`    at name (url:line:col)`) or of
+/// SpiderMonkey and JavaScriptCore (`name@url:line:col`).
+pub(crate) fn parse(stack: &str) -> Vec<StackFrame> {
+    stack
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (symbol, location) = if let Some(frame) = line.strip_prefix("at ") {
+                match frame
+                    .strip_suffix(')')
+                    .and_then(|frame| frame.split_once(" ("))
+                {
+                    Some((symbol, location)) => (Some(symbol), location),
+                    None => (None, frame),
+                }
+            } else {
+                let (symbol, location) = line.rsplit_once('@')?;
+                ((!symbol.is_empty()).then_some(symbol), location)
+            };
+            Some(parse_frame(symbol, location))
+        })
+        .collect()
+}
+
+fn parse_frame(symbol: Option<&str>, location: &str) -> StackFrame {
+    let symbol = symbol.map(demangle);
+    // WebAssembly functions are located by their index and offset within the module
+    if location.contains(":wasm-function[") {
+        return StackFrame {
+            symbol,
+            file: None,
+            line: None,
+            col: None,
+        };
+    }
+    let mut parts = location.rsplitn(3, ':');
+    let col = parts.next().and_then(|col| col.parse().ok());
+    let line = parts.next().and_then(|line| line.parse().ok());
+    match (parts.next(), line, col) {
+        (Some(file), Some(line), Some(col)) => StackFrame {
+            symbol,
+            file: Some(file.to_owned()),
+            line: Some(line),
+            col: Some(col),
+        },
+        _ => StackFrame {
+            symbol,
+            file: (!location.is_empty()).then(|| location.to_owned()),
+            line: None,
+            col: None,
+        },
+    }
+}
+
+/// Demangles the name of a function and drops its hash. V8 prefixes the names of WebAssembly
+/// functions with the module, and wasm-bindgen demangles them unless told not to.
+fn demangle(symbol: &str) -> String {
+    let symbol = symbol
+        .split_once(".wasm.")
+        .map_or(symbol, |(_, symbol)| symbol);
+    match rustc_demangle::try_demangle(symbol) {
+        Ok(demangled) => format!("{demangled:#}"),
+        Err(_) => {
+            let hash = symbol.rsplit_once("::h").filter(|(_, hash)| {
+                hash.len() == 16 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+            });
+            hash.map_or(symbol, |(symbol, _)| symbol).to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_v8() {
+        let stack = "Error\n    at imports.wbg.__wbg_new_8a6f238a6ece86ea (http://localhost:8080/app.js:301:21)\n    at app_bg.wasm.tasktrace::trace::Trace::leaf::h0123456789abcdef (wasm://wasm/app_bg-0012abcd:wasm-function[412]:0x3f4a1)\n    at app_bg.wasm._ZN3app6worker28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE (wasm://wasm/app_bg-0012abcd:wasm-function[97]:0x1234)\n    at http://localhost:8080/snippets/glue.js:12:5";
+        assert_eq!(
+            parse(stack),
+            [
+                StackFrame {
+                    symbol: Some("imports.wbg.__wbg_new_8a6f238a6ece86ea".to_owned()),
+                    file: Some("http://localhost:8080/app.js".to_owned()),
+                    line: Some(301),
+                    col: Some(21),
+                },
+                StackFrame {
+                    symbol: Some(LEAF.to_owned()),
+                    file: None,
+                    line: None,
+                    col: None,
+                },
+                StackFrame {
+                    symbol: Some("app::worker::{{closure}}".to_owned()),
+                    file: None,
+                    line: None,
+                    col: None,
+                },
+                StackFrame {
+                    symbol: None,
+                    file: Some("http://localhost:8080/snippets/glue.js".to_owned()),
+                    line: Some(12),
+                    col: Some(5),
+                },
+            ]
+        );
+        assert!(is_fn(&parse(stack)[1], LEAF));
+    }
+
+    #[test]
+    fn parse_spidermonkey() {
+        let stack = "tasktrace::trace::Trace::root_inner::h0123456789abcdef@http://localhost:8080/app_bg.wasm:wasm-function[388]:0x3e0c2\n@http://localhost:8080/index.js:4:1\n";
+        let frames = parse(stack);
+        assert_eq!(frames.len(), 2);
+        assert!(is_fn(&frames[0], ROOT));
+        assert_eq!(frames[0].file, None);
+        assert_eq!(frames[1].symbol, None);
+        assert_eq!(frames[1].line, Some(4));
+    }
+}
@@ -0,0 +1,485 @@
+//! Labelling the branches of combinators.
+//!
+//! A `join!` or `select!` shows up in a trace as a frame with one child per branch, but the
+//! frames of the branches are the closures and generic polls that the macros expand to, which
+//! don't tell the branches apart. A future wrapped in [`labelled`] is shown as a frame named after
+//! its label instead, and [`join!`](crate::join), [`traced_select`] and, with the `tokio` feature,
+//! [`traced_timeout`] label their branches without any other frames of their own:
+//!
+//! ```text
+//! ╼ server::handle::{{closure}} at src/server.rs:42:25
+//!   ├╼ fetch user
+//!   │  └╼ server::fetch_user::{{closure}} at src/server.rs:61:33
+//!   └╼ fetch orders
+//!      └╼ server::fetch_orders::{{closure}} at src/server.rs:70:17
+//! ```
+//!
+//! [`annotated`] does the same with a label built at runtime, for context that symbols can't
+//! convey, like the id of the request being handled.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+thread_local! {
+    /// The labels of the [`Labelled`] and [`Annotated`] futures being polled on this thread,
+    /// outermost first.
+    static CURRENT_LABELS: RefCell<Vec<Label>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The label of a [`Labelled`] or an [`Annotated`] future.
+#[derive(Clone)]
+pub(crate) enum Label {
+    Static(&'static str),
+    Annotation(Arc<str>),
+}
+
+impl Label {
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Label::Static(label) => label,
+            Label::Annotation(label) => label,
+        }
+    }
+}
+
+/// Pushes `label` until the returned guard is dropped.
+fn push(label: Label) -> Pop {
+    CURRENT_LABELS.with(|labels| labels.borrow_mut().push(label));
+    Pop
+}
+
+struct Pop;
+
+impl Drop for Pop {
+    fn drop(&mut self) {
+        CURRENT_LABELS.with(|labels| labels.borrow_mut().pop());
+    }
+}
+
+/// The number of labelled futures that are currently being polled.
+pub(crate) fn depth() -> usize {
+    CURRENT_LABELS.with(|labels| labels.borrow().len())
+}
+
+/// The labels of the futures that are currently being polled, outermost first.
+pub(crate) fn current() -> Vec<Label> {
+    CURRENT_LABELS.with(|labels| labels.borrow().clone())
+}
+
+/// Returns whether `symbol` is the poll of a [`Labelled`] or an [`Annotated`] future, which
+/// traces show as its label.
+pub(crate) fn is_labelled_poll(symbol: &str) -> bool {
+    (symbol.starts_with("<tasktrace::label::Labelled<")
+        || symbol.starts_with("<tasktrace::label::Annotated<"))
+        && symbol.ends_with(">::poll")
+}
+
+/// Returns whether `symbol` is the poll of one of the combinators of this module, which traces
+/// leave out so that the labels of their branches take their place.
+pub(crate) fn is_combinator_poll(symbol: &str) -> bool {
+    ["Branch<", "Join", "Select<"].iter().any(|name| {
+        symbol
+            .strip_prefix("<tasktrace::label::")
+            .is_some_and(|symbol| symbol.starts_with(name))
+    }) && symbol.ends_with(">::poll")
+}
+
+/// Shows `fut` in traces as a frame named `label`, with the frames of `fut` below it.
+///
+/// ```rust
+/// # async fn example(jobs: futures::channel::oneshot::Receiver<u32>) {
+/// let job = tasktrace::labelled("next job", jobs).await;
+/// # }
+/// ```
+///
+/// The wrapper costs a thread local access per poll, whether the task is traced or not.
+pub fn labelled<F: Future>(label: &'static str, fut: F) -> Labelled<F> {
+    Labelled { fut, label }
+}
+
+pin_project! {
+    /// The future returned by [`labelled`].
+    #[must_use = "futures do nothing unless polled"]
+    pub struct Labelled<F> {
+        #[pin]
+        fut: F,
+        label: &'static str,
+    }
+}
+
+impl<F: Future> Future for Labelled<F> {
+    type Output = F::Output;
+
+    // Never inlined so that the frame that stands for the label exists
+    #[inline(never)]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let _pop = push(Label::Static(this.label));
+        this.fut.poll(cx)
+    }
+}
+
+/// Shows `fut` in traces as a frame named `label`, like [`labelled`] but with a label built at
+/// runtime.
+///
+/// ```rust
+/// # async fn process(order: u64) {}
+/// # async fn example(order: u64) {
+/// tasktrace::annotated(format!("processing order {order}"), process(order)).await;
+/// # }
+/// ```
+///
+/// The label is allocated once, and the wrapper costs a thread local access and a reference count
+/// increment per poll, whether the task is traced or not.
+pub fn annotated<F: Future>(label: impl Into<String>, fut: F) -> Annotated<F> {
+    Annotated {
+        fut,
+        label: Arc::from(label.into()),
+    }
+}
+
+pin_project! {
+    /// The future returned by [`annotated`].
+    #[must_use = "futures do nothing unless polled"]
+    pub struct Annotated<F> {
+        #[pin]
+        fut: F,
+        label: Arc<str>,
+    }
+}
+
+impl<F: Future> Future for Annotated<F> {
+    type Output = F::Output;
+
+    // Never inlined so that the frame that stands for the label exists
+    #[inline(never)]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let _pop = push(Label::Annotation(Arc::clone(this.label)));
+        this.fut.poll(cx)
+    }
+}
+
+/// Waits for all the futures, labelling the branch of each in traces like [`labelled`], and
+/// returns a tuple of their outputs.
+///
+/// ```rust
+/// # async fn fetch_user() -> u32 { 1 }
+/// # async fn fetch_orders() -> Vec<u32> { vec![] }
+/// # async fn example() {
+/// let (user, orders) = tasktrace::join!(
+///     "fetch user" => fetch_user(),
+///     "fetch orders" => fetch_orders(),
+/// );
+/// # }
+/// ```
+///
+/// Like `futures::join!` the futures are polled concurrently from the task that awaits them, in
+/// the order they are given. Up to 8 futures can be joined.
+#[macro_export]
+// The frame that awaits the futures points at the invocation rather than at this definition
+#[collapse_debuginfo(yes)]
+macro_rules! join {
+    ($($label:expr => $fut:expr),+ $(,)?) => {
+        $crate::__private::join(($($crate::__private::Branch::new($label, $fut),)+)).await
+    };
+}
+
+pin_project! {
+    /// A future of a [`join!`](crate::join) and, once it completed, its output.
+    #[doc(hidden)]
+    #[project = BranchProj]
+    pub enum Branch<F: Future> {
+        Pending { #[pin] fut: Labelled<F> },
+        Done { output: Option<F::Output> },
+    }
+}
+
+impl<F: Future> Branch<F> {
+    pub fn new(label: &'static str, fut: F) -> Self {
+        Branch::Pending {
+            fut: labelled(label, fut),
+        }
+    }
+
+    /// Takes the output of the completed future.
+    fn take(self: Pin<&mut Self>) -> F::Output {
+        match self.project() {
+            BranchProj::Done { output } => output.take().expect("output already taken"),
+            BranchProj::Pending { .. } => unreachable!("the future didn't complete"),
+        }
+    }
+}
+
+impl<F: Future> Future for Branch<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if let BranchProj::Pending { fut } = self.as_mut().project() {
+            let output = std::task::ready!(fut.poll(cx));
+            self.set(Branch::Done {
+                output: Some(output),
+            });
+        }
+        Poll::Ready(())
+    }
+}
+
+/// The tuples of [`Branch`]es that [`join!`](crate::join) can wait for.
+#[doc(hidden)]
+pub trait Branches {
+    type Join: Future;
+
+    fn join(self) -> Self::Join;
+}
+
+#[doc(hidden)]
+pub fn join<B: Branches>(branches: B) -> B::Join {
+    branches.join()
+}
+
+macro_rules! impl_join {
+    ($($join:ident($($fut:ident: $F:ident),+);)+) => {$(
+        pin_project! {
+            #[doc(hidden)]
+            #[must_use = "futures do nothing unless polled"]
+            pub struct $join<$($F: Future),+> {
+                $(#[pin] $fut: Branch<$F>,)+
+            }
+        }
+
+        impl<$($F: Future),+> Branches for ($(Branch<$F>,)+) {
+            type Join = $join<$($F),+>;
+
+            fn join(self) -> Self::Join {
+                let ($($fut,)+) = self;
+                $join { $($fut),+ }
+            }
+        }
+
+        impl<$($F: Future),+> Future for $join<$($F),+> {
+            type Output = ($($F::Output,)+);
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                let mut this = self.project();
+                let mut done = true;
+                $(done &= this.$fut.as_mut().poll(cx).is_ready();)+
+                if !done {
+                    return Poll::Pending;
+                }
+                Poll::Ready(($(this.$fut.take(),)+))
+            }
+        }
+    )+};
+}
+
+impl_join! {
+    Join1(a: A);
+    Join2(a: A, b: B);
+    Join3(a: A, b: B, c: C);
+    Join4(a: A, b: B, c: C, d: D);
+    Join5(a: A, b: B, c: C, d: D, e: E);
+    Join6(a: A, b: B, c: C, d: D, e: E, f: F);
+    Join7(a: A, b: B, c: C, d: D, e: E, f: F, g: G);
+    Join8(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H);
+}
+
+/// Waits for the first of two futures to complete and returns its output, dropping the other one.
+/// The branch of each is labelled in traces like [`labelled`].
+///
+/// ```rust
+/// # async fn recv() -> Option<u32> { None }
+/// # async fn shutdown() {}
+/// # async fn example() {
+/// let job = tasktrace::traced_select(
+///     "next job",
+///     recv(),
+///     "shutdown",
+///     async { shutdown().await; None },
+/// )
+/// .await;
+/// # }
+/// ```
+///
+/// Both futures have the same output, so futures of different outputs are mapped to a common one
+/// first, like the branches of `tokio::select!`. When both are ready, `a` wins.
+pub fn traced_select<A, B>(label_a: &'static str, a: A, label_b: &'static str, b: B) -> Select<A, B>
+where
+    A: Future,
+    B: Future<Output = A::Output>,
+{
+    Select {
+        a: labelled(label_a, a),
+        b: labelled(label_b, b),
+    }
+}
+
+pin_project! {
+    /// The future returned by [`traced_select`].
+    #[must_use = "futures do nothing unless polled"]
+    pub struct Select<A, B> {
+        #[pin]
+        a: Labelled<A>,
+        #[pin]
+        b: Labelled<B>,
+    }
+}
+
+impl<A, B> Future for Select<A, B>
+where
+    A: Future,
+    B: Future<Output = A::Output>,
+{
+    type Output = A::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<A::Output> {
+        let this = self.project();
+        if let Poll::Ready(output) = this.a.poll(cx) {
+            return Poll::Ready(output);
+        }
+        this.b.poll(cx)
+    }
+}
+
+/// Like `tokio::time::timeout`, but labels the branch of `fut` in traces like [`labelled`].
+///
+/// ```rust
+/// # async fn fetch_user() -> u32 { 1 }
+/// # async fn example() {
+/// use std::time::Duration;
+///
+/// let user = tasktrace::traced_timeout("fetch user", Duration::from_secs(1), fetch_user()).await;
+/// # }
+/// ```
+///
+/// The timer shows up next to the branch of `fut`, below tokio's `Timeout`.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, like `tokio::time::timeout`.
+#[cfg(feature = "tokio")]
+pub fn traced_timeout<F: Future>(
+    label: &'static str,
+    duration: std::time::Duration,
+    fut: F,
+) -> tokio::time::Timeout<Labelled<F>> {
+    tokio::time::timeout(duration, labelled(label, fut))
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+    use crate::{branches, traced, Frame};
+
+    #[tokio::test]
+    async fn join() {
+        let (fut, trace_handle) = traced(async {
+            crate::join!(
+                "first" => pending(),
+                "second" => async { crate::join!("inner" => pending()) },
+                "third" => pending(),
+            )
+        });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let labels: Vec<Vec<_>> = branches(&trace)
+            .map(|branch| {
+                branch
+                    .frames()
+                    .filter(|frame| frame.file().is_none())
+                    .filter_map(Frame::symbol)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            [vec!["first"], vec!["second", "inner"], vec!["third"]]
+        );
+        let rendered = trace.to_string();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert!(lines[0].starts_with("╼ tasktrace::label::tests::join::{{closure}}"));
+        assert_eq!(lines[1], "  ├╼ first");
+        assert!(!rendered.contains("Join"));
+        assert!(!rendered.contains("Labelled"));
+        assert!(current().is_empty());
+    }
+
+    #[tokio::test]
+    async fn annotated() {
+        let order = 42;
+        let (fut, trace_handle) = traced(async move {
+            labelled(
+                "orders",
+                super::annotated(format!("processing order {order}"), pending()),
+            )
+            .await
+        });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let labels: Vec<_> = branches(&trace)
+            .next()
+            .unwrap()
+            .frames()
+            .filter(|frame| frame.file().is_none())
+            .filter_map(Frame::symbol)
+            .collect();
+        assert_eq!(labels, ["orders", "processing order 42"]);
+        assert!(!trace.to_string().contains("Annotated"));
+    }
+
+    #[tokio::test]
+    async fn select() {
+        let (fut, trace_handle) =
+            traced(async { traced_select("left", pending(), "right", pending()).await });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let rendered = trace.to_string();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines[1], "  ├╼ left");
+        assert!(lines.contains(&"  └╼ right"));
+        assert!(!rendered.contains("Select"));
+
+        let (tx, rx) = futures::channel::oneshot::channel();
+        tx.send(7).unwrap();
+        let first = traced_select(
+            "pending",
+            async {
+                pending().await;
+                0
+            },
+            "ready",
+            async { rx.await.unwrap() },
+        );
+        assert_eq!(first.await, 7);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn timeout() {
+        let (fut, trace_handle) = traced(traced_timeout(
+            "slow call",
+            std::time::Duration::from_secs(1),
+            pending(),
+        ));
+        let task = tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(
+            branches(&trace)
+                .filter(|branch| branch
+                    .frames()
+                    .any(|frame| frame.symbol() == Some("slow call")))
+                .count(),
+            1
+        );
+        assert!(task.await.unwrap().is_err());
+    }
+}
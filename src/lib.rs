@@ -13,7 +13,10 @@
 //!     let (foo_fut, trace_handle) = tasktrace::traced(foo());
 //!     tokio::spawn(foo_fut);
 //!
-//!     println!("{}", trace_handle.backtrace().await.unwrap());
+//!     match trace_handle.backtrace().await {
+//!         Ok(trace) => println!("{trace}"),
+//!         Err(err) => println!("no trace: {err}"),
+//!     }
 //! }
 //!
 //! async fn pending() {
@@ -50,27 +53,24 @@
 //! This example program will print out something along the lines of:
 //!
 //! ```text
-//! ╼ <tasktrace::TracedTask<F> as core::future::future::Future>::poll::{{closure}} at /home/petrosagg/projects/tasktrace/src/lib.rs:116:50
-//!  └╼ taskdump::foo::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:18:11
-//!     └╼ taskdump::bar::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:22:5
-//!        └╼ <tokio::future::poll_fn::PollFn<F> as core::future::future::Future>::poll at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/future/poll_fn.rs:58:9
-//!           ├╼ taskdump::bar::{{closure}}::{{closure}} at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/macros/join.rs:126:24
-//!           │  └╼ <tokio::future::maybe_done::MaybeDone<Fut> as core::future::future::Future>::poll at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/future/maybe_done.rs:68:48
-//!           │     └╼ taskdump::fiz::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:26:15
-//!           │        └╼ taskdump::pending::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:14:8
-//!           │           └╼ <core::future::poll_fn::PollFn<F> as core::future::future::Future>::poll at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/future/poll_fn.rs:64:9
-//!           │              └╼ taskdump::pending::{{closure}}::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:12:22
-//!           │                 └╼ <core::task::wake::Waker as core::clone::Clone>::clone at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/task/wake.rs:342:29
-//!           │                    └╼ tasktrace::clone_raw at /home/petrosagg/projects/tasktrace/src/lib.rs:135:5
-//!           └╼ taskdump::bar::{{closure}}::{{closure}} at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/macros/join.rs:126:24
-//!              └╼ <tokio::future::maybe_done::MaybeDone<Fut> as core::future::future::Future>::poll at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/future/maybe_done.rs:68:48
-//!                 └╼ taskdump::buz::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:30:11
-//!                    └╼ taskdump::baz::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:34:15
-//!                       └╼ taskdump::pending::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:14:8
-//!                          └╼ <core::future::poll_fn::PollFn<F> as core::future::future::Future>::poll at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/future/poll_fn.rs:64:9
-//!                             └╼ taskdump::pending::{{closure}}::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:12:22
-//!                                └╼ <core::task::wake::Waker as core::clone::Clone>::clone at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/task/wake.rs:342:29
-//!                                   └╼ tasktrace::clone_raw at /home/petrosagg/projects/tasktrace/src/lib.rs:135:5
+//! ╼ taskdump::foo::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:18:11
+//!  └╼ taskdump::bar::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:22:5
+//!     └╼ <tokio::future::poll_fn::PollFn<F> as core::future::future::Future>::poll at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/future/poll_fn.rs:58:9
+//!        ├╼ taskdump::bar::{{closure}}::{{closure}} at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/macros/join.rs:126:24
+//!        │  └╼ <tokio::future::maybe_done::MaybeDone<Fut> as core::future::future::Future>::poll at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/future/maybe_done.rs:68:48
+//!        │     └╼ taskdump::fiz::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:26:15
+//!        │        └╼ taskdump::pending::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:14:8
+//!        │           └╼ <core::future::poll_fn::PollFn<F> as core::future::future::Future>::poll at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/future/poll_fn.rs:64:9
+//!        │              └╼ taskdump::pending::{{closure}}::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:12:22
+//!        │                 └╼ <core::task::wake::Waker as core::clone::Clone>::clone at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/task/wake.rs:342:29
+//!        └╼ taskdump::bar::{{closure}}::{{closure}} at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/macros/join.rs:126:24
+//!           └╼ <tokio::future::maybe_done::MaybeDone<Fut> as core::future::future::Future>::poll at /home/petrosagg/.cargo/registry/src/index.crates.io-6f17d22bba15001f/tokio-1.29.1/src/future/maybe_done.rs:68:48
+//!              └╼ taskdump::buz::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:30:11
+//!                 └╼ taskdump::baz::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:34:15
+//!                    └╼ taskdump::pending::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:14:8
+//!                       └╼ <core::future::poll_fn::PollFn<F> as core::future::future::Future>::poll at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/future/poll_fn.rs:64:9
+//!                          └╼ taskdump::pending::{{closure}}::{{closure}} at /home/petrosagg/projects/tasktrace/examples/taskdump.rs:12:22
+//!                             └╼ <core::task::wake::Waker as core::clone::Clone>::clone at /rustc/8ede3aae28fe6e4d52b38157d7bfe0d3bceef225/library/core/src/task/wake.rs:342:29
 //! ```
 //!
 //! ## How it works
@@ -84,133 +84,3773 @@
 //!
 //! If multiple futures are being waited on (e.g through `select!`) then multiple stacktraces will
 //! be captured for each polled future and their combined stacktrace will be displayed as a tree.
+//!
+//! The frames of the traced task's own poll and of the waker that records the backtraces are part
+//! of every trace and left out by default, [`TraceOptions::keep_internal_frames`] keeps them.
+//!
+//! Calls that got inlined by the optimizer are expanded into frames of their own, as long as the
+//! binary carries the debug info needed to resolve them. [`TraceOptions::expand_inlined`] leaves
+//! them out instead. Symbols are demangled, [`TraceOptions::demangle`] shows them as they are found
+//! in the binary.
+//!
+//! ## Hand written futures
+//!
+//! Manually implemented futures are traced like any other. Instead of the `{{closure}}` frames of
+//! an async fn they appear as `<T as core::future::future::Future>::poll` frames, and any children
+//! they poll appear beneath them. A branch is only recorded once its leaf interacts with the
+//! waker, so a hand written leaf future must clone `cx.waker()` (or call `wake_by_ref` on it) to be
+//! visible. A leaf that returns `Poll::Pending` without touching the waker produces no branch.
+//! Leaves that only clone when `Waker::will_wake` says the waker changed, like the ones built on
+//! `AtomicWaker`, are recorded too since the waker of a traced poll never matches a stored one.
+//! A request for a trace of a poll in which no leaf at all touched the waker fails with
+//! [`TraceError::NoLeafCaptured`].
+//!
+//! ## Combinators with their own wakers
+//!
+//! Combinators that poll their children with the task's waker, like `for_each`, `fold`, `join!`
+//! and `select!`, are transparent and the trace continues into the child futures. Combinators that
+//! poll their children with wakers of their own instead, most notably `FuturesUnordered` and the
+//! adapters built on it like `buffer_unordered`, `buffered` and large `join_all`s, hide their
+//! children. The trace of such a task ends at the combinator that registered the task's waker.
+//!
+//! `futures::future::Shared` is one of them. A shared future awaited from several branches appears
+//! under every one of them, each ending in `Shared::poll`, but the frames of the shared future
+//! itself are hidden. `Shared` polls it with a waker of its own that wakes every branch, whichever
+//! of them polled it, so its leaves never see the waker of the task. Traces mark the `Shared::poll`
+//! frames with `[shared]` to tell them apart from leaves.
+//!
+//! The frames of the transparent combinators, along with those of the runtime and the standard
+//! library, are the same in every trace. [`TraceFilter::runtime_noise`] collapses them so that the
+//! frames of the closures they drive stand out.
+//!
+//! ## Other executors
+//!
+//! Nothing in the capture is specific to tokio. The traced waker wraps whatever waker the executor
+//! passes in, so tasks running on executors like `smol` or `futures::executor` are traced the same
+//! way, see the `smol` example. Leaves that call `wake_by_ref` on the waker instead of cloning it are
+//! recorded as well.
+//!
+//! Executors that poll their futures by hand can skip the request channel altogether and trace a
+//! poll of their choosing with [`capture_once`].
+//!
+//! ## Tracing a component
+//!
+//! Nothing requires the traced future to be the top level one of a task. A library can wrap the
+//! future of one of its components with [`traced`] wherever it is created and hand out the
+//! handle, without the application having to trace its tasks. The traces of the handle are rooted
+//! at the component, and tasks that are traced themselves still see the component's frames in
+//! their own traces, below a `TracedTask` frame. The component only answers requests while it is
+//! polled, i.e. while the task that owns it polls it.
+//!
+//! ## Tracing a task from itself
+//!
+//! A task may request a backtrace of itself, e.g. from a debug command handled by the traced task.
+//! This works, but the request is served on the task's next poll, so the captured trace also
+//! contains the branch leading to the `backtrace().await` that is waiting for the trace. That
+//! branch describes the request and not what the task was otherwise doing. Use
+//! [`TraceHandle::is_current_task`] to detect this situation.
+//!
+//! ## `!Send` futures
+//!
+//! `traced` places no `Send` bound on the wrapped future, so `!Send` work driven by a
+//! `tokio::task::LocalSet` can be traced as well. A trace is only produced when the traced future
+//! is polled after the request arrives, which on a single threaded scheduler happens as soon as the
+//! requesting task yields:
+//!
+//! ```rust
+//! use std::rc::Rc;
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() {
+//!     let local = tokio::task::LocalSet::new();
+//!     let (_tx, rx) = futures::channel::oneshot::channel::<()>();
+//!     let (fut, trace_handle) = tasktrace::traced(async {
+//!         let not_send = Rc::new(());
+//!         let _ = rx.await;
+//!         drop(not_send);
+//!     });
+//!     let capture = local.spawn_local(async move { trace_handle.backtrace().await });
+//!
+//!     local
+//!         .run_until(async move {
+//!             tokio::select! {
+//!                 _ = fut => unreachable!(),
+//!                 trace = capture => match trace.unwrap() {
+//!                     Ok(trace) => println!("{trace}"),
+//!                     Err(err) => println!("no trace: {err}"),
+//!                 },
+//!             }
+//!         })
+//!         .await;
+//! }
+//! ```
+//!
+//! The same holds for executors like `futures::executor::LocalPool` where the requester runs on
+//! the same thread as the traced task. The request has to be driven by the pool so that the traced
+//! task gets polled while the requester waits:
+//!
+//! ```rust
+//! use futures::executor::LocalPool;
+//! use futures::task::LocalSpawnExt;
+//!
+//! let mut pool = LocalPool::new();
+//! let (_tx, rx) = futures::channel::oneshot::channel::<()>();
+//! let (fut, trace_handle) = tasktrace::traced(async { rx.await.unwrap() });
+//! pool.spawner().spawn_local(fut).unwrap();
+//! pool.run_until_stalled();
+//!
+//! match pool.run_until(trace_handle.backtrace()) {
+//!     Ok(trace) => println!("{trace}"),
+//!     Err(err) => println!("no trace: {err}"),
+//! }
+//! ```
 
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
 use std::future::Future;
-use std::mem::ManuallyDrop;
+use std::mem::{self, ManuallyDrop};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::pin::Pin;
-use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
 
 use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
-use futures_channel::oneshot::Sender;
+use futures_channel::oneshot::{Receiver, Sender};
 use futures_core::Stream;
 use pin_project_lite::pin_project;
-use scoped_trace::Trace;
+
+use crate::time::Instant;
+
+mod age;
+mod aggregate;
+mod backend;
+#[cfg(feature = "console")]
+pub mod console;
+mod diff;
+#[cfg(feature = "tracing")]
+mod events;
+mod filter;
+mod health;
+mod hotspots;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+mod io;
+// Only the parser of the stacks is used on other targets, by its tests
+#[cfg(feature = "wasm")]
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+mod js;
+mod label;
+mod local;
+mod panic;
+#[cfg(feature = "registry")]
+pub mod registry;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod render;
+mod report;
+mod resource;
+mod scope;
+#[cfg(feature = "http")]
+pub mod server;
+mod shape;
+#[cfg(all(unix, feature = "signal"))]
+mod signal;
+mod snapshot;
+mod stable;
+#[cfg(feature = "metrics")]
+mod stats;
+mod stream;
+#[cfg(all(feature = "tokio-unstable", tokio_unstable))]
+pub mod taskdump;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(all(feature = "thread-backtrace", target_os = "linux"))]
+mod thread_backtrace;
+mod time;
+mod trace;
+mod transition;
+mod wakes;
+mod watchdog;
+
+pub use age::traced_with_frame_ages;
+pub use aggregate::{aggregate, aggregate_with_precision, AggregatedTrace, Precision};
+pub use backend::{BacktraceBackend, CaptureBackend, ResolvedFrame, StackBounds};
+pub use diff::TraceDiff;
+#[cfg(feature = "tracing")]
+pub use events::tracing_sink;
+pub use filter::TraceFilter;
+pub use health::HealthReport;
+pub use hotspots::{Aggregator, HotSpot};
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub use io::{traced_io, TracedIo};
+#[cfg(feature = "tokio")]
+pub use label::traced_timeout;
+pub use label::{annotated, labelled, traced_select, Annotated, Labelled, Select};
+pub use local::LocalRegistry;
+pub use panic::install_panic_hook;
+pub use report::{TraceGroup, TraceReport};
+#[cfg(feature = "tokio")]
+pub use resource::traced_blocking;
+pub use resource::{waiting_on, WaitingOn};
+#[cfg(feature = "tokio")]
+pub use scope::spawn_linked;
+pub use scope::{traced_scope, TraceTree};
+pub use shape::TraceShape;
+#[cfg(all(unix, feature = "signal", feature = "registry"))]
+pub use signal::install_signal_dumper;
+#[cfg(all(unix, feature = "signal"))]
+pub use signal::run_with_dump_on_signal;
+pub use snapshot::TraceSnapshot;
+pub use stream::{traced_stream, TracedStream};
+#[cfg(feature = "macros")]
+pub use tasktrace_macros::{instrument, traced};
+pub use time::{Clock, SystemClock};
+pub use trace::{
+    branches, fold, leaf_count, to_dot, write_trace, write_trace_fmt, Branch, Frame, FrameOrigin,
+    LeafFingerprint, Trace,
+};
+pub use transition::{traced_with_transition_callback, LeafTransition};
+pub use wakes::traced_with_wake_tracing;
+pub use watchdog::{watchdog, SlowPoll, Watchdog};
+
+/// What the exported macros expand to.
+#[doc(hidden)]
+pub mod __private {
+    use std::future::Future;
+
+    use crate::{TraceHandle, TracedTask};
+
+    pub use crate::label::{join, Branch};
+
+    /// The task of a `#[traced]` function, registered under its path with the `registry` feature.
+    pub fn traced_fn<F: Future>(fut: F, name: &'static str) -> (TracedTask<F>, TraceHandle) {
+        #[cfg(feature = "registry")]
+        return crate::registry::traced_registered(fut, name);
+        #[cfg(not(feature = "registry"))]
+        {
+            let _ = name;
+            crate::traced(fut)
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Globally enables or disables tracing at runtime. Tracing is enabled by default, and can't be
+/// enabled at all without the `enabled` feature.
+///
+/// While disabled traced tasks don't look for trace requests when polled, and
+/// [`TraceHandle::backtrace`] fails with [`TraceError::Disabled`] without sending a request.
+/// Requests that were already queued when tracing got disabled are answered once it is enabled
+/// again.
+///
+/// A poll of a traced task costs a relaxed atomic load on top of polling its future while tracing
+/// is disabled. That leaves out the bookkeeping of the poll as well, so the polls made in the
+/// meantime don't count towards [`TraceHandle::stats`], [`TraceHandle::last_polled_at`] or the
+/// polls of [`TraceHandle::backtrace_at_poll`].
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns whether tracing is currently enabled. See [`set_enabled`].
+pub fn is_enabled() -> bool {
+    cfg!(feature = "enabled") && ENABLED.load(Ordering::Relaxed)
+}
 
 pub fn traced<F: Future>(fut: F) -> (TracedTask<F>, TraceHandle) {
+    traced_with_state(fut, None, false, Arc::new(SystemClock))
+}
+
+/// Like [`traced`] but lets at most `capacity` trace requests wait for the task to be polled.
+/// Further requests fail with [`TraceError::Overloaded`] until the task catches up.
+///
+/// The requests of [`traced`] queue up without a limit, which costs memory for every request made
+/// to a task that is stuck and never polled again. Bounding the queue caps that memory, at the cost
+/// of turning away legitimate requesters when many of them ask at once. Note that requests waiting
+/// for a specific poll through [`TraceHandle::backtrace_at_poll`] leave the queue once the task is
+/// polled, and so don't count towards the capacity while they wait.
+pub fn traced_bounded<F: Future>(fut: F, capacity: usize) -> (TracedTask<F>, TraceHandle) {
+    traced_with_state(fut, Some(capacity), false, Arc::new(SystemClock))
+}
+
+/// Like [`traced`] but keeps the most recently captured trace around until the task is polled
+/// again. Requests made in the meantime are answered right away with that trace, since the task
+/// can't have moved since it was captured.
+///
+/// This suits monitoring loops that request traces much more often than the task gets polled.
+/// Only requests for the next poll are answered from the cache, requests for a specific poll
+/// through [`TraceHandle::backtrace_at_poll`] always wait for it.
+pub fn traced_cached<F: Future>(fut: F) -> (TracedTask<F>, TraceHandle) {
+    traced_with_state(fut, None, true, Arc::new(SystemClock))
+}
+
+/// Like [`traced_cached`] but also captures a trace on every `n`th poll of the task, so that
+/// [`TraceHandle::last_trace`] has a recent trace at hand even while the task is idle and won't be
+/// polled to answer a request.
+///
+/// With `n` set to 1 every poll is captured, which makes every request made while the task is
+/// parked return right away, at the cost of a capture per poll.
+///
+/// # Panics
+///
+/// Panics if `n` is zero.
+pub fn traced_with_snapshots<F: Future>(fut: F, n: usize) -> (TracedTask<F>, TraceHandle) {
+    assert!(n > 0, "cannot snapshot every 0 polls");
+    let (mut task, handle) = traced_with_state(fut, None, true, Arc::new(SystemClock));
+    task.snapshot_every = Some(n);
+    (task, handle)
+}
+
+fn traced_with_state<F>(
+    fut: F,
+    capacity: Option<usize>,
+    cached: bool,
+    clock: Arc<dyn Clock>,
+) -> (TracedTask<F>, TraceHandle) {
     let (req_tx, req_rx) = futures_channel::mpsc::unbounded();
-    let handle = TraceHandle { req_tx };
-    let task = TracedTask { fut, req_rx };
+    let state = Arc::new(TaskState {
+        name: OnceLock::new(),
+        #[cfg(feature = "tokio")]
+        tokio_id: OnceLock::new(),
+        created: clock.now(),
+        clock,
+        capacity,
+        queued: AtomicUsize::new(0),
+        polls: AtomicUsize::new(0),
+        last_poll_nanos: AtomicU64::new(0),
+        latest: cached.then(|| Mutex::new(None)),
+        busy_nanos: AtomicU64::new(0),
+        children: Mutex::new(Vec::new()),
+        status: Mutex::new(None),
+        holding: Mutex::new(Vec::new()),
+        waiting: Mutex::new(None),
+        watchers: Mutex::new(Vec::new()),
+        watched: AtomicBool::new(false),
+        req_tx,
+        ended: Mutex::new((None, Vec::new())),
+        last_wake: Mutex::new(None),
+        #[cfg(all(feature = "thread-backtrace", target_os = "linux"))]
+        polled_by: thread_backtrace::PolledBy::new(),
+    });
+    let handle = TraceHandle {
+        state: Arc::clone(&state),
+    };
+    let task = TracedTask {
+        fut,
+        req_rx,
+        registered: None,
+        hooks: None,
+        state,
+        polls: 0,
+        armed: Vec::new(),
+        sampler: None,
+        completion: None,
+        cache: None,
+        snapshot_every: None,
+        limits: CaptureLimits::default(),
+        wakes: None,
+        transitions: None,
+        frame_ages: None,
+        on_end: None,
+        dump_on_drop: None,
+    };
     (task, handle)
 }
 
-pub struct TraceHandle {
-    req_tx: UnboundedSender<TraceRequest>,
+/// Like [`traced`] but reads the time through `clock` instead of the clock of the platform, see
+/// [`Clock`], e.g. to test a monitor of [`TraceHandle::stats`] or [`TraceHandle::last_polled_at`]
+/// with a clock that the test advances by hand, like the `MockClock` of the `test-util` feature.
+pub fn traced_with_clock<F: Future>(fut: F, clock: impl Clock) -> (TracedTask<F>, TraceHandle) {
+    traced_with_state(fut, None, false, Arc::new(clock))
 }
 
-impl TraceHandle {
-    pub async fn backtrace(&self) -> Option<Trace> {
-        let (tx, rx) = futures_channel::oneshot::channel();
-        self.req_tx.unbounded_send(TraceRequest(tx)).ok()?;
-        rx.await.ok()
-    }
+/// Like [`traced`] but gives the task a name that is attached to all of its traces, see
+/// [`Trace::name`]. Rendered traces start with a `Task "<name>":` line.
+pub fn traced_named<F: Future>(fut: F, name: impl Into<String>) -> (TracedTask<F>, TraceHandle) {
+    let (task, handle) = traced(fut);
+    let _ = task.state.name.set(name.into());
+    (task, handle)
 }
 
-struct TraceRequest(Sender<Trace>);
+/// Like [`traced`] but runs `before` right before every trace capture and passes each captured
+/// trace to `after` before it is handed to the requesters.
+pub fn traced_with_hooks<F: Future>(
+    fut: F,
+    before: impl Fn() + Send + 'static,
+    after: impl Fn(&Trace) + Send + 'static,
+) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.hooks = Some(Hooks {
+        before: Box::new(before),
+        after: Box::new(after),
+    });
+    (task, handle)
+}
 
-pin_project! {
-    pub struct TracedTask<F> {
-        #[pin]
-        fut: F,
-        #[pin]
-        req_rx: UnboundedReceiver<TraceRequest>,
-    }
+/// Like [`traced`] but also captures a trace on every `n`th poll of the task and passes it to
+/// `sink`. Busy tasks get sampled more often than idle ones.
+///
+/// # Panics
+///
+/// Panics if `n` is zero.
+pub fn traced_every_n_polls<F: Future>(
+    fut: F,
+    n: usize,
+    sink: impl Fn(Trace) + Send + 'static,
+) -> (TracedTask<F>, TraceHandle) {
+    assert!(n > 0, "cannot sample every 0 polls");
+    let (mut task, handle) = traced(fut);
+    task.sampler = Some(Sampler {
+        every: n,
+        sink: Box::new(sink),
+    });
+    (task, handle)
 }
 
-impl<F: Future> Future for TracedTask<F> {
-    type Output = F::Output;
+/// Like [`traced`] but also traces the task every `interval`, whether it is busy or idle, and
+/// passes the traces to `sink`, e.g. to build a profile of where the task spends its time. Unlike
+/// with [`traced_every_n_polls`], idle tasks are traced as well.
+///
+/// The traces come from [`TraceHandle::watch_every`], driven by a task that is spawned on the
+/// current tokio runtime and ends along with the traced task. Requires the `tokio` feature.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, like `tokio::spawn`.
+#[cfg(feature = "tokio")]
+pub fn traced_every<F: Future>(
+    fut: F,
+    interval: Duration,
+    mut sink: impl FnMut(Trace) + Send + 'static,
+) -> (TracedTask<F>, TraceHandle) {
+    let (task, handle) = traced(fut);
+    let watch = handle.watch_every(interval);
+    tokio::spawn(async move {
+        let mut watch = std::pin::pin!(watch);
+        while let Some(trace) = std::future::poll_fn(|cx| watch.as_mut().poll_next(cx)).await {
+            sink(trace);
+        }
+    });
+    (task, handle)
+}
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut this = self.project();
+/// Like [`traced`] but captures a fresh trace at most once every `min_interval`. Requests that
+/// arrive sooner are answered with the most recently captured trace, which protects the task's
+/// throughput from callers that request traces too aggressively.
+pub fn traced_with_min_capture_interval<F: Future>(
+    fut: F,
+    min_interval: Duration,
+) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.cache = Some(CaptureCache {
+        min_interval,
+        last: None,
+    });
+    (task, handle)
+}
 
-        if let Poll::Ready(Some(req)) = this.req_rx.as_mut().poll_next(cx) {
-            let trace_waker = TracedWaker(cx.waker());
-            let raw_waker =
-                RawWaker::new(&trace_waker as *const _ as *const (), &TRACE_WAKER_VTABLE);
-            // SAFETY: RawWaker is well formed
-            let waker = unsafe { ManuallyDrop::new(Waker::from_raw(raw_waker)) };
-            let mut traced_cx = Context::from_waker(&waker);
+/// Like [`traced`] but bounds how much every capture of the task records, for tasks with so many
+/// leaves, e.g. a large `FuturesUnordered`, that capturing all of them would take too long.
+///
+/// ```rust
+/// # async fn work() {}
+/// let limits = tasktrace::CaptureLimits::new().max_leaves(100).max_frames(64);
+/// let (fut, trace_handle) = tasktrace::traced_with_limits(work(), limits);
+/// # let _ = (fut, trace_handle);
+/// ```
+pub fn traced_with_limits<F: Future>(
+    fut: F,
+    limits: CaptureLimits,
+) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.limits = limits;
+    (task, handle)
+}
 
-            let (result, trace) = Trace::root(|| this.fut.poll(&mut traced_cx));
-            // Drain the request queue if there are multiple requests waiting
-            while let Poll::Ready(Some(req)) = this.req_rx.as_mut().poll_next(cx) {
-                let _ = req.0.send(trace.clone());
-            }
-            let _ = req.0.send(trace);
-            result
-        } else {
-            this.fut.poll(cx)
-        }
-    }
+/// Like [`traced`] but walks and symbolizes the stacks of the task's leaves through `backend`, see
+/// [`CaptureBackend`].
+pub fn traced_with_backend<F: Future>(
+    fut: F,
+    backend: impl CaptureBackend,
+) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.limits.backend = Some(backend::Backend(Arc::new(backend)));
+    (task, handle)
 }
 
-struct TracedWaker<'a>(&'a Waker);
+/// Like [`traced`] but also returns a [`CompletionSignal`] that resolves once the task is done,
+/// either because it completed or because it was dropped before completing.
+pub fn traced_with_completion<F: Future>(fut: F) -> (TracedTask<F>, TraceHandle, CompletionSignal) {
+    let (mut task, handle) = traced(fut);
+    let (tx, rx) = futures_channel::oneshot::channel();
+    task.completion = Some(tx);
+    (task, handle, CompletionSignal(rx))
+}
 
-const TRACE_WAKER_VTABLE: RawWakerVTable =
-    RawWakerVTable::new(clone_raw, wake_raw, wake_by_ref_raw, drop_raw);
+/// Like [`traced`] but passes a trace of the task to `sink` if it is dropped before completing,
+/// e.g. because it was aborted or lost a `select!`, to find out where cancelled work was parked.
+///
+/// There is no poll to trace once the task is being dropped, so the trace is captured by polling
+/// the future one last time with a no-op waker. The leaves register that waker like any other,
+/// which makes for a complete trace, but the poll runs the future as usual and may let it make
+/// progress before it is dropped. If it completes during that poll, its output is dropped along
+/// with it. No trace is captured for tasks that were never polled, since polling them would start
+/// them, or while tracing is disabled or the thread is panicking.
+pub fn traced_dump_on_drop<F: Future>(
+    fut: F,
+    sink: impl Fn(Trace) + Send + 'static,
+) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.dump_on_drop = Some(Box::new(move |fut| {
+        let (_, trace) = capture_once(fut, Waker::noop());
+        sink(trace);
+    }));
+    (task, handle)
+}
 
-/// Clones a TracedWaker. Crucially, cloning converts it into the original wrapped Waker.
+/// Spawns `fut` on the current tokio runtime with [`traced`] and returns its `JoinHandle` along
+/// with its trace handle. The handle knows the id of the tokio task, see
+/// [`TraceHandle::tokio_id`].
 ///
-/// SAFETY:
-/// The data pointer must point to a valid TracedWaker
-unsafe fn clone_raw(data: *const ()) -> RawWaker {
-    Trace::leaf();
-    let this: &TracedWaker = &*(data as *const TracedWaker);
-    let waker: Waker = this.0.clone();
-    // SAFETY: Waker is a repr(transparent) over RawWaker
-    std::mem::transmute::<Waker, RawWaker>(waker)
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, like `tokio::spawn`.
+#[cfg(feature = "tokio")]
+pub fn spawn_traced<F>(fut: F) -> (tokio::task::JoinHandle<F::Output>, TraceHandle)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, handle) = traced(fut);
+    let join_handle = tokio::spawn(task);
+    let _ = handle.state.tokio_id.set(join_handle.id());
+    (join_handle, handle)
 }
 
-/// SAFETY:
-/// The data pointer must point to a valid TracedWaker
-unsafe fn wake_by_ref_raw(data: *const ()) {
-    Trace::leaf();
-    let this: &TracedWaker = &*(data as *const TracedWaker);
-    this.0.wake_by_ref();
+/// Polls `fut` once with `waker`, capturing a trace of the poll.
+///
+/// This is what a [`TracedTask`] does when a trace was requested, for executors that drive their
+/// futures by hand and want to trace a poll of their choosing. The capture happens regardless of
+/// [`set_enabled`].
+///
+/// ```rust
+/// # use std::pin::pin;
+/// # async fn work() {}
+/// # let waker = futures::task::noop_waker();
+/// let mut fut = pin!(work());
+/// let (poll, trace) = tasktrace::capture_once(fut.as_mut(), &waker);
+/// # let _ = (poll, trace);
+/// ```
+pub fn capture_once<F: Future + ?Sized>(
+    fut: Pin<&mut F>,
+    waker: &Waker,
+) -> (Poll<F::Output>, Trace) {
+    capture(fut, waker, &CaptureLimits::default())
 }
 
-/// No one can end up holding an owned Waker that is backed by a TracedWaker since cloning the
-/// original &Waker (backed by a TracedWaker) produces an owned Waker that is backed by the cloned
-/// *inner* Waker. Therefore this method will never get called.
-unsafe fn wake_raw(_data: *const ()) {
-    unreachable!("an owned Waker backed by TracedWaker is never constructed");
+/// Like [`capture_once`] but records no more than `limits` allow.
+fn capture<F: Future + ?Sized>(
+    fut: Pin<&mut F>,
+    waker: &Waker,
+    limits: &CaptureLimits,
+) -> (Poll<F::Output>, Trace) {
+    let trace_waker = TracedWaker(waker);
+    let waker = trace_waker.waker();
+    let mut traced_cx = Context::from_waker(&waker);
+    Trace::root(|| fut.poll(&mut traced_cx), limits)
 }
 
-/// No one can end up holding an owned Waker that is backed by a TracedWaker since cloning the
-/// original &Waker (backed by a TracedWaker) produces an owned Waker that is backed by the cloned
-/// *inner* Waker. Therefore this method will never get called.
-unsafe fn drop_raw(_data: *const ()) {
-    unreachable!("an owned Waker backed by TracedWaker is never constructed");
+/// Like [`capture`] but polls the next item of a stream.
+fn capture_next<S: Stream + ?Sized>(
+    stream: Pin<&mut S>,
+    waker: &Waker,
+    limits: &CaptureLimits,
+) -> (Poll<Option<S::Item>>, Trace) {
+    let trace_waker = TracedWaker(waker);
+    let waker = trace_waker.waker();
+    let mut traced_cx = Context::from_waker(&waker);
+    Trace::root(|| stream.poll_next(&mut traced_cx), limits)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// How a traced task ended. See [`traced_with_completion`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// The task ran to completion.
+    Completed,
+    /// The task was dropped before it completed.
+    Cancelled,
+}
 
-    #[tokio::test]
-    async fn it_works() {
-        let (foo_fut, trace_handle) = traced(foo());
-        tokio::spawn(foo_fut);
+/// A future that resolves when a traced task ends. See [`traced_with_completion`].
+pub struct CompletionSignal(Receiver<Completion>);
 
-        println!("{}", trace_handle.backtrace().await.unwrap());
+impl Future for CompletionSignal {
+    type Output = Completion;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Completion> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|completion| completion.unwrap_or(Completion::Cancelled))
     }
+}
 
-    async fn pending() {
-        let mut waker = None;
-        std::future::poll_fn(|cx| {
-            waker = Some(cx.waker().clone());
-            std::task::Poll::Pending
-        })
-        .await
+/// The poll counters of a traced task, see [`TraceHandle::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    polls: usize,
+    busy: Duration,
+    age: Duration,
+}
+
+impl TaskStats {
+    /// The number of times the task was polled, including an ongoing poll.
+    pub fn polls(&self) -> usize {
+        self.polls
+    }
+
+    /// The cumulative time spent inside the task's `poll` method, not counting an ongoing poll.
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+
+    /// The time since the task was created.
+    pub fn age(&self) -> Duration {
+        self.age
+    }
+
+    /// The average time a poll took, or zero if the task was never polled.
+    pub fn mean_poll(&self) -> Duration {
+        match u32::try_from(self.polls) {
+            Ok(0) => Duration::ZERO,
+            Ok(polls) => self.busy / polls,
+            Err(_) => Duration::from_secs_f64(self.busy.as_secs_f64() / self.polls as f64),
+        }
+    }
+}
+
+/// A handle for requesting backtraces of a traced task.
+///
+/// The handle can be cloned to let several collectors request traces of the same task. All the
+/// requests that are pending when the task is polled are answered with the same trace, captured
+/// once during that poll. Requests that arrive while the task is being polled are answered during
+/// the next poll.
+#[derive(Clone)]
+pub struct TraceHandle {
+    state: Arc<TaskState>,
+}
+
+/// A handle that doesn't keep the traced task's state alive, see [`TraceHandle::downgrade`].
+#[derive(Clone)]
+pub struct WeakTraceHandle {
+    state: Weak<TaskState>,
+}
+
+impl WeakTraceHandle {
+    /// Returns a handle to the task, or `None` if the task and all of its strong handles are gone.
+    pub fn upgrade(&self) -> Option<TraceHandle> {
+        self.state.upgrade().map(|state| TraceHandle { state })
+    }
+}
+
+impl TraceHandle {
+    /// Captures a backtrace of the task during its next poll.
+    ///
+    /// Fails with [`TraceError::TaskFinished`] once the task is gone, in which case there is no
+    /// point in asking again.
+    pub async fn backtrace(&self) -> Result<Trace, TraceError> {
+        self.backtrace_with(TraceOptions::default()).await
+    }
+
+    /// Like [`TraceHandle::backtrace`] but shapes the trace according to `options`.
+    pub async fn backtrace_with(&self, options: TraceOptions) -> Result<Trace, TraceError> {
+        if options.include_spawned {
+            let trace = self.request(None, options.clone()).await?;
+            Ok(self.splice_spawned(trace, options).await)
+        } else {
+            self.request(None, options).await
+        }
+    }
+
+    /// Like [`TraceHandle::backtrace`] but gives up with [`TraceError::Timeout`] if the task
+    /// is not polled within `timeout`, e.g. because it is stuck in a blocking section.
+    ///
+    /// The request is withdrawn on timeout and the task drops it without capturing a trace.
+    /// Requires the `tokio` feature and must be called from within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn backtrace_timeout(&self, timeout: Duration) -> Result<Trace, TraceError> {
+        tokio::time::timeout(timeout, self.backtrace())
+            .await
+            .map_err(|_| {
+                #[cfg(feature = "metrics")]
+                stats::record_timeout();
+                TraceError::Timeout
+            })?
+    }
+
+    /// Captures a backtrace once the task has settled, i.e. once the fingerprints of its polls (see
+    /// [`Trace::fingerprints`]) stayed the same for `quiet`, for snapshots that shouldn't catch the
+    /// task halfway between two await points. Returns the trace of the last poll before the quiet
+    /// period, which may be the poll this method requests to start with.
+    ///
+    /// Every poll of the task is traced while waiting, like with [`TraceHandle::watch`], so a task
+    /// that keeps moving is never returned. Requires the `tokio` feature and must be called from
+    /// within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn backtrace_when_parked(&self, quiet: Duration) -> Result<Trace, TraceError> {
+        let mut polls = std::pin::pin!(self.watch());
+        let mut trace = self.backtrace().await?;
+        let mut fingerprints = trace.fingerprints();
+        let mut deadline = tokio::time::Instant::now() + quiet;
+        loop {
+            let next = std::future::poll_fn(|cx| polls.as_mut().poll_next(cx));
+            match tokio::time::timeout_at(deadline, next).await {
+                Err(_elapsed) => return Ok(trace),
+                Ok(None) => return Err(TraceError::TaskFinished),
+                Ok(Some(polled)) => {
+                    let polled = TraceOptions::default().apply(&polled)?;
+                    let moved = polled.fingerprints();
+                    if moved != fingerprints {
+                        fingerprints = moved;
+                        deadline = tokio::time::Instant::now() + quiet;
+                    }
+                    trace = polled;
+                }
+            }
+        }
+    }
+
+    /// Captures a backtrace and symbolizes as much of it as fits in `max`, see
+    /// [`TraceOptions::symbolization_budget`], so that the call returns in a predictable time once
+    /// the task answered, however large the trace. The result may be partially symbolized, in
+    /// which case [`Trace::fully_symbolized`] resolves the rest later.
+    pub async fn backtrace_with_budget(&self, max: Duration) -> Result<Trace, TraceError> {
+        let trace = self
+            .backtrace_with(TraceOptions::default().symbolization_budget(max))
+            .await?;
+        trace.resolve();
+        Ok(trace)
+    }
+
+    /// Captures a backtrace and renders it to a string, for consumers that only forward the
+    /// rendered output and don't want to depend on the trace type.
+    pub async fn backtrace_string(&self) -> Option<String> {
+        Some(self.backtrace().await.ok()?.to_string())
+    }
+
+    /// Captures a backtrace and returns only its fingerprints, see [`Trace::fingerprints`], for
+    /// monitoring loops that poll often and just need to notice when the task moves. The trace is
+    /// never symbolized.
+    pub async fn backtrace_fingerprints_only(&self) -> Result<Vec<u64>, TraceError> {
+        Ok(self.backtrace().await?.fingerprints())
+    }
+
+    /// Captures a backtrace and adds it to `aggregator`, see [`Aggregator::add`], for sampling
+    /// profilers that only keep the counts of the samples.
+    pub async fn backtrace_into_aggregator(
+        &self,
+        aggregator: &mut Aggregator,
+    ) -> Result<(), TraceError> {
+        aggregator.add(&self.backtrace().await?);
+        Ok(())
+    }
+
+    /// Captures a backtrace during the `n`th poll of the task, counting from 1.
+    ///
+    /// Fails with [`TraceError::Cancelled`] if the task has already been polled more than `n`
+    /// times by the time it receives the request, and like [`TraceHandle::backtrace`] otherwise.
+    pub async fn backtrace_at_poll(&self, n: usize) -> Result<Trace, TraceError> {
+        self.request(Some(n), TraceOptions::default()).await
+    }
+
+    async fn request(
+        &self,
+        at_poll: Option<usize>,
+        options: TraceOptions,
+    ) -> Result<Trace, TraceError> {
+        if at_poll.is_none() {
+            if let Some(trace) = self.cached() {
+                return options.apply(&trace);
+            }
+        }
+        let rx = self.send_request(at_poll, options)?;
+        rx.await.unwrap_or(Err(TraceError::Cancelled))
+    }
+
+    /// Returns the trace cached by a task created with [`traced_cached`], if it hasn't been polled
+    /// since the trace was captured.
+    fn cached(&self) -> Option<Trace> {
+        let latest = self.state.latest.as_ref()?.lock().unwrap();
+        match &*latest {
+            Some((poll, trace))
+                if *poll == self.state.polls.load(Ordering::Relaxed)
+                    && !self.state.req_tx.is_closed() =>
+            {
+                Some(trace.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Requests a backtrace without waiting for it, for synchronous code that can't `.await`.
+    /// `callback` is called with the trace from within the next poll of the task, so it should
+    /// return quickly. Tasks created with [`traced_cached`] call it right away if they have a
+    /// trace of their current poll.
+    ///
+    /// Fails right away if the request can't be queued, e.g. with [`TraceError::TaskFinished`]
+    /// once the task is gone. If the task is dropped before it gets to answer, `callback` is
+    /// dropped without being called.
+    pub fn request_backtrace(
+        &self,
+        callback: impl FnOnce(Result<Trace, TraceError>) + Send + 'static,
+    ) -> Result<(), TraceError> {
+        if let Some(trace) = self.cached() {
+            callback(TraceOptions::default().apply(&trace));
+            return Ok(());
+        }
+        self.queue_request(
+            None,
+            TraceOptions::default(),
+            Reply::Callback(Box::new(callback)),
+        )
+    }
+
+    /// Queues a request without waiting for the answer.
+    fn send_request(
+        &self,
+        at_poll: Option<usize>,
+        options: TraceOptions,
+    ) -> Result<Receiver<Result<Trace, TraceError>>, TraceError> {
+        let (tx, rx) = futures_channel::oneshot::channel();
+        self.queue_request(at_poll, options, Reply::Channel(tx))?;
+        Ok(rx)
+    }
+
+    fn queue_request(
+        &self,
+        at_poll: Option<usize>,
+        options: TraceOptions,
+        tx: Reply,
+    ) -> Result<(), TraceError> {
+        if !is_enabled() {
+            return Err(TraceError::Disabled);
+        }
+        let queued = self.state.queued.fetch_add(1, Ordering::Relaxed);
+        if self
+            .state
+            .capacity
+            .is_some_and(|capacity| queued >= capacity)
+        {
+            self.state.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(TraceError::Overloaded);
+        }
+        self.state
+            .req_tx
+            .unbounded_send(TraceRequest {
+                tx,
+                at_poll,
+                options,
+            })
+            .map_err(|_| {
+                self.state.queued.fetch_sub(1, Ordering::Relaxed);
+                TraceError::TaskFinished
+            })
+    }
+
+    /// Blocks the current thread until the task is traced, for synchronous contexts like a debugger
+    /// or an admin command handler. Gives up with [`TraceError::Timeout`] after `timeout`.
+    ///
+    /// The thread that drives the traced task must not be blocked, or the task never gets to
+    /// answer. Calling this from within the traced task fails with [`TraceError::WouldDeadlock`]
+    /// and, with the `tokio` feature, so does calling it from within a tokio runtime.
+    pub fn backtrace_blocking(&self, timeout: Duration) -> Result<Trace, TraceError> {
+        #[cfg(feature = "tokio")]
+        let in_runtime = tokio::runtime::Handle::try_current().is_ok();
+        #[cfg(not(feature = "tokio"))]
+        let in_runtime = false;
+        if in_runtime || self.is_current_task() {
+            return Err(TraceError::WouldDeadlock);
+        }
+
+        let mut rx = self.send_request(None, TraceOptions::default())?;
+        let deadline = Instant::now() + timeout;
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(response) = Pin::new(&mut rx).poll(&mut cx) {
+                return response.unwrap_or(Err(TraceError::Cancelled));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                #[cfg(feature = "metrics")]
+                stats::record_timeout();
+                return Err(TraceError::Timeout);
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+
+    /// Returns the most recently captured trace of a task created with [`traced_cached`] or
+    /// [`traced_with_snapshots`] right away, without waiting for the task to be polled. The trace
+    /// may be from an earlier poll than the last one, and is `None` if nothing was captured yet or
+    /// the task caches no traces.
+    pub fn last_trace(&self) -> Option<Trace> {
+        let latest = self.state.latest.as_ref()?.lock().unwrap();
+        latest.as_ref().map(|(_, trace)| trace.clone())
+    }
+
+    /// Returns a handle that doesn't keep the task's state and request channel alive, for
+    /// monitors that hold on to the handles of many tasks for longer than the tasks live.
+    pub fn downgrade(&self) -> WeakTraceHandle {
+        WeakTraceHandle {
+            state: Arc::downgrade(&self.state),
+        }
+    }
+
+    /// Returns whether the task has completed, or was dropped or unwrapped, so that it can't be
+    /// traced any more.
+    pub fn is_finished(&self) -> bool {
+        self.state.req_tx.is_closed()
+    }
+
+    /// How the task ended, or `None` while it is still running, i.e. until it completes or is
+    /// dropped or unwrapped.
+    pub fn completion(&self) -> Option<Completion> {
+        self.state.ended.lock().unwrap().0
+    }
+
+    /// Returns a future that resolves once the task ends, like the [`CompletionSignal`] of
+    /// [`traced_with_completion`] but for any task. It resolves right away if the task already
+    /// ended.
+    pub fn finished(&self) -> CompletionSignal {
+        let (tx, rx) = futures_channel::oneshot::channel();
+        let mut ended = self.state.ended.lock().unwrap();
+        match ended.0 {
+            Some(completion) => {
+                let _ = tx.send(completion);
+            }
+            None => ended.1.push(tx),
+        }
+        CompletionSignal(rx)
+    }
+
+    /// The name of the task, if it was created through [`traced_named`].
+    pub fn name(&self) -> Option<&str> {
+        self.state.name.get().map(String::as_str)
+    }
+
+    /// The id of the tokio task running the traced task, if it was spawned through
+    /// [`spawn_traced`] or, with the `tokio-unstable` feature, polled from within a tokio task.
+    /// The same id shows up in `tokio-console` and in `tokio::task::id`.
+    #[cfg(feature = "tokio")]
+    pub fn tokio_id(&self) -> Option<tokio::task::Id> {
+        self.state.tokio_id.get().copied()
+    }
+
+    /// When the task was last polled, or `None` if it never was. A task that is stuck, e.g. in a
+    /// deadlock, keeps reporting the same time, which tells it apart from one that is merely slow
+    /// to answer its trace requests.
+    pub fn last_polled_at(&self) -> Option<Instant> {
+        if self.state.polls.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let since_created =
+            Duration::from_nanos(self.state.last_poll_nanos.load(Ordering::Relaxed));
+        Some(self.state.created + since_created)
+    }
+
+    /// Returns the poll counters of the task. They are kept for every traced task, whether it is
+    /// ever traced or not.
+    pub fn stats(&self) -> TaskStats {
+        TaskStats {
+            polls: self.state.polls.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.state.busy_nanos.load(Ordering::Relaxed)),
+            age: self.state.since_created(),
+        }
+    }
+
+    /// Returns the fraction of the task's lifetime, between 0.0 and 1.0, that was spent inside its
+    /// `poll` method. A ratio close to 1.0 means the task is CPU bound while a ratio close to 0.0
+    /// means it spends most of its time parked.
+    pub fn busy_ratio(&self) -> f64 {
+        self.state.busy_ratio(Duration::ZERO)
+    }
+
+    /// Returns a stream of traces, one for every poll of the task, that ends when the task does.
+    ///
+    /// Every watched poll pays the cost of a capture. Use [`TraceHandle::watch_sampled`] to limit
+    /// how often that happens. Dropping the stream stops the captures from the next poll on.
+    pub fn watch(&self) -> impl Stream<Item = Trace> {
+        self.watch_sampled(Duration::ZERO)
+    }
+
+    /// Like [`TraceHandle::watch`] but captures at most one trace every `min_interval`. The polls
+    /// in between are not traced at all.
+    pub fn watch_sampled(&self, min_interval: Duration) -> impl Stream<Item = Trace> {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let mut watchers = self.state.watchers.lock().unwrap();
+        // A finished task clears its watchers after closing the request channel, so checking under
+        // the lock guarantees that the stream ends either way
+        if !self.state.req_tx.is_closed() {
+            watchers.push(Watcher {
+                tx,
+                min_interval,
+                last: None,
+            });
+            self.state.watched.store(true, Ordering::Relaxed);
+        }
+        rx
+    }
+
+    /// Returns a stream that requests a fresh trace of the task right away and then `interval`
+    /// after every trace it yields, for dashboards that keep displaying where a task is stuck. It
+    /// ends once the task does. Requires the `tokio` feature and must be polled from within a tokio
+    /// runtime.
+    ///
+    /// Unlike [`TraceHandle::watch`], which only traces the polls the task makes, the requests
+    /// wake the task, so that idle tasks are traced as well. Requests that fail, e.g. with
+    /// [`TraceError::Overloaded`], are retried after `interval` without yielding anything.
+    ///
+    /// ```rust,no_run
+    /// use futures::StreamExt;
+    ///
+    /// # async fn example(trace_handle: tasktrace::TraceHandle) {
+    /// let mut watch = trace_handle.watch_every(std::time::Duration::from_secs(1));
+    /// while let Some(trace) = watch.next().await {
+    ///     println!("{trace}");
+    /// }
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub fn watch_every(&self, interval: Duration) -> impl Stream<Item = Trace> + Send {
+        let handle = self.clone();
+        WatchEvery {
+            next: Some(Box::pin(async move { handle.backtrace().await })),
+            handle: self.clone(),
+            interval,
+        }
+    }
+
+    /// Records a human readable description of what the task is currently doing, e.g. "retrying
+    /// connection". Replaces any previously set status.
+    pub fn set_status(&self, status: &str) {
+        *self.state.status.lock().unwrap() = Some(status.to_owned());
+    }
+
+    /// Returns the last status set through [`TraceHandle::set_status`], if any.
+    pub fn status(&self) -> Option<String> {
+        self.state.status.lock().unwrap().clone()
+    }
+
+    /// Records that the task holds `resource`, e.g. "lock:accounts", until
+    /// [`TraceHandle::release`] is called with it, so that `registry::who_holds` can find the task
+    /// that a contended resource waits for. A task can hold several resources at once. Holding the
+    /// resource the task was waiting on ends the wait.
+    pub fn set_holding(&self, resource: &str) {
+        let mut holding = self.state.holding.lock().unwrap();
+        if !holding.iter().any(|held| held == resource) {
+            holding.push(resource.to_owned());
+        }
+        let mut waiting = self.state.waiting.lock().unwrap();
+        if waiting.as_deref() == Some(resource) {
+            *waiting = None;
+        }
+    }
+
+    /// Records that the task no longer holds `resource`, see [`TraceHandle::set_holding`].
+    pub fn release(&self, resource: &str) {
+        self.state
+            .holding
+            .lock()
+            .unwrap()
+            .retain(|held| held != resource);
+    }
+
+    /// Returns the resources the task holds, in the order it took them.
+    pub fn holding(&self) -> Vec<String> {
+        self.state.holding.lock().unwrap().clone()
+    }
+
+    /// Records that the task waits for `resource` until it holds it or
+    /// [`TraceHandle::clear_waiting`] is called. Replaces any previously set resource.
+    ///
+    /// This is the counterpart of [`waiting_on`], which describes the leaves of the trace instead,
+    /// for code that knows what it waits for before it gets to the await point.
+    pub fn set_waiting(&self, resource: &str) {
+        *self.state.waiting.lock().unwrap() = Some(resource.to_owned());
+    }
+
+    /// Records that the task no longer waits for a resource, e.g. because it gave up on it.
+    pub fn clear_waiting(&self) {
+        *self.state.waiting.lock().unwrap() = None;
+    }
+
+    /// Returns the resource set through [`TraceHandle::set_waiting`], if the task still waits for
+    /// it.
+    pub fn waiting(&self) -> Option<String> {
+        self.state.waiting.lock().unwrap().clone()
+    }
+}
+
+/// The reason a trace could not be captured.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum TraceError {
+    /// The traced task has finished, or was dropped, before it could be traced.
+    TaskFinished,
+    /// The request was dropped without a trace, e.g. because the task was dropped while the
+    /// request was still pending.
+    Cancelled,
+    /// Tracing is disabled, see [`set_enabled`], or compiled out along with the `enabled`
+    /// feature.
+    Disabled,
+    /// The task was not polled in time, see [`TraceHandle::backtrace_blocking`].
+    Timeout,
+    /// Waiting for the trace would block the thread that has to produce it, see
+    /// [`TraceHandle::backtrace_blocking`].
+    WouldDeadlock,
+    /// Too many requests are waiting for the task to be polled, see [`traced_bounded`].
+    Overloaded,
+    /// The task was polled but none of its futures interacted with the waker, so there is nothing
+    /// to show. See the [crate level documentation](crate#hand-written-futures).
+    NoLeafCaptured,
+    /// The task panicked while it was being traced. `trace` holds the leaves that the poll
+    /// reached before the panic, and the panic resumes once every request is answered, so it
+    /// still reaches whatever polls the task.
+    Panicked {
+        /// The message the task panicked with.
+        message: String,
+        /// What was captured up to the panic.
+        trace: Box<Trace>,
+    },
+}
+
+impl fmt::Debug for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::TaskFinished => f.write_str("TaskFinished"),
+            TraceError::Cancelled => f.write_str("Cancelled"),
+            TraceError::Disabled => f.write_str("Disabled"),
+            TraceError::Timeout => f.write_str("Timeout"),
+            TraceError::WouldDeadlock => f.write_str("WouldDeadlock"),
+            TraceError::Overloaded => f.write_str("Overloaded"),
+            TraceError::NoLeafCaptured => f.write_str("NoLeafCaptured"),
+            TraceError::Panicked { message, trace } => f
+                .debug_struct("Panicked")
+                .field("message", message)
+                .field("trace", &format_args!("{trace}"))
+                .finish(),
+        }
+    }
+}
+
+/// Panics are equal if their messages and rendered traces are.
+impl PartialEq for TraceError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                TraceError::Panicked { message, trace },
+                TraceError::Panicked {
+                    message: other_message,
+                    trace: other_trace,
+                },
+            ) => message == other_message && trace.to_string() == other_trace.to_string(),
+            _ => mem::discriminant(self) == mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for TraceError {}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::TaskFinished => f.write_str("the traced task has finished"),
+            TraceError::Cancelled => f.write_str("the trace request was cancelled"),
+            TraceError::Disabled => f.write_str("tracing is disabled"),
+            TraceError::Timeout => f.write_str("timed out waiting for a trace"),
+            TraceError::WouldDeadlock => f.write_str("waiting for a trace would deadlock"),
+            TraceError::Overloaded => f.write_str("too many trace requests are pending"),
+            TraceError::NoLeafCaptured => f.write_str("no future of the task registered its waker"),
+            TraceError::Panicked { message, .. } => {
+                write!(f, "the traced task panicked: {message}")
+            }
+        }
+    }
+}
+
+impl Error for TraceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TraceError::Cancelled => Some(&futures_channel::oneshot::Canceled),
+            TraceError::TaskFinished
+            | TraceError::Disabled
+            | TraceError::Timeout
+            | TraceError::WouldDeadlock
+            | TraceError::Overloaded
+            | TraceError::NoLeafCaptured
+            | TraceError::Panicked { .. } => None,
+        }
+    }
+}
+
+/// Options that shape the trace returned by [`TraceHandle::backtrace_with`].
+#[derive(Debug, Clone, Default)]
+pub struct TraceOptions {
+    max_depth: Option<usize>,
+    keep_internal_frames: bool,
+    include_spawned: bool,
+    collapse_inlined: bool,
+    mangled: bool,
+    symbolization_budget: Option<Duration>,
+}
+
+impl TraceOptions {
+    /// Creates options that return the full trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps at most `max_depth` frames of every branch, counting from the root. The rest of each
+    /// branch is replaced by a single `… (N frames omitted)` frame.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Keeps the frames of this crate that are otherwise left out of every trace, i.e. the poll of
+    /// the [`TracedTask`] at the root and the frames of its waker at every leaf.
+    pub fn keep_internal_frames(mut self) -> Self {
+        self.keep_internal_frames = true;
+        self
+    }
+
+    /// Whether to show the functions that were inlined into their callers as frames of their own,
+    /// which is the default. Optimized builds inline many functions, and with debug info the
+    /// symbolization recovers them. Without the expansion every branch only has a frame per
+    /// function that exists in the binary, like an unwinder that ignores the debug info would see.
+    pub fn expand_inlined(mut self, expand_inlined: bool) -> Self {
+        self.collapse_inlined = !expand_inlined;
+        self
+    }
+
+    /// Whether to demangle the symbols, which is the default. Without demangling the symbols of the
+    /// built-in capture are shown as they are found in the binary, e.g.
+    /// `_ZN3app5serve17h0123456789abcdefE`, to be fed to other tools or told apart when the
+    /// demangled names of two instances are the same. [`TraceFilter`] matches crates by the
+    /// demangled names, it can't tell the mangled ones apart. The symbols of a [`CaptureBackend`]
+    /// are shown as it resolves them either way.
+    pub fn demangle(mut self, demangle: bool) -> Self {
+        self.mangled = !demangle;
+        self
+    }
+
+    /// Stops symbolizing the trace once `budget` has passed, leaving the frames it didn't get to as
+    /// bare addresses, so that symbolizing a huge trace on a slow path takes a bounded time. The
+    /// budget starts when the trace is first inspected, see [`Trace::resolve`], and
+    /// [`Trace::is_partially_symbolized`] tells whether it ran out. The frames of this crate and of
+    /// labelled futures are recognized by their symbols, so the bare ones stay in the trace.
+    pub fn symbolization_budget(mut self, budget: Duration) -> Self {
+        self.symbolization_budget = Some(budget);
+        self
+    }
+
+    /// Appends the traces of the tasks spawned through `spawn_linked` or [`traced_scope`],
+    /// recursively, each under a `[spawned task]` root frame. Spawned tasks that have finished are
+    /// left out. [`TraceHandle::backtrace_deep`] places them where they were spawned instead.
+    pub fn include_spawned(mut self) -> Self {
+        self.include_spawned = true;
+        self
+    }
+
+    /// Returns a copy of `trace` shaped according to the options.
+    fn apply(&self, trace: &Trace) -> Result<Trace, TraceError> {
+        if leaf_count(trace) == 0 {
+            return Err(TraceError::NoLeafCaptured);
+        }
+        let mut trace = trace.clone();
+        if self.keep_internal_frames {
+            trace = trace.with_internal_frames();
+        }
+        if self.collapse_inlined {
+            trace = trace.without_inlined();
+        }
+        if self.mangled {
+            trace = trace.mangled();
+        }
+        if let Some(budget) = self.symbolization_budget {
+            trace = trace.with_budget(budget);
+        }
+        if let Some(max_depth) = self.max_depth {
+            trace = trace.truncated(max_depth);
+        }
+        Ok(trace)
+    }
+}
+
+/// Limits on what a capture records, see [`traced_with_limits`].
+///
+/// Unlike [`TraceOptions`], which shape a trace after it was captured, the limits apply while the
+/// poll is being captured and bound both the time spent capturing and the memory the trace takes.
+/// What was left out is marked in the rendered trace.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureLimits {
+    pub(crate) max_leaves: Option<usize>,
+    pub(crate) max_frames: Option<usize>,
+    /// What walks the stacks, when not the built-in walk, see [`traced_with_backend`].
+    pub(crate) backend: Option<backend::Backend>,
+}
+
+impl CaptureLimits {
+    /// Creates limits that record everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records at most `max_leaves` leaves per poll. The stack is not even walked for the leaves
+    /// after those, which show up as a single `… (N leaves omitted)` root.
+    pub fn max_leaves(mut self, max_leaves: usize) -> Self {
+        self.max_leaves = Some(max_leaves);
+        self
+    }
+
+    /// Records at most `max_frames` frames of every leaf, counting from the root. The innermost
+    /// frames past those are replaced by a single `… (N frames omitted)` frame.
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = Some(max_frames);
+        self
+    }
+}
+
+/// Wakes a thread blocked in [`TraceHandle::backtrace_blocking`].
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+struct TraceRequest {
+    tx: Reply,
+    /// The poll during which the trace should be captured. `None` means the next one.
+    at_poll: Option<usize>,
+    options: TraceOptions,
+}
+
+impl TraceRequest {
+    /// Sends a copy of `trace`, shaped according to the options of the request.
+    fn answer(self, trace: &Trace) {
+        self.tx.send(self.options.apply(trace));
+    }
+
+    /// Lets the requester know that the task panicked during the capture of `trace`.
+    fn panicked(self, message: &str, trace: &Trace) {
+        // The task may have panicked before any of its futures registered the waker
+        let trace = self.options.apply(trace).unwrap_or_else(|_| trace.clone());
+        self.tx.send(Err(TraceError::Panicked {
+            message: message.to_owned(),
+            trace: Box::new(trace),
+        }));
+    }
+}
+
+/// Where the answer to a [`TraceRequest`] goes.
+enum Reply {
+    Channel(Sender<Result<Trace, TraceError>>),
+    Callback(Box<dyn FnOnce(Result<Trace, TraceError>) + Send>),
+}
+
+impl Reply {
+    fn send(self, response: Result<Trace, TraceError>) {
+        match self {
+            Reply::Channel(tx) => {
+                let _ = tx.send(response);
+            }
+            Reply::Callback(callback) => callback(response),
+        }
+    }
+
+    /// Returns whether the requester gave up waiting for the answer.
+    fn is_canceled(&self) -> bool {
+        match self {
+            Reply::Channel(tx) => tx.is_canceled(),
+            Reply::Callback(_) => false,
+        }
+    }
+}
+
+/// State shared between a [`TracedTask`] and its [`TraceHandle`].
+struct TaskState {
+    /// The name given through [`traced_named`].
+    name: OnceLock<String>,
+    /// The id of the tokio task, for tasks spawned through [`spawn_traced`] or, with the
+    /// `tokio-unstable` feature, polled from within one.
+    #[cfg(feature = "tokio")]
+    tokio_id: OnceLock<tokio::task::Id>,
+    /// Where the time comes from, see [`traced_with_clock`].
+    clock: Arc<dyn Clock>,
+    /// When the task was created.
+    created: Instant,
+    /// The number of requests that may wait for the task to be polled, see [`traced_bounded`].
+    capacity: Option<usize>,
+    /// The number of requests waiting for the task to be polled. Counted before a request is sent,
+    /// so a task that sees none can skip the request channel, whose waker the request wakes.
+    queued: AtomicUsize,
+    /// The number of times the task was polled, including the ongoing poll.
+    polls: AtomicUsize,
+    /// When the last poll started, in nanoseconds since the task was created.
+    last_poll_nanos: AtomicU64,
+    /// The last trace along with the poll it was captured during, for tasks created with
+    /// [`traced_cached`].
+    latest: Option<Mutex<Option<(usize, Trace)>>>,
+    /// The cumulative time spent polling the task, in nanoseconds.
+    busy_nanos: AtomicU64,
+    /// The handles of the children spawned through [`traced_scope`].
+    children: Mutex<Vec<scope::Child>>,
+    /// The last status reported through [`TraceHandle::set_status`].
+    status: Mutex<Option<String>>,
+    /// The resources reported through [`TraceHandle::set_holding`].
+    holding: Mutex<Vec<String>>,
+    /// The resource reported through [`TraceHandle::set_waiting`].
+    waiting: Mutex<Option<String>>,
+    /// The streams returned by [`TraceHandle::watch`].
+    watchers: Mutex<Vec<Watcher>>,
+    /// Whether `watchers` may be non-empty, so unwatched polls don't take the lock.
+    watched: AtomicBool,
+    /// The sending half of the request channel, shared by all the handles.
+    req_tx: UnboundedSender<TraceRequest>,
+    /// How the task ended, once it did, and the signals returned by [`TraceHandle::finished`]
+    /// until then.
+    ended: Mutex<(Option<Completion>, Vec<Sender<Completion>>)>,
+    /// The backtrace of the last wake, see [`TraceHandle::last_wake_trace`].
+    last_wake: Mutex<Option<Trace>>,
+    /// The thread that last polled the task, see
+    /// [`TraceHandle::backtrace_with_thread_fallback`].
+    #[cfg(all(feature = "thread-backtrace", target_os = "linux"))]
+    polled_by: thread_backtrace::PolledBy,
+}
+
+impl TaskState {
+    /// Records how the task ended and resolves the signals waiting for it, unless it already ended.
+    fn end(&self, completion: Completion) {
+        let mut ended = self.ended.lock().unwrap();
+        if ended.0.is_none() {
+            ended.0 = Some(completion);
+            for tx in ended.1.drain(..) {
+                let _ = tx.send(completion);
+            }
+        }
+    }
+
+    /// The time since the task was created, by its clock.
+    fn since_created(&self) -> Duration {
+        self.clock.now().duration_since(self.created)
+    }
+
+    /// The time since `earlier`, by the clock of the task.
+    fn since(&self, earlier: Instant) -> Duration {
+        self.clock.now().duration_since(earlier)
+    }
+
+    /// The fraction of the task's lifetime spent inside its `poll` method, counting `polling` on
+    /// top for the poll in progress, if any.
+    fn busy_ratio(&self, polling: Duration) -> f64 {
+        let busy = Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)) + polling;
+        let lifetime = self.since_created();
+        if lifetime.is_zero() {
+            return 0.0;
+        }
+        (busy.as_secs_f64() / lifetime.as_secs_f64()).min(1.0)
+    }
+}
+
+struct Watcher {
+    tx: UnboundedSender<Trace>,
+    min_interval: Duration,
+    // When the last trace was sent
+    last: Option<Instant>,
+}
+
+impl Watcher {
+    /// Returns whether the watcher wants a trace of a poll that started at `now`.
+    fn is_due(&self, now: Instant) -> bool {
+        self.last
+            .is_none_or(|last| now.duration_since(last) >= self.min_interval)
+    }
+}
+
+/// The stream returned by [`TraceHandle::watch_every`].
+#[cfg(feature = "tokio")]
+struct WatchEvery {
+    handle: TraceHandle,
+    interval: Duration,
+    /// The pending request, `None` once the task finished.
+    next: Option<Request>,
+}
+
+#[cfg(feature = "tokio")]
+type Request = Pin<Box<dyn Future<Output = Result<Trace, TraceError>> + Send>>;
+
+#[cfg(feature = "tokio")]
+impl Stream for WatchEvery {
+    type Item = Trace;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Trace>> {
+        loop {
+            let Some(next) = &mut self.next else {
+                return Poll::Ready(None);
+            };
+            let result = std::task::ready!(next.as_mut().poll(cx));
+            if let Err(TraceError::TaskFinished | TraceError::Cancelled) = result {
+                self.next = None;
+                return Poll::Ready(None);
+            }
+            let (handle, interval) = (self.handle.clone(), self.interval);
+            self.next = Some(Box::pin(async move {
+                tokio::time::sleep(interval).await;
+                handle.backtrace().await
+            }));
+            if let Ok(trace) = result {
+                return Poll::Ready(Some(trace));
+            }
+        }
+    }
+}
+
+struct Hooks {
+    before: Box<dyn Fn() + Send>,
+    after: Box<dyn Fn(&Trace) + Send>,
+}
+
+struct Sampler {
+    every: usize,
+    sink: Box<dyn Fn(Trace) + Send>,
+}
+
+/// The most recent capture of a task, used to answer requests that arrive too soon after it.
+struct CaptureCache {
+    min_interval: Duration,
+    last: Option<(Instant, Trace)>,
+}
+
+impl CaptureCache {
+    /// Returns the last captured trace if it was captured less than `min_interval` before `now`.
+    fn recent(&self, now: Instant) -> Option<&Trace> {
+        match &self.last {
+            Some((at, trace)) if now.duration_since(*at) < self.min_interval => Some(trace),
+            _ => None,
+        }
+    }
+}
+
+pin_project! {
+    #[project = TracedTaskProj]
+    pub struct TracedTask<F> {
+        #[pin]
+        fut: F,
+        #[pin]
+        req_rx: UnboundedReceiver<TraceRequest>,
+        // The waker last registered with the request channel
+        registered: Option<Waker>,
+        hooks: Option<Hooks>,
+        state: Arc<TaskState>,
+        // The number of times the task has been polled
+        polls: usize,
+        // Requests waiting for a specific poll
+        armed: Vec<TraceRequest>,
+        sampler: Option<Sampler>,
+        completion: Option<Sender<Completion>>,
+        cache: Option<CaptureCache>,
+        // Captures a trace every that many polls, see `traced_with_snapshots`
+        snapshot_every: Option<usize>,
+        limits: CaptureLimits,
+        // Wraps the waker of every poll, see `traced_with_wake_tracing`
+        wakes: Option<wakes::WakeTracing>,
+        // Reports the moves of the task, see `traced_with_transition_callback`
+        transitions: Option<transition::Transitions>,
+        // Annotates the frames with their ages, see `traced_with_frame_ages`
+        frame_ages: Option<age::FrameAges>,
+        // Runs once the task completes or is dropped, whichever comes first
+        on_end: Option<Box<dyn FnOnce() + Send>>,
+        // Captures a last trace if the task is dropped before completing
+        dump_on_drop: Option<Box<dyn FnOnce(Pin<&mut F>) + Send>>,
+    }
+
+    impl<F> PinnedDrop for TracedTask<F> {
+        fn drop(mut this: Pin<&mut Self>) {
+            let task = this.as_mut().project();
+            if let Some(dump) = task.dump_on_drop.take() {
+                if *task.polls > 0 && is_enabled() && !thread::panicking() {
+                    dump(task.fut);
+                }
+            }
+            // SAFETY: Abandoning the task leaves the pinned future alone
+            unsafe { this.get_unchecked_mut() }.abandon();
+        }
+    }
+}
+
+impl<F> TracedTask<F> {
+    /// Returns another handle to the task, like the one returned along with it.
+    pub fn handle(&self) -> TraceHandle {
+        TraceHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Returns the wrapped future, which is no longer traced. Requests for a trace fail with
+    /// [`TraceError::TaskFinished`] from then on, as if the task had been dropped.
+    ///
+    /// A task can only be unwrapped before it is first polled, since polling pins it in place.
+    pub fn into_inner(self) -> F {
+        let mut this = ManuallyDrop::new(self);
+        this.abandon();
+        // SAFETY: The task is never used or dropped again, so every field is moved out or dropped
+        // exactly once
+        unsafe {
+            let fut = ptr::read(&this.fut);
+            let TracedTask {
+                fut: _,
+                req_rx,
+                registered,
+                hooks,
+                state,
+                polls: _,
+                armed,
+                sampler,
+                completion,
+                cache,
+                snapshot_every: _,
+                limits,
+                wakes,
+                transitions,
+                frame_ages,
+                on_end,
+                dump_on_drop,
+            } = &mut *this;
+            ptr::drop_in_place(req_rx);
+            ptr::drop_in_place(registered);
+            ptr::drop_in_place(hooks);
+            ptr::drop_in_place(state);
+            ptr::drop_in_place(armed);
+            ptr::drop_in_place(sampler);
+            ptr::drop_in_place(completion);
+            ptr::drop_in_place(cache);
+            ptr::drop_in_place(limits);
+            ptr::drop_in_place(wakes);
+            ptr::drop_in_place(transitions);
+            ptr::drop_in_place(frame_ages);
+            ptr::drop_in_place(on_end);
+            ptr::drop_in_place(dump_on_drop);
+            fut
+        }
+    }
+
+    /// Lets everyone waiting on the task know that it won't be polled to completion.
+    fn abandon(&mut self) {
+        self.req_rx.close();
+        self.state.watchers.lock().unwrap().clear();
+        if let Some(tx) = self.completion.take() {
+            let _ = tx.send(Completion::Cancelled);
+        }
+        self.state.end(Completion::Cancelled);
+        if let Some(on_end) = self.on_end.take() {
+            on_end();
+        }
+    }
+}
+
+impl<F: Future> Future for TracedTask<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_with(
+            cx,
+            (),
+            |fut, cx, ()| fut.poll(cx),
+            |fut, waker, limits, ()| capture(fut, waker, limits),
+            |_| true,
+        )
+    }
+}
+
+impl<F> TracedTask<F> {
+    /// Polls the wrapped value with `poll`, or with `capture` when a trace is due, passing `arg`
+    /// to whichever of them is called and answering trace requests along the way. The task ends
+    /// once the poll returns a ready output for which `is_last` holds.
+    fn poll_with<A, T>(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        arg: A,
+        poll: impl FnOnce(Pin<&mut F>, &mut Context<'_>, A) -> Poll<T>,
+        capture: impl FnOnce(Pin<&mut F>, &Waker, &CaptureLimits, A) -> (Poll<T>, Trace),
+        is_last: impl Fn(&T) -> bool,
+    ) -> Poll<T> {
+        let mut this = self.project();
+        if !is_enabled() {
+            // Tracing is disabled or compiled out, all that is left to do is to notice the end of
+            // the task
+            let result = poll(this.fut.as_mut(), cx, arg);
+            if matches!(&result, Poll::Ready(output) if is_last(output)) {
+                this.finish(cx);
+            }
+            return result;
+        }
+        let poll_start = this.state.clock.now();
+        let _current = scope::enter(this.state);
+        #[cfg(all(feature = "thread-backtrace", target_os = "linux"))]
+        let state = Arc::clone(this.state);
+        #[cfg(all(feature = "thread-backtrace", target_os = "linux"))]
+        let _polling = thread_backtrace::enter(&state.polled_by);
+
+        *this.polls += 1;
+        this.state.polls.store(*this.polls, Ordering::Relaxed);
+        // Tasks spawned by other means learn the id of the tokio task that polls them first
+        #[cfg(all(feature = "tokio-unstable", tokio_unstable))]
+        if *this.polls == 1 {
+            if let Some(id) = tokio::task::try_id() {
+                let _ = this.state.tokio_id.set(id);
+            }
+        }
+        let since_created = poll_start.duration_since(this.state.created);
+        this.state
+            .last_poll_nanos
+            .store(since_created.as_nanos() as u64, Ordering::Relaxed);
+
+        // The future may see a different waker than the request channel, see `WakeTracing`
+        let wake_recorder = this
+            .wakes
+            .as_mut()
+            .map(|wakes| wakes.waker(cx.waker(), this.state));
+        let poll_fut = |fut: Pin<&mut F>, cx: &mut Context<'_>, arg| match &wake_recorder {
+            Some(waker) => poll(fut, &mut Context::from_waker(waker), arg),
+            None => poll(fut, cx, arg),
+        };
+
+        // Gather all the requests that want a trace of this poll. Polling the channel leaves the
+        // waker registered with it, so it only needs polling for queued requests or a new waker
+        let mut requesters = Vec::new();
+        let registered = this
+            .registered
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()));
+        if !registered || this.state.queued.load(Ordering::Relaxed) > 0 {
+            while let Poll::Ready(Some(req)) = this.req_rx.as_mut().poll_next(cx) {
+                this.state.queued.fetch_sub(1, Ordering::Relaxed);
+                // The requester gave up waiting, e.g. after a timeout
+                if req.tx.is_canceled() {
+                    continue;
+                }
+                match req.at_poll {
+                    // Too late, dropping the request lets the requester know
+                    Some(n) if n < *this.polls => {}
+                    Some(n) if n > *this.polls => this.armed.push(req),
+                    _ => requesters.push(req),
+                }
+            }
+            // Cloning the waker of an enclosing capture would record a leaf
+            if !registered && !Trace::is_capturing() {
+                *this.registered = Some(cx.waker().clone());
+            }
+        }
+        let sample = match this.sampler {
+            Some(sampler) => this.polls.is_multiple_of(sampler.every),
+            None => false,
+        };
+        let watched = this.state.watched.load(Ordering::Relaxed) && {
+            let mut watchers = this.state.watchers.lock().unwrap();
+            // Dropped streams stop the captures
+            watchers.retain(|watcher| !watcher.tx.is_closed());
+            if watchers.is_empty() {
+                this.state.watched.store(false, Ordering::Relaxed);
+            }
+            watchers.iter().any(|watcher| watcher.is_due(poll_start))
+        };
+        let snapshot = this
+            .snapshot_every
+            .is_some_and(|n| this.polls.is_multiple_of(n));
+        // Samples, snapshots, watches and requests for this specific poll always get a fresh
+        // trace
+        let mut fresh = sample
+            || snapshot
+            || watched
+            || this.transitions.is_some()
+            || this.frame_ages.is_some();
+        let mut i = 0;
+        while i < this.armed.len() {
+            if this.armed[i].tx.is_canceled() {
+                this.armed.swap_remove(i);
+            } else if this.armed[i].at_poll == Some(*this.polls) {
+                requesters.push(this.armed.swap_remove(i));
+                fresh = true;
+            } else {
+                i += 1;
+            }
+        }
+        if !fresh && !requesters.is_empty() {
+            let recent = this
+                .cache
+                .as_ref()
+                .and_then(|cache| cache.recent(poll_start));
+            if let Some(trace) = recent {
+                for req in requesters.drain(..) {
+                    req.answer(trace);
+                }
+            }
+        }
+
+        let result = if requesters.is_empty() && !fresh {
+            poll_fut(this.fut.as_mut(), cx, arg)
+        } else {
+            if let Some(hooks) = this.hooks {
+                (hooks.before)();
+            }
+            let waker = wake_recorder.as_ref().unwrap_or(cx.waker());
+            let captured = catch_unwind(AssertUnwindSafe(|| {
+                capture(this.fut.as_mut(), waker, this.limits, arg)
+            }));
+            let (result, mut trace) = match captured {
+                Ok(captured) => captured,
+                Err(payload) => {
+                    let mut trace = Trace::take_panicked();
+                    if let Some(name) = this.state.name.get() {
+                        trace = trace.named(name);
+                    }
+                    let message = panic_message(&*payload);
+                    for req in requesters {
+                        req.panicked(message, &trace);
+                    }
+                    resume_unwind(payload);
+                }
+            };
+            #[cfg(feature = "metrics")]
+            stats::record_trace(&trace);
+            trace = trace.with_busy_ratio(this.state.busy_ratio(this.state.since(poll_start)));
+            if let Some(name) = this.state.name.get() {
+                trace = trace.named(name);
+            }
+            if let Some(ages) = this.frame_ages {
+                trace = ages.observe(&trace);
+            }
+            if let Some(hooks) = this.hooks {
+                (hooks.after)(&trace);
+            }
+            if let Some(cache) = this.cache {
+                cache.last = Some((this.state.clock.now(), trace.clone()));
+            }
+            if let (Some(latest), true) = (&this.state.latest, result.is_pending()) {
+                *latest.lock().unwrap() = Some((*this.polls, trace.clone()));
+            }
+            for mut req in requesters {
+                match &result {
+                    Poll::Pending => req.answer(&trace),
+                    // A task that completed during the capture has nothing left to trace
+                    Poll::Ready(output) if is_last(output) => {
+                        req.tx.send(Err(TraceError::TaskFinished));
+                    }
+                    // A stream that yielded an item wasn't waiting on anything, so the
+                    // request waits for the next poll instead
+                    Poll::Ready(_) => {
+                        req.at_poll = Some(*this.polls + 1);
+                        this.armed.push(req);
+                    }
+                }
+            }
+            if watched && result.is_pending() {
+                for watcher in this.state.watchers.lock().unwrap().iter_mut() {
+                    if watcher.is_due(poll_start) {
+                        watcher.last = Some(poll_start);
+                        let _ = watcher.tx.unbounded_send(trace.clone());
+                    }
+                }
+            }
+            if let (Some(transitions), true) = (this.transitions.as_mut(), result.is_pending()) {
+                transitions.observe(*this.polls, &trace);
+            }
+            if let (true, Some(sampler)) = (sample, &this.sampler) {
+                (sampler.sink)(trace);
+            }
+            result
+        };
+
+        let busy = this.state.since(poll_start);
+        #[cfg(feature = "metrics")]
+        stats::record_poll(busy);
+        let busy = busy.as_nanos() as u64;
+        this.state.busy_nanos.fetch_add(busy, Ordering::Relaxed);
+        if matches!(&result, Poll::Ready(output) if is_last(output)) {
+            this.finish(cx);
+        }
+        result
+    }
+}
+
+impl<F> TracedTaskProj<'_, F> {
+    /// Lets everyone waiting on the task know that it completed.
+    fn finish(&mut self, cx: &mut Context<'_>) {
+        // The task won't be polled again, so answer any outstanding and future requests now
+        self.req_rx.as_mut().get_mut().close();
+        while let Poll::Ready(Some(req)) = self.req_rx.as_mut().poll_next(cx) {
+            req.tx.send(Err(TraceError::TaskFinished));
+        }
+        for req in self.armed.drain(..) {
+            req.tx.send(Err(TraceError::TaskFinished));
+        }
+        self.state.watchers.lock().unwrap().clear();
+        if let Some(tx) = self.completion.take() {
+            let _ = tx.send(Completion::Completed);
+        }
+        self.state.end(Completion::Completed);
+        if let Some(on_end) = self.on_end.take() {
+            on_end();
+        }
+        *self.dump_on_drop = None;
+    }
+}
+
+/// Returns the message a panic was raised with, if it has one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("Box<dyn Any>", String::as_str),
+    }
+}
+
+struct TracedWaker<'a>(&'a Waker);
+
+impl TracedWaker<'_> {
+    /// Returns a waker backed by this one, which must not outlive it.
+    fn waker(&self) -> ManuallyDrop<Waker> {
+        let raw_waker = RawWaker::new(self as *const _ as *const (), &TRACE_WAKER_VTABLE);
+        // SAFETY: RawWaker is well formed
+        unsafe { ManuallyDrop::new(Waker::from_raw(raw_waker)) }
+    }
+}
+
+const TRACE_WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_raw, wake_raw, wake_by_ref_raw, drop_raw);
+
+/// Clones a TracedWaker. Crucially, cloning converts it into the original wrapped Waker.
+///
+/// SAFETY:
+/// The data pointer must point to a valid TracedWaker
+unsafe fn clone_raw(data: *const ()) -> RawWaker {
+    Trace::leaf();
+    let this: &TracedWaker = &*(data as *const TracedWaker);
+    let waker: Waker = this.0.clone();
+    // SAFETY: Waker is a repr(transparent) over RawWaker
+    std::mem::transmute::<Waker, RawWaker>(waker)
+}
+
+/// SAFETY:
+/// The data pointer must point to a valid TracedWaker
+unsafe fn wake_by_ref_raw(data: *const ()) {
+    Trace::leaf();
+    let this: &TracedWaker = &*(data as *const TracedWaker);
+    this.0.wake_by_ref();
+}
+
+/// No one can end up holding an owned Waker that is backed by a TracedWaker since cloning the
+/// original &Waker (backed by a TracedWaker) produces an owned Waker that is backed by the cloned
+/// *inner* Waker. Therefore this method will never get called.
+unsafe fn wake_raw(_data: *const ()) {
+    unreachable!("an owned Waker backed by TracedWaker is never constructed");
+}
+
+/// No one can end up holding an owned Waker that is backed by a TracedWaker since cloning the
+/// original &Waker (backed by a TracedWaker) produces an owned Waker that is backed by the cloned
+/// *inner* Waker. Therefore this method will never get called.
+unsafe fn drop_raw(_data: *const ()) {
+    unreachable!("an owned Waker backed by TracedWaker is never constructed");
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+
+    #[tokio::test]
+    async fn it_works() {
+        let (foo_fut, trace_handle) = traced(foo());
+        tokio::spawn(foo_fut);
+
+        println!("{}", trace_handle.backtrace().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn local_set_run_until() {
+        let local = tokio::task::LocalSet::new();
+        let (fut, trace_handle) = traced(async {
+            let not_send = std::rc::Rc::new(());
+            foo().await;
+            drop(not_send);
+        });
+        let capture = local.spawn_local(async move { trace_handle.backtrace().await });
+
+        let trace = local
+            .run_until(async move {
+                tokio::select! {
+                    _ = fut => unreachable!(),
+                    trace = capture => trace.unwrap().unwrap(),
+                }
+            })
+            .await;
+        assert!(trace.to_string().contains("test_util::pending"));
+    }
+
+    #[tokio::test]
+    async fn manual_future() {
+        let (fut, trace_handle) = traced(Join(Box::pin(fiz()), Box::pin(ManualLeaf(None))));
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(
+            trace.contains("<tasktrace::tests::Join<A,B> as core::future::future::Future>::poll")
+        );
+        assert!(
+            trace.contains("<tasktrace::tests::ManualLeaf as core::future::future::Future>::poll")
+        );
+        assert!(trace.contains("tests::fiz"));
+    }
+
+    #[tokio::test]
+    async fn hooks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let before_calls = Arc::new(AtomicUsize::new(0));
+        let after_calls = Arc::new(AtomicUsize::new(0));
+        let (fut, trace_handle) = traced_with_hooks(
+            foo(),
+            {
+                let before_calls = Arc::clone(&before_calls);
+                move || {
+                    before_calls.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+            {
+                let after_calls = Arc::clone(&after_calls);
+                let before_calls = Arc::clone(&before_calls);
+                move |trace| {
+                    assert_eq!(before_calls.load(Ordering::SeqCst), 1);
+                    assert!(trace.to_string().contains("test_util::pending"));
+                    after_calls.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+        );
+        tokio::spawn(fut);
+
+        trace_handle.backtrace().await.unwrap();
+        assert_eq!(before_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(after_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn coalesced_requests() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let captures = Arc::new(AtomicUsize::new(0));
+        let (fut, trace_handle) = traced_with_hooks(foo(), || {}, {
+            let captures = Arc::clone(&captures);
+            move |_| {
+                captures.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        // Every request is queued before the task is first polled
+        let requests = tokio::spawn(async move {
+            futures::future::join_all((0..8).map(|_| trace_handle.backtrace())).await
+        });
+        tokio::task::yield_now().await;
+        tokio::spawn(fut);
+
+        let traces: Vec<_> = requests.await.unwrap().into_iter().flatten().collect();
+        assert_eq!(traces.len(), 8);
+        assert_eq!(captures.load(Ordering::SeqCst), 1);
+        // The answers share the symbolization
+        assert!(!traces[7].is_resolved());
+        traces[0].resolve();
+        assert!(traces[7].is_resolved());
+        assert_eq!(traces[7].to_string(), traces[0].to_string());
+    }
+
+    #[tokio::test]
+    async fn last_polled_at() {
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (fut, trace_handle) = traced(rx);
+        assert_eq!(trace_handle.last_polled_at(), None);
+
+        let before = Instant::now();
+        let task = tokio::spawn(fut);
+        tokio::task::yield_now().await;
+        let polled = trace_handle.last_polled_at().unwrap();
+        assert!(polled >= before);
+
+        // Only polls move it forward
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(trace_handle.last_polled_at(), Some(polled));
+        tx.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        assert!(trace_handle.last_polled_at().unwrap() > polled);
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (fut, trace_handle) = traced(async {
+            let _ = rx.await;
+            std::thread::sleep(Duration::from_millis(20));
+        });
+        let stats = trace_handle.stats();
+        assert_eq!(stats.polls(), 0);
+        assert_eq!(stats.busy(), Duration::ZERO);
+        assert_eq!(stats.mean_poll(), Duration::ZERO);
+
+        let task = tokio::spawn(fut);
+        tokio::task::yield_now().await;
+        tx.send(()).unwrap();
+        task.await.unwrap();
+        let stats = trace_handle.stats();
+        assert_eq!(stats.polls(), 2);
+        assert!(stats.busy() >= Duration::from_millis(20));
+        assert_eq!(stats.mean_poll(), stats.busy() / 2);
+        assert!(stats.age() >= stats.busy());
+    }
+
+    #[tokio::test]
+    async fn busy_ratio() {
+        let (fut, trace_handle) = traced(async {
+            std::thread::sleep(Duration::from_millis(50));
+            pending().await;
+        });
+        tokio::spawn(fut);
+        trace_handle.backtrace().await.unwrap();
+        assert!(trace_handle.busy_ratio() > 0.5);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(trace_handle.busy_ratio() < 0.5);
+    }
+
+    #[tokio::test]
+    async fn stream_adapters() {
+        use futures::stream::{self, StreamExt};
+
+        let (fut, trace_handle) = traced(stream::iter(0..3).for_each(|_| pending()));
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.contains("ForEach<St,Fut,F>"));
+        assert!(trace.contains("test_util::pending"));
+
+        let (fut, trace_handle) = traced(stream::iter(0..3).fold((), |(), _| pending()));
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.contains("Fold<St,Fut,T,F>"));
+        assert!(trace.contains("test_util::pending"));
+
+        // FuturesUnordered polls its children with wakers of its own so the trace stops there
+        let buffered = stream::iter(0..3).map(|_| pending()).buffer_unordered(3);
+        let (fut, trace_handle) = traced(buffered.collect::<Vec<_>>());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.contains("FuturesUnordered<Fut>"));
+        assert!(!trace.contains("test_util::pending"));
+    }
+
+    #[tokio::test]
+    async fn backtrace_at_poll() {
+        let (fut, trace_handle) = traced(async {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            pending().await;
+        });
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace_at_poll(5).await.unwrap();
+        assert!(trace.to_string().contains("yield_now"));
+        let trace = trace_handle.backtrace_at_poll(11).await.unwrap();
+        assert!(trace.to_string().contains("test_util::pending"));
+        assert_eq!(
+            trace_handle.backtrace_at_poll(3).await.err(),
+            Some(TraceError::Cancelled)
+        );
+    }
+
+    #[tokio::test]
+    async fn backtrace_tree() {
+        let (fut, trace_handle) = traced(async {
+            let (child, _child_handle) = traced_scope(fiz());
+            tokio::spawn(child);
+            baz().await;
+        });
+        tokio::spawn(fut);
+
+        let tree = tokio::spawn(async move { trace_handle.backtrace_tree().await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(tree.trace().to_string().contains("tests::baz"));
+        assert_eq!(tree.children().len(), 1);
+        let child = &tree.children()[0];
+        assert!(child.trace().to_string().contains("tests::fiz"));
+        assert!(child.children().is_empty());
+        assert!(tree.to_string().contains("spawned task:"));
+
+        let (fut, trace_handle) = traced(async {});
+        fut.await;
+        assert_eq!(
+            trace_handle.backtrace_tree().await.err(),
+            Some(TraceError::TaskFinished)
+        );
+    }
+
+    #[tokio::test]
+    async fn finished_children_are_dropped() {
+        let (fut, trace_handle) = traced(async {
+            for _ in 0..100 {
+                let (child, _child_handle) = traced_scope(async {});
+                child.await;
+            }
+        });
+        fut.await;
+        // Only the last child is still recorded, until the next one is spawned
+        assert_eq!(trace_handle.state.children.lock().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn spawn_linked() {
+        let (fut, trace_handle) = traced(async {
+            let _child = super::spawn_linked(fiz());
+            baz().await;
+        });
+        tokio::spawn(fut);
+
+        let options = TraceOptions::new().include_spawned();
+        let trace = trace_handle.backtrace_with(options).await.unwrap();
+        let roots = trace.roots();
+        assert_eq!(roots.len(), 2);
+        assert!(roots[0]
+            .to_string()
+            .contains("tests::spawn_linked::{{closure}}"));
+        assert_eq!(roots[1].symbol(), Some("[spawned task]"));
+        assert_eq!(super::leaf_count(&trace), 2);
+        let rendered = trace.to_string();
+        assert!(rendered.contains("tests::baz"));
+        assert!(rendered.contains("\n╼ [spawned task]\n  └╼ tasktrace::tests::fiz::{{closure}}"));
+
+        // Without the option the trace stops at the parent
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(!trace.contains("tests::fiz"));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn backtrace_deep() {
+        async fn supervise() {
+            let (_child, _child_handle) = super::spawn_linked(fiz());
+            baz().await;
+        }
+        let (fut, trace_handle) = traced(async {
+            supervise().await;
+        });
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace_deep().await.unwrap();
+        assert_eq!(trace.roots().len(), 1);
+        assert_eq!(super::leaf_count(&trace), 2);
+        let supervise = &trace.roots()[0].children()[0];
+        assert!(supervise
+            .symbol()
+            .unwrap()
+            .ends_with("::supervise::{{closure}}"));
+        let [waiting, spawned] = supervise.children() else {
+            panic!("expected two children, got {}", supervise.children().len());
+        };
+        assert!(waiting.symbol().unwrap().contains("tests::baz"));
+        assert!(spawned
+            .symbol()
+            .unwrap()
+            .starts_with("[spawned task at src/lib.rs:"));
+        assert!(spawned.children()[0]
+            .symbol()
+            .unwrap()
+            .contains("tests::fiz"));
+
+        // The plain trace stops at the parent
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(!trace.contains("tests::fiz"));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn tracing_span() {
+        use tracing::Instrument;
+
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let (fut, trace_handle) = traced(async {
+            futures::join!(pending().instrument(tracing::info_span!("fetch")), fiz())
+        });
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap();
+        let rendered = trace.to_string();
+        let spans: Vec<_> = rendered
+            .lines()
+            .filter(|line| line.contains("[span: "))
+            .collect();
+        assert_eq!(spans.len(), 1, "{rendered}");
+        assert!(spans[0].contains("<core::task::wake::Waker as core::clone::Clone>::clone"));
+        assert!(spans[0].ends_with(" [span: fetch]"));
+        // The span is below the future it instruments
+        let instrumented = rendered.find("tracing::instrument::Instrumented").unwrap();
+        assert!(instrumented < rendered.find("[span: fetch]").unwrap());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn spawn_traced() {
+        let (join_handle, trace_handle) = super::spawn_traced(foo());
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert!(trace.to_string().contains("tests::fiz"));
+        assert_eq!(trace_handle.tokio_id(), Some(join_handle.id()));
+
+        join_handle.abort();
+        assert!(join_handle.await.unwrap_err().is_cancelled());
+        assert_eq!(traced(foo()).1.tokio_id(), None);
+    }
+
+    #[test]
+    fn local_pool() {
+        use futures::executor::LocalPool;
+        use futures::task::LocalSpawnExt;
+
+        let mut pool = LocalPool::new();
+        let (fut, trace_handle) = traced(foo());
+        pool.spawner().spawn_local(fut).unwrap();
+        pool.run_until_stalled();
+
+        let trace = pool.run_until(trace_handle.backtrace()).unwrap();
+        assert!(trace.to_string().contains("tests::fiz"));
+        assert!(trace.to_string().contains("tests::baz"));
+    }
+
+    #[tokio::test]
+    async fn backtrace_string() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace_string().await.unwrap();
+        assert!(trace.contains("tests::fiz"));
+    }
+
+    #[tokio::test]
+    async fn every_n_polls() {
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let (fut, trace_handle) = traced_every_n_polls(
+            async {
+                for _ in 0..9 {
+                    tokio::task::yield_now().await;
+                }
+                pending().await;
+            },
+            5,
+            {
+                let samples = Arc::clone(&samples);
+                move |trace| samples.lock().unwrap().push(trace.to_string())
+            },
+        );
+        tokio::spawn(fut);
+        trace_handle.backtrace_at_poll(10).await.unwrap();
+
+        let samples = samples.lock().unwrap();
+        assert_eq!(samples.len(), 2);
+        assert!(samples[0].contains("yield_now"));
+        assert!(samples[1].contains("test_util::pending"));
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        use futures::StreamExt;
+
+        let (fut, trace_handle) = traced(async {
+            for _ in 0..3 {
+                tokio::task::yield_now().await;
+            }
+        });
+        let watch = trace_handle.watch();
+        tokio::spawn(fut);
+
+        // One trace for every poll that left the task pending, then the stream ends
+        let traces: Vec<_> = watch.map(|trace| trace.to_string()).collect().await;
+        assert_eq!(traces.len(), 3);
+        assert!(traces.iter().all(|trace| trace.contains("yield_now")));
+
+        // Watching a finished task ends right away
+        assert_eq!(trace_handle.watch().count().await, 0);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn watch_every() {
+        use futures::StreamExt;
+
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (fut, trace_handle) = traced(async { rx.await.unwrap() });
+        let task = tokio::spawn(fut);
+        let start = tokio::time::Instant::now();
+        let mut watch = Box::pin(trace_handle.watch_every(Duration::from_secs(1)));
+
+        // The task is idle but traced every interval nonetheless
+        for i in 0..3 {
+            let trace = watch.next().await.unwrap();
+            assert!(trace.to_string().contains("Receiver"));
+            assert_eq!(start.elapsed(), Duration::from_secs(i));
+        }
+
+        tx.send(()).unwrap();
+        task.await.unwrap();
+        assert!(watch.next().await.is_none());
+        assert!(watch.next().await.is_none());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn traced_every() {
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (traces_tx, mut traces) = futures_channel::mpsc::unbounded();
+        let (fut, _trace_handle) = super::traced_every(
+            async { rx.await.unwrap() },
+            Duration::from_secs(1),
+            move |trace| traces_tx.unbounded_send(trace).unwrap(),
+        );
+        let task = tokio::spawn(fut);
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        tx.send(()).unwrap();
+        task.await.unwrap();
+        // The idle task was traced right away and then every second, until it ended
+        let mut count = 0;
+        while let Some(trace) = futures::StreamExt::next(&mut traces).await {
+            assert!(trace.to_string().contains("Receiver"));
+            count += 1;
+        }
+        assert_eq!(count, 3);
+    }
+
+    #[tokio::test]
+    async fn watch_sampled() {
+        use futures::StreamExt;
+
+        let (fut, trace_handle) = traced(async {
+            for _ in 0..5 {
+                tokio::task::yield_now().await;
+            }
+        });
+        let sampled = trace_handle.watch_sampled(Duration::from_secs(3600));
+        tokio::spawn(fut);
+        assert_eq!(sampled.count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn watch_dropped() {
+        let (fut, trace_handle) = traced(async {
+            for _ in 0..3 {
+                tokio::task::yield_now().await;
+            }
+            pending().await;
+        });
+        drop(trace_handle.watch());
+        tokio::spawn(fut);
+        trace_handle.backtrace_at_poll(4).await.unwrap();
+        assert!(trace_handle.state.watchers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn into_inner() {
+        let (fut, trace_handle) = traced(async { 42 });
+        let fut = fut.into_inner();
+        assert_eq!(
+            trace_handle.backtrace().await.err(),
+            Some(TraceError::TaskFinished)
+        );
+        assert_eq!(fut.await, 42);
+    }
+
+    #[tokio::test]
+    async fn handle() {
+        let (fut, _trace_handle) = traced(foo());
+        let trace_handle = fut.handle();
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert!(trace.to_string().contains("tests::fiz"));
+    }
+
+    #[test]
+    fn capture_once() {
+        let fut = foo();
+        futures::pin_mut!(fut);
+        let waker = futures::task::noop_waker();
+        let (poll, trace) = super::capture_once(fut.as_mut(), &waker);
+        assert!(poll.is_pending());
+
+        let roots = trace.roots();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(
+            roots[0].symbol(),
+            Some("tasktrace::tests::foo::{{closure}}")
+        );
+        let trace = trace.to_string();
+        assert!(trace.contains("tests::fiz"));
+        assert!(trace.contains("tests::buz"));
+    }
+
+    #[tokio::test]
+    async fn named() {
+        let (fut, trace_handle) = traced_named(foo(), "worker 1");
+        assert_eq!(trace_handle.name(), Some("worker 1"));
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(trace.name(), Some("worker 1"));
+        let busy_ratio = trace.busy_ratio().unwrap();
+        assert!((0.0..=1.0).contains(&busy_ratio));
+        let header = format!("Task \"worker 1\" (busy {:.0}%):\n", busy_ratio * 100.0);
+        let rendered = trace.to_string();
+        assert!(rendered.starts_with(&format!("{header}╼ tasktrace::tests::foo::{{{{closure}}}}")));
+
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        assert_eq!(trace_handle.name(), None);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(trace.name(), None);
+        assert!(trace.busy_ratio().is_some());
+        assert!(trace.to_string().starts_with("╼ "));
+    }
+
+    #[tokio::test]
+    async fn no_leaf_captured() {
+        // Spins without ever touching the waker
+        let (fut, trace_handle) = traced(std::future::poll_fn(|_| Poll::<()>::Pending));
+        futures::pin_mut!(fut);
+
+        let request = trace_handle.backtrace();
+        futures::pin_mut!(request);
+        assert!(futures::poll!(&mut request).is_pending());
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert_eq!(request.await.err(), Some(TraceError::NoLeafCaptured));
+    }
+
+    #[tokio::test]
+    async fn completion() {
+        let (fut, _trace_handle, completion) = traced_with_completion(async {});
+        tokio::spawn(fut);
+        assert_eq!(completion.await, Completion::Completed);
+
+        let (fut, _trace_handle, completion) = traced_with_completion(foo());
+        let task = tokio::spawn(fut);
+        task.abort();
+        assert_eq!(completion.await, Completion::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn min_capture_interval() {
+        use std::sync::atomic::AtomicUsize;
+
+        let captures = Arc::new(AtomicUsize::new(0));
+        let (mut fut, trace_handle) =
+            traced_with_min_capture_interval(foo(), Duration::from_secs(60));
+        fut.hooks = Some(Hooks {
+            before: Box::new(|| {}),
+            after: Box::new({
+                let captures = Arc::clone(&captures);
+                move |_| {
+                    captures.fetch_add(1, Ordering::SeqCst);
+                }
+            }),
+        });
+        tokio::spawn(fut);
+
+        let first = trace_handle.backtrace_string().await.unwrap();
+        let second = trace_handle.backtrace_string().await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(captures.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn mock_clock() {
+        use std::sync::atomic::AtomicUsize;
+
+        use futures::{FutureExt, StreamExt};
+
+        use crate::test_util::{MockClock, TestExecutor};
+
+        // Every poll takes a second
+        let clock = MockClock::new();
+        let ticking = clock.clone();
+        let start = clock.now();
+        let (mut fut, trace_handle) = traced_with_clock(
+            std::future::poll_fn(move |cx| {
+                ticking.advance(Duration::from_secs(1));
+                let _waker = cx.waker().clone();
+                Poll::<()>::Pending
+            }),
+            clock.clone(),
+        );
+        fut.cache = Some(CaptureCache {
+            min_interval: Duration::from_secs(10),
+            last: None,
+        });
+        let captures = Arc::new(AtomicUsize::new(0));
+        fut.hooks = Some(Hooks {
+            before: Box::new(|| {}),
+            after: Box::new({
+                let captures = Arc::clone(&captures);
+                move |_| {
+                    captures.fetch_add(1, Ordering::SeqCst);
+                }
+            }),
+        });
+        let mut executor = TestExecutor::new(fut);
+
+        clock.advance(Duration::from_secs(9));
+        assert!(executor.poll_once().is_pending());
+        assert_eq!(
+            trace_handle.last_polled_at(),
+            Some(start + Duration::from_secs(9))
+        );
+        let stats = trace_handle.stats();
+        assert_eq!(stats.polls(), 1);
+        assert_eq!(stats.busy(), Duration::from_secs(1));
+        assert_eq!(stats.age(), Duration::from_secs(10));
+        assert_eq!(trace_handle.busy_ratio(), 0.1);
+
+        // Captured at 11s, the trace is reused until 21s
+        let mut request = |advance| {
+            clock.advance(Duration::from_secs(advance));
+            trace_handle
+                .request_backtrace(|trace| drop(trace.unwrap()))
+                .unwrap();
+            assert!(executor.poll_once().is_pending());
+            captures.load(Ordering::SeqCst)
+        };
+        assert_eq!(request(0), 1);
+        assert_eq!(request(5), 1);
+        assert_eq!(request(5), 2);
+
+        // Sampled at 23s and 29s, skipping 27s
+        let mut sampled = trace_handle.watch_sampled(Duration::from_secs(5));
+        for advance in [0, 3, 1] {
+            clock.advance(Duration::from_secs(advance));
+            assert!(executor.poll_once().is_pending());
+        }
+        drop(executor);
+        let mut count = 0;
+        while let Some(Some(_)) = sampled.next().now_or_never() {
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn backtrace_with_budget() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+
+        let trace = trace_handle
+            .backtrace_with_budget(Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(!trace.is_partially_symbolized());
+        assert!(trace.to_string().contains("test_util::pending"));
+
+        let trace = trace_handle
+            .backtrace_with_budget(Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(trace.is_resolved());
+        assert!(trace.is_partially_symbolized());
+        let rendered = trace.to_string();
+        assert!(!rendered.contains("test_util::pending"));
+        assert!(rendered.starts_with("╼ 0x"));
+
+        let full = trace.fully_symbolized();
+        assert!(!full.is_partially_symbolized());
+        assert!(full.to_string().contains("test_util::pending"));
+        assert!(trace.is_partially_symbolized());
+    }
+
+    #[tokio::test]
+    async fn concurrent_clones() {
+        use std::sync::atomic::AtomicUsize;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<TraceHandle>();
+
+        let captures = Arc::new(AtomicUsize::new(0));
+        let (fut, trace_handle) = traced_with_hooks(foo(), || {}, {
+            let captures = Arc::clone(&captures);
+            move |_| {
+                captures.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        tokio::spawn(fut);
+        tokio::task::yield_now().await;
+
+        // Both requests are queued before the task gets polled again so they share a capture
+        let first = tokio::spawn({
+            let trace_handle = trace_handle.clone();
+            async move { trace_handle.backtrace_string().await }
+        });
+        let second = tokio::spawn({
+            let trace_handle = trace_handle.clone();
+            async move { trace_handle.backtrace_string().await }
+        });
+        let first = first.await.unwrap().unwrap();
+        let second = second.await.unwrap().unwrap();
+        assert_eq!(first, second);
+        assert_eq!(captures.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn trace_error() {
+        fn assert_error<E: Error + Send + Sync + 'static>() {}
+        assert_error::<TraceError>();
+
+        let (fut, trace_handle) = traced(foo());
+        let request = trace_handle.backtrace();
+        futures::pin_mut!(request);
+        assert!(futures::poll!(&mut request).is_pending());
+        drop(fut);
+        let err = request.await.err().unwrap();
+        assert_eq!(err, TraceError::Cancelled);
+        assert_eq!(err.to_string(), "the trace request was cancelled");
+        assert!(err
+            .source()
+            .unwrap()
+            .is::<futures_channel::oneshot::Canceled>());
+
+        let err = trace_handle.backtrace().await.err().unwrap();
+        assert_eq!(err, TraceError::TaskFinished);
+        assert_eq!(err.to_string(), "the traced task has finished");
+        assert!(err.source().is_none());
+
+        assert_eq!(TraceError::Disabled.to_string(), "tracing is disabled");
+        assert!(TraceError::Disabled.source().is_none());
+
+        assert_eq!(
+            TraceError::Timeout.to_string(),
+            "timed out waiting for a trace"
+        );
+        assert!(TraceError::Timeout.source().is_none());
+
+        assert_eq!(
+            TraceError::WouldDeadlock.to_string(),
+            "waiting for a trace would deadlock"
+        );
+        assert!(TraceError::WouldDeadlock.source().is_none());
+
+        assert_eq!(
+            TraceError::Overloaded.to_string(),
+            "too many trace requests are pending"
+        );
+        assert!(TraceError::Overloaded.source().is_none());
+
+        assert_eq!(
+            TraceError::NoLeafCaptured.to_string(),
+            "no future of the task registered its waker"
+        );
+        assert!(TraceError::NoLeafCaptured.source().is_none());
+
+        let panicked = TraceError::Panicked {
+            message: "boom".to_owned(),
+            trace: Box::new(Trace::from_roots(Vec::new())),
+        };
+        assert_eq!(panicked.to_string(), "the traced task panicked: boom");
+        assert!(panicked.source().is_none());
+
+        // Boxed like `anyhow` and friends do, the variants can still be matched on
+        let boxed: Box<dyn Error + Send + Sync + 'static> = Box::new(TraceError::Overloaded);
+        assert_eq!(
+            boxed.downcast_ref::<TraceError>(),
+            Some(&TraceError::Overloaded)
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn backtrace_when_parked() {
+        let (fut, trace_handle) = traced(async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            pending().await;
+        });
+        tokio::spawn(fut);
+
+        // The task moves at 1s and 2s and then stays put
+        let start = tokio::time::Instant::now();
+        let trace = trace_handle
+            .backtrace_when_parked(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(7));
+        assert!(trace.to_string().contains("test_util::pending"));
+
+        drop(trace_handle);
+        let (fut, trace_handle) = traced(async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        tokio::spawn(fut);
+        let err = trace_handle
+            .backtrace_when_parked(Duration::from_secs(5))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err, TraceError::TaskFinished);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn backtrace_timeout() {
+        use std::sync::atomic::AtomicUsize;
+
+        let captures = Arc::new(AtomicUsize::new(0));
+        let (fut, trace_handle) = traced_with_hooks(foo(), || {}, {
+            let captures = Arc::clone(&captures);
+            move |_| {
+                captures.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        futures::pin_mut!(fut);
+
+        // The task is never polled so the request can't be answered
+        let start = tokio::time::Instant::now();
+        let err = trace_handle
+            .backtrace_timeout(Duration::from_secs(5))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err, TraceError::Timeout);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        // The withdrawn request doesn't trigger a capture once the task gets polled
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert_eq!(captures.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn cached() {
+        let (fut, trace_handle) = traced_cached(foo());
+        let mut fut = Box::pin(fut);
+
+        let mut request = Box::pin(trace_handle.backtrace());
+        assert!(futures::poll!(&mut request).is_pending());
+        assert!(futures::poll!(&mut fut).is_pending());
+        let first = request.await.unwrap();
+
+        // The task hasn't been polled since, so no capture is needed
+        let mut request = Box::pin(trace_handle.backtrace());
+        let Poll::Ready(second) = futures::poll!(&mut request) else {
+            panic!("the cached trace was not returned right away");
+        };
+        assert_eq!(second.unwrap().to_string(), first.to_string());
+        let options = TraceOptions::new().max_depth(1);
+        let truncated = trace_handle.backtrace_with(options).await.unwrap();
+        assert_eq!(truncated.roots()[0].children().len(), 1);
+
+        // Polling the task invalidates the cached trace
+        assert!(futures::poll!(&mut fut).is_pending());
+        let mut request = Box::pin(trace_handle.backtrace());
+        assert!(futures::poll!(&mut request).is_pending());
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert!(request.await.is_ok());
+
+        // Traces of a task that is gone aren't handed out
+        drop(fut);
+        let err = trace_handle.backtrace().await.err().unwrap();
+        assert_eq!(err, TraceError::TaskFinished);
+    }
+
+    #[test]
+    fn dump_on_drop() {
+        let dumped = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let dumped = Arc::clone(&dumped);
+            move |trace: Trace| dumped.lock().unwrap().push(trace.to_string())
+        };
+
+        let (fut, _trace_handle) = traced_dump_on_drop(foo(), sink.clone());
+        let mut fut = Box::pin(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        drop(fut);
+        let traces = std::mem::take(&mut *dumped.lock().unwrap());
+        assert_eq!(traces.len(), 1);
+        assert!(traces[0].contains("tests::fiz"));
+        assert!(traces[0].contains("tests::baz"));
+
+        // Tasks that never started or that completed have nothing to dump
+        let (fut, _trace_handle) = traced_dump_on_drop(foo(), sink.clone());
+        drop(fut);
+        let (fut, _trace_handle) = traced_dump_on_drop(async {}, sink);
+        let mut fut = Box::pin(fut);
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+        drop(fut);
+        assert!(dumped.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn request_backtrace() {
+        let (fut, trace_handle) = traced(foo());
+        let mut fut = Box::pin(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let answer = Arc::new(Mutex::new(None));
+        trace_handle
+            .request_backtrace({
+                let answer = Arc::clone(&answer);
+                move |trace| *answer.lock().unwrap() = Some(trace)
+            })
+            .unwrap();
+        assert!(answer.lock().unwrap().is_none());
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        let trace = answer.lock().unwrap().take().unwrap().unwrap();
+        assert!(trace.to_string().contains("tests::fiz"));
+
+        drop(fut);
+        let err = trace_handle.request_backtrace(|_| unreachable!());
+        assert_eq!(err, Err(TraceError::TaskFinished));
+    }
+
+    #[test]
+    fn panicked() {
+        let (fut, trace_handle) = traced(async {
+            futures::join!(fiz(), async {
+                futures::pending!();
+                panic!("boom");
+            })
+        });
+        let mut fut = Box::pin(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+
+        let answer = Arc::new(Mutex::new(None));
+        trace_handle
+            .request_backtrace({
+                let answer = Arc::clone(&answer);
+                move |trace| *answer.lock().unwrap() = Some(trace)
+            })
+            .unwrap();
+        // The panic carries on once the request is answered
+        let payload = catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(&mut cx)));
+        assert_eq!(panic_message(&*payload.unwrap_err()), "boom");
+        let Some(Err(TraceError::Panicked { message, trace })) = answer.lock().unwrap().take()
+        else {
+            panic!("the request wasn't told about the panic");
+        };
+        assert_eq!(message, "boom");
+        assert!(trace.to_string().contains("tests::fiz"));
+
+        // The capture that was interrupted doesn't linger on the thread
+        let (fut, trace_handle) = traced(foo());
+        let mut fut = Box::pin(fut);
+        trace_handle
+            .request_backtrace({
+                let answer = Arc::clone(&answer);
+                move |trace| *answer.lock().unwrap() = Some(trace)
+            })
+            .unwrap();
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        let trace = answer.lock().unwrap().take().unwrap().unwrap();
+        assert!(trace
+            .to_string()
+            .starts_with("╼ tasktrace::tests::foo::{{closure}}"));
+    }
+
+    #[test]
+    fn request_wakes() {
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let (fut, trace_handle) = traced(foo());
+        let mut fut = Box::pin(fut);
+        let first = Arc::new(Flag(AtomicBool::new(false)));
+        let second = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(Arc::clone(&first));
+        assert!(fut
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        // Polls with the same waker and no queued request leave the channel alone, but a new
+        // waker has to be registered with it
+        assert!(fut
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        let waker = Waker::from(Arc::clone(&second));
+        assert!(fut
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+
+        trace_handle.request_backtrace(|_| {}).unwrap();
+        assert!(!first.0.load(Ordering::SeqCst));
+        assert!(second.0.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn snapshots() {
+        let (fut, trace_handle) = traced_with_snapshots(foo(), 2);
+        let mut fut = Box::pin(fut);
+
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert!(trace_handle.last_trace().is_none());
+        assert!(futures::poll!(&mut fut).is_pending());
+        let trace = trace_handle.last_trace().unwrap();
+        assert!(trace.to_string().contains("tests::fiz"));
+
+        // The snapshot is of the last poll, so requests don't have to wait for the next one
+        let mut request = Box::pin(trace_handle.backtrace());
+        assert!(futures::poll!(&mut request).is_ready());
+
+        // Tasks without a cache never have a trace at hand
+        assert!(traced(foo()).1.last_trace().is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_request() {
+        use std::sync::atomic::AtomicUsize;
+
+        let captures = Arc::new(AtomicUsize::new(0));
+        let (fut, trace_handle) = traced_with_hooks(foo(), || {}, {
+            let captures = Arc::clone(&captures);
+            move |_| {
+                captures.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        futures::pin_mut!(fut);
+
+        // The request is queued and then given up on before the task gets polled
+        let mut request = Box::pin(trace_handle.backtrace());
+        assert!(futures::poll!(&mut request).is_pending());
+        drop(request);
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert_eq!(captures.load(Ordering::SeqCst), 0);
+
+        // Requests for a later poll are dropped once their requester is gone as well
+        let mut request = Box::pin(trace_handle.backtrace_at_poll(3));
+        assert!(futures::poll!(&mut request).is_pending());
+        assert!(futures::poll!(&mut fut).is_pending());
+        drop(request);
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert_eq!(captures.load(Ordering::SeqCst), 0);
+
+        // Requests that are still awaited are answered
+        let mut request = Box::pin(trace_handle.backtrace());
+        assert!(futures::poll!(&mut request).is_pending());
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert!(request.await.is_ok());
+        assert_eq!(captures.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn serde() {
+        use serde_json::Value;
+
+        /// Returns the first frame that has more than one child.
+        fn join_point(frames: &[Value]) -> Option<&Value> {
+            frames.iter().find_map(|frame| {
+                let children = frame["children"].as_array().unwrap();
+                if children.len() > 1 {
+                    Some(frame)
+                } else {
+                    join_point(children)
+                }
+            })
+        }
+
+        let (fut, trace_handle) = traced_named(foo(), "fooer");
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let json = serde_json::to_value(&trace).unwrap();
+        assert_eq!(trace.to_json_value(), json);
+        assert_eq!(json["name"], "fooer");
+        assert!(json["poll_duration"].is_object());
+        assert!(json["busy_ratio"].is_f64());
+        let roots = json["roots"].as_array().unwrap();
+        assert_eq!(roots.len(), 1);
+        let root = &roots[0];
+        assert!(root["symbol"].as_str().unwrap().contains("tests::foo"));
+        assert!(root["file"].as_str().unwrap().ends_with("lib.rs"));
+        assert!(root["line"].is_u64());
+        assert!(root["col"].is_u64());
+
+        let join = join_point(roots).unwrap();
+        let branches = join["children"].as_array().unwrap();
+        assert_eq!(branches.len(), 2);
+        let rendered = [&branches[0], &branches[1]].map(|branch| branch.to_string());
+        assert!(rendered[0].contains("tests::fiz"));
+        assert!(rendered[1].contains("tests::buz"));
+
+        let deserialized: Trace = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized.to_string(), trace.to_string());
+        assert_eq!(deserialized.name(), Some("fooer"));
+        assert_eq!(deserialized.poll_duration(), trace.poll_duration());
+        assert!(deserialized.busy_ratio().is_some());
+    }
+
+    #[tokio::test]
+    async fn fold() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let folded = super::fold(&trace);
+        let lines: Vec<_> = folded.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_ne!(lines[0], lines[1]);
+        for line in &lines {
+            assert!(line.starts_with("tasktrace::tests::foo::{{closure}};"));
+            assert!(line.ends_with("<core::task::wake::Waker as core::clone::Clone>::clone 1"));
+            assert!(!line.contains("clone_raw"));
+        }
+        assert!(lines[0].contains(";tasktrace::tests::fiz::{{closure}};"));
+        assert!(lines[1].contains(";tasktrace::tests::buz::{{closure}};"));
+    }
+
+    #[tokio::test]
+    async fn fingerprints() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let fingerprints = trace.fingerprints();
+        assert_eq!(fingerprints.len(), 2);
+        assert_ne!(fingerprints[0], fingerprints[1]);
+        assert!(!trace.is_resolved());
+        // The task is parked at the same places
+        let again = trace_handle.backtrace_fingerprints_only().await.unwrap();
+        assert_eq!(again, fingerprints);
+
+        let (fut, trace_handle) = traced(fiz());
+        tokio::spawn(fut);
+        let other = trace_handle.backtrace_fingerprints_only().await.unwrap();
+        assert_eq!(other.len(), 1);
+        assert!(!fingerprints.contains(&other[0]));
+
+        // Symbolized traces are fingerprinted by their frames
+        let filtered = TraceFilter::new().apply(&trace).fingerprints();
+        assert_eq!(filtered.len(), 2);
+        assert_ne!(filtered[0], filtered[1]);
+    }
+
+    #[tokio::test]
+    async fn mangled_symbols() {
+        let (fut, trace_handle) = traced(fiz());
+        tokio::spawn(fut);
+        let symbols = |trace: &Trace| -> Vec<String> {
+            let branch = crate::branches(trace).next().unwrap();
+            branch
+                .frames()
+                .map(|frame| frame.symbol().unwrap().to_owned())
+                .collect()
+        };
+        let demangled = symbols(&trace_handle.backtrace().await.unwrap());
+        let options = TraceOptions::new().demangle(false);
+        let mangled = symbols(&trace_handle.backtrace_with(options).await.unwrap());
+
+        // The same frames are kept, and their symbols demangle to the usual ones
+        assert_eq!(mangled.len(), demangled.len());
+        for (mangled, demangled) in mangled.iter().zip(&demangled) {
+            assert!(
+                mangled.starts_with("_ZN") || mangled.starts_with("_R"),
+                "{mangled}"
+            );
+            let name = backtrace::SymbolName::new(mangled.as_bytes());
+            assert_eq!(format!("{name:#}"), *demangled);
+        }
+    }
+
+    #[tokio::test]
+    async fn leaf_histogram() {
+        let (fut, trace_handle) = traced(async {
+            let pending = futures::future::join_all((0..3).map(|_| pending()));
+            tokio::join!(pending, tokio::time::sleep(Duration::from_secs(60)))
+        });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let histogram = trace.leaf_histogram();
+        assert_eq!(histogram.len(), 2);
+        assert_eq!(histogram.values().sum::<usize>(), 4);
+        let (pending, count) = histogram
+            .iter()
+            .find(|(leaf, _)| {
+                leaf.symbol()
+                    .unwrap()
+                    .starts_with("tasktrace::test_util::pending")
+            })
+            .unwrap();
+        assert_eq!(*count, 3);
+        assert!(pending.to_string().contains(" at "));
+    }
+
+    #[tokio::test]
+    async fn branch_ids() {
+        let (fut, trace_handle) = traced(async { tokio::join!(fiz(), buz()) });
+        tokio::spawn(fut);
+        let ids = |trace: &Trace| {
+            let mut ids: Vec<_> = crate::branches(trace).map(|branch| branch.id()).collect();
+            ids.sort();
+            ids
+        };
+        let first = ids(&trace_handle.backtrace().await.unwrap());
+        assert_eq!(first.len(), 2);
+        assert_ne!(first[0], first[1]);
+        // The second capture sees the branches in the other order
+        let second = ids(&trace_handle.backtrace().await.unwrap());
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn branches() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let branches: Vec<_> = super::branches(&trace).collect();
+        assert_eq!(branches.len(), 2);
+        for (branch, expected) in branches.iter().zip(["tests::fiz", "tests::buz"]) {
+            let symbols: Vec<_> = branch.frames().filter_map(Frame::symbol).collect();
+            assert_eq!(symbols[0], "tasktrace::tests::foo::{{closure}}");
+            assert!(symbols.iter().any(|symbol| symbol.contains(expected)));
+            assert!(branch.leaf().children().is_empty());
+            assert!(branch.frames().any(|frame| frame.line().is_some()));
+        }
+    }
+
+    #[tokio::test]
+    async fn deferred_resolution() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        // Nothing was symbolized in the poll that captured the trace
+        assert!(!trace.is_resolved());
+
+        let trace = std::thread::spawn(move || {
+            trace.resolve();
+            trace
+        })
+        .join()
+        .unwrap();
+        assert!(trace.is_resolved());
+        assert!(trace.to_string().contains("tests::fiz"));
+    }
+
+    #[tokio::test]
+    async fn to_dot() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let dot = super::to_dot(&trace);
+        assert!(dot.starts_with("digraph trace {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("    n0 [label=\"tasktrace::tests::foo::{{closure}}\\n"));
+        assert!(dot.contains("src/lib.rs:"));
+        // One node per frame and one edge per call
+        let frames = trace.to_string().lines().count();
+        assert_eq!(dot.matches(" [label=\"").count(), frames);
+        assert_eq!(dot.matches(" -> ").count(), frames - 1);
+    }
+
+    #[tokio::test]
+    async fn write_trace() {
+        let (fut, trace_handle) = traced_named(foo(), "foo");
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let mut bytes = Vec::new();
+        super::write_trace(&mut bytes, &trace).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), trace.to_string());
+
+        let mut string = String::new();
+        super::write_trace_fmt(&mut string, &trace).unwrap();
+        assert_eq!(string, trace.to_string());
+    }
+
+    #[tokio::test]
+    async fn leaf_count() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(super::leaf_count(&trace), 2);
+
+        let (fut, trace_handle) = traced(pending());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(super::leaf_count(&trace), 1);
+
+        // Identical leaves count individually, even though they share a branch of the tree
+        let (fut, trace_handle) = traced(futures::future::join_all((0..3).map(|_| pending())));
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(super::leaf_count(&trace), 3);
+
+        // Without the backtraces, e.g. after deserializing, only the distinct branches are known
+        let symbolized = Trace::from_roots(trace.roots().to_vec());
+        assert_eq!(super::leaf_count(&symbolized), 1);
+    }
+
+    #[tokio::test]
+    async fn bounded() {
+        let (fut, trace_handle) = traced_bounded(foo(), 2);
+        futures::pin_mut!(fut);
+
+        // Nobody polls the task so the requests pile up
+        let first = trace_handle.backtrace();
+        let second = trace_handle.backtrace();
+        futures::pin_mut!(first, second);
+        assert!(futures::poll!(&mut first).is_pending());
+        assert!(futures::poll!(&mut second).is_pending());
+        assert_eq!(
+            trace_handle.backtrace().await.err(),
+            Some(TraceError::Overloaded)
+        );
+
+        // Polling the task drains the queue
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert!(first.await.is_ok());
+        assert!(second.await.is_ok());
+        let third = trace_handle.backtrace();
+        futures::pin_mut!(third);
+        assert!(futures::poll!(&mut third).is_pending());
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert!(third.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn completes_during_capture() {
+        let (ready_tx, ready_rx) = futures_channel::oneshot::channel::<()>();
+        let (fut, trace_handle) = traced(async move {
+            ready_rx.await.unwrap();
+        });
+        futures::pin_mut!(fut);
+        assert!(futures::poll!(&mut fut).is_pending());
+
+        // The task completes on the poll that serves the request
+        let request = trace_handle.backtrace();
+        futures::pin_mut!(request);
+        assert!(futures::poll!(&mut request).is_pending());
+        ready_tx.send(()).unwrap();
+        assert!(futures::poll!(&mut fut).is_ready());
+        assert_eq!(request.await.err(), Some(TraceError::TaskFinished));
+
+        // The completed task is still around but answers right away
+        assert_eq!(
+            trace_handle.backtrace().await.err(),
+            Some(TraceError::TaskFinished)
+        );
+    }
+
+    #[tokio::test]
+    async fn max_depth() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+
+        let options = TraceOptions::new().max_depth(3);
+        let trace = trace_handle.backtrace_with(options).await.unwrap();
+        let lines: Vec<_> = trace.to_string().lines().map(str::to_owned).collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("tests::foo"));
+        assert!(lines[1].contains("tests::bar"));
+        assert!(lines[3].starts_with("        └╼ … ("));
+        assert!(lines[3].ends_with(" frames omitted)"));
+
+        // Requests without a max depth still get the full trace
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.contains("tests::fiz"));
+    }
+
+    #[tokio::test]
+    async fn capture_limits() {
+        let limits = CaptureLimits::new().max_leaves(1);
+        let (fut, trace_handle) = traced_with_limits(foo(), limits);
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(crate::leaf_count(&trace), 2);
+        let rendered = trace.to_string();
+        assert!(rendered.contains("tests::fiz"));
+        assert!(!rendered.contains("tests::buz"));
+        assert!(rendered.contains("\n╼ … (1 leaves omitted)"));
+
+        let limits = CaptureLimits::new().max_frames(4);
+        let (fut, trace_handle) = traced_with_limits(foo(), limits);
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert_eq!(crate::leaf_count(&trace), 2);
+        for branch in crate::branches(&trace) {
+            assert!(branch
+                .leaf()
+                .symbol()
+                .unwrap()
+                .ends_with(" frames omitted)"));
+            // The internal frame at the root counts towards the limit but is stripped, making room
+            // for the placeholder
+            assert_eq!(branch.frames().count(), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn internal_frames() {
+        const INTERNAL: [&str; 3] = [
+            "tasktrace::TracedTask",
+            "tasktrace::clone_raw",
+            "tasktrace::wake_by_ref_raw",
+        ];
+
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.starts_with("╼ tasktrace::tests::foo::{{closure}}"));
+        assert!(INTERNAL.iter().all(|symbol| !trace.contains(symbol)));
+
+        let options = TraceOptions::new().keep_internal_frames();
+        let trace = trace_handle
+            .backtrace_with(options)
+            .await
+            .unwrap()
+            .to_string();
+        assert!(trace.starts_with("╼ tasktrace::capture::{{closure}}"));
+        assert_eq!(trace.matches("tasktrace::clone_raw").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn nested() {
+        let (inner, inner_handle) = traced(foo());
+        let (outer, outer_handle) = traced(inner);
+        tokio::spawn(outer);
+
+        // Both requests are served by the same poll of the outer task
+        let (inner_trace, outer_trace) =
+            tokio::join!(inner_handle.backtrace(), outer_handle.backtrace());
+        let inner_trace = inner_trace.unwrap().to_string();
+        let outer_trace = outer_trace.unwrap().to_string();
+        let leaves = |trace: &str| trace.matches("Waker as core::clone::Clone>::clone").count();
+
+        // Every leaf is recorded once per capture
+        assert!(inner_trace.starts_with("╼ tasktrace::tests::foo::{{closure}}"));
+        assert_eq!(leaves(&inner_trace), 2);
+
+        // The outer task sees the inner one as any other future, including the leaf of the inner
+        // task waiting for trace requests
+        assert!(outer_trace
+            .starts_with("╼ <tasktrace::TracedTask<F> as core::future::future::Future>::poll at "));
+        assert_eq!(leaves(&outer_trace), 3);
+        assert!(outer_trace.contains("UnboundedReceiver<T> as futures_core::stream::Stream>"));
+        assert!(outer_trace.contains("tests::fiz"));
+        assert!(outer_trace.contains("tests::buz"));
+    }
+
+    #[tokio::test]
+    async fn nested_with_backend() {
+        let (inner, inner_handle) = traced_with_backend(foo(), BacktraceBackend);
+        let (outer, outer_handle) = traced_with_backend(inner, BacktraceBackend);
+        tokio::spawn(outer);
+
+        let (inner_trace, outer_trace) =
+            tokio::join!(inner_handle.backtrace(), outer_handle.backtrace());
+        let leaves = |trace: Trace| {
+            trace
+                .to_string()
+                .matches("Waker as core::clone::Clone>::clone")
+                .count()
+        };
+
+        // The calls into the waker of the outer task aren't recorded again for the inner one
+        assert_eq!(leaves(inner_trace.unwrap()), 2);
+        assert_eq!(leaves(outer_trace.unwrap()), 3);
+    }
+
+    #[test]
+    fn backtrace_blocking() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (fut, trace_handle) = traced(foo());
+        runtime.spawn(fut);
+
+        let trace =
+            std::thread::spawn(move || trace_handle.backtrace_blocking(Duration::from_secs(10)))
+                .join()
+                .unwrap()
+                .unwrap();
+        assert!(trace.to_string().contains("tests::fiz"));
+
+        // A task that is never polled can't answer
+        let (_fut, trace_handle) = traced(foo());
+        let err = trace_handle
+            .backtrace_blocking(Duration::from_millis(10))
+            .err();
+        assert_eq!(err, Some(TraceError::Timeout));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn backtrace_blocking_in_runtime() {
+        let (fut, trace_handle) = traced(foo());
+        tokio::spawn(fut);
+        let err = trace_handle
+            .backtrace_blocking(Duration::from_secs(10))
+            .err();
+        assert_eq!(err, Some(TraceError::WouldDeadlock));
+    }
+
+    #[tokio::test]
+    async fn downgrade() {
+        let (fut, trace_handle) = traced(foo());
+        let weak = trace_handle.downgrade();
+        tokio::spawn(fut);
+        assert!(!trace_handle.is_finished());
+        let trace_handle = weak.upgrade().unwrap();
+        assert!(trace_handle.backtrace().await.is_ok());
+
+        // Once the task is gone the strong handles keep it finished, the weak ones let it go
+        let (fut, trace_handle) = traced(foo());
+        let weak = trace_handle.downgrade();
+        drop(fut);
+        assert!(trace_handle.is_finished());
+        assert!(weak.upgrade().unwrap().is_finished());
+        drop(trace_handle);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn finished() {
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (fut, trace_handle) = traced(rx);
+        let finished = trace_handle.finished();
+        tokio::spawn(fut);
+        assert_eq!(trace_handle.completion(), None);
+        tx.send(()).unwrap();
+        assert_eq!(finished.await, Completion::Completed);
+        assert_eq!(trace_handle.completion(), Some(Completion::Completed));
+        // Signals asked for later resolve right away
+        assert_eq!(trace_handle.finished().await, Completion::Completed);
+
+        let (fut, trace_handle) = traced(foo());
+        let finished = trace_handle.finished();
+        drop(fut);
+        assert_eq!(finished.await, Completion::Cancelled);
+        assert_eq!(trace_handle.completion(), Some(Completion::Cancelled));
+    }
+
+    #[test]
+    fn status() {
+        let (_fut, trace_handle) = traced(foo());
+        assert_eq!(trace_handle.status(), None);
+        trace_handle.set_status("retrying connection");
+        assert_eq!(
+            trace_handle.status().as_deref(),
+            Some("retrying connection")
+        );
+        trace_handle.set_status("waiting for lock");
+        assert_eq!(trace_handle.status().as_deref(), Some("waiting for lock"));
+    }
+
+    #[test]
+    fn resources() {
+        let (_fut, trace_handle) = traced(foo());
+        trace_handle.set_holding("lock:orders");
+        trace_handle.set_waiting("lock:accounts");
+        assert_eq!(trace_handle.waiting().as_deref(), Some("lock:accounts"));
+
+        trace_handle.set_holding("lock:accounts");
+        trace_handle.set_holding("lock:accounts");
+        assert_eq!(trace_handle.holding(), ["lock:orders", "lock:accounts"]);
+        assert_eq!(trace_handle.waiting(), None);
+
+        trace_handle.release("lock:orders");
+        assert_eq!(trace_handle.holding(), ["lock:accounts"]);
+        trace_handle.set_waiting("lock:orders");
+        trace_handle.clear_waiting();
+        assert_eq!(trace_handle.waiting(), None);
+    }
+
+    #[tokio::test]
+    async fn async_stream() {
+        use futures::StreamExt;
+
+        let stream = async_stream::stream! {
+            yield 1;
+            pending().await;
+            yield 2;
+        };
+        let (fut, trace_handle) = traced(stream.collect::<Vec<_>>());
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.contains("tests::async_stream::{{closure}}::{{closure}}"));
+        assert!(trace.contains("test_util::pending"));
+    }
+
+    #[tokio::test]
+    async fn self_trace() {
+        let (handle_tx, handle_rx) = futures_channel::oneshot::channel::<TraceHandle>();
+        let (fut, trace_handle) = traced(async move {
+            let trace_handle = handle_rx.await.unwrap();
+            assert!(trace_handle.is_current_task());
+            trace_handle.backtrace().await.unwrap().to_string()
+        });
+        assert!(!trace_handle.is_current_task());
+        let task = tokio::spawn(fut);
+        handle_tx.send(trace_handle).ok().unwrap();
+
+        let trace = task.await.unwrap();
+        assert!(trace.contains("TraceHandle::request"));
+    }
+
+    #[tokio::test]
+    async fn newtype_futures() {
+        let (fut, trace_handle) = traced(Transparent(Delegating {
+            inner: foo(),
+            polls: 0,
+        }));
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace
+            .contains("<tasktrace::tests::Transparent<F> as core::future::future::Future>::poll"));
+        assert!(trace
+            .contains("<tasktrace::tests::Delegating<F> as core::future::future::Future>::poll"));
+        assert!(trace.contains("tests::fiz"));
+        assert!(trace.contains("tests::baz"));
+    }
+
+    #[repr(transparent)]
+    #[must_use]
+    struct Transparent<F>(F);
+
+    impl<F: Future> Future for Transparent<F> {
+        type Output = F::Output;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+            // SAFETY: the inner future is structurally pinned
+            unsafe { self.map_unchecked_mut(|this| &mut this.0) }.poll(cx)
+        }
+    }
+
+    pin_project! {
+        struct Delegating<F> {
+            #[pin]
+            inner: F,
+            polls: usize,
+        }
+    }
+
+    impl<F: Future> Future for Delegating<F> {
+        type Output = F::Output;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+            let this = self.project();
+            *this.polls += 1;
+            this.inner.poll(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_future() {
+        use futures::FutureExt;
+
+        let shared = fiz().shared();
+        let (fut, trace_handle) = traced(async move {
+            tokio::join!(shared.clone(), shared);
+        });
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap();
+        let branches: Vec<_> = crate::branches(&trace).collect();
+        assert_eq!(branches.len(), 2);
+        for branch in &branches {
+            // Below `Shared::poll` there is only the waker it stores for the branch
+            let below: Vec<_> = branch
+                .frames()
+                .skip_while(|frame| !frame.is_shared_poll())
+                .filter_map(Frame::symbol)
+                .collect();
+            assert_eq!(below.len(), 3, "{trace}");
+            assert!(below[1].starts_with("futures_util::future::future::shared::Inner<"));
+            assert_eq!(
+                below[2],
+                "<core::task::wake::Waker as core::clone::Clone>::clone"
+            );
+        }
+        // The frames of the shared future itself appear under neither branch
+        let rendered = trace.to_string();
+        assert!(!rendered.contains("tasktrace::tests::fiz"), "{rendered}");
+        assert!(!rendered.contains("test_util::pending"), "{rendered}");
+        assert_eq!(rendered.matches(" [shared]").count(), 2, "{rendered}");
+    }
+
+    #[tokio::test]
+    async fn atomic_waker_leaf() {
+        let (fut, trace_handle) = traced(AtomicWakerLeaf(Default::default()));
+        tokio::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace
+            .contains("<tasktrace::tests::AtomicWakerLeaf as core::future::future::Future>::poll"));
+        assert!(trace.contains("AtomicWaker::register"));
+    }
+
+    /// A leaf future that registers its waker in an `AtomicWaker` and never completes.
+    struct AtomicWakerLeaf(futures::task::AtomicWaker);
+
+    impl Future for AtomicWakerLeaf {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.0.register(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    /// A hand written combinator that polls both of its children on every poll.
+    struct Join<A, B>(Pin<Box<A>>, Pin<Box<B>>);
+
+    impl<A: Future, B: Future> Future for Join<A, B> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let a = self.0.as_mut().poll(cx);
+            let b = self.1.as_mut().poll(cx);
+            match (a, b) {
+                (Poll::Ready(_), Poll::Ready(_)) => Poll::Ready(()),
+                _ => Poll::Pending,
+            }
+        }
+    }
+
+    /// A hand written leaf future that stashes the waker and never completes.
+    struct ManualLeaf(Option<Waker>);
+
+    impl Future for ManualLeaf {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.0 = Some(cx.waker().clone());
+            Poll::Pending
+        }
     }
 
     async fn foo() {
@@ -0,0 +1,144 @@
+//! A registry of traced tasks for single threaded executors.
+//!
+//! The [registry](crate::registry) is shared by the whole process and waits for the tasks through
+//! tokio's timers. A [`LocalRegistry`] belongs to the thread that creates it instead, e.g. the one
+//! running a `tokio::task::LocalSet` or a `futures::executor::LocalPool`: it is neither `Send` nor
+//! `Sync`, needs no runtime, and can be shared between the local tasks by cloning it.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::rc::Rc;
+
+use crate::{traced, Trace, TraceError, TraceHandle, TraceOptions, TracedTask};
+
+/// Traced tasks recorded under a name, for tasks that run on the same thread as the code that
+/// inspects them.
+///
+/// ```rust
+/// use std::rc::Rc;
+///
+/// use futures::executor::LocalPool;
+/// use futures::task::LocalSpawnExt;
+///
+/// let registry = tasktrace::LocalRegistry::new();
+/// let mut pool = LocalPool::new();
+/// let (_tx, rx) = futures::channel::oneshot::channel::<()>();
+/// let (fut, _) = registry.traced_local(
+///     async move {
+///         let not_send = Rc::new(());
+///         let _ = rx.await;
+///         drop(not_send);
+///     },
+///     "local worker",
+/// );
+/// pool.spawner().spawn_local(fut).unwrap();
+///
+/// for (name, trace) in pool.run_until(registry.dump_all()) {
+///     match trace {
+///         Ok(trace) => println!("{name}:\n{trace}"),
+///         Err(err) => println!("{name}: {err}"),
+///     }
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct LocalRegistry {
+    tasks: Rc<RefCell<BTreeMap<String, TraceHandle>>>,
+}
+
+impl LocalRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like [`traced`] but also registers the task under `name`, like
+    /// [`traced_registered`](crate::registry::traced_registered) does with the process wide
+    /// registry. `fut` needn't be `Send`. A task registered later under the same name replaces
+    /// this one.
+    pub fn traced_local<F: Future>(&self, fut: F, name: &str) -> (TracedTask<F>, TraceHandle) {
+        let (task, handle) = traced(fut);
+        let mut tasks = self.tasks.borrow_mut();
+        // The tasks can't remove themselves when they end, their hook has to be `Send`
+        tasks.retain(|_, handle| !handle.is_finished());
+        tasks.insert(name.to_owned(), handle.clone());
+        (task, handle)
+    }
+
+    /// The names of the registered tasks that haven't finished, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.prune();
+        self.tasks.borrow().keys().cloned().collect()
+    }
+
+    /// Captures a backtrace of every registered task that hasn't finished, sorted by name.
+    ///
+    /// The requests are sent to all the tasks up front, and the traces are produced as the
+    /// executor polls the tasks, so the returned future has to be driven by the same executor.
+    /// There is no timeout: tasks that are registered but never spawned keep the dump waiting.
+    pub async fn dump_all(&self) -> Vec<(String, Result<Trace, TraceError>)> {
+        self.prune();
+        let tasks = self.tasks.borrow().clone();
+        let requests: Vec<_> = tasks
+            .into_iter()
+            .map(|(name, handle)| (name, handle.send_request(None, TraceOptions::default())))
+            .collect();
+        let mut dump = Vec::with_capacity(requests.len());
+        for (name, request) in requests {
+            let trace = match request {
+                Ok(rx) => rx.await.unwrap_or(Err(TraceError::Cancelled)),
+                Err(err) => Err(err),
+            };
+            dump.push((name, trace));
+        }
+        dump
+    }
+
+    fn prune(&self) {
+        self.tasks
+            .borrow_mut()
+            .retain(|_, handle| !handle.is_finished());
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::test_util::pending;
+
+    #[tokio::test]
+    async fn local_set() {
+        let registry = LocalRegistry::new();
+        let local = tokio::task::LocalSet::new();
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (waiter, _) = registry.traced_local(
+            async move {
+                let not_send = Rc::new(());
+                let _ = rx.await;
+                drop(not_send);
+            },
+            "waiter",
+        );
+        let (idler, _) = registry.traced_local(pending(), "idler");
+        let waiter = local.spawn_local(waiter);
+        local.spawn_local(idler);
+
+        let dump = local.run_until(registry.dump_all()).await;
+        let names: Vec<_> = dump.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["idler", "waiter"]);
+        assert!(dump[0].1.as_ref().unwrap().to_string().contains("pending"));
+        assert!(dump[1]
+            .1
+            .as_ref()
+            .unwrap()
+            .to_string()
+            .contains("tasktrace::local::tests::local_set::{{closure}}"));
+
+        // Finished tasks are left out
+        tx.send(()).unwrap();
+        local.run_until(waiter).await.unwrap();
+        assert_eq!(registry.names(), ["idler"]);
+    }
+}
@@ -0,0 +1,92 @@
+//! Linking panics to the traced task they happened in.
+//!
+//! The message of a panic in an async task points at the line that panicked, and its backtrace
+//! ends in the worker loop of the runtime. [`install_panic_hook`] adds the logical trace of the
+//! traced task that was being polled at the time, which shows how the task got there.
+
+use std::fmt::Write;
+use std::panic;
+use std::sync::atomic::Ordering;
+
+use crate::{scope, TaskState};
+
+/// Installs a panic hook that runs the previous one and then, if the panic happened while a traced
+/// task was being polled on the thread, prints the name and the last trace of that task to stderr.
+///
+/// ```rust
+/// tasktrace::install_panic_hook();
+/// ```
+///
+/// The poll that panicked can't be captured any more, so the trace is the last one cached by
+/// tasks created with [`traced_cached`](crate::traced_cached) or
+/// [`traced_with_snapshots`](crate::traced_with_snapshots). Other tasks are only named.
+pub fn install_panic_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        if let Some(note) = scope::with_current(describe) {
+            eprint!("{note}");
+        }
+    }));
+}
+
+/// Describes the task that panicked, along with its last cached trace if it has one.
+fn describe(state: &TaskState) -> String {
+    let mut note = String::from("note: panicked while polling traced task");
+    let polls = state.polls.load(Ordering::Relaxed);
+    // The panic may have interrupted the task while it held the lock
+    let latest = state
+        .latest
+        .as_ref()
+        .and_then(|latest| latest.try_lock().ok());
+    match latest.as_ref().and_then(|latest| latest.as_ref()) {
+        // The trace starts with the name of the task
+        Some((poll, trace)) => {
+            let _ = writeln!(
+                note,
+                " (poll {polls}), last traced at poll {poll}:\n{trace}"
+            );
+        }
+        None => {
+            if let Some(name) = state.name.get() {
+                let _ = write!(note, " {name:?}");
+            }
+            let _ = writeln!(note, " (poll {polls}), no trace was cached");
+        }
+    }
+    note
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::{traced_cached, traced_named};
+
+    #[tokio::test]
+    async fn describe_current() {
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+        let (fut, trace_handle) = traced_cached(async move {
+            let _ = rx.await;
+            scope::with_current(describe).unwrap()
+        });
+        let _ = fut.state.name.set("panicky".to_owned());
+        let task = tokio::spawn(fut);
+
+        trace_handle.backtrace().await.unwrap();
+        tx.send(()).unwrap();
+        let note = task.await.unwrap();
+        assert!(note.starts_with(
+            "note: panicked while polling traced task (poll 2), last traced at poll 1:\n\
+             Task \"panicky\" (busy "
+        ));
+        assert!(note.contains("%):\n╼ tasktrace::panic::tests::describe_current::{{closure}}"));
+
+        let (fut, _trace_handle) =
+            traced_named(async { scope::with_current(describe).unwrap() }, "uncached");
+        assert_eq!(
+            fut.await,
+            "note: panicked while polling traced task \"uncached\" (poll 1), no trace was cached\n"
+        );
+        assert!(scope::with_current(describe).is_none());
+    }
+}
@@ -0,0 +1,458 @@
+//! A process wide registry of traced tasks.
+//!
+//! Tasks created through [`traced_registered`] are recorded under a name until they complete or
+//! get dropped, so that [`dump_all`] can capture all of them at once without the caller having to
+//! keep their handles around. The name is only used to label the task: every task gets an entry of
+//! its own, even if it shares its name with other ones.
+//!
+//! [`group_by_leaf`] counts the registered tasks by the place they wait at, and
+//! [`start_wait_point_recorder`] keeps a series of those counts over time. [`who_holds`] and
+//! [`waiting_for`] tell which tasks hold and wait for the resources they report through
+//! [`TraceHandle::set_holding`] and [`TraceHandle::set_waiting`].
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::time::Instant;
+use crate::{traced, Trace, TraceError, TraceHandle, TraceOptions, TracedTask};
+
+/// How long [`dump_all`] waits for the tasks to answer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The registered tasks by id, along with their names. The ids grow with each registration.
+static TASKS: Mutex<BTreeMap<u64, (String, TraceHandle)>> = Mutex::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Like [`traced`] but also registers the task under `name`. Several tasks can be registered under
+/// the same name.
+pub fn traced_registered<F: Future>(fut: F, name: &str) -> (TracedTask<F>, TraceHandle) {
+    register(traced(fut), name)
+}
+
+/// Registers the task of `traced` under `name`, like [`traced_registered`] does.
+pub(crate) fn register<F: Future>(
+    (mut task, handle): (TracedTask<F>, TraceHandle),
+    name: &str,
+) -> (TracedTask<F>, TraceHandle) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    TASKS
+        .lock()
+        .unwrap()
+        .insert(id, (name.to_owned(), handle.clone()));
+    task.on_end = Some(Box::new(move || {
+        TASKS.lock().unwrap().remove(&id);
+    }));
+    (task, handle)
+}
+
+/// The registered tasks along with their handles, sorted by name and then by registration.
+pub(crate) fn tasks() -> Vec<(String, TraceHandle)> {
+    let mut tasks: Vec<_> = TASKS.lock().unwrap().values().cloned().collect();
+    tasks.sort_by(|(a, _), (b, _)| a.cmp(b));
+    tasks
+}
+
+/// The names of the registered tasks, sorted, once per task.
+#[cfg(feature = "http")]
+pub(crate) fn names() -> Vec<String> {
+    tasks().into_iter().map(|(name, _)| name).collect()
+}
+
+/// The handle of the task registered last under `name`, if any.
+#[cfg(feature = "http")]
+pub(crate) fn lookup(name: &str) -> Option<TraceHandle> {
+    TASKS
+        .lock()
+        .unwrap()
+        .values()
+        .rev()
+        .find(|(task, _)| task == name)
+        .map(|(_, handle)| handle.clone())
+}
+
+/// Describes how long ago `handle`'s task was last polled, as `last polled 142s ago` or `never
+/// polled`, for dumps of tasks that couldn't be traced.
+#[cfg(any(feature = "http", all(unix, feature = "signal")))]
+pub(crate) fn describe_last_poll(handle: &TraceHandle) -> String {
+    match handle.last_polled_at() {
+        Some(at) => format!("last polled {:.0?} ago", handle.state.since(at)),
+        None => "never polled".to_owned(),
+    }
+}
+
+/// Captures a backtrace of every registered task, sorted by name and then by registration. Waits
+/// up to [`DEFAULT_TIMEOUT`] for the tasks to answer.
+pub async fn dump_all() -> Vec<(String, Result<Trace, TraceError>)> {
+    dump_all_with_timeout(DEFAULT_TIMEOUT).await
+}
+
+/// Like [`dump_all`] but waits up to `timeout` for the tasks to answer. The requests are sent to
+/// all the tasks up front so a task that is wedged only fails its own entry with
+/// [`TraceError::Timeout`].
+///
+/// The timeout bounds the whole dump rather than each task, so the dump takes at most `timeout`
+/// however many tasks are registered. The traces that were captured by then are returned, the
+/// tasks that didn't answer in time fail with [`TraceError::Timeout`].
+///
+/// Must be called from within a tokio runtime.
+pub async fn dump_all_with_timeout(timeout: Duration) -> Vec<(String, Result<Trace, TraceError>)> {
+    dump_all_bounded(timeout, usize::MAX).await
+}
+
+/// Like [`dump_all_with_timeout`] but keeps at most `max_concurrent` requests outstanding at a
+/// time, so that dumping thousands of tasks doesn't queue a request on every one of them at once.
+/// The next request is sent as soon as the oldest outstanding one is answered. Tasks that didn't
+/// get their request sent before the deadline fail with [`TraceError::Timeout`] as well.
+///
+/// Must be called from within a tokio runtime.
+///
+/// # Panics
+///
+/// Panics if `max_concurrent` is 0.
+pub async fn dump_all_bounded(
+    timeout: Duration,
+    max_concurrent: usize,
+) -> Vec<(String, Result<Trace, TraceError>)> {
+    assert!(max_concurrent > 0, "max_concurrent must be positive");
+    dump_handles(tasks(), timeout, max_concurrent).await
+}
+
+/// Counts the registered tasks by the function they are parked in, e.g. to tell at a glance that
+/// 120 tasks wait in `db::query` and 30 in `channel::recv`. That is the innermost frame of the
+/// deepest branch of their trace that isn't part of the standard library, since every branch ends
+/// in the waker's `clone`. Tasks that can't be traced within [`DEFAULT_TIMEOUT`] are left out.
+///
+/// Must be called from within a tokio runtime.
+pub async fn group_by_leaf() -> HashMap<String, usize> {
+    let mut groups = HashMap::new();
+    for (_, trace) in dump_all().await {
+        let Ok(trace) = trace else {
+            continue;
+        };
+        let Some(leaf) = trace.parked_at() else {
+            continue;
+        };
+        let symbol = leaf.symbol().unwrap_or("??");
+        *groups.entry(symbol.to_owned()).or_default() += 1;
+    }
+    groups
+}
+
+/// Finds the registered task that holds `resource`, see [`TraceHandle::set_holding`], along with
+/// its name. If several tasks claim to hold it, the one registered first is returned.
+pub fn who_holds(resource: &str) -> Option<(String, TraceHandle)> {
+    TASKS
+        .lock()
+        .unwrap()
+        .values()
+        .find(|(_, handle)| handle.holding().iter().any(|held| held == resource))
+        .cloned()
+}
+
+/// The registered tasks that wait for `resource`, see [`TraceHandle::set_waiting`], sorted by
+/// name and then by registration.
+pub fn waiting_for(resource: &str) -> Vec<(String, TraceHandle)> {
+    tasks()
+        .into_iter()
+        .filter(|(_, handle)| handle.waiting().as_deref() == Some(resource))
+        .collect()
+}
+
+/// Captures a backtrace of the task that holds `resource`, see [`who_holds`], to tell where the
+/// task that the waiters of a contended resource wait for is stuck itself. Waits up to
+/// [`DEFAULT_TIMEOUT`] for the task to answer. Returns `None` if no registered task holds the
+/// resource.
+///
+/// Must be called from within a tokio runtime.
+pub async fn backtrace_of_holder(resource: &str) -> Option<(String, Result<Trace, TraceError>)> {
+    let holder = who_holds(resource)?;
+    dump_handles(vec![holder], DEFAULT_TIMEOUT, 1).await.pop()
+}
+
+/// How many samples [`wait_point_series`] keeps. Older samples are dropped first.
+pub const MAX_WAIT_POINT_SAMPLES: usize = 1024;
+
+/// The samples of [`start_wait_point_recorder`], oldest first.
+static WAIT_POINTS: Mutex<VecDeque<(Instant, HashMap<String, usize>)>> =
+    Mutex::new(VecDeque::new());
+
+/// Records what [`group_by_leaf`] returns every `interval`, for charting how the places the tasks
+/// wait at evolve over time, e.g. to spot a spike of tasks that all wait on the same lock. The
+/// samples are read with [`wait_point_series`].
+///
+/// Recording goes on until the returned task is aborted. A sample that takes longer than
+/// `interval`, because some tasks take up to [`DEFAULT_TIMEOUT`] to answer, delays the next one.
+/// Every recorder adds to the same series, so there is usually only one per process.
+///
+/// Must be called from within a tokio runtime.
+///
+/// # Panics
+///
+/// Panics if `interval` is zero.
+pub fn start_wait_point_recorder(interval: Duration) -> tokio::task::JoinHandle<()> {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    tokio::spawn(async move {
+        loop {
+            ticks.tick().await;
+            let groups = group_by_leaf().await;
+            let mut samples = WAIT_POINTS.lock().unwrap();
+            if samples.len() == MAX_WAIT_POINT_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back((Instant::now(), groups));
+        }
+    })
+}
+
+/// The samples recorded by [`start_wait_point_recorder`], oldest first, each along with the time it
+/// was taken. Only the last [`MAX_WAIT_POINT_SAMPLES`] are kept.
+pub fn wait_point_series() -> Vec<(Instant, HashMap<String, usize>)> {
+    WAIT_POINTS.lock().unwrap().iter().cloned().collect()
+}
+
+/// Captures a backtrace of every task of `tasks`, like [`dump_all_bounded`].
+pub(crate) async fn dump_handles(
+    tasks: Vec<(String, TraceHandle)>,
+    timeout: Duration,
+    max_concurrent: usize,
+) -> Vec<(String, Result<Trace, TraceError>)> {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    let mut dump = Vec::with_capacity(tasks.len());
+    let mut tasks = tasks.into_iter();
+    let mut outstanding = VecDeque::new();
+    loop {
+        while outstanding.len() < max_concurrent {
+            let Some((name, handle)) = tasks.next() else {
+                break;
+            };
+            let request = if tokio::time::Instant::now() < deadline {
+                handle.send_request(None, TraceOptions::default())
+            } else {
+                Err(TraceError::Timeout)
+            };
+            outstanding.push_back((name, request));
+        }
+        let Some((name, request)) = outstanding.pop_front() else {
+            break;
+        };
+        let trace = match request {
+            Ok(rx) => match tokio::time::timeout_at(deadline, rx).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(_)) => Err(TraceError::Cancelled),
+                Err(_) => {
+                    #[cfg(feature = "metrics")]
+                    crate::stats::record_timeout();
+                    Err(TraceError::Timeout)
+                }
+            },
+            Err(err) => Err(err),
+        };
+        dump.push((name, trace));
+    }
+    dump
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use super::*;
+    use crate::test_util::pending;
+
+    #[tokio::test]
+    async fn dump_all_registered() {
+        let (fiz, _) = traced_registered(pending(), "fiz");
+        let (buz, _) = traced_registered(pending(), "buz");
+        let (wedged, _) = traced_registered(pending(), "wedged");
+        let fiz = tokio::spawn(fiz);
+        let buz = tokio::spawn(buz);
+
+        // Other tests may register tasks of their own concurrently
+        let names = ["buz", "fiz", "wedged"];
+        let dump: Vec<_> = dump_all_with_timeout(Duration::from_millis(100))
+            .await
+            .into_iter()
+            .filter(|(name, _)| names.contains(&name.as_str()))
+            .collect();
+        let dumped: Vec<_> = dump.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(dumped, names);
+        assert!(dump[0].1.as_ref().unwrap().to_string().contains("pending"));
+        assert!(dump[1].1.as_ref().unwrap().to_string().contains("pending"));
+        assert_eq!(dump[2].1.as_ref().err(), Some(&TraceError::Timeout));
+
+        // Finished tasks are removed from the registry
+        fiz.abort();
+        buz.abort();
+        let _ = fiz.await;
+        let _ = buz.await;
+        drop(wedged);
+        assert!(tasks()
+            .iter()
+            .all(|(name, _)| !names.contains(&name.as_str())));
+    }
+
+    #[tokio::test]
+    async fn dump_all_bounded_registered() {
+        let names = ["bounded-a", "bounded-b", "bounded-c", "bounded-wedged"];
+        let mut tasks = Vec::new();
+        for name in &names[..3] {
+            let (fut, _) = traced_registered(pending(), name);
+            tasks.push(tokio::spawn(fut));
+        }
+        let (_wedged, _) = traced_registered(pending(), "bounded-wedged");
+
+        // Other tests may register tasks of their own concurrently
+        let dump: Vec<_> = dump_all_bounded(Duration::from_millis(100), 2)
+            .await
+            .into_iter()
+            .filter(|(name, _)| names.contains(&name.as_str()))
+            .collect();
+        let dumped: Vec<_> = dump.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(dumped, names);
+        assert!(dump[..3].iter().all(|(_, trace)| trace.is_ok()));
+        assert_eq!(dump[3].1.as_ref().err(), Some(&TraceError::Timeout));
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn total_deadline() {
+        let names = ["deadline-a-wedged", "deadline-b"];
+        let (_wedged, _) = traced_registered(pending(), names[0]);
+        let (fut, _) = traced_registered(pending(), names[1]);
+        let task = tokio::spawn(fut);
+
+        // The wedged task takes up the whole deadline, so the request of the other one is never sent
+        let start = std::time::Instant::now();
+        let dump: Vec<_> = dump_all_bounded(Duration::from_millis(50), 1)
+            .await
+            .into_iter()
+            .filter(|(name, _)| names.contains(&name.as_str()))
+            .collect();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(dump.len(), 2);
+        assert!(dump
+            .iter()
+            .all(|(_, trace)| trace.as_ref().err() == Some(&TraceError::Timeout)));
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn group_by_leaf() {
+        struct Parked;
+
+        impl Future for Parked {
+            type Output = ();
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                drop(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+
+        let mut tasks = Vec::new();
+        for name in ["parked-a", "parked-b"] {
+            let (fut, _) = traced_registered(Parked, name);
+            tasks.push(tokio::spawn(fut));
+        }
+        let (fut, _) = traced_registered(pending(), "parked-elsewhere");
+        tasks.push(tokio::spawn(fut));
+
+        let groups = super::group_by_leaf().await;
+        let parked = "<tasktrace::registry::tests::group_by_leaf::{{closure}}::Parked as \
+                      core::future::future::Future>::poll";
+        assert_eq!(groups.get(parked), Some(&2));
+        // Other tests may register tasks of their own concurrently
+        assert!(groups["tasktrace::test_util::pending::{{closure}}::{{closure}}"] >= 1);
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_point_series() {
+        let (fut, _) = traced_registered(pending(), "recorded");
+        let task = tokio::spawn(fut);
+
+        let recorder = start_wait_point_recorder(Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        recorder.abort();
+        let series = super::wait_point_series();
+        // Tasks of other tests that never answer can delay the samples
+        assert!(series.len() >= 2);
+        assert!(series.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        for (_, groups) in &series {
+            assert!(groups["tasktrace::test_util::pending::{{closure}}::{{closure}}"] >= 1);
+        }
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn backtrace_of_holder() {
+        let (holder, holder_handle) = traced_registered(pending(), "holder");
+        let (waiter, waiter_handle) = traced_registered(pending(), "waiter");
+        let holder = tokio::spawn(holder);
+        let waiter = tokio::spawn(waiter);
+        holder_handle.set_holding("lock:backtrace_of_holder");
+        waiter_handle.set_waiting("lock:backtrace_of_holder");
+
+        let (name, _) = who_holds("lock:backtrace_of_holder").unwrap();
+        assert_eq!(name, "holder");
+        let waiters = waiting_for("lock:backtrace_of_holder");
+        assert_eq!(waiters.len(), 1);
+        assert_eq!(waiters[0].0, "waiter");
+        let (name, trace) = super::backtrace_of_holder("lock:backtrace_of_holder")
+            .await
+            .unwrap();
+        assert_eq!(name, "holder");
+        assert!(trace.unwrap().to_string().contains("test_util::pending"));
+
+        holder_handle.release("lock:backtrace_of_holder");
+        assert!(who_holds("lock:backtrace_of_holder").is_none());
+        assert!(super::backtrace_of_holder("lock:backtrace_of_holder")
+            .await
+            .is_none());
+        holder.abort();
+        waiter.abort();
+    }
+
+    #[tokio::test]
+    async fn same_name() {
+        let (first, first_handle) = traced_registered(pending(), "namesake");
+        let (second, _) = traced_registered(pending(), "namesake");
+        let first = tokio::spawn(first);
+        let second = tokio::spawn(second);
+
+        let namesakes = || -> Vec<TraceHandle> {
+            tasks()
+                .into_iter()
+                .filter(|(name, _)| name == "namesake")
+                .map(|(_, handle)| handle)
+                .collect()
+        };
+        assert_eq!(namesakes().len(), 2);
+        let dump = dump_all_with_timeout(Duration::from_millis(100)).await;
+        let traces: Vec<_> = dump.iter().filter(|(name, _)| name == "namesake").collect();
+        assert_eq!(traces.len(), 2);
+        assert!(traces.iter().all(|(_, trace)| trace.is_ok()));
+
+        // The second task ending leaves the first one registered
+        second.abort();
+        let _ = second.await;
+        let remaining = namesakes();
+        assert_eq!(remaining.len(), 1);
+        assert!(Arc::ptr_eq(&remaining[0].state, &first_handle.state));
+        first.abort();
+        let _ = first.await;
+        assert!(namesakes().is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+//! Pulling the traces of the registered tasks of other processes.
+//!
+//! A [`RemoteRegistryServer`] answers dump requests with the traces of the tasks in the
+//! [registry](crate::registry) of its process, and a [`RemoteRegistryClient`] sends them, so that
+//! an ops tool can collect the traces of every instance of a service in one place. Both work over
+//! any connection implementing tokio's `AsyncRead` and `AsyncWrite`, e.g. a TCP or Unix socket.
+//!
+//! Every message is prefixed with its length as a little endian `u32`. A request is a single
+//! byte, `1` for a dump, and is answered with a [`TraceSnapshot`] in its binary format.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::registry::{self, DEFAULT_TIMEOUT};
+use crate::TraceSnapshot;
+
+/// The request for a dump of the registry.
+const DUMP: u8 = 1;
+/// The longest message that is read, to not allocate wildly for a corrupted stream.
+const MAX_MESSAGE_LEN: u32 = 64 << 20;
+
+/// Serves the registry of this process to [`RemoteRegistryClient`]s.
+#[derive(Debug, Clone)]
+pub struct RemoteRegistryServer {
+    timeout: Duration,
+}
+
+impl RemoteRegistryServer {
+    /// Creates a server that waits up to [`DEFAULT_TIMEOUT`] for the tasks to answer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how long every dump waits for the tasks to answer, see
+    /// [`registry::dump_all_with_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Answers the requests sent over `conn` until the client hangs up.
+    ///
+    /// ```rust
+    /// # use tokio::io::{AsyncRead, AsyncWrite};
+    /// async fn handle(conn: impl AsyncRead + AsyncWrite + Unpin) -> std::io::Result<()> {
+    ///     tasktrace::remote::RemoteRegistryServer::new()
+    ///         .serve_connection(conn)
+    ///         .await
+    /// }
+    /// ```
+    ///
+    /// Tasks that can't be traced in time are left out of the dumps. Fails with
+    /// [`io::ErrorKind::InvalidData`] if the client sends something other than a request.
+    pub async fn serve_connection(
+        &self,
+        mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    ) -> io::Result<()> {
+        loop {
+            let request = match read_message(&mut conn).await {
+                Ok(request) => request,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            if request != [DUMP] {
+                return Err(invalid("unknown request"));
+            }
+            let snapshot: TraceSnapshot = registry::dump_all_with_timeout(self.timeout)
+                .await
+                .into_iter()
+                .filter_map(|(name, trace)| Some((name, trace.ok()?)))
+                .collect();
+            let mut response = Vec::new();
+            snapshot.write_to(&mut response)?;
+            write_message(&mut conn, &response).await?;
+        }
+    }
+}
+
+impl Default for RemoteRegistryServer {
+    fn default() -> Self {
+        RemoteRegistryServer {
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// Requests dumps from a [`RemoteRegistryServer`].
+///
+/// ```rust
+/// # use tokio::io::{AsyncRead, AsyncWrite};
+/// async fn dump_fleet(
+///     conns: Vec<impl AsyncRead + AsyncWrite + Unpin>,
+/// ) -> std::io::Result<Vec<tasktrace::TraceSnapshot>> {
+///     let dumps = conns.into_iter().map(|conn| async move {
+///         tasktrace::remote::RemoteRegistryClient::new(conn).dump().await
+///     });
+///     futures::future::try_join_all(dumps).await
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RemoteRegistryClient<T> {
+    conn: T,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> RemoteRegistryClient<T> {
+    /// Creates a client that talks to the server on the other end of `conn`.
+    pub fn new(conn: T) -> Self {
+        RemoteRegistryClient { conn }
+    }
+
+    /// Captures the traces of the tasks registered in the server's process.
+    pub async fn dump(&mut self) -> io::Result<TraceSnapshot> {
+        write_message(&mut self.conn, &[DUMP]).await?;
+        let response = read_message(&mut self.conn).await?;
+        TraceSnapshot::read_from(&response[..])
+    }
+
+    /// Returns the connection to the server.
+    pub fn into_inner(self) -> T {
+        self.conn
+    }
+}
+
+async fn write_message(w: &mut (impl AsyncWrite + Unpin), message: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(message.len()).map_err(|_| invalid("message too long"))?;
+    w.write_all(&len.to_le_bytes()).await?;
+    w.write_all(message).await?;
+    w.flush().await
+}
+
+async fn read_message(r: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let len = r.read_u32_le().await?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid("message too long"));
+    }
+    let mut message = vec![0; len as usize];
+    r.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::registry::traced_registered;
+    use crate::test_util::pending;
+
+    #[tokio::test]
+    async fn dump() {
+        let (fut, _trace_handle) = traced_registered(pending(), "remote worker");
+        tokio::spawn(fut);
+        let (client, server) = tokio::io::duplex(1024);
+        let serve =
+            tokio::spawn(async move { RemoteRegistryServer::new().serve_connection(server).await });
+
+        let mut client = RemoteRegistryClient::new(client);
+        for _ in 0..2 {
+            let snapshot = client.dump().await.unwrap();
+            // Other tests may register tasks of their own concurrently
+            let (_, trace) = snapshot
+                .tasks()
+                .iter()
+                .find(|(name, _)| name == "remote worker")
+                .unwrap();
+            assert!(trace
+                .to_string()
+                .starts_with("╼ tasktrace::test_util::pending::{{closure}}"));
+        }
+        drop(client);
+        serve.await.unwrap().unwrap();
+
+        // Anything but a request ends the connection
+        let (mut client, server) = tokio::io::duplex(1024);
+        let serve =
+            tokio::spawn(async move { RemoteRegistryServer::new().serve_connection(server).await });
+        write_message(&mut client, b"dump").await.unwrap();
+        let err = serve.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
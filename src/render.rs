@@ -0,0 +1,487 @@
+//! Rendering of traces for terminals.
+//!
+//! [`render_colored`] draws the same tree as the `Display` implementation of [`Trace`] but
+//! highlights the frames of the application, dims the frames of its dependencies, marks the leaf
+//! of every branch, i.e. what the task waits on, in yellow and can turn the source locations into
+//! OSC 8 hyperlinks that supporting terminals open on click, to the local files or to the pages of
+//! a source host. It can also draw the tree with plain
+//! ASCII characters for terminals and log pipelines that mangle the box drawing ones, or with the
+//! lines of `tracing-tree` to match the span logs next to it, annotate the
+//! branches with the time the poll spent in them, and show the source line of every frame whose
+//! file can be read locally.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Component, Path, PathBuf};
+
+use crate::trace::{display_tree_with, Header, TreeStyle};
+use crate::{Frame, Trace};
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const LEAF: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+/// Options for [`render_colored`].
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    color: bool,
+    hyperlinks: bool,
+    source_link: Option<SourceLink>,
+    style: &'static TreeStyle,
+    timings: bool,
+    source: bool,
+    source_context: usize,
+}
+
+impl RenderOptions {
+    /// Creates options that use color if stdout is a terminal, emit no hyperlinks, draw the tree
+    /// with box drawing characters and leave out timings and source lines.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates options like [`RenderOptions::new`] that draw the tree with the lines of
+    /// `tracing-tree`, so that traces logged next to its span output look the same:
+    ///
+    /// ```text
+    /// ┐ server::handle::{{closure}} at src/server.rs:42:25
+    ///   ├─ server::fetch_user::{{closure}} at src/server.rs:61:33
+    ///   └─ server::fetch_orders::{{closure}} at src/server.rs:70:17
+    /// ```
+    pub fn tracing_tree() -> Self {
+        RenderOptions {
+            style: &TreeStyle::TRACING_TREE,
+            ..Self::default()
+        }
+    }
+
+    /// Sets whether frames are colored: the frames of the application bold, the frames of its
+    /// dependencies dim and the leaves of the branches yellow.
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Sets whether source locations are emitted as OSC 8 hyperlinks to `file://` URLs, or to the
+    /// URLs of [`RenderOptions::source_link`] if set. Only absolute paths of files that can exist
+    /// locally are linked to `file://` URLs.
+    pub fn hyperlinks(mut self, hyperlinks: bool) -> Self {
+        self.hyperlinks = hyperlinks;
+        self
+    }
+
+    /// Links source locations to the files of `commit` on a source host instead of to local files,
+    /// e.g. to `{base_url}/blob/{commit}/src/main.rs#L42` for `src/main.rs:42`, and turns on
+    /// [`RenderOptions::hyperlinks`]. `base_url` is the URL of the repository, like
+    /// `https://github.com/owner/repo`.
+    ///
+    /// Relative paths are linked as they are, absolute ones only below the prefix set with
+    /// [`RenderOptions::source_link_prefix`]. The sources of dependencies and the standard library
+    /// aren't linked.
+    pub fn source_link(mut self, base_url: impl Into<String>, commit: impl Into<String>) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_owned();
+        let link = self.source_link.get_or_insert_with(SourceLink::default);
+        link.base_url = base_url;
+        link.commit = commit.into();
+        self.hyperlinks = true;
+        self
+    }
+
+    /// Sets the directory the repository was built in, which is stripped from absolute paths to
+    /// get the paths within the repository for [`RenderOptions::source_link`].
+    pub fn source_link_prefix(mut self, prefix: impl Into<PathBuf>) -> Self {
+        let link = self.source_link.get_or_insert_with(SourceLink::default);
+        link.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Sets whether the tree is drawn with ASCII characters only, e.g. `|-` and `` `- `` in place
+    /// of `├╼` and `└╼`. Turning it off draws the tree with box drawing characters again, also for
+    /// [`RenderOptions::tracing_tree`].
+    pub fn ascii(mut self, ascii: bool) -> Self {
+        self.style = if ascii {
+            &TreeStyle::ASCII
+        } else {
+            &TreeStyle::UNICODE
+        };
+        self
+    }
+
+    /// Sets whether the duration of the captured poll is shown above the tree, and the time spent
+    /// in every branch next to its leaf, e.g. `[1.2ms]`, see [`Frame::elapsed`].
+    pub fn timings(mut self, timings: bool) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    /// Sets whether the line of source code every frame points at is shown below it, marked with
+    /// a `>`. Frames whose file can't be read are shown without it.
+    pub fn source(mut self, source: bool) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Sets how many lines before and after the line of every frame are shown along with it, if
+    /// [`RenderOptions::source`] is set. Defaults to none.
+    pub fn source_context(mut self, lines: usize) -> Self {
+        self.source_context = lines;
+        self
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            color: io::stdout().is_terminal(),
+            hyperlinks: false,
+            source_link: None,
+            style: &TreeStyle::UNICODE,
+            timings: false,
+            source: false,
+            source_context: 0,
+        }
+    }
+}
+
+/// Renders `trace` as a tree for display in a terminal.
+///
+/// Which frames belong to dependencies is decided by their path: sources under `.cargo/registry`
+/// or the `/rustc/` prefix of the standard library are dependencies, as are the `<...>` paths of
+/// [`TraceFilter::remap_default_paths`](crate::TraceFilter::remap_default_paths). Everything else
+/// belongs to the application.
+pub fn render_colored(trace: &Trace, options: &RenderOptions) -> String {
+    let mut rendered = String::new();
+    let mut render = || {
+        if let Some(name) = trace.name() {
+            writeln!(rendered, "{}", Header(name, trace.busy_ratio()))?;
+        }
+        if let Some(duration) = trace.poll_duration().filter(|_| options.timings) {
+            writeln!(rendered, "Poll took {duration:.1?}:")?;
+        }
+        // Every file is read at most once per rendering, `None` if it couldn't be
+        let sources = RefCell::new(HashMap::new());
+        let below = |f: &mut dyn Write, frame: &Frame, prefix: &str| {
+            render_source(f, frame, prefix, options, &mut sources.borrow_mut())
+        };
+        display_tree_with(
+            &mut rendered,
+            trace.roots(),
+            false,
+            options.style,
+            &|f, frame| render_frame(f, frame, options),
+            options.source.then_some(&below),
+        )
+    };
+    render().expect("writing to a String cannot fail");
+    rendered
+}
+
+fn render_frame(f: &mut dyn Write, frame: &Frame, options: &RenderOptions) -> fmt::Result {
+    let dependency = frame.file().is_none_or(is_dependency);
+    if options.color {
+        f.write_str(if frame.children().is_empty() {
+            LEAF
+        } else if dependency {
+            DIM
+        } else {
+            BOLD
+        })?;
+    }
+    if let Some(symbol) = frame.symbol() {
+        f.write_str(symbol)?;
+    }
+    if options.color && !dependency {
+        f.write_str(RESET)?;
+    }
+    if let Some(file) = frame.file() {
+        f.write_str(" at ")?;
+        let url = match &options.source_link {
+            _ if !options.hyperlinks => None,
+            Some(link) if !dependency => link.url(file, frame.line()),
+            Some(_) => None,
+            None if file.is_absolute() && !file.starts_with("/rustc") => Some(file_url(file)),
+            None => None,
+        };
+        if let Some(url) = &url {
+            write!(f, "\x1b]8;;{url}\x1b\\")?;
+        }
+        frame.display_location(f)?;
+        if url.is_some() {
+            f.write_str("\x1b]8;;\x1b\\")?;
+        }
+    }
+    if options.color && dependency {
+        f.write_str(RESET)?;
+    }
+    frame.display_annotations(f)?;
+    match frame.elapsed() {
+        Some(elapsed) if options.timings => write!(f, " [{elapsed:.1?}]"),
+        _ => Ok(()),
+    }
+}
+
+/// The `file://` URL of the absolute path `file`, with the bytes that URLs can't contain
+/// percent-encoded, e.g. spaces and `#`.
+fn file_url(file: &Path) -> String {
+    let mut url = String::from("file://");
+    push_encoded(&mut url, &file.to_string_lossy());
+    url
+}
+
+/// Appends `path` to `url`, percent-encoding the bytes that URLs can't contain.
+fn push_encoded(url: &mut String, path: &str) {
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            let _ = write!(url, "%{byte:02X}");
+        }
+    }
+}
+
+/// Where [`RenderOptions::source_link`] links to.
+#[derive(Debug, Clone, Default)]
+struct SourceLink {
+    base_url: String,
+    commit: String,
+    prefix: Option<PathBuf>,
+}
+
+impl SourceLink {
+    /// The URL of `line` of `file` on the source host, if `file` is part of the repository.
+    fn url(&self, file: &Path, line: Option<u32>) -> Option<String> {
+        let relative = match &self.prefix {
+            Some(prefix) if file.is_absolute() => file.strip_prefix(prefix).ok()?,
+            _ if file.is_absolute() => return None,
+            _ => file,
+        };
+        let mut url = format!("{}/blob/{}", self.base_url, self.commit);
+        for component in relative.components() {
+            let Component::Normal(component) = component else {
+                return None;
+            };
+            url.push('/');
+            push_encoded(&mut url, &component.to_string_lossy());
+        }
+        if let Some(line) = line {
+            let _ = write!(url, "#L{line}");
+        }
+        Some(url)
+    }
+}
+
+fn render_source(
+    f: &mut dyn Write,
+    frame: &Frame,
+    prefix: &str,
+    options: &RenderOptions,
+    sources: &mut HashMap<PathBuf, Option<Vec<String>>>,
+) -> fmt::Result {
+    let (Some(file), Some(line)) = (frame.file(), frame.line()) else {
+        return Ok(());
+    };
+    let lines = sources.entry(file.to_owned()).or_insert_with(|| {
+        let source = fs::read_to_string(file).ok()?;
+        Some(source.lines().map(str::to_owned).collect())
+    });
+    let line = line as usize;
+    let Some(lines) = lines
+        .as_ref()
+        .filter(|lines| (1..=lines.len()).contains(&line))
+    else {
+        return Ok(());
+    };
+    let first = line.saturating_sub(options.source_context).max(1);
+    let last = (line + options.source_context).min(lines.len());
+    let width = last.to_string().len();
+    for n in first..=last {
+        let marker = if n == line { '>' } else { ' ' };
+        let rendered = format!("{prefix}{n:>width$} {marker} {}", lines[n - 1]);
+        f.write_char('\n')?;
+        if options.color && n != line {
+            write!(f, "{DIM}{}{RESET}", rendered.trim_end())?;
+        } else {
+            f.write_str(rendered.trim_end())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `file` is a source file of a dependency or the standard library, including the
+/// paths shortened by [`TraceFilter::remap_default_paths`](crate::TraceFilter::remap_default_paths).
+pub(crate) fn is_dependency(file: &Path) -> bool {
+    let components: Vec<_> = file.components().collect();
+    file.starts_with("/rustc")
+        || file.to_str().is_some_and(|file| file.starts_with('<'))
+        || components.windows(2).any(|pair| {
+            pair[0] == Component::Normal(".cargo".as_ref())
+                && pair[1] == Component::Normal("registry".as_ref())
+        })
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::{frame_at, pending};
+    use crate::traced;
+
+    #[tokio::test]
+    async fn hyperlinks() {
+        let (fut, trace_handle) = traced(pending());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let options = RenderOptions::new().color(true).hyperlinks(true);
+        let rendered = render_colored(&trace, &options);
+        let first = rendered.lines().next().unwrap();
+        assert!(first.starts_with("╼ \x1b[1mtasktrace::test_util::pending::{{closure}}\x1b[0m"));
+        assert!(first.contains(" at \x1b]8;;file://"));
+        assert!(first.contains("src/test_util.rs\x1b\\"));
+        // The standard library has no local sources to link to
+        let dependency = rendered.lines().nth(1).unwrap();
+        assert!(dependency.starts_with("  └╼ \x1b[2m<core::future::poll_fn::PollFn<F>"));
+        assert!(!dependency.contains("\x1b]8;;"));
+        // The leaf stands out, whoever it belongs to
+        let leaf = rendered.lines().last().unwrap();
+        assert!(leaf.starts_with("        └╼ \x1b[1;33m<core::task::wake::Waker"));
+        assert_eq!(rendered.matches(LEAF).count(), 1);
+
+        // Without color or hyperlinks the rendering is the plain one
+        let plain = RenderOptions::new().color(false);
+        assert_eq!(render_colored(&trace, &plain), trace.to_string());
+    }
+
+    #[test]
+    fn file_urls() {
+        let file = Path::new("/home/me/my project/#1/main.rs");
+        assert_eq!(file_url(file), "file:///home/me/my%20project/%231/main.rs");
+        assert_eq!(file_url(Path::new("/tmp/ü.rs")), "file:///tmp/%C3%BC.rs");
+    }
+
+    #[test]
+    fn source_links() {
+        let options = RenderOptions::new()
+            .color(false)
+            .source_link("https://github.com/me/app/", "abc123")
+            .source_link_prefix("/build/app");
+        let link = options.source_link.as_ref().unwrap();
+        let url = |file: &str, line| link.url(Path::new(file), line);
+        assert_eq!(
+            url("/build/app/src/my main.rs", Some(42)).as_deref(),
+            Some("https://github.com/me/app/blob/abc123/src/my%20main.rs#L42")
+        );
+        assert_eq!(
+            url("src/lib.rs", None).as_deref(),
+            Some("https://github.com/me/app/blob/abc123/src/lib.rs")
+        );
+        assert_eq!(url("/elsewhere/src/lib.rs", Some(1)), None);
+        assert_eq!(url("../outside.rs", Some(1)), None);
+
+        let trace = Trace::from_roots(vec![frame_at("app::main", 7, 5, Vec::new())]);
+        assert_eq!(
+            render_colored(&trace, &options),
+            "╼ app::main at \x1b]8;;https://github.com/me/app/blob/abc123/src/app.rs#L7\x1b\\\
+             src/app.rs:7:5\x1b]8;;\x1b\\"
+        );
+        // Links are only emitted along with the other hyperlinks
+        let options = options.hyperlinks(false);
+        assert_eq!(render_colored(&trace, &options), trace.to_string());
+    }
+
+    #[tokio::test]
+    async fn ascii() {
+        let (fut, trace_handle) = traced(async { futures::join!(pending(), pending()) });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let options = RenderOptions::new().color(false).ascii(true);
+        let rendered = render_colored(&trace, &options);
+        assert!(rendered.is_ascii());
+        assert!(rendered.starts_with("> tasktrace::render::tests::ascii::{{closure}}"));
+        assert!(rendered.contains("|- "));
+        assert!(rendered.contains("|  `- "));
+        // Same tree, different characters
+        let unicode: Vec<_> = trace
+            .to_string()
+            .lines()
+            .map(|line| match line.strip_prefix("╼ ") {
+                Some(root) => format!("> {root}"),
+                None => line
+                    .replace('╼', "-")
+                    .replace(['├', '│'], "|")
+                    .replace('└', "`"),
+            })
+            .collect();
+        assert_eq!(rendered, unicode.join("\n"));
+    }
+
+    #[tokio::test]
+    async fn tracing_tree() {
+        let (fut, trace_handle) = traced(async { futures::join!(pending(), pending()) });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let rendered = render_colored(&trace, &RenderOptions::tracing_tree().color(false));
+        assert!(rendered.starts_with("┐ tasktrace::render::tests::tracing_tree::{{closure}}"));
+        assert!(rendered.contains("├─ "));
+        assert!(rendered.contains("│  └─ "));
+        assert!(!rendered.contains('╼'));
+        let unicode = trace.to_string().replacen("╼ ", "┐ ", 1).replace('╼', "─");
+        assert_eq!(rendered, unicode);
+    }
+
+    #[tokio::test]
+    async fn timings() {
+        let (fut, trace_handle) = traced(async { futures::join!(pending(), slow()) });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let leaves: Vec<_> = crate::branches(&trace)
+            .map(|branch| branch.leaf().elapsed().unwrap())
+            .collect();
+        assert_eq!(leaves.len(), 2);
+        assert!(leaves[1] >= Duration::from_millis(20));
+        assert!(leaves[0] < leaves[1]);
+        assert!(trace.poll_duration().unwrap() >= leaves[0] + leaves[1]);
+
+        let options = RenderOptions::new().color(false).timings(true);
+        let rendered = render_colored(&trace, &options);
+        assert!(rendered.starts_with("Poll took "));
+        assert_eq!(
+            rendered.lines().filter(|line| line.ends_with("s]")).count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn source() {
+        let (fut, trace_handle) = traced(pending());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let options = RenderOptions::new()
+            .color(false)
+            .source(true)
+            .source_context(1);
+        let rendered = render_colored(&trace, &options);
+        let lines: Vec<_> = rendered.lines().collect();
+        assert!(lines[0].starts_with("╼ tasktrace::test_util::pending::{{closure}}"));
+        assert!(lines[1].starts_with("  │  ") && lines[1].ends_with("       })"));
+        assert!(lines[2].ends_with(" >     .await"));
+        // The sources of the standard library aren't around
+        assert!(lines[4].starts_with("  └╼ <core::future::poll_fn::PollFn<F>"));
+        assert!(lines[5].starts_with("     └╼ tasktrace::test_util::pending::{{closure}}"));
+        assert!(lines[7].ends_with(" >         waker = Some(cx.waker().clone());"));
+    }
+
+    async fn slow() {
+        std::thread::sleep(Duration::from_millis(20));
+        pending().await
+    }
+}
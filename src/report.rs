@@ -0,0 +1,172 @@
+//! Rendering of the traces of many tasks at once.
+//!
+//! A dump of a whole application usually holds hundreds of tasks parked at the same few places,
+//! e.g. every connection waiting on its socket. [`TraceReport`] renders each distinct trace once
+//! along with the tasks that share it, the way thread dumps are usually collapsed.
+
+use std::fmt;
+
+use crate::trace::display_tree;
+use crate::{Frame, Precision, Trace};
+
+/// The traces of many tasks, grouped by identical trees.
+///
+/// ```rust
+/// # async fn dump(handles: Vec<(String, tasktrace::TraceHandle)>) {
+/// let mut report = tasktrace::TraceReport::new();
+/// for (name, handle) in handles {
+///     if let Ok(trace) = handle.backtrace().await {
+///         report.add(name, trace);
+///     }
+/// }
+/// eprintln!("{report}");
+/// # }
+/// ```
+///
+/// Traces are identical if their trees consist of the same functions at the same lines. The
+/// rendered report lists the groups with the most tasks first.
+#[derive(Default)]
+pub struct TraceReport {
+    groups: Vec<TraceGroup>,
+}
+
+/// The tasks of a [`TraceReport`] that share the same trace.
+pub struct TraceGroup {
+    names: Vec<String>,
+    trace: Trace,
+}
+
+impl TraceReport {
+    /// Creates an empty report.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the trace of the task called `name`.
+    pub fn add(&mut self, name: impl Into<String>, trace: Trace) {
+        let name = name.into();
+        let roots = trace.roots();
+        match self
+            .groups
+            .iter_mut()
+            .find(|group| same_tree(group.trace.roots(), roots))
+        {
+            Some(group) => group.names.push(name),
+            None => self.groups.push(TraceGroup {
+                names: vec![name],
+                trace,
+            }),
+        }
+    }
+
+    /// The groups of tasks, in the order in which they are rendered.
+    pub fn groups(&self) -> Vec<&TraceGroup> {
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        // Stable, so groups of the same size keep the order in which they were first seen
+        groups.sort_by_key(|group| std::cmp::Reverse(group.names.len()));
+        groups
+    }
+}
+
+impl<N: Into<String>> FromIterator<(N, Trace)> for TraceReport {
+    fn from_iter<I: IntoIterator<Item = (N, Trace)>>(iter: I) -> Self {
+        let mut report = TraceReport::new();
+        for (name, trace) in iter {
+            report.add(name, trace);
+        }
+        report
+    }
+}
+
+impl TraceGroup {
+    /// The names of the tasks, in the order in which they were added.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The trace that the tasks share, as it was captured from the first of them.
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+}
+
+/// Renders every group as a `N tasks with this trace: "a", "b"` line followed by the tree.
+impl fmt::Display for TraceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, group) in self.groups().into_iter().enumerate() {
+            if i > 0 {
+                writeln!(f, "\n")?;
+            }
+            match group.names.len() {
+                1 => write!(f, "1 task with this trace: ")?,
+                n => write!(f, "{n} tasks with this trace: ")?,
+            }
+            for (i, name) in group.names.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{name:?}")?;
+            }
+            writeln!(f)?;
+            display_tree(f, group.trace.roots(), false, &|f, frame| {
+                write!(f, "{frame}")
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn same_tree(roots: &[Frame], other: &[Frame]) -> bool {
+    roots.len() == other.len()
+        && roots
+            .iter()
+            .zip(other)
+            .all(|(a, b)| a.same_shape(b, Precision::SymbolLine))
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+    use crate::traced;
+
+    #[tokio::test]
+    async fn groups() {
+        let mut report = TraceReport::new();
+        for (name, fut) in [
+            ("a", either(true)),
+            ("b", either(false)),
+            ("c", either(true)),
+        ] {
+            let (fut, trace_handle) = traced(fut);
+            tokio::spawn(fut);
+            report.add(name, trace_handle.backtrace().await.unwrap());
+        }
+
+        let groups = report.groups();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].names(), ["a", "c"]);
+        assert_eq!(groups[1].names(), ["b"]);
+
+        let rendered = report.to_string();
+        let headers: Vec<_> = rendered
+            .lines()
+            .filter(|line| line.contains("with this trace"))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                "2 tasks with this trace: \"a\", \"c\"",
+                "1 task with this trace: \"b\""
+            ]
+        );
+        assert_eq!(rendered.matches("tests::either::{{closure}}").count(), 2);
+    }
+
+    async fn either(first: bool) {
+        if first {
+            return pending().await;
+        }
+        pending().await
+    }
+}
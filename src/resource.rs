@@ -0,0 +1,140 @@
+//! Labelling the resources that leaves wait on.
+//!
+//! The leaf of a branch is usually a frame deep inside a runtime, e.g. tokio's `Registration`,
+//! which doesn't tell which socket or channel the task is waiting for. Wrapping the future that
+//! awaits the resource in [`waiting_on`] attaches a description to every leaf it reaches, so that
+//! the trace reads `[waiting on: TcpStream read]` next to it.
+//!
+//! Blocking sections are where the trace of a task usually ends without saying why. With the
+//! `tokio` feature, [`traced_blocking`] runs them on the blocking thread pool labelled as such.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+thread_local! {
+    /// The description of the innermost [`WaitingOn`] being polled on this thread, if any.
+    static CURRENT_RESOURCE: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// The description of the resource the future that is currently being polled waits on, if any.
+pub(crate) fn current() -> Option<&'static str> {
+    CURRENT_RESOURCE.with(Cell::get)
+}
+
+/// Marks every leaf that `fut` reaches as waiting on `resource`.
+///
+/// ```rust
+/// # async fn example(jobs: futures::channel::oneshot::Receiver<u32>) {
+/// let job = tasktrace::waiting_on("job queue", jobs).await;
+/// # }
+/// ```
+///
+/// Descriptions nest, the innermost one applies. The wrapper costs a thread local access per poll,
+/// whether the task is traced or not.
+pub fn waiting_on<F: Future>(resource: &'static str, fut: F) -> WaitingOn<F> {
+    WaitingOn { fut, resource }
+}
+
+pin_project! {
+    /// The future returned by [`waiting_on`].
+    #[must_use = "futures do nothing unless polled"]
+    pub struct WaitingOn<F> {
+        #[pin]
+        fut: F,
+        resource: &'static str,
+    }
+}
+
+/// Runs `f` on the blocking thread pool of the current tokio runtime, like
+/// `tokio::task::spawn_blocking`, marking the leaves of the task that awaits it as waiting on a
+/// `blocking section`.
+///
+/// ```rust
+/// # async fn example() {
+/// let sum = tasktrace::traced_blocking(|| (0..1_000u64).sum::<u64>()).await.unwrap();
+/// # }
+/// ```
+///
+/// The trace of the awaiting task shows where it waits for the section, but not what the section
+/// itself is doing: the stack of another thread can't be captured. Sections run through
+/// `tokio::task::block_in_place` can't be traced at all, since they block the very poll that
+/// would capture the trace, so [`TraceHandle::backtrace_timeout`](crate::TraceHandle::backtrace_timeout) and
+/// [`watchdog`](crate::watchdog) are the way to find them.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, like `tokio::task::spawn_blocking`.
+#[cfg(feature = "tokio")]
+pub fn traced_blocking<F, R>(f: F) -> WaitingOn<tokio::task::JoinHandle<R>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    waiting_on("blocking section", tokio::task::spawn_blocking(f))
+}
+
+impl<F: Future> Future for WaitingOn<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        struct Restore(Option<&'static str>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT_RESOURCE.with(|current| current.set(self.0));
+            }
+        }
+
+        let this = self.project();
+        let _restore =
+            Restore(CURRENT_RESOURCE.with(|current| current.replace(Some(this.resource))));
+        this.fut.poll(cx)
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+    use crate::{branches, traced};
+
+    #[tokio::test]
+    async fn waiting_on() {
+        let (fut, trace_handle) = traced(async {
+            futures::join!(
+                super::waiting_on("socket", pending()),
+                super::waiting_on("outer", async {
+                    futures::join!(super::waiting_on("channel", pending()), pending())
+                }),
+            )
+        });
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let resources: Vec<_> = branches(&trace)
+            .map(|branch| branch.leaf().resource())
+            .collect();
+        assert_eq!(resources, [Some("socket"), Some("channel"), Some("outer")]);
+        assert!(trace.to_string().contains(" [waiting on: socket]"));
+        assert_eq!(current(), None);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn traced_blocking() {
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let (fut, trace_handle) = traced(super::traced_blocking(move || rx.recv()));
+        let task = tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+        assert!(trace
+            .to_string()
+            .contains(" [waiting on: blocking section]"));
+
+        tx.send(()).unwrap();
+        assert!(task.await.unwrap().unwrap().is_ok());
+    }
+}
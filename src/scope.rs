@@ -0,0 +1,262 @@
+//! Tracing of child tasks spawned by a traced task.
+//!
+//! A traced task that spawns its children through [`traced_scope`] records their handles so that
+//! [`TraceHandle::backtrace_tree`] can capture the whole task hierarchy in one go, or
+//! [`TraceOptions::include_spawned`] can splice the children into the task's own trace. Children
+//! spawned through [`spawn_linked`] also record where they were spawned, so that
+//! [`TraceHandle::backtrace_deep`] can graft their traces below the frame that spawned them.
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::panic::Location;
+use std::ptr;
+use std::sync::Arc;
+
+use crate::{
+    traced, Frame, TaskState, Trace, TraceError, TraceHandle, TraceOptions, TracedTask,
+    WeakTraceHandle,
+};
+
+thread_local! {
+    /// The state of the traced task currently being polled on this thread, if any.
+    static CURRENT_TASK: Cell<*const TaskState> = const { Cell::new(ptr::null()) };
+}
+
+/// Marks `state` as the currently polled task until the returned guard is dropped.
+pub(crate) fn enter(state: &TaskState) -> impl Drop {
+    struct Exit(*const TaskState);
+
+    impl Drop for Exit {
+        fn drop(&mut self) {
+            CURRENT_TASK.with(|current| current.set(self.0));
+        }
+    }
+
+    Exit(CURRENT_TASK.with(|current| current.replace(state)))
+}
+
+/// A child recorded by its parent.
+pub(crate) struct Child {
+    /// Weak so that finished children that nobody else holds on to are freed even if the parent
+    /// is never traced.
+    handle: WeakTraceHandle,
+    /// Where the child was spawned, for children spawned through [`spawn_linked`].
+    spawned_at: Option<Arc<SpawnPoint>>,
+}
+
+struct SpawnPoint {
+    location: &'static Location<'static>,
+    /// The backtrace of the spawn, from the root of the thread down.
+    stack: Trace,
+}
+
+/// Calls `f` with the state of the traced task currently being polled on this thread, if any.
+pub(crate) fn with_current<R>(f: impl FnOnce(&TaskState) -> R) -> Option<R> {
+    // The thread may be exiting, e.g. when a panic hook runs late
+    let current = CURRENT_TASK.try_with(Cell::get).unwrap_or(ptr::null());
+    // SAFETY: The pointer is only set while the task is being polled on this thread, during which
+    // its TracedTask keeps the state alive.
+    (!current.is_null()).then(|| f(unsafe { &*current }))
+}
+
+/// Like [`traced`] but also registers the new task as a child of the traced task that is currently
+/// being polled, if any. The child's trace is then included in the parent's
+/// [`TraceHandle::backtrace_tree`].
+///
+/// ```rust
+/// # async fn child() {}
+/// # async fn parent() {
+/// let (child, _child_handle) = tasktrace::traced_scope(child());
+/// tokio::spawn(child);
+/// # }
+/// ```
+pub fn traced_scope<F: Future>(fut: F) -> (TracedTask<F>, TraceHandle) {
+    let (task, handle) = traced(fut);
+    with_current(|parent| parent.add_child(&handle, None));
+    (task, handle)
+}
+
+/// Spawns `fut` on the current tokio runtime as a child of the traced task that is currently being
+/// polled, see [`traced_scope`], and records where it was spawned. The child's trace can then be
+/// included in the parent's through [`TraceOptions::include_spawned`], or grafted below the frame
+/// that spawned it through [`TraceHandle::backtrace_deep`].
+///
+/// ```rust
+/// # async fn child() {}
+/// # async fn parent() {
+/// let (join_handle, _child_handle) = tasktrace::spawn_linked(child());
+/// join_handle.await.unwrap();
+/// # }
+/// ```
+///
+/// Spawning from within a traced task walks the stack, which costs a few microseconds.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, like `tokio::spawn`.
+#[cfg(feature = "tokio")]
+#[track_caller]
+pub fn spawn_linked<F>(fut: F) -> (tokio::task::JoinHandle<F::Output>, TraceHandle)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, handle) = traced(fut);
+    let location = Location::caller();
+    with_current(|parent| {
+        let spawned_at = SpawnPoint {
+            location,
+            stack: Trace::here(),
+        };
+        parent.add_child(&handle, Some(spawned_at))
+    });
+    (tokio::spawn(task), handle)
+}
+
+impl TaskState {
+    /// Records a child of the task, dropping the ones that have finished since the last time so
+    /// that a long lived task that spawns many short lived children doesn't accumulate them.
+    fn add_child(&self, handle: &TraceHandle, spawned_at: Option<SpawnPoint>) {
+        let mut children = self.children.lock().unwrap();
+        children.retain(|child| child.live().is_some());
+        children.push(Child {
+            handle: handle.downgrade(),
+            spawned_at: spawned_at.map(Arc::new),
+        });
+    }
+
+    /// The handles of the children that haven't finished yet, along with where they were spawned.
+    fn live_children(&self) -> Vec<(TraceHandle, Option<Arc<SpawnPoint>>)> {
+        let mut children = self.children.lock().unwrap();
+        children.retain(|child| child.live().is_some());
+        children
+            .iter()
+            .filter_map(|child| Some((child.live()?, child.spawned_at.clone())))
+            .collect()
+    }
+}
+
+impl Child {
+    /// A handle to the child, unless it has finished.
+    fn live(&self) -> Option<TraceHandle> {
+        self.handle.upgrade().filter(|handle| !handle.is_finished())
+    }
+}
+
+impl TraceHandle {
+    /// Returns whether this is called from within the traced task itself, i.e. while the task
+    /// this handle belongs to is being polled on the current thread.
+    ///
+    /// A backtrace requested from within the task also records the path that leads to the
+    /// request itself, see the [crate level documentation](crate#tracing-a-task-from-itself).
+    pub fn is_current_task(&self) -> bool {
+        CURRENT_TASK.with(|current| ptr::eq(current.get(), Arc::as_ptr(&self.state)))
+    }
+
+    /// Captures a backtrace of the task along with the backtraces of all the children it spawned
+    /// through [`traced_scope`], recursively. Fails like [`TraceHandle::backtrace`] if the task
+    /// itself can't be traced. Children that have finished or fail to be traced are left out.
+    pub async fn backtrace_tree(&self) -> Result<TraceTree, TraceError> {
+        let trace = self.backtrace().await?;
+        let handles = self.state.live_children();
+        let mut children = Vec::with_capacity(handles.len());
+        for (child, _) in handles {
+            if let Ok(tree) = Box::pin(child.backtrace_tree()).await {
+                children.push(tree);
+            }
+        }
+        Ok(TraceTree { trace, children })
+    }
+
+    /// Captures a backtrace of the task with the backtraces of the children it spawned grafted in,
+    /// recursively.
+    ///
+    /// The trace of a child spawned through [`spawn_linked`] goes below the deepest frame of the
+    /// task that is still in the function that spawned it, as `[spawned task at file:line:col]`,
+    /// or next to the roots if the task has left that function altogether. The ones of children
+    /// spawned through [`traced_scope`] go next to the roots as `[spawned task]`, like with
+    /// [`TraceOptions::include_spawned`]. Children that have finished or fail to be traced are
+    /// left out.
+    pub async fn backtrace_deep(&self) -> Result<Trace, TraceError> {
+        let trace = self.backtrace().await?;
+        let mut roots = trace.roots().to_vec();
+        for (child, spawned_at) in self.state.live_children() {
+            let Ok(spawned) = Box::pin(child.backtrace_deep()).await else {
+                continue;
+            };
+            let branches = spawned.roots().iter().map(Frame::branches).sum();
+            let children = spawned.roots().to_vec();
+            match spawned_at {
+                Some(spawned_at) => {
+                    let label = format!("[spawned task at {}]", spawned_at.location);
+                    let path: Vec<_> = crate::branches(&spawned_at.stack)
+                        .next()
+                        .map(|branch| branch.frames().collect())
+                        .unwrap_or_default();
+                    let child = Frame::placeholder(label, branches, children);
+                    crate::trace::graft(&mut roots, &path, child);
+                }
+                None => roots.push(Frame::placeholder(
+                    "[spawned task]".to_owned(),
+                    branches,
+                    children,
+                )),
+            }
+        }
+        Ok(trace.with_roots(roots))
+    }
+
+    /// Appends the traces of the children of the task to its `trace`, see
+    /// [`TraceOptions::include_spawned`].
+    pub(crate) async fn splice_spawned(&self, trace: Trace, options: TraceOptions) -> Trace {
+        let mut roots = trace.roots().to_vec();
+        for (child, _) in self.state.live_children() {
+            if let Ok(spawned) = Box::pin(child.backtrace_with(options.clone())).await {
+                let branches = spawned.roots().iter().map(Frame::branches).sum();
+                let children = spawned.roots().to_vec();
+                roots.push(Frame::placeholder(
+                    "[spawned task]".to_owned(),
+                    branches,
+                    children,
+                ));
+            }
+        }
+        trace.with_roots(roots)
+    }
+}
+
+/// The backtrace of a task along with the backtraces of its children.
+pub struct TraceTree {
+    trace: Trace,
+    children: Vec<TraceTree>,
+}
+
+impl TraceTree {
+    /// The backtrace of the task itself.
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// The trees of the children spawned by the task.
+    pub fn children(&self) -> &[TraceTree] {
+        &self.children
+    }
+
+    fn display(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        for line in self.trace.to_string().lines() {
+            writeln!(f, "{:indent$}{line}", "")?;
+        }
+        for child in &self.children {
+            writeln!(f, "{:indent$}spawned task:", "")?;
+            child.display(f, indent + 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TraceTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(f, 0)
+    }
+}
@@ -0,0 +1,189 @@
+//! A debug endpoint that serves the traces of the registered tasks over HTTP.
+//!
+//! [`serve`] answers two routes, both with plain text:
+//!
+//! - `GET /tasks` lists the names of the tasks in the [registry](crate::registry), one per line.
+//! - `GET /tasks/<name>/trace` captures and renders the trace of the task called `<name>`, which
+//!   is percent-decoded, or of the one registered last if several tasks share the name.
+//!
+//! The server speaks just enough HTTP/1.1 to be used with `curl` or a browser and closes every
+//! connection after its response. It is meant for debugging and has no authentication, so it
+//! should only listen on addresses that untrusted clients can't reach.
+
+use std::io;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::registry::{self, DEFAULT_TIMEOUT};
+use crate::TraceError;
+
+/// The longest request head that is read before giving up on a connection.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Serves the debug endpoint on `addr` until accepting a connection fails.
+///
+/// ```rust,no_run
+/// # async fn example() -> std::io::Result<()> {
+/// tokio::spawn(tasktrace::server::serve("127.0.0.1:6060"));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Every connection is handled on a task of its own, spawned on the current tokio runtime. Traces
+/// that aren't captured within [`DEFAULT_TIMEOUT`] are answered with `504 Gateway Timeout`, along
+/// with how long ago the task was last polled.
+pub async fn serve(addr: impl ToSocketAddrs) -> io::Result<()> {
+    serve_listener(TcpListener::bind(addr).await?).await
+}
+
+async fn serve_listener(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            // The client is gone, there is nobody left to tell
+            let _ = handle(stream).await;
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        match stream.read(&mut buf).await? {
+            0 => return Ok(()),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return respond(&mut stream, "400 Bad Request", "malformed request line\n").await;
+    };
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "only GET is supported\n",
+        )
+        .await;
+    }
+    let path = target.split('?').next().unwrap_or_default();
+
+    if path == "/tasks" {
+        let mut names = String::new();
+        for name in registry::names() {
+            names.push_str(&name);
+            names.push('\n');
+        }
+        return respond(&mut stream, "200 OK", &names).await;
+    }
+    let name = path
+        .strip_prefix("/tasks/")
+        .and_then(|rest| rest.strip_suffix("/trace"))
+        .and_then(percent_decode);
+    let Some(name) = name else {
+        return respond(&mut stream, "404 Not Found", "no such route\n").await;
+    };
+    let Some(handle) = registry::lookup(&name) else {
+        return respond(&mut stream, "404 Not Found", "no such task\n").await;
+    };
+    match handle.backtrace_timeout(DEFAULT_TIMEOUT).await {
+        Ok(trace) => respond(&mut stream, "200 OK", &format!("{trace}\n")).await,
+        Err(TraceError::Timeout) => {
+            let body = format!(
+                "the task was not polled in time, {}\n",
+                registry::describe_last_poll(&handle)
+            );
+            respond(&mut stream, "504 Gateway Timeout", &body).await
+        }
+        Err(err) => respond(&mut stream, "503 Service Unavailable", &format!("{err}\n")).await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Decodes the `%XX` escapes of `s`, or returns `None` if they don't make up valid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            // `from_str_radix` would take a sign, e.g. `%+1`
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::registry::traced_registered;
+    use crate::test_util::pending;
+
+    #[tokio::test]
+    async fn routes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener));
+        let (fut, _trace_handle) = traced_registered(pending(), "http worker");
+        tokio::spawn(fut);
+
+        let get = |target: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let tasks = get("/tasks").await;
+        assert!(tasks.starts_with("HTTP/1.1 200 OK\r\n"));
+        // Other tests may register tasks of their own concurrently
+        assert!(tasks.lines().any(|line| line == "http worker"));
+
+        let trace = get("/tasks/http%20worker/trace").await;
+        assert!(trace.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(trace.contains("\r\n\r\n╼ tasktrace::test_util::pending::{{closure}}"));
+
+        let (_wedged, _trace_handle) = traced_registered(pending(), "http wedged");
+        let wedged = get("/tasks/http%20wedged/trace").await;
+        assert!(wedged.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(wedged.ends_with("the task was not polled in time, never polled\n"));
+
+        let missing = get("/tasks/nobody/trace").await;
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(missing.ends_with("no such task\n"));
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%-1"), None);
+        assert_eq!(percent_decode("%4a%4A").as_deref(), Some("JJ"));
+        assert_eq!(percent_decode("%C3%BC").as_deref(), Some("ü"));
+    }
+}
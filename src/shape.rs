@@ -0,0 +1,139 @@
+//! Comparison of traces by their structure.
+//!
+//! The source locations in a trace differ between machines, toolchains and unrelated edits, so two
+//! traces of the same code path rarely compare equal as strings. A [`TraceShape`] only keeps the
+//! symbols of the frames and the way they nest, which makes it suitable for assertions in tests.
+
+use std::fmt;
+
+use crate::{Frame, Trace};
+
+/// The symbols of a trace along with the way they nest, without any source locations.
+///
+/// ```rust
+/// use tasktrace::TraceShape;
+///
+/// let expected = TraceShape::from_roots([TraceShape::frame(
+///     "app::serve::{{closure}}",
+///     [TraceShape::frame("app::accept::{{closure}}", [])],
+/// )]);
+/// # let _ = expected;
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct TraceShape {
+    // `None` for the node that holds the roots of a trace
+    symbol: Option<String>,
+    children: Vec<TraceShape>,
+}
+
+impl TraceShape {
+    /// The shape of `trace`. Frames whose symbol is unknown are kept as `??`.
+    pub fn of(trace: &Trace) -> Self {
+        Self::from_roots(trace.roots().iter().map(Self::of_frame))
+    }
+
+    /// The shape of a trace made of `roots`, each built with [`TraceShape::frame`].
+    pub fn from_roots(roots: impl IntoIterator<Item = TraceShape>) -> Self {
+        TraceShape {
+            symbol: None,
+            children: roots.into_iter().collect(),
+        }
+    }
+
+    /// A frame of `symbol` that called `children`.
+    pub fn frame(
+        symbol: impl Into<String>,
+        children: impl IntoIterator<Item = TraceShape>,
+    ) -> Self {
+        TraceShape {
+            symbol: Some(symbol.into()),
+            children: children.into_iter().collect(),
+        }
+    }
+
+    fn of_frame(frame: &Frame) -> Self {
+        Self::frame(
+            frame.symbol().unwrap_or("??"),
+            frame.children().iter().map(Self::of_frame),
+        )
+    }
+
+    fn display(&self, f: &mut fmt::Formatter<'_>, prefix: &str) -> fmt::Result {
+        for (i, child) in self.children.iter().enumerate() {
+            let (branch, indent) = if i == self.children.len() - 1 {
+                ("└╼", "   ")
+            } else {
+                ("├╼", "│  ")
+            };
+            let symbol = child.symbol.as_deref().unwrap_or_default();
+            writeln!(f, "{prefix}{branch} {symbol}")?;
+            child.display(f, &format!("{prefix}{indent}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders the shape as a tree of symbols, so that failed assertions show where two shapes differ.
+impl fmt::Debug for TraceShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some(symbol) => {
+                writeln!(f, "{symbol}")?;
+                self.display(f, "")
+            }
+            None => {
+                for root in &self.children {
+                    writeln!(f, "╼ {}", root.symbol.as_deref().unwrap_or_default())?;
+                    root.display(f, "  ")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+    use crate::traced;
+
+    #[tokio::test]
+    async fn same_path_different_locations() {
+        let (fut, first) = traced(either(true));
+        tokio::spawn(fut);
+        let first = first.backtrace().await.unwrap();
+        let (fut, second) = traced(either(false));
+        tokio::spawn(fut);
+        let second = second.backtrace().await.unwrap();
+
+        // The branches await at different lines but go through the same functions
+        assert_ne!(first.to_string(), second.to_string());
+        assert_eq!(TraceShape::of(&first), TraceShape::of(&second));
+
+        let expected = TraceShape::from_roots([TraceShape::frame(
+            "tasktrace::shape::tests::either::{{closure}}",
+            [TraceShape::frame(
+                "tasktrace::test_util::pending::{{closure}}",
+                [TraceShape::frame(
+                    "<core::future::poll_fn::PollFn<F> as core::future::future::Future>::poll",
+                    [TraceShape::frame(
+                        "tasktrace::test_util::pending::{{closure}}::{{closure}}",
+                        [TraceShape::frame(
+                            "<core::task::wake::Waker as core::clone::Clone>::clone",
+                            [],
+                        )],
+                    )],
+                )],
+            )],
+        )]);
+        assert_eq!(TraceShape::of(&first), expected);
+    }
+
+    async fn either(first: bool) {
+        if first {
+            return pending().await;
+        }
+        pending().await
+    }
+}
@@ -0,0 +1,210 @@
+//! Dumping traces to stderr when the process receives a signal.
+
+use std::future::Future;
+use std::io;
+use std::os::raw::c_int;
+use std::thread;
+
+#[cfg(feature = "registry")]
+use std::fmt::Write;
+#[cfg(feature = "registry")]
+use std::future::poll_fn;
+#[cfg(feature = "registry")]
+use std::pin::Pin;
+
+#[cfg(feature = "registry")]
+use futures_core::Stream;
+use signal_hook::iterator::{Handle, Signals};
+
+#[cfg(feature = "registry")]
+use crate::registry;
+use crate::{traced, Trace, TraceError};
+
+/// Wraps `fut` with [`traced`] and prints its trace to stderr every time the process receives
+/// `signal`, for as long as the returned future is alive.
+///
+/// ```rust,no_run
+/// # async fn serve() {}
+/// #[tokio::main]
+/// async fn main() -> std::io::Result<()> {
+///     tasktrace::run_with_dump_on_signal(signal_hook::consts::SIGUSR1, serve())?.await;
+///     Ok(())
+/// }
+/// ```
+///
+/// The signal handler itself only records the signal. The trace is requested and printed from a
+/// dedicated thread, so nothing that isn't async signal safe runs in the handler. As with any
+/// trace request the trace is only captured the next time the future is polled, so a future that
+/// is stuck without yielding won't be dumped until it yields. Signals that arrive while a dump is
+/// in progress are coalesced into the next dump.
+///
+/// Returns an error if the signal handler can't be installed, e.g. for signals like `SIGKILL` that
+/// can't be handled.
+pub fn run_with_dump_on_signal<F: Future>(
+    signal: c_int,
+    fut: F,
+) -> io::Result<impl Future<Output = F::Output>> {
+    run_with_dump_on_signal_to(signal, fut, |trace| eprintln!("{trace}"))
+}
+
+fn run_with_dump_on_signal_to<F: Future>(
+    signal: c_int,
+    fut: F,
+    mut sink: impl FnMut(Trace) + Send + 'static,
+) -> io::Result<impl Future<Output = F::Output>> {
+    let mut signals = Signals::new([signal])?;
+    let (fut, trace_handle) = traced(fut);
+
+    let signals_handle = signals.handle();
+    thread::Builder::new()
+        .name("tasktrace-signal".into())
+        .spawn(move || {
+            for _ in signals.forever() {
+                match futures_executor::block_on(trace_handle.backtrace()) {
+                    Ok(trace) => sink(trace),
+                    Err(TraceError::Disabled) => {}
+                    Err(_) => break,
+                }
+            }
+        })?;
+
+    Ok(async move {
+        let _close = CloseOnDrop(signals_handle);
+        fut.await
+    })
+}
+
+/// Prints the traces of all the tasks in the [registry](crate::registry) to stderr every time the
+/// process receives `signal`, like the thread dump of the JVM on `SIGQUIT`.
+///
+/// ```rust,no_run
+/// # async fn serve() {}
+/// #[tokio::main]
+/// async fn main() -> std::io::Result<()> {
+///     tasktrace::install_signal_dumper(signal_hook::consts::SIGQUIT)?;
+///     let (fut, _trace_handle) = tasktrace::registry::traced_registered(serve(), "server");
+///     fut.await;
+///     Ok(())
+/// }
+/// ```
+///
+/// The signal handler itself only records the signal and wakes a background task, spawned on the
+/// current tokio runtime, which captures the traces like [`registry::dump_all`] does and prints
+/// them. Must be called from within a tokio runtime and requires the `registry` feature in
+/// addition to `signal`.
+///
+/// Returns an error if the signal handler can't be installed or if there is no current runtime.
+#[cfg(feature = "registry")]
+pub fn install_signal_dumper(signal: c_int) -> io::Result<()> {
+    install_signal_dumper_to(signal, |dump| eprint!("{dump}"))
+}
+
+#[cfg(feature = "registry")]
+fn install_signal_dumper_to(
+    signal: c_int,
+    mut sink: impl FnMut(String) + Send + 'static,
+) -> io::Result<()> {
+    let runtime = tokio::runtime::Handle::try_current().map_err(io::Error::other)?;
+    let mut signals = Signals::new([signal])?;
+
+    let (wake_tx, mut wake_rx) = futures_channel::mpsc::unbounded();
+    thread::Builder::new()
+        .name("tasktrace-signal".into())
+        .spawn(move || {
+            for _ in signals.forever() {
+                if wake_tx.unbounded_send(()).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    runtime.spawn(async move {
+        while poll_fn(|cx| Pin::new(&mut wake_rx).poll_next(cx))
+            .await
+            .is_some()
+        {
+            // Signals that arrived while the previous dump was in progress are coalesced
+            while wake_rx.try_recv().is_ok() {}
+
+            let mut dump = String::new();
+            let tasks = registry::tasks();
+            let handles: Vec<_> = tasks.iter().map(|(_, handle)| handle.clone()).collect();
+            let traces = registry::dump_handles(tasks, registry::DEFAULT_TIMEOUT, usize::MAX).await;
+            for ((name, trace), handle) in traces.into_iter().zip(handles) {
+                match trace {
+                    Ok(trace) => {
+                        let _ = writeln!(dump, "task {name}:\n{trace}");
+                    }
+                    Err(err) => {
+                        let last_poll = registry::describe_last_poll(&handle);
+                        let _ = writeln!(dump, "task {name}: {err} ({last_poll})");
+                    }
+                }
+            }
+            sink(dump);
+        }
+    });
+    Ok(())
+}
+
+/// Stops the dumping thread when the wrapped future completes or is dropped.
+struct CloseOnDrop(Handle);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn dump_on_signal() {
+        let (tx, rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = futures_channel::oneshot::channel();
+        let fut = run_with_dump_on_signal_to(
+            signal_hook::consts::SIGUSR2,
+            async {
+                ready_rx.await.unwrap();
+                pending().await
+            },
+            move |trace| tx.send(trace.to_string()).unwrap(),
+        )
+        .unwrap();
+        tokio::spawn(fut);
+        ready_tx.send(()).unwrap();
+        tokio::task::yield_now().await;
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGUSR2).unwrap();
+        let trace = tokio::task::spawn_blocking(move || rx.recv_timeout(Duration::from_secs(10)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(trace.contains("test_util::pending"));
+    }
+
+    #[cfg(feature = "registry")]
+    #[tokio::test]
+    async fn signal_dumper() {
+        let (tx, rx) = mpsc::channel();
+        let (fut, _trace_handle) = registry::traced_registered(pending(), "signal-dumper");
+        tokio::spawn(fut);
+        install_signal_dumper_to(signal_hook::consts::SIGUSR1, move |dump| {
+            let _ = tx.send(dump);
+        })
+        .unwrap();
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGUSR1).unwrap();
+        let dump = tokio::task::spawn_blocking(move || rx.recv_timeout(Duration::from_secs(10)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(dump.contains("task signal-dumper:\n"));
+        assert!(dump.contains("test_util::pending"));
+    }
+}
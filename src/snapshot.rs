@@ -0,0 +1,320 @@
+//! Saving dumps to files and reading them back.
+//!
+//! A dump taken in production is most useful once it can be looked at offline, with the rest of
+//! this crate's tooling. [`TraceSnapshot`] holds the symbolized traces of many tasks along with
+//! when they were taken, and writes them in a compact binary format that only depends on the
+//! version of the format, not on the binary that captured them. The `tasktrace-view` example
+//! renders a snapshot file.
+//!
+//! The format starts with the magic bytes `TTSNAP` and a version byte. Integers are little endian,
+//! strings are prefixed with their length as a `u32` and optional values with a byte telling
+//! whether they are present. Version 2 added the busy ratio of every trace, files of version 1 are
+//! still read.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::time::{SystemTime, UNIX_EPOCH};
+use crate::{Frame, Trace};
+
+const MAGIC: &[u8; 6] = b"TTSNAP";
+const VERSION: u8 = 2;
+
+/// The longest string that is read back, to not allocate wildly for a corrupted file.
+const MAX_STRING_LEN: u32 = 1 << 20;
+/// The deepest tree that is read back, to not overflow the stack for a corrupted file.
+const MAX_DEPTH: usize = 4096;
+
+/// The traces of many tasks, taken at the same time, that can be saved and read back.
+///
+/// ```rust
+/// # fn save(traces: Vec<(String, tasktrace::Trace)>) -> std::io::Result<()> {
+/// let snapshot: tasktrace::TraceSnapshot = traces.into_iter().collect();
+/// snapshot.write_to(std::io::BufWriter::new(std::fs::File::create("dump.ttsnap")?))?;
+///
+/// let snapshot = tasktrace::TraceSnapshot::read_from(std::fs::File::open("dump.ttsnap")?)?;
+/// eprintln!("{snapshot}");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Only the symbolized trees are saved, so the read back traces render and compare like the
+/// original ones but the number of leaves is the number of distinct branches.
+#[derive(Clone)]
+pub struct TraceSnapshot {
+    taken_at: SystemTime,
+    tasks: Vec<(String, Trace)>,
+}
+
+impl TraceSnapshot {
+    /// Creates an empty snapshot taken now.
+    pub fn new() -> Self {
+        TraceSnapshot {
+            taken_at: SystemTime::now(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Adds the trace of the task called `name`.
+    pub fn add(&mut self, name: impl Into<String>, trace: Trace) {
+        self.tasks.push((name.into(), trace));
+    }
+
+    /// When the snapshot was created.
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+
+    /// The names and traces of the tasks, in the order in which they were added.
+    pub fn tasks(&self) -> &[(String, Trace)] {
+        &self.tasks
+    }
+
+    /// Writes the snapshot to `w`. The snapshot is written in many small pieces, so writers like
+    /// a `File` are best wrapped in a `BufWriter`.
+    pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        let taken_at = self.taken_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        w.write_all(&taken_at.as_secs().to_le_bytes())?;
+        w.write_all(&taken_at.subsec_nanos().to_le_bytes())?;
+        write_len(&mut w, self.tasks.len())?;
+        for (name, trace) in &self.tasks {
+            write_str(&mut w, name)?;
+            write_opt(&mut w, trace.name(), write_str)?;
+            write_opt(&mut w, trace.busy_ratio(), write_f64)?;
+            write_frames(&mut w, trace.roots())?;
+        }
+        w.flush()
+    }
+
+    /// Reads a snapshot written by [`TraceSnapshot::write_to`]. Fails with
+    /// [`io::ErrorKind::InvalidData`] if `r` doesn't hold a snapshot, or one of a newer version.
+    pub fn read_from(mut r: impl Read) -> io::Result<Self> {
+        let mut magic = [0; 6];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a trace snapshot"));
+        }
+        let version = read_u8(&mut r)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid(format!("unsupported snapshot version {version}")));
+        }
+        let secs = u64::from_le_bytes(read_array(&mut r)?);
+        let nanos = u32::from_le_bytes(read_array(&mut r)?);
+        let taken_at = UNIX_EPOCH + Duration::new(secs, nanos);
+        let count = read_u32(&mut r)?;
+        let mut tasks = Vec::new();
+        for _ in 0..count {
+            let name = read_str(&mut r)?;
+            let trace_name = read_opt(&mut r, read_str)?;
+            let busy_ratio = match version {
+                1 => None,
+                _ => read_opt(&mut r, read_f64)?,
+            };
+            let mut trace = Trace::from_roots(read_frames(&mut r, 0)?);
+            if let Some(trace_name) = trace_name {
+                trace = trace.named(&trace_name);
+            }
+            if let Some(busy_ratio) = busy_ratio {
+                trace = trace.with_busy_ratio(busy_ratio);
+            }
+            tasks.push((name, trace));
+        }
+        Ok(TraceSnapshot { taken_at, tasks })
+    }
+}
+
+impl Default for TraceSnapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: Into<String>> FromIterator<(N, Trace)> for TraceSnapshot {
+    fn from_iter<I: IntoIterator<Item = (N, Trace)>>(iter: I) -> Self {
+        let mut snapshot = TraceSnapshot::new();
+        for (name, trace) in iter {
+            snapshot.add(name, trace);
+        }
+        snapshot
+    }
+}
+
+/// Renders every task as a `task name:` line followed by its trace, like the signal dumps.
+impl fmt::Display for TraceSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, trace)) in self.tasks.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "task {name}:\n{trace}")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_frames(w: &mut impl Write, frames: &[Frame]) -> io::Result<()> {
+    write_len(w, frames.len())?;
+    for frame in frames {
+        write_opt(w, frame.symbol(), write_str)?;
+        let file = frame.file().map(|file| file.to_string_lossy());
+        write_opt(w, file.as_deref(), write_str)?;
+        write_opt(w, frame.line(), write_u32)?;
+        write_opt(w, frame.col(), write_u32)?;
+        write_opt(w, frame.span(), write_str)?;
+        write_opt(w, frame.resource(), write_str)?;
+        write_len(w, frame.branches())?;
+        write_frames(w, frame.children())?;
+    }
+    Ok(())
+}
+
+fn read_frames(r: &mut impl Read, depth: usize) -> io::Result<Vec<Frame>> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("trace too deep"));
+    }
+    let count = read_u32(r)?;
+    let mut frames = Vec::new();
+    for _ in 0..count {
+        let symbol = read_opt(r, read_str)?;
+        let file = read_opt(r, read_str)?;
+        let line = read_opt(r, read_u32)?;
+        let col = read_opt(r, read_u32)?;
+        let span = read_opt(r, read_str)?;
+        let resource = read_opt(r, read_str)?;
+        let branches = read_u32(r)? as usize;
+        let children = read_frames(r, depth + 1)?;
+        frames.push(
+            Frame::placeholder(String::new(), branches, children)
+                .with_parts(symbol, file.map(Into::into), line, col)
+                .with_annotations(span, resource),
+        );
+    }
+    Ok(frames)
+}
+
+fn write_len(w: &mut impl Write, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid("too many entries for a snapshot"))?;
+    write_u32(w, len)
+}
+
+fn write_u32(w: &mut impl Write, n: u32) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+fn write_f64(w: &mut impl Write, n: f64) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+fn write_str(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write_len(w, s.len())?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_opt<W: Write, T>(
+    w: &mut W,
+    value: Option<T>,
+    write: impl FnOnce(&mut W, T) -> io::Result<()>,
+) -> io::Result<()> {
+    match value {
+        Some(value) => {
+            w.write_all(&[1])?;
+            write(w, value)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    Ok(read_array::<1>(r)?[0])
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+    Ok(f64::from_le_bytes(read_array(r)?))
+}
+
+fn read_str(r: &mut impl Read) -> io::Result<String> {
+    let len = read_u32(r)?;
+    if len > MAX_STRING_LEN {
+        return Err(invalid("string too long"));
+    }
+    let mut bytes = vec![0; len as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn read_opt<R: Read, T>(
+    r: &mut R,
+    read: impl FnOnce(&mut R) -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    match read_u8(r)? {
+        0 => Ok(None),
+        1 => read(r).map(Some),
+        _ => Err(invalid("malformed optional value")),
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::pending;
+    use crate::{traced, traced_named};
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (fut, trace_handle) = traced(async { futures::join!(pending(), pending()) });
+        tokio::spawn(fut);
+        let joined = trace_handle.backtrace().await.unwrap();
+        let (fut, trace_handle) = traced_named(pending(), "worker");
+        tokio::spawn(fut);
+        let named = trace_handle.backtrace().await.unwrap();
+
+        let snapshot: TraceSnapshot = [("joined", joined), ("named", named)].into_iter().collect();
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"TTSNAP\x02"));
+
+        let read = TraceSnapshot::read_from(&bytes[..]).unwrap();
+        assert_eq!(read.taken_at(), snapshot.taken_at());
+        assert_eq!(read.tasks().len(), 2);
+        for ((name, trace), (read_name, read_trace)) in snapshot.tasks().iter().zip(read.tasks()) {
+            assert_eq!(name, read_name);
+            assert_eq!(trace.name(), read_trace.name());
+            assert_eq!(trace.busy_ratio(), read_trace.busy_ratio());
+            assert_eq!(trace.to_string(), read_trace.to_string());
+        }
+        assert_eq!(read.to_string(), snapshot.to_string());
+        assert!(read.to_string().starts_with("task joined:\n╼ "));
+
+        // Cut short or tampered with
+        assert!(TraceSnapshot::read_from(&bytes[..bytes.len() - 1]).is_err());
+        bytes[6] = 3;
+        let err = TraceSnapshot::read_from(&bytes[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Files of the first version are still read
+        let mut bytes = Vec::new();
+        TraceSnapshot::new().write_to(&mut bytes).unwrap();
+        bytes[6] = 1;
+        assert!(TraceSnapshot::read_from(&bytes[..])
+            .unwrap()
+            .tasks()
+            .is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+//! A line based text format for traces that stays the same across versions of this crate.
+//!
+//! The `Display` rendering of a trace is meant for people and changes whenever it can be made more
+//! readable. [`Trace::to_stable_text`] writes the symbolized tree in a format that tools can parse
+//! instead, and [`Trace::from_stable_text`] reads it back. Changes that old readers can't skip get
+//! a new version, and the readers of this crate keep reading the old ones.
+
+use std::fmt::Write as _;
+use std::io;
+
+use crate::{Frame, Trace};
+
+const HEADER: &str = "tasktrace-trace v1";
+
+/// The deepest tree that is read back, like for snapshots.
+const MAX_DEPTH: usize = 4096;
+
+impl Trace {
+    /// Writes the symbolized trace in a text format that stays the same across versions of this
+    /// crate, for tools that parse traces and shouldn't break when the `Display` rendering
+    /// changes. [`Trace::from_stable_text`] reads it back.
+    ///
+    /// Version 1 consists of lines ending in `\n` whose fields are separated by tabs. The first line
+    /// is the header `tasktrace-trace v1`, every other line a record whose first field tells its
+    /// kind:
+    ///
+    /// - `name` with the name of the task, see [`Trace::name`],
+    /// - `busy_ratio` with the busy ratio of the task, see [`Trace::busy_ratio`],
+    /// - `frame` with the depth of the frame, 0 for the roots, the number of branches through it, its
+    ///   symbol, file, line, column, span and resource.
+    ///
+    /// The frames come in depth first order, every frame right after its parent and its older
+    /// siblings' descendants, so a frame is a child of the closest frame above it that is one level
+    /// less deep. Missing values are written as `-`. Other values escape a backslash as `\\`, a tab as
+    /// `\t`, a newline as `\n`, a carriage return as `\r` and a value that is just `-` as `\-`. Readers
+    /// skip the records of other kinds and the fields after the ones they know, which is how version 1
+    /// grows.
+    pub fn to_stable_text(&self) -> String {
+        let mut text = String::new();
+        text.push_str(HEADER);
+        text.push('\n');
+        if let Some(name) = self.name() {
+            let _ = writeln!(text, "name\t{}", escape(Some(name)));
+        }
+        if let Some(busy_ratio) = self.busy_ratio() {
+            let _ = writeln!(text, "busy_ratio\t{busy_ratio}");
+        }
+        for root in self.roots() {
+            write_frame(&mut text, root, 0);
+        }
+        text
+    }
+
+    /// Reads a trace written by [`Trace::to_stable_text`], of this or an older version of the
+    /// crate. Fails with [`io::ErrorKind::InvalidData`] if `text` isn't a trace in a version of the
+    /// format this crate knows.
+    ///
+    /// Like for [snapshots](crate::TraceSnapshot), only the symbolized tree is read back.
+    pub fn from_stable_text(text: &str) -> io::Result<Trace> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            Some((_, header)) if header.starts_with("tasktrace-trace ") => {
+                return Err(invalid(format!("unsupported format {header:?}")));
+            }
+            _ => return Err(invalid("not a trace in the stable text format")),
+        }
+        let mut name = None;
+        let mut busy_ratio = None;
+        let mut roots = Vec::new();
+        // The frames whose children may still follow, outermost first
+        let mut open: Vec<Frame> = Vec::new();
+        for (i, line) in lines {
+            let in_line = |message: &str| invalid(format!("line {}: {message}", i + 1));
+            let mut fields = line.split('\t');
+            match fields.next() {
+                Some("name") => {
+                    name = unescape(fields.next().ok_or_else(|| in_line("missing name"))?)
+                        .map_err(&in_line)?;
+                }
+                Some("busy_ratio") => {
+                    let field = fields.next().ok_or_else(|| in_line("missing busy ratio"))?;
+                    busy_ratio = Some(field.parse().map_err(|_| in_line("invalid busy ratio"))?);
+                }
+                Some("frame") => {
+                    let mut next = || fields.next().ok_or_else(|| in_line("missing frame field"));
+                    let depth: usize = parse(next()?).map_err(&in_line)?;
+                    let branches = parse(next()?).map_err(&in_line)?;
+                    let mut string = || unescape(next()?).map_err(&in_line);
+                    let symbol = string()?;
+                    let file = string()?;
+                    let line = string()?.map(|line| parse(&line)).transpose();
+                    let line = line.map_err(&in_line)?;
+                    let col = string()?.map(|col| parse(&col)).transpose();
+                    let col = col.map_err(&in_line)?;
+                    let span = string()?;
+                    let resource = string()?;
+                    if depth > open.len() {
+                        return Err(in_line("frame without a parent"));
+                    }
+                    if depth > MAX_DEPTH {
+                        return Err(in_line("trace too deep"));
+                    }
+                    close(&mut open, &mut roots, depth);
+                    open.push(
+                        Frame::placeholder(String::new(), branches, Vec::new())
+                            .with_parts(symbol, file.map(Into::into), line, col)
+                            .with_annotations(span, resource),
+                    );
+                }
+                // Added in a later revision of the version
+                _ => {}
+            }
+        }
+        close(&mut open, &mut roots, 0);
+
+        let mut trace = Trace::from_roots(roots);
+        if let Some(name) = name {
+            trace = trace.named(&name);
+        }
+        if let Some(busy_ratio) = busy_ratio {
+            trace = trace.with_busy_ratio(busy_ratio);
+        }
+        Ok(trace)
+    }
+}
+
+fn write_frame(text: &mut String, frame: &Frame, depth: usize) {
+    let file = frame.file().map(|file| file.to_string_lossy());
+    let _ = writeln!(
+        text,
+        "frame\t{depth}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        frame.branches(),
+        escape(frame.symbol()),
+        escape(file.as_deref()),
+        escape(frame.line().map(|line| line.to_string()).as_deref()),
+        escape(frame.col().map(|col| col.to_string()).as_deref()),
+        escape(frame.span()),
+        escape(frame.resource()),
+    );
+    for child in frame.children() {
+        write_frame(text, child, depth + 1);
+    }
+}
+
+/// Closes the frames of `open` that are `depth` or more levels deep, adding each to its parent or,
+/// at the top, to `roots`.
+fn close(open: &mut Vec<Frame>, roots: &mut Vec<Frame>, depth: usize) {
+    while open.len() > depth {
+        let frame = open.pop().unwrap();
+        match open.last_mut() {
+            Some(parent) => parent.push_child(frame),
+            None => roots.push(frame),
+        }
+    }
+}
+
+fn escape(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "-".to_owned();
+    };
+    if value == "-" {
+        return "\\-".to_owned();
+    }
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> Result<Option<String>, &'static str> {
+    if field == "-" {
+        return Ok(None);
+    }
+    let mut value = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => value.push('\\'),
+            Some('t') => value.push('\t'),
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some('-') => value.push('-'),
+            _ => return Err("invalid escape"),
+        }
+    }
+    Ok(Some(value))
+}
+
+fn parse<T: std::str::FromStr>(field: &str) -> Result<T, &'static str> {
+    field.parse().map_err(|_| "invalid number")
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame_at, pending};
+    use crate::traced_named;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (fut, trace_handle) =
+            traced_named(async { futures::join!(pending(), pending()) }, "worker");
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let text = trace.to_stable_text();
+        assert!(text.starts_with("tasktrace-trace v1\nname\tworker\nbusy_ratio\t"));
+        let read = Trace::from_stable_text(&text).unwrap();
+        assert_eq!(read.name(), Some("worker"));
+        assert_eq!(read.busy_ratio(), trace.busy_ratio());
+        assert_eq!(read.to_string(), trace.to_string());
+        assert_eq!(read.to_stable_text(), text);
+    }
+
+    #[test]
+    fn escaping() {
+        let leaf =
+            frame_at("-", 2, 1, Vec::new()).with_annotations(Some("a\tb\\c\nd".to_owned()), None);
+        let trace = Trace::from_roots(vec![frame_at("app::main", 1, 1, vec![leaf])]);
+        let text = trace.to_stable_text();
+        assert_eq!(
+            text,
+            "tasktrace-trace v1\n\
+             frame\t0\t1\tapp::main\tsrc/app.rs\t1\t1\t-\t-\n\
+             frame\t1\t1\t\\-\tsrc/app.rs\t2\t1\ta\\tb\\\\c\\nd\t-\n"
+        );
+        let read = Trace::from_stable_text(&text).unwrap();
+        let leaf = &read.roots()[0].children()[0];
+        assert_eq!(leaf.symbol(), Some("-"));
+        assert_eq!(leaf.span(), Some("a\tb\\c\nd"));
+        assert_eq!(leaf.resource(), None);
+    }
+
+    #[test]
+    fn invalid_text() {
+        let invalid = |text: &str| Trace::from_stable_text(text).err().unwrap().to_string();
+        assert_eq!(
+            invalid("tasktrace-trace v2\n"),
+            "unsupported format \"tasktrace-trace v2\""
+        );
+        assert_eq!(
+            invalid("╼ app::main\n"),
+            "not a trace in the stable text format"
+        );
+        assert_eq!(
+            invalid("tasktrace-trace v1\nframe\t1\t1\t-\t-\t-\t-\t-\t-\n"),
+            "line 2: frame without a parent"
+        );
+        assert_eq!(
+            invalid("tasktrace-trace v1\nframe\t0\tmany\t-\t-\t-\t-\t-\t-\n"),
+            "line 2: invalid number"
+        );
+        assert_eq!(
+            invalid("tasktrace-trace v1\nframe\t0\t1\t\\x\t-\t-\t-\t-\t-\n"),
+            "line 2: invalid escape"
+        );
+
+        // Later additions are skipped
+        let trace = Trace::from_stable_text(
+            "tasktrace-trace v1\nfuture\t1\nframe\t0\t1\tapp::main\t-\t-\t-\t-\t-\tmore\n",
+        )
+        .unwrap();
+        assert_eq!(trace.to_string(), "╼ app::main");
+    }
+}
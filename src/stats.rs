@@ -0,0 +1,90 @@
+//! Exporting statistics of the traced tasks through the `metrics` facade.
+//!
+//! With the `metrics` feature every traced task reports to the installed `metrics` recorder:
+//!
+//! - `tasktrace_polls_total`, a counter of the polls of all traced tasks.
+//! - `tasktrace_poll_duration_seconds`, a histogram of how long those polls took.
+//! - `tasktrace_trace_leaves`, a histogram of the number of leaves of every captured trace.
+//! - `tasktrace_trace_timeouts_total`, a counter of the trace requests that timed out, i.e. of
+//!   tasks that weren't polled in time to answer.
+//!
+//! They are cheap enough to keep on in production, and point at the tasks worth pulling a full
+//! trace of.
+
+use std::time::Duration;
+
+use crate::{leaf_count, Trace};
+
+/// Records a poll of a traced task that took `duration`.
+pub(crate) fn record_poll(duration: Duration) {
+    metrics::counter!("tasktrace_polls_total").increment(1);
+    metrics::histogram!("tasktrace_poll_duration_seconds").record(duration.as_secs_f64());
+}
+
+/// Records a trace captured by a traced task.
+pub(crate) fn record_trace(trace: &Trace) {
+    metrics::histogram!("tasktrace_trace_leaves").record(leaf_count(trace) as f64);
+}
+
+/// Records a trace request that timed out.
+pub(crate) fn record_timeout() {
+    metrics::counter!("tasktrace_trace_timeouts_total").increment(1);
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use futures::future::{self, Either};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    use super::*;
+    use crate::test_util::pending;
+    use crate::{traced, TraceError};
+
+    #[test]
+    fn exported() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let (fut, trace_handle) = traced(future::join(pending(), pending()));
+            let trace = futures::executor::block_on(async {
+                match future::select(Box::pin(fut), Box::pin(trace_handle.backtrace())).await {
+                    Either::Left(_) => unreachable!("the task never completes"),
+                    Either::Right((trace, _)) => trace.unwrap(),
+                }
+            });
+            assert_eq!(leaf_count(&trace), 2);
+
+            let (_fut, trace_handle) = traced(pending());
+            let err = trace_handle.backtrace_blocking(Duration::from_millis(10));
+            assert_eq!(err.err(), Some(TraceError::Timeout));
+        });
+
+        let metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key.key().name().to_owned(), value))
+            .collect();
+        let value = |name: &str| {
+            metrics
+                .iter()
+                .find(|(metric, _)| metric == name)
+                .map(|(_, value)| value)
+                .unwrap()
+        };
+        assert_eq!(*value("tasktrace_polls_total"), DebugValue::Counter(2));
+        let DebugValue::Histogram(durations) = value("tasktrace_poll_duration_seconds") else {
+            panic!("poll durations are a histogram");
+        };
+        assert_eq!(durations.len(), 2);
+        let DebugValue::Histogram(leaves) = value("tasktrace_trace_leaves") else {
+            panic!("leaves are a histogram");
+        };
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0], 2.0);
+        assert_eq!(
+            *value("tasktrace_trace_timeouts_total"),
+            DebugValue::Counter(1)
+        );
+    }
+}
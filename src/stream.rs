@@ -0,0 +1,104 @@
+//! Tracing streams.
+//!
+//! Long-lived stream processors are usually driven by a `for_each` or a `while let` loop in some
+//! task, but the stream itself is what's worth tracing. [`traced_stream`] traces it directly,
+//! without wrapping it in a future that drives it.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+
+use crate::{capture_next, traced_with_state, SystemClock, TraceHandle, TracedTask};
+
+pin_project! {
+    /// A stream traced with [`traced_stream`].
+    pub struct TracedStream<S> {
+        #[pin]
+        task: TracedTask<S>,
+    }
+}
+
+/// Like [`traced`](crate::traced) but for a stream. Requests are answered during the next call
+/// to `poll_next` that returns `Poll::Pending`, with a trace of what the stream is waiting on.
+///
+/// ```rust
+/// # async fn example() {
+/// use futures::StreamExt;
+///
+/// let (stream, trace_handle) = tasktrace::traced_stream(futures::stream::pending::<()>());
+/// tokio::spawn(stream.for_each(|_| async {}));
+/// # let _ = trace_handle;
+/// # }
+/// ```
+///
+/// A stream that yields an item wasn't waiting on anything, so requests made while items are
+/// ready wait for the stream to run dry. Yielding an item doesn't end the task, it ends once the
+/// stream does.
+pub fn traced_stream<S: Stream>(stream: S) -> (TracedStream<S>, TraceHandle) {
+    let (task, handle) = traced_with_state(stream, None, false, Arc::new(SystemClock));
+    (TracedStream { task }, handle)
+}
+
+impl<S> TracedStream<S> {
+    /// Returns another handle to the stream, like the one returned along with it.
+    pub fn handle(&self) -> TraceHandle {
+        self.task.handle()
+    }
+
+    /// Returns the wrapped stream, which is no longer traced, see [`TracedTask::into_inner`].
+    pub fn into_inner(self) -> S {
+        self.task.into_inner()
+    }
+}
+
+impl<S: Stream> Stream for TracedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        self.project().task.poll_with(
+            cx,
+            (),
+            |stream, cx, ()| stream.poll_next(cx),
+            |stream, waker, limits, ()| capture_next(stream, waker, limits),
+            Option::is_none,
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.task.fut.size_hint()
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::TraceError;
+
+    #[tokio::test]
+    async fn items() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let (stream, trace_handle) = traced_stream(rx.map(|n: u32| n * 2));
+        let collect = tokio::spawn(stream.collect::<Vec<_>>());
+
+        tx.unbounded_send(1).unwrap();
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.starts_with("╼ <futures_util::stream::stream::map::Map<St,F>"));
+        assert!(trace.contains("futures_channel::mpsc::UnboundedReceiver<T>"));
+        // Yielding items doesn't end the stream
+        tx.unbounded_send(2).unwrap();
+        assert!(trace_handle.backtrace().await.is_ok());
+
+        drop(tx);
+        assert_eq!(collect.await.unwrap(), [2, 4]);
+        assert!(trace_handle.is_finished());
+        assert!(matches!(
+            trace_handle.backtrace().await,
+            Err(TraceError::TaskFinished)
+        ));
+    }
+}
@@ -0,0 +1,217 @@
+//! Combining the traces with tokio's task dumps.
+//!
+//! tokio can dump the tasks of a runtime itself through the unstable `Handle::dump`, tracing every
+//! idle task by polling it in a special mode. Its traces describe tokio tasks by id, the traces of
+//! this crate describe traced tasks by name, and an operator looking at both has to match them up.
+//! [`dump`] does that: every task of tokio's dump comes with the traces of the registered tasks it
+//! runs, matched by their [`TraceHandle::tokio_id`].
+//!
+//! Requires the `tokio-unstable` feature and building with `RUSTFLAGS="--cfg tokio_unstable"`,
+//! like tokio's dumps themselves, on the targets tokio supports dumps on. The feature alone doesn't
+//! enable anything, so that `--all-features` builds don't need the flag.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use crate::registry::{self, traced_registered, DEFAULT_TIMEOUT};
+use crate::{Trace, TraceError, TraceHandle};
+
+/// Spawns `fut` on the current tokio runtime through `tokio::task::Builder` under `name`, which
+/// tokio's dumps and `tokio-console` show, and registers it in the [registry](crate::registry)
+/// under the same name.
+///
+/// ```rust
+/// # async fn serve() {}
+/// # fn example() -> std::io::Result<()> {
+/// let (join_handle, trace_handle) = tasktrace::taskdump::spawn_named("http server", serve())?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Fails like `tokio::task::Builder::spawn`.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, like `tokio::spawn`.
+pub fn spawn_named<F>(
+    name: &str,
+    fut: F,
+) -> io::Result<(tokio::task::JoinHandle<F::Output>, TraceHandle)>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, handle) = traced_registered(fut, name);
+    let join_handle = tokio::task::Builder::new().name(name).spawn(task)?;
+    let _ = handle.state.tokio_id.set(join_handle.id());
+    Ok((join_handle, handle))
+}
+
+/// Dumps the tasks of `runtime` through `Handle::dump` and attaches the traces of the registered
+/// tasks to the tokio tasks running them. Waits up to [`DEFAULT_TIMEOUT`] for the registered
+/// tasks to answer.
+///
+/// ```rust,no_run
+/// # async fn example() {
+/// let dump = tasktrace::taskdump::dump(&tokio::runtime::Handle::current()).await;
+/// println!("{dump}");
+/// # }
+/// ```
+pub async fn dump(runtime: &tokio::runtime::Handle) -> CombinedDump {
+    dump_with_timeout(runtime, DEFAULT_TIMEOUT).await
+}
+
+/// Like [`dump`] but waits up to `timeout` for the registered tasks to answer. Tasks that don't
+/// answer in time get [`TraceError::Timeout`] as their trace.
+///
+/// tokio's dump is taken first and the traces of the registered tasks afterwards, since tokio
+/// traces a task by polling it, so the two describe consecutive polls of a task.
+pub async fn dump_with_timeout(
+    runtime: &tokio::runtime::Handle,
+    timeout: Duration,
+) -> CombinedDump {
+    let dump = runtime.dump().await;
+    let mut tasks: Vec<_> = dump
+        .tasks()
+        .iter()
+        .map(|task| CombinedTask {
+            id: task.id(),
+            tokio_trace: task.trace().to_string(),
+            traces: Vec::new(),
+        })
+        .collect();
+
+    let handles: Vec<_> = registry::tasks()
+        .into_iter()
+        .filter(|(_, handle)| {
+            let id = handle.tokio_id();
+            tasks.iter().any(|task| Some(task.id) == id)
+        })
+        .collect();
+    let ids: Vec<_> = handles
+        .iter()
+        .map(|(_, handle)| handle.tokio_id())
+        .collect();
+    let traces = registry::dump_handles(handles, timeout, usize::MAX).await;
+    for (id, trace) in ids.into_iter().zip(traces) {
+        if let Some(task) = tasks.iter_mut().find(|task| Some(task.id) == id) {
+            task.traces.push(trace);
+        }
+    }
+    CombinedDump { tasks }
+}
+
+/// The dump returned by [`dump`].
+pub struct CombinedDump {
+    tasks: Vec<CombinedTask>,
+}
+
+impl CombinedDump {
+    /// The tasks of tokio's dump, in its order.
+    pub fn tasks(&self) -> &[CombinedTask] {
+        &self.tasks
+    }
+}
+
+/// Renders every task as tokio's trace of it followed by the traces of the registered tasks it
+/// runs, e.g.
+///
+/// ```text
+/// TASK 12:
+/// tokio trace:
+/// ╼ server::serve::{{closure}} at src/server.rs:18:20
+///   └╼ tokio::sync::notify::Notified::poll_notified at tokio/src/sync/notify.rs:996:32
+/// trace of "http server":
+/// ╼ server::serve::{{closure}} at src/server.rs:18:20
+///   └╼ <tokio::sync::notify::Notified as core::future::future::Future>::poll at tokio/src/sync/notify.rs:1103:9
+/// ```
+impl fmt::Display for CombinedDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, task) in self.tasks.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "TASK {}:", task.id)?;
+            writeln!(f, "tokio trace:\n{}", task.tokio_trace.trim_end())?;
+            for (name, trace) in &task.traces {
+                match trace {
+                    Ok(trace) => writeln!(f, "trace of {name:?}:\n{trace}")?,
+                    Err(err) => writeln!(f, "trace of {name:?}: {err}")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A task of a [`CombinedDump`].
+pub struct CombinedTask {
+    id: tokio::task::Id,
+    tokio_trace: String,
+    traces: Vec<(String, Result<Trace, TraceError>)>,
+}
+
+impl CombinedTask {
+    /// The id of the tokio task.
+    pub fn id(&self) -> tokio::task::Id {
+        self.id
+    }
+
+    /// tokio's trace of the task, rendered.
+    pub fn tokio_trace(&self) -> &str {
+        &self.tokio_trace
+    }
+
+    /// The names and traces of the registered tasks that the tokio task runs, sorted by name.
+    /// Usually there is one, none for tasks that aren't traced.
+    pub fn traces(&self) -> &[(String, Result<Trace, TraceError>)] {
+        &self.traces
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn combined() {
+        // tokio only traces the futures that end in one of its own resources
+        let sleep = async { tokio::time::sleep(Duration::from_secs(60)).await };
+        let (join_handle, trace_handle) = spawn_named("taskdump worker", sleep).unwrap();
+        // Registered tasks spawned by other means are matched as well
+        let (fut, _) = traced_registered(std::future::pending::<()>(), "taskdump idler");
+        let idler = tokio::spawn(fut);
+        tokio::task::yield_now().await;
+        assert_eq!(trace_handle.tokio_id(), Some(join_handle.id()));
+
+        let dump = dump(&tokio::runtime::Handle::current()).await;
+        let task = dump
+            .tasks()
+            .iter()
+            .find(|task| task.id() == join_handle.id())
+            .unwrap();
+        assert!(task
+            .tokio_trace()
+            .contains("tasktrace::taskdump::tests::combined::{{closure}}"));
+        let [(name, trace)] = task.traces() else {
+            panic!("expected one trace, got {}", task.traces().len());
+        };
+        assert_eq!(name, "taskdump worker");
+        assert!(trace
+            .as_ref()
+            .unwrap()
+            .to_string()
+            .starts_with("╼ tasktrace::taskdump::tests::combined::{{closure}}"));
+        let idler = dump
+            .tasks()
+            .iter()
+            .find(|task| task.id() == idler.id())
+            .unwrap();
+        assert_eq!(idler.traces()[0].0, "taskdump idler");
+        assert!(dump
+            .to_string()
+            .contains("trace of \"taskdump worker\":\n╼ "));
+    }
+}
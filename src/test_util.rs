@@ -0,0 +1,284 @@
+//! Helpers for asserting on traces in tests.
+//!
+//! Rendered traces contain absolute paths, line and column numbers that change between machines,
+//! toolchains and unrelated edits. [`normalize`] reduces a trace to its shape so that it can be
+//! compared against a golden file, e.g. with `insta`. [`assert_trace_matches!`] asserts on the
+//! symbols of a branch of a trace instead, and [`TestExecutor`] polls a future by hand so that
+//! there is no runtime deciding when it gets traced. [`MockClock`] stands still until the test
+//! advances it, for asserting on the time based features.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+
+use crate::time::Instant;
+use crate::{capture_once, Clock, Frame, Trace};
+
+/// Renders `trace` keeping only the tree structure and the symbol of every frame.
+pub fn normalize(trace: &Trace) -> String {
+    let mut normalized = String::new();
+    for line in trace.to_string().lines() {
+        let symbol = match line.split_once(" at ") {
+            Some((symbol, _location)) => symbol,
+            None => line,
+        };
+        normalized.push_str(symbol);
+        normalized.push('\n');
+    }
+    normalized
+}
+
+/// Whether a branch of `trace`, from a root towards a leaf, has frames whose symbols contain the
+/// `patterns` in order. Frames in between are skipped, so that the internal frames of executors
+/// and combinators needn't be spelled out.
+pub fn matches(trace: &Trace, patterns: &[&str]) -> bool {
+    trace
+        .roots()
+        .iter()
+        .any(|root| branch_matches(root, patterns))
+}
+
+fn branch_matches(frame: &Frame, patterns: &[&str]) -> bool {
+    let rest = match patterns.split_first() {
+        None => return true,
+        Some((pattern, rest))
+            if frame
+                .symbol()
+                .is_some_and(|symbol| symbol.contains(pattern)) =>
+        {
+            rest
+        }
+        Some(_) => patterns,
+    };
+    rest.is_empty()
+        || frame
+            .children()
+            .iter()
+            .any(|child| branch_matches(child, rest))
+}
+
+/// Asserts that a branch of a trace has frames whose symbols contain the given patterns, from the
+/// root towards the leaf, see [`matches`]. Panics with the [normalized](normalize) trace
+/// otherwise.
+///
+/// ```rust
+/// use tasktrace::assert_trace_matches;
+/// use tasktrace::test_util::TestExecutor;
+///
+/// use futures::channel::oneshot;
+///
+/// async fn serve(rx: oneshot::Receiver<()>) {
+///     accept(rx).await
+/// }
+///
+/// async fn accept(rx: oneshot::Receiver<()>) {
+///     let _ = rx.await;
+/// }
+///
+/// let (_tx, rx) = oneshot::channel();
+/// let mut executor = TestExecutor::new(serve(rx));
+/// let (poll, trace) = executor.poll_traced();
+/// assert!(poll.is_pending());
+/// assert_trace_matches!(trace, ["serve", "accept", "Receiver"]);
+/// ```
+#[macro_export]
+macro_rules! assert_trace_matches {
+    ($trace:expr, [$($pattern:expr),* $(,)?] $(,)?) => {{
+        let trace: &$crate::Trace = &$trace;
+        let patterns: &[&str] = &[$($pattern),*];
+        if !$crate::test_util::matches(trace, patterns) {
+            panic!(
+                "no branch of the trace matches {:?}:\n{}",
+                patterns,
+                $crate::test_util::normalize(trace),
+            );
+        }
+    }};
+}
+
+/// Polls a future by hand, on the current thread, for tests that trace it at polls of their
+/// choosing.
+pub struct TestExecutor<F: Future> {
+    fut: Pin<Box<F>>,
+    woken: Arc<Woken>,
+    waker: Waker,
+    done: bool,
+}
+
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl<F: Future> TestExecutor<F> {
+    /// Creates an executor for `fut`, which isn't polled until [`TestExecutor::poll_once`] or
+    /// [`TestExecutor::poll_traced`] is called.
+    pub fn new(fut: F) -> Self {
+        let woken = Arc::new(Woken(AtomicBool::new(false)));
+        TestExecutor {
+            fut: Box::pin(fut),
+            waker: Waker::from(Arc::clone(&woken)),
+            woken,
+            done: false,
+        }
+    }
+
+    /// Polls the future once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the future has already completed.
+    pub fn poll_once(&mut self) -> Poll<F::Output> {
+        self.start_poll();
+        let poll = self
+            .fut
+            .as_mut()
+            .poll(&mut Context::from_waker(&self.waker));
+        self.done = poll.is_ready();
+        poll
+    }
+
+    /// Polls the future once, capturing a trace of the poll like [`capture_once`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the future has already completed.
+    pub fn poll_traced(&mut self) -> (Poll<F::Output>, Trace) {
+        self.start_poll();
+        let (poll, trace) = capture_once(self.fut.as_mut(), &self.waker);
+        self.done = poll.is_ready();
+        (poll, trace)
+    }
+
+    /// Whether the future was woken since it was last polled.
+    pub fn is_woken(&self) -> bool {
+        self.woken.0.load(Ordering::SeqCst)
+    }
+
+    fn start_poll(&mut self) {
+        assert!(!self.done, "polled a TestExecutor whose future completed");
+        self.woken.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A [`Clock`] that only moves when the test advances it.
+///
+/// Clones share their time, so a test keeps one clone to advance the clock of the tasks and
+/// watchdogs it gave the others to.
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<Instant>>);
+
+impl MockClock {
+    /// Creates a clock that stands at the current time of the platform.
+    pub fn new() -> Self {
+        MockClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Registers the waker and never completes, like a leaf future waiting on something that never
+/// happens. Shared by the tests of every module.
+#[cfg(test)]
+pub(crate) async fn pending() {
+    let mut waker = None;
+    std::future::poll_fn(|cx| {
+        waker = Some(cx.waker().clone());
+        std::task::Poll::Pending
+    })
+    .await
+}
+
+/// A frame of `symbol` at `line` and `col` of `src/app.rs` that calls `children`, for tests of
+/// traces that differ in their locations only.
+#[cfg(all(test, feature = "enabled"))]
+pub(crate) fn frame_at(symbol: &str, line: u32, col: u32, children: Vec<Frame>) -> Frame {
+    let branches = children.iter().map(Frame::branches).sum::<usize>().max(1);
+    Frame::placeholder(String::new(), branches, children).with_parts(
+        Some(symbol.to_owned()),
+        Some("src/app.rs".into()),
+        Some(line),
+        Some(col),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "enabled")]
+    #[tokio::test]
+    async fn normalize_strips_locations() {
+        let (fut, trace_handle) = crate::traced(pending());
+        tokio::spawn(fut);
+        let trace = trace_handle.backtrace().await.unwrap();
+
+        let expected = "\
+╼ tasktrace::test_util::pending::{{closure}}
+  └╼ <core::future::poll_fn::PollFn<F> as core::future::future::Future>::poll
+     └╼ tasktrace::test_util::pending::{{closure}}::{{closure}}
+        └╼ <core::task::wake::Waker as core::clone::Clone>::clone
+";
+        assert_eq!(normalize(&trace), expected);
+    }
+
+    #[test]
+    fn executor() {
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let mut executor = TestExecutor::new(wait(rx));
+        let (poll, trace) = executor.poll_traced();
+        assert!(poll.is_pending());
+        crate::assert_trace_matches!(trace, ["wait", "Receiver", "clone"]);
+        assert!(!executor.is_woken());
+
+        tx.send(()).unwrap();
+        assert!(executor.is_woken());
+        assert!(executor.poll_once().is_ready());
+    }
+
+    #[test]
+    fn matches_branches() {
+        let mut executor = TestExecutor::new(async {
+            crate::join!("left" => pending(), "right" => pending());
+        });
+        let (_, trace) = executor.poll_traced();
+        assert!(matches(&trace, &["left", "pending::{{closure}}"]));
+        assert!(matches(&trace, &["right"]));
+        assert!(matches(&trace, &[]));
+        // The patterns have to be in order, along the same branch
+        assert!(!matches(&trace, &["pending::{{closure}}", "left"]));
+        assert!(!matches(&trace, &["left", "right"]));
+    }
+
+    #[test]
+    #[should_panic(expected = "no branch of the trace matches [\"missing\"]")]
+    fn assert_mismatch() {
+        let (_, trace) = TestExecutor::new(pending()).poll_traced();
+        crate::assert_trace_matches!(trace, ["missing"]);
+    }
+
+    async fn wait(rx: futures_channel::oneshot::Receiver<()>) {
+        rx.await.unwrap()
+    }
+}
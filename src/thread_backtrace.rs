@@ -0,0 +1,287 @@
+//! Falling back to the backtrace of the thread when a task doesn't answer.
+//!
+//! A task that runs blocking code holds on to the executor thread that polls it, and neither it
+//! nor the tasks queued behind it on that thread get to answer their trace requests. Every poll
+//! records the thread it runs on, so that [`TraceHandle::backtrace_with_thread_fallback`] can walk
+//! the stack of that thread instead once the task fails to answer in time. The walk runs in a
+//! `SIGURG` handler on the thread itself, since a thread's stack can only be walked from within.
+//!
+//! The handler walks the stack through the unwinder, which isn't async signal safe in theory but
+//! is what sampling profilers do in practice. Applications that handle `SIGURG` themselves
+//! shouldn't enable the `thread-backtrace` feature.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+use crate::time::Instant;
+use crate::{Trace, TraceError, TraceHandle};
+
+/// The most frames recorded of a stack, the innermost ones.
+const MAX_FRAMES: usize = 256;
+/// How long the handler gets to walk the stack before the capture is given up.
+const HANDLER_TIMEOUT: Duration = Duration::from_millis(100);
+
+// The capture goes from idle to requested when the signal is sent, to running once the handler
+// picks it up, and to done once it has walked the stack
+const IDLE: u8 = 0;
+const REQUESTED: u8 = 1;
+const RUNNING: u8 = 2;
+const DONE: u8 = 3;
+
+/// Serializes the captures, which share the statics below.
+static CAPTURE: Mutex<()> = Mutex::new(());
+static STATE: AtomicU8 = AtomicU8::new(IDLE);
+/// The instruction pointers of the walked stack, innermost first.
+static IPS: [AtomicUsize; MAX_FRAMES] = [const { AtomicUsize::new(0) }; MAX_FRAMES];
+static LEN: AtomicUsize = AtomicUsize::new(0);
+static INSTALL: Once = Once::new();
+
+thread_local! {
+    static CURRENT_THREAD: Local = Local(Arc::new(Marker {
+        // SAFETY: pthread_self has no preconditions.
+        pthread: unsafe { libc::pthread_self() },
+        name: thread::current().name().map(str::to_owned),
+        alive: Mutex::new(true),
+    }));
+}
+
+/// A thread that polled a traced task.
+pub(crate) struct Marker {
+    pthread: libc::pthread_t,
+    name: Option<String>,
+    /// Cleared when the thread exits, after which it mustn't be signalled. Held while it is.
+    alive: Mutex<bool>,
+}
+
+struct Local(Arc<Marker>);
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        *self.0.alive.lock().unwrap() = false;
+    }
+}
+
+/// The thread that last polled a task, and whether it still is.
+pub(crate) struct PolledBy {
+    thread: Mutex<Option<Arc<Marker>>>,
+    /// The marker in `thread`, so that polls on the same thread as the last one skip the lock.
+    last: AtomicPtr<Marker>,
+    polling: AtomicBool,
+}
+
+impl PolledBy {
+    pub(crate) fn new() -> Self {
+        PolledBy {
+            thread: Mutex::new(None),
+            last: AtomicPtr::new(ptr::null_mut()),
+            polling: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Records that the current thread polls the task until the returned guard is dropped.
+pub(crate) fn enter(polled_by: &PolledBy) -> Polling<'_> {
+    // The thread local is gone while the thread exits
+    let _ = CURRENT_THREAD.try_with(|local| {
+        let marker = Arc::as_ptr(&local.0).cast_mut();
+        if polled_by.last.load(Ordering::Relaxed) != marker {
+            *polled_by.thread.lock().unwrap() = Some(Arc::clone(&local.0));
+            polled_by.last.store(marker, Ordering::Relaxed);
+        }
+    });
+    polled_by.polling.store(true, Ordering::Relaxed);
+    Polling(polled_by)
+}
+
+pub(crate) struct Polling<'a>(&'a PolledBy);
+
+impl Drop for Polling<'_> {
+    fn drop(&mut self) {
+        self.0.polling.store(false, Ordering::Relaxed);
+    }
+}
+
+impl TraceHandle {
+    /// Like [`TraceHandle::backtrace`] but, if the task doesn't answer within `grace`, returns
+    /// the backtrace of the thread that last polled it instead. Requires the `thread-backtrace`
+    /// feature, Linux, and must be called from within a tokio runtime.
+    ///
+    /// A task that doesn't answer is usually one whose poll, or the poll of another task on the
+    /// same executor thread, runs blocking code, which the logical traces can't show. The thread's
+    /// backtrace shows where it blocks. It goes under a root frame named
+    /// `[thread backtrace of "<thread name>", ...]` that tells whether the task is still being
+    /// polled and for how long, and spans the whole stack of the thread, the executor included.
+    ///
+    /// ```rust,no_run
+    /// # async fn example(trace_handle: tasktrace::TraceHandle) {
+    /// let grace = std::time::Duration::from_secs(1);
+    /// match trace_handle.backtrace_with_thread_fallback(grace).await {
+    ///     Ok(trace) => println!("{trace}"),
+    ///     Err(err) => println!("no trace: {err}"),
+    /// }
+    /// # }
+    /// ```
+    ///
+    /// Fails with [`TraceError::Timeout`] if the task doesn't answer in time and the thread can't
+    /// be walked either, e.g. because the task was never polled or the thread has exited.
+    pub async fn backtrace_with_thread_fallback(
+        &self,
+        grace: Duration,
+    ) -> Result<Trace, TraceError> {
+        match self.backtrace_timeout(grace).await {
+            Err(TraceError::Timeout) => self.thread_backtrace().ok_or(TraceError::Timeout),
+            result => result,
+        }
+    }
+
+    fn thread_backtrace(&self) -> Option<Trace> {
+        let polled_by = &self.state.polled_by;
+        let marker = polled_by.thread.lock().unwrap().clone()?;
+        let polling = polled_by.polling.load(Ordering::Relaxed);
+        let since = self.state.since(self.last_polled_at()?);
+        let ips = walk(&marker)?;
+        let name = marker.name.as_deref().unwrap_or("<unnamed>");
+        let label = if polling {
+            format!("[thread backtrace of {name:?}, in a poll of the task for {since:.1?}]")
+        } else {
+            format!("[thread backtrace of {name:?}, which last polled the task {since:.1?} ago]")
+        };
+        let trace = Trace::from_thread_backtrace(label, &ips);
+        Some(match self.name() {
+            Some(name) => trace.named(name),
+            None => trace,
+        })
+    }
+}
+
+/// Walks the stack of the thread of `marker` from a signal handler, returning its instruction
+/// pointers innermost first, or `None` if the thread can't be signalled or doesn't answer.
+fn walk(marker: &Marker) -> Option<Vec<usize>> {
+    let _capture = CAPTURE.lock().unwrap_or_else(|err| err.into_inner());
+    INSTALL.call_once(install);
+    {
+        let alive = marker.alive.lock().unwrap();
+        // SAFETY: The thread hasn't exited, which it can't while `alive` is locked.
+        if !*alive || unsafe { libc::pthread_equal(marker.pthread, libc::pthread_self()) } != 0 {
+            return None;
+        }
+        STATE.store(REQUESTED, Ordering::Release);
+        // SAFETY: As above.
+        if unsafe { libc::pthread_kill(marker.pthread, libc::SIGURG) } != 0 {
+            STATE.store(IDLE, Ordering::Relaxed);
+            return None;
+        }
+    }
+    let deadline = Instant::now() + HANDLER_TIMEOUT;
+    while STATE.load(Ordering::Acquire) != DONE {
+        // Signals may be blocked or take a while to be delivered. A handler that picked the
+        // capture up gets to finish it though, since it is about to write to the statics
+        if Instant::now() >= deadline
+            && STATE
+                .compare_exchange(REQUESTED, IDLE, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            return None;
+        }
+        thread::sleep(Duration::from_micros(50));
+    }
+    let len = LEN.load(Ordering::Relaxed);
+    let ips = IPS[..len]
+        .iter()
+        .map(|ip| ip.load(Ordering::Relaxed))
+        .collect();
+    STATE.store(IDLE, Ordering::Relaxed);
+    Some(ips)
+}
+
+fn install() {
+    // SAFETY: The handler only touches atomics and walks the stack.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Blocking calls that the signal interrupts carry on
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGURG, &action, ptr::null_mut());
+    }
+}
+
+extern "C" fn handler(_: libc::c_int) {
+    // Signals of captures that were given up arrive late, or not at all
+    if STATE
+        .compare_exchange(REQUESTED, RUNNING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+    // SAFETY: errno is thread local.
+    let errno = unsafe { *libc::__errno_location() };
+    let mut len = 0;
+    let mut above_handler = false;
+    // SAFETY: Captures are serialized, so no other walk runs concurrently.
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            if !above_handler {
+                above_handler = ptr::eq(frame.symbol_address(), handler as *const c_void);
+                return true;
+            }
+            IPS[len].store(frame.ip() as usize, Ordering::Relaxed);
+            len += 1;
+            len < MAX_FRAMES
+        });
+    }
+    LEN.store(len, Ordering::Relaxed);
+    STATE.store(DONE, Ordering::Release);
+    // SAFETY: As above.
+    unsafe { *libc::__errno_location() = errno };
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use crate::traced;
+
+    use super::*;
+    use crate::test_util::pending;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn blocked_thread() {
+        let (fut, trace_handle) = traced(async {
+            tokio::task::yield_now().await;
+            block();
+        });
+        tokio::spawn(fut);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let grace = Duration::from_millis(50);
+        let trace = trace_handle
+            .backtrace_with_thread_fallback(grace)
+            .await
+            .unwrap();
+        let [root] = trace.roots() else {
+            panic!("expected one root, got {}", trace.roots().len());
+        };
+        let label = root.symbol().unwrap();
+        assert!(label.starts_with("[thread backtrace of \"tokio-"));
+        assert!(label.contains(", in a poll of the task for "));
+        let rendered = trace.to_string();
+        assert!(rendered.contains("tasktrace::thread_backtrace::tests::block"));
+
+        // Tasks that answer in time are traced as usual
+        let (fut, trace_handle) = traced(pending());
+        tokio::spawn(fut);
+        let trace = trace_handle
+            .backtrace_with_thread_fallback(grace)
+            .await
+            .unwrap();
+        assert!(!trace.to_string().contains("[thread backtrace"));
+    }
+
+    #[inline(never)]
+    fn block() {
+        thread::sleep(Duration::from_millis(500));
+    }
+}
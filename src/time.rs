@@ -0,0 +1,36 @@
+//! The clocks of the platform.
+//!
+//! The ones of `std` panic on wasm32-unknown-unknown, where the ones of `web-time` read the clocks
+//! of the browser instead. Everywhere else they are the same types.
+//!
+//! The time based features of a traced task read the time through a [`Clock`], so that tests can
+//! replace the clock of the platform by one they advance by hand.
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the time based features of traced tasks read the current time from, see
+/// [`traced_with_clock`](crate::traced_with_clock) and
+/// [`Watchdog::with_clock`](crate::Watchdog::with_clock).
+///
+/// The clock of a task times its polls, and with them its statistics and busy ratio, as well as
+/// [`TraceHandle::last_polled_at`](crate::TraceHandle::last_polled_at), the minimum interval
+/// between its captures and the intervals of its sampled watches. The durations measured within a
+/// single capture, like [`Trace::poll_duration`](crate::Trace::poll_duration), always come from
+/// [`SystemClock`].
+pub trait Clock: Send + Sync + 'static {
+    /// The current time. Must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the platform, which every task uses unless given another one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,1914 @@
+//! Capturing and rendering of traces.
+//!
+//! A trace is captured by running a closure through [`Trace::root`]. Every call to [`Trace::leaf`]
+//! made while the closure runs records the backtrace between the two calls. Nested captures each
+//! record the backtrace up to their own root. The backtraces are only symbolized when the trace is
+//! first inspected, at which point they are knitted together into a tree of [`Frame`]s that shares
+//! their common prefixes.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fmt::{self, Write as _};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+use std::ptr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+use backtrace::{BacktraceFrame, BacktraceSymbol};
+
+use crate::aggregate::Precision;
+use crate::backend::{Backend, CaptureBackend, ResolvedFrame, StackBounds};
+use crate::time::Instant;
+use crate::CaptureLimits;
+
+thread_local! {
+    /// The traces being captured on this thread, the innermost one last. There is more than one
+    /// when a traced task polls another traced task that is being traced as well.
+    static CAPTURES: RefCell<Vec<Capture>> = const { RefCell::new(Vec::new()) };
+    /// What the innermost capture interrupted by a panic recorded, see [`Trace::take_panicked`].
+    static PANICKED: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+/// An in progress capture started by [`Trace::root`].
+struct Capture {
+    /// The address of the [`Trace::root_inner`] instance that started the capture. Unwinding
+    /// stops when it reaches this frame.
+    root_addr: *const c_void,
+    backtraces: Vec<Leaf>,
+    /// When the previous leaf was recorded, or the capture started if there was none yet.
+    last: Instant,
+    /// The time spent recording the leaves, which doesn't count towards the poll.
+    overhead: Duration,
+    limits: CaptureLimits,
+    /// The number of leaves that weren't recorded because of [`CaptureLimits::max_leaves`].
+    omitted_leaves: usize,
+    /// The number of leaves whose walk ended before reaching the root, see [`Trace::is_truncated`].
+    truncated_leaves: usize,
+    /// The number of [`labelled`](crate::labelled) futures that were being polled when the
+    /// capture started, whose frames are above its root.
+    label_depth: usize,
+}
+
+impl Capture {
+    fn is_full(&self) -> bool {
+        self.limits
+            .max_leaves
+            .is_some_and(|max_leaves| self.backtraces.len() >= max_leaves)
+    }
+}
+
+/// The backtrace recorded by a call to [`Trace::leaf`].
+#[derive(Clone)]
+struct Leaf {
+    /// The frames ordered from the leaf up to the root.
+    frames: LeafFrames,
+    /// The name of the `tracing` span that was entered when the leaf was recorded.
+    span: Option<&'static str>,
+    /// The resource that the leaf waits on, see [`waiting_on`](crate::waiting_on).
+    resource: Option<&'static str>,
+    /// The labels of the [`labelled`](crate::labelled) futures between the root and the leaf,
+    /// outermost first.
+    labels: Vec<crate::label::Label>,
+    /// The time the poll spent since the previous leaf was recorded.
+    elapsed: Duration,
+    /// The number of innermost frames that weren't recorded because of
+    /// [`CaptureLimits::max_frames`].
+    omitted_frames: usize,
+}
+
+/// The frames of a [`Leaf`].
+#[derive(Clone)]
+enum LeafFrames {
+    Walked(Vec<StackFrame>),
+    /// The instruction pointers recorded by the [`CaptureBackend`] of the task, which symbolizes
+    /// them as well.
+    Custom(Backend, Vec<usize>),
+}
+
+/// The logical stack trace of a task, captured during one of its polls.
+///
+/// Every leaf future that registered the task's waker during the poll contributes one branch.
+/// Branches share the frames they have in common, so a `join!` shows up as a frame with one child
+/// per joined future. The trace renders as a tree through its `Display` implementation and can be
+/// inspected programmatically through [`Trace::roots`].
+///
+/// Capturing a trace only records the instruction pointers of every branch. They are symbolized
+/// when the trace is first inspected, or ahead of time through [`Trace::resolve`], so that traces
+/// can be sampled from the poll path cheaply and symbolized elsewhere, e.g. on a thread of their
+/// own.
+///
+/// Cloning a trace is cheap, the clones share the captured branches and their symbolization, so
+/// that the one capture a burst of requests gets answered with is symbolized only once.
+#[derive(Clone)]
+pub struct Trace {
+    /// The unresolved backtraces, one per leaf.
+    backtraces: Arc<[Leaf]>,
+    /// The number of frames to keep from every branch, see [`Trace::truncated`].
+    max_depth: Option<usize>,
+    /// Whether to keep the frames of this crate that wrap every trace, see
+    /// [`Trace::with_internal_frames`].
+    internal_frames: bool,
+    /// Whether to leave out the frames of inlined functions, see [`Trace::without_inlined`].
+    without_inlined: bool,
+    /// Whether to show the symbols as found in the binary, see [`Trace::mangled`].
+    mangled: bool,
+    /// How long the symbolization may take, see [`Trace::with_budget`].
+    budget: Option<Duration>,
+    /// The name of the traced task, see [`Trace::name`].
+    name: Option<String>,
+    /// How long the captured poll took, see [`Trace::poll_duration`].
+    poll_duration: Option<Duration>,
+    /// The busy ratio of the task as of the capture, see [`Trace::busy_ratio`].
+    busy_ratio: Option<f64>,
+    /// The number of leaves that weren't recorded because of [`CaptureLimits::max_leaves`].
+    omitted_leaves: usize,
+    /// The number of leaves whose walk ended before reaching the root, see [`Trace::is_truncated`].
+    truncated_leaves: usize,
+    /// The symbolized tree, built when the trace or one of its clones is first inspected.
+    roots: Arc<OnceLock<Vec<Frame>>>,
+}
+
+impl Trace {
+    /// Runs `f`, capturing a backtrace on every call to [`Trace::leaf`] it makes. Captures can be
+    /// nested, in which case every leaf is recorded by all of them.
+    /// The capture records no more than `limits` allow.
+    pub(crate) fn root<F: FnOnce() -> R, R>(f: F, limits: &CaptureLimits) -> (R, Trace) {
+        Self::root_inner(f, limits)
+    }
+
+    // Never inlined so that it gets a distinct frame that bounds the captured backtraces.
+    #[inline(never)]
+    fn root_inner<F: FnOnce() -> R, R>(f: F, limits: &CaptureLimits) -> (R, Trace) {
+        /// Ends the capture if `f` panics, keeping what it recorded until then.
+        struct Pop;
+
+        impl Drop for Pop {
+            fn drop(&mut self) {
+                let capture = CAPTURES.with(|captures| captures.borrow_mut().pop().unwrap());
+                let mut trace = Trace::from_backtraces(capture.backtraces);
+                trace.omitted_leaves = capture.omitted_leaves;
+                trace.truncated_leaves = capture.truncated_leaves;
+                PANICKED.with(|panicked| *panicked.borrow_mut() = Some(trace));
+            }
+        }
+
+        let start = Instant::now();
+        let capture = Capture {
+            root_addr: Self::root_inner::<F, R> as *const c_void,
+            backtraces: Vec::new(),
+            last: start,
+            overhead: Duration::ZERO,
+            limits: limits.clone(),
+            omitted_leaves: 0,
+            truncated_leaves: 0,
+            label_depth: crate::label::depth(),
+        };
+        CAPTURES.with(|captures| captures.borrow_mut().push(capture));
+        let pop = Pop;
+        let result = f();
+        mem::forget(pop);
+        let elapsed = start.elapsed();
+        let capture = CAPTURES.with(|captures| captures.borrow_mut().pop().unwrap());
+        let mut trace = Trace::from_backtraces(capture.backtraces);
+        trace.poll_duration = Some(elapsed.saturating_sub(capture.overhead));
+        trace.omitted_leaves = capture.omitted_leaves;
+        trace.truncated_leaves = capture.truncated_leaves;
+        (result, trace)
+    }
+
+    /// Returns what the last capture on this thread that was interrupted by a panic recorded
+    /// before it, or an empty trace if there was none. Captures can't finish their trace in that
+    /// case, so they leave it here for whoever catches the panic.
+    pub(crate) fn take_panicked() -> Trace {
+        PANICKED
+            .with(|panicked| panicked.borrow_mut().take())
+            .unwrap_or_else(|| Trace::from_backtraces(Vec::new()))
+    }
+
+    /// Returns whether a trace is being captured on this thread.
+    pub(crate) fn is_capturing() -> bool {
+        CAPTURES.with(|captures| !captures.borrow().is_empty())
+    }
+
+    /// Records the backtrace up to every enclosing [`Trace::root`], if any.
+    // Never inlined so that it gets a distinct frame, below which frames belong to the capture
+    // itself and are left out.
+    #[inline(never)]
+    pub(crate) fn leaf() {
+        let now = Instant::now();
+        CAPTURES.with(|captures| {
+            let mut captures = captures.borrow_mut();
+            if captures.is_empty() {
+                return;
+            }
+            // Leaves past the limit of every capture aren't worth walking the stack for
+            if captures.iter().all(Capture::is_full) {
+                for capture in captures.iter_mut() {
+                    capture.omitted_leaves += 1;
+                }
+                return;
+            }
+            // Captures with backends of their own walk the stack themselves, but the leaf still
+            // has to be checked against the ones of the inner captures
+            let walked = if captures
+                .iter()
+                .any(|capture| capture.limits.backend.is_none())
+            {
+                let Some(walked) = walk_leaf(&captures) else {
+                    return;
+                };
+                Some(walked)
+            } else if captures.len() > 1 && is_nested_leaf(&captures) {
+                return;
+            } else {
+                None
+            };
+            let span = current_span();
+            let resource = crate::resource::current();
+            let labels = crate::label::current();
+            let recorded = Instant::now();
+            for (i, capture) in captures.iter_mut().rev().enumerate() {
+                capture.overhead += recorded - now;
+                if capture.is_full() {
+                    capture.omitted_leaves += 1;
+                    continue;
+                }
+                // The frames closest to the root are the ones kept, like TraceOptions::max_depth
+                let keep = |len: usize| {
+                    capture
+                        .limits
+                        .max_frames
+                        .map_or(0, |max_frames| len.saturating_sub(max_frames))
+                };
+                let (frames, start) = match (&capture.limits.backend, &walked) {
+                    (Some(backend), _) => {
+                        let started = Instant::now();
+                        let bounds = StackBounds {
+                            leaf: Trace::leaf as *const () as usize,
+                            root: capture.root_addr as usize,
+                        };
+                        let ips = backend.0.capture(&bounds);
+                        capture.overhead += started.elapsed();
+                        let start = keep(ips.len());
+                        (
+                            LeafFrames::Custom(backend.clone(), ips[start..].to_vec()),
+                            start,
+                        )
+                    }
+                    (None, Some((frames, ends))) => {
+                        // The unwinder gave up before reaching the root, e.g. at an FFI boundary,
+                        // so the branch lacks the frames closest to it
+                        let end = ends.get(i).copied().unwrap_or_else(|| {
+                            capture.truncated_leaves += 1;
+                            frames.len()
+                        });
+                        let start = keep(end);
+                        (LeafFrames::Walked(frames[start..end].to_vec()), start)
+                    }
+                    (None, None) => {
+                        unreachable!("the stack is walked for captures without a backend")
+                    }
+                };
+                capture.backtraces.push(Leaf {
+                    frames,
+                    span,
+                    resource,
+                    labels: labels
+                        .get(capture.label_depth..)
+                        .unwrap_or_default()
+                        .to_vec(),
+                    elapsed: now.saturating_duration_since(capture.last),
+                    omitted_frames: start,
+                });
+                capture.last = recorded;
+            }
+        });
+    }
+
+    /// Records the backtrace of the current thread, from its root down to the caller of this
+    /// function, as a trace with a single branch.
+    // Never inlined so that it gets a distinct frame, like `leaf`
+    #[inline(never)]
+    pub(crate) fn here() -> Trace {
+        let frames = walk_here();
+        Trace::from_backtraces(vec![Leaf {
+            frames: LeafFrames::Walked(frames),
+            span: current_span(),
+            resource: None,
+            labels: Vec::new(),
+            elapsed: Duration::ZERO,
+            omitted_frames: 0,
+        }])
+    }
+
+    fn from_backtraces(backtraces: Vec<Leaf>) -> Self {
+        Trace {
+            backtraces: backtraces.into(),
+            max_depth: None,
+            internal_frames: false,
+            without_inlined: false,
+            mangled: false,
+            budget: None,
+            name: None,
+            poll_duration: None,
+            busy_ratio: None,
+            omitted_leaves: 0,
+            truncated_leaves: 0,
+            roots: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Builds a trace with a single branch out of the instruction pointers of a thread's stack,
+    /// innermost first, under a root frame called `label`.
+    #[cfg(all(feature = "thread-backtrace", target_os = "linux"))]
+    pub(crate) fn from_thread_backtrace(label: String, ips: &[usize]) -> Self {
+        let mut frames = resolve_ips(&crate::BacktraceBackend, ips, None);
+        frames.insert(0, Frame::placeholder(label, 0, Vec::new()));
+        let mut child = Vec::new();
+        for mut frame in frames.into_iter().rev() {
+            frame.branches = 1;
+            frame.children = child;
+            child = vec![frame];
+        }
+        Trace::from_roots(child)
+    }
+
+    /// Builds a trace out of already symbolized frames.
+    pub(crate) fn from_roots(roots: Vec<Frame>) -> Self {
+        Trace {
+            backtraces: Arc::new([]),
+            max_depth: None,
+            internal_frames: false,
+            without_inlined: false,
+            mangled: false,
+            budget: None,
+            name: None,
+            poll_duration: None,
+            busy_ratio: None,
+            omitted_leaves: 0,
+            truncated_leaves: 0,
+            roots: Arc::new(OnceLock::from(roots)),
+        }
+    }
+
+    /// A trace of the same poll of the same task as this one that consists of the already
+    /// symbolized `roots`, e.g. a filtered copy of them.
+    pub(crate) fn with_roots(&self, roots: Vec<Frame>) -> Self {
+        let mut trace = Trace::from_roots(roots);
+        trace.name = self.name.clone();
+        trace.poll_duration = self.poll_duration;
+        trace.busy_ratio = self.busy_ratio;
+        trace.truncated_leaves = self.truncated_leaves;
+        trace
+    }
+
+    /// The outermost frames of the trace, usually the frames of the traced future itself. There are
+    /// none if no leaf was reached during the poll.
+    ///
+    /// The first call symbolizes the captured backtraces, which is comparatively expensive.
+    pub fn roots(&self) -> &[Frame] {
+        self.resolve();
+        self.roots.get().unwrap()
+    }
+
+    /// Symbolizes the captured backtraces now instead of when the trace is first inspected. Does
+    /// nothing if the trace is already symbolized.
+    pub fn resolve(&self) {
+        self.roots.get_or_init(|| {
+            let deadline = self.budget.map(|budget| Instant::now() + budget);
+            let mut roots = resolve(&self.backtraces, self.mangled, deadline);
+            if !self.internal_frames {
+                roots = strip_internal(roots);
+            }
+            if self.without_inlined {
+                roots = strip_inlined(roots);
+            }
+            // Only now, the internal frames and the labels are recognized by their demangled names
+            if self.mangled {
+                use_mangled(&mut roots);
+            }
+            if let Some(max_depth) = self.max_depth {
+                truncate(&mut roots, max_depth);
+            }
+            if self.omitted_leaves > 0 {
+                let label = format!("… ({} leaves omitted)", self.omitted_leaves);
+                roots.push(Frame::placeholder(label, self.omitted_leaves, Vec::new()));
+            }
+            if self.truncated_leaves > 0 {
+                let count = self.truncated_leaves;
+                let branches = if count == 1 { "branch" } else { "branches" };
+                let label = format!("⚠ ({count} {branches} cut short by the unwinder)");
+                roots.push(Frame::placeholder(label, 0, Vec::new()));
+            }
+            roots
+        });
+    }
+
+    /// Returns whether the trace was already symbolized, see [`Trace::resolve`].
+    pub fn is_resolved(&self) -> bool {
+        self.roots.get().is_some()
+    }
+
+    /// Returns whether the symbolization of the trace ran out of its budget, see
+    /// [`TraceOptions::symbolization_budget`](crate::TraceOptions::symbolization_budget), leaving
+    /// the frames it didn't get to as bare addresses. Symbolizes the trace if that didn't happen
+    /// yet.
+    pub fn is_partially_symbolized(&self) -> bool {
+        self.budget.is_some() && has_unsymbolized(self.roots())
+    }
+
+    /// A copy of the trace that is symbolized in full, without a budget, for when there is time
+    /// to symbolize the frames that [`Trace::is_partially_symbolized`] left as bare addresses.
+    /// Returns a clone if there are none.
+    pub fn fully_symbolized(&self) -> Trace {
+        let mut trace = self.clone();
+        if self.is_partially_symbolized() {
+            trace.budget = None;
+            trace.roots = Arc::new(OnceLock::new());
+        }
+        trace
+    }
+
+    /// How long the poll that the trace was captured from took, leaving out the time spent
+    /// capturing it. `None` for traces that weren't captured from a poll, e.g. snapshots.
+    pub fn poll_duration(&self) -> Option<Duration> {
+        self.poll_duration
+    }
+
+    /// The name of the task the trace was captured from, if it was given one through
+    /// [`traced_named`](crate::traced_named).
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Hashes the instruction pointers of every captured branch, without symbolizing anything. There
+    /// is one fingerprint per leaf and they are sorted, so two captures of a task that is parked at
+    /// the same places have the same fingerprints even if its combinators polled the branches in a
+    /// different order. Comparing them is the cheapest way to tell whether a task made progress.
+    /// They are only comparable between traces of the same binary.
+    ///
+    /// Traces that only hold symbolized frames, e.g. filtered or deserialized ones, are
+    /// fingerprinted by the symbols and source locations of their branches instead.
+    pub fn fingerprints(&self) -> Vec<u64> {
+        let mut fingerprints: Vec<_> = if self.backtraces.is_empty() {
+            branches(self).map(|branch| branch.id()).collect()
+        } else {
+            self.backtraces
+                .iter()
+                .map(|leaf| {
+                    let mut hasher = DefaultHasher::new();
+                    match &leaf.frames {
+                        LeafFrames::Walked(frames) => {
+                            for frame in frames {
+                                hash_frame(frame, &mut hasher);
+                            }
+                        }
+                        LeafFrames::Custom(_, ips) => ips.hash(&mut hasher),
+                    }
+                    hasher.finish()
+                })
+                .collect()
+        };
+        fingerprints.sort_unstable();
+        fingerprints
+    }
+
+    /// Counts the branches of the trace by the place they wait at, i.e. the innermost frame of each
+    /// branch that isn't part of the standard library, whose waker frames every branch ends with.
+    /// Branches that took different paths to the same place count together, so a task that awaits
+    /// the same thing in 40 places of a fan-out has a single entry with a count of 40.
+    pub fn leaf_histogram(&self) -> HashMap<LeafFingerprint, usize> {
+        let mut histogram = HashMap::new();
+        for branch in branches(self) {
+            let leaf = branch
+                .frames()
+                .rev()
+                .find(|frame| frame.origin() != FrameOrigin::Std)
+                .unwrap_or(branch.leaf());
+            // Identical branches share their path, deserialized traces don't know how many
+            let count = branch.leaf().branches().max(1);
+            *histogram
+                .entry(LeafFingerprint(leaf.with_children(Vec::new())))
+                .or_default() += count;
+        }
+        histogram
+    }
+
+    /// The frame the task is parked in, i.e. the innermost frame of the deepest branch that isn't
+    /// part of the standard library, since every branch ends in the waker's `clone`. `None` if no
+    /// leaf was reached.
+    pub(crate) fn parked_at(&self) -> Option<&Frame> {
+        let deepest = branches(self).max_by_key(|branch| branch.frames().count())?;
+        let leaf = deepest
+            .frames()
+            .rev()
+            .find(|frame| frame.origin() != FrameOrigin::Std)
+            .unwrap_or(deepest.leaf());
+        Some(leaf)
+    }
+
+    /// Serializes the trace into an untyped JSON tree, for processing that doesn't want to bind to
+    /// a schema of its own. It is the same tree that serializing the trace with `serde_json`
+    /// produces.
+    #[cfg(feature = "serde")]
+    pub fn to_json_value(&self) -> serde_json::Value {
+        // Traces have nothing that JSON can't represent, e.g. maps with non-string keys
+        serde_json::to_value(self).expect("traces serialize into JSON")
+    }
+
+    /// Returns whether the stack walk of some of the branches ended before it reached the root of
+    /// the trace, which happens on platforms whose unwinder stops early, e.g. at an FFI boundary or
+    /// because of missing unwind info. The frames of such a branch closest to the root are missing
+    /// and its topmost frame shows up as a root of the trace of its own. A marker root frame
+    /// tells how many branches are affected.
+    ///
+    /// Only the built-in stack walk is checked, the stacks of a
+    /// [`CaptureBackend`](crate::CaptureBackend) are taken as they come.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated_leaves > 0
+    }
+
+    /// The fraction of the task's lifetime that it had spent being polled when the trace was
+    /// captured, like [`TraceHandle::busy_ratio`](crate::TraceHandle::busy_ratio). `None` for
+    /// traces that weren't captured from a poll.
+    pub fn busy_ratio(&self) -> Option<f64> {
+        self.busy_ratio
+    }
+
+    /// Records the busy ratio of the task the trace was captured from.
+    pub(crate) fn with_busy_ratio(mut self, busy_ratio: f64) -> Self {
+        self.busy_ratio = Some(busy_ratio);
+        self
+    }
+
+    /// Attributes the trace to the task called `name`.
+    pub(crate) fn named(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Keeps at most `max_depth` frames of every branch and replaces the rest with a marker frame.
+    /// Applied when the trace gets symbolized, unless that already happened.
+    pub(crate) fn truncated(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        if self.roots.get().is_some() {
+            // The clones that share the tree keep it whole
+            if let Some(roots) = Arc::make_mut(&mut self.roots).get_mut() {
+                truncate(roots, max_depth);
+            }
+        } else {
+            // Or they would get it truncated once this trace is symbolized
+            self.roots = Arc::new(OnceLock::new());
+        }
+        self
+    }
+
+    /// Keeps the frames of the poll of the [`TracedTask`](crate::TracedTask) and of its waker,
+    /// which are otherwise left out of every trace. Already symbolized traces are symbolized again.
+    pub(crate) fn with_internal_frames(mut self) -> Self {
+        if !self.internal_frames && !self.backtraces.is_empty() {
+            self.internal_frames = true;
+            self.roots = Arc::new(OnceLock::new());
+        }
+        self
+    }
+
+    /// Leaves out the frames of the functions that were inlined into their callers. Already
+    /// symbolized traces are symbolized again.
+    pub(crate) fn without_inlined(mut self) -> Self {
+        if !self.without_inlined && !self.backtraces.is_empty() {
+            self.without_inlined = true;
+            self.roots = Arc::new(OnceLock::new());
+        }
+        self
+    }
+
+    /// Stops symbolizing once `budget` has passed and leaves the remaining frames as bare
+    /// addresses. Already symbolized traces are kept as they are.
+    pub(crate) fn with_budget(mut self, budget: Duration) -> Self {
+        if self.roots.get().is_none() {
+            self.budget = Some(budget);
+            self.roots = Arc::new(OnceLock::new());
+        }
+        self
+    }
+
+    /// Shows the symbols of the built-in capture as they are found in the binary, e.g.
+    /// `_ZN3app5serve17h0123456789abcdefE`, instead of demangled. Already symbolized traces are
+    /// symbolized again.
+    pub(crate) fn mangled(mut self) -> Self {
+        if !self.mangled && !self.backtraces.is_empty() {
+            self.mangled = true;
+            self.roots = Arc::new(OnceLock::new());
+        }
+        self
+    }
+}
+
+/// Renders the trace as a tree, preceded by a `Task "<name>":` line for named tasks.
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            writeln!(f, "{}", Header(name, self.busy_ratio))?;
+        }
+        display_tree(f, self.roots(), false, &|f, frame| write!(f, "{frame}"))
+    }
+}
+
+/// The line that names the task above its tree, as `Task "name" (busy 12%):`, or without the
+/// ratio if it isn't known. Traces of unnamed tasks have no header, their busy ratio is only
+/// available through [`Trace::busy_ratio`].
+pub(crate) struct Header<'a>(pub(crate) &'a str, pub(crate) Option<f64>);
+
+impl fmt::Display for Header<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Task {:?}", self.0)?;
+        if let Some(busy_ratio) = self.1 {
+            write!(f, " (busy {:.0}%)", busy_ratio * 100.0)?;
+        }
+        f.write_str(":")
+    }
+}
+
+/// Writes a single frame of a tree, without the tree drawing around it.
+pub(crate) type Label<'a> = &'a dyn Fn(&mut dyn fmt::Write, &Frame) -> fmt::Result;
+
+/// Writes lines below a frame of a tree, each of which begins with a newline and the given prefix
+/// that continues the tree drawing.
+pub(crate) type Below<'a> = &'a dyn Fn(&mut dyn fmt::Write, &Frame, &str) -> fmt::Result;
+
+/// The characters a tree is drawn with.
+#[derive(Debug)]
+pub(crate) struct TreeStyle {
+    root: &'static str,
+    child: &'static str,
+    last_child: &'static str,
+    indent: &'static str,
+    last_indent: &'static str,
+    times: &'static str,
+}
+
+impl TreeStyle {
+    pub(crate) const UNICODE: TreeStyle = TreeStyle {
+        root: "╼ ",
+        child: "├╼",
+        last_child: "└╼",
+        indent: "│  ",
+        last_indent: "   ",
+        times: "×",
+    };
+
+    pub(crate) const ASCII: TreeStyle = TreeStyle {
+        root: "> ",
+        child: "|-",
+        last_child: "`-",
+        indent: "|  ",
+        last_indent: "   ",
+        times: "x",
+    };
+
+    /// The lines of `tracing-tree`, which opens a span with `┐` and continues it with `├─`.
+    pub(crate) const TRACING_TREE: TreeStyle = TreeStyle {
+        root: "┐ ",
+        child: "├─",
+        last_child: "└─",
+        indent: "│  ",
+        last_indent: "   ",
+        times: "×",
+    };
+}
+
+/// Renders `roots` as a tree, writing every frame through `label`. With `counts`, frames that are
+/// reached by a different number of branches than their parent are annotated with that number.
+pub(crate) fn display_tree(
+    f: &mut dyn fmt::Write,
+    roots: &[Frame],
+    counts: bool,
+    label: Label<'_>,
+) -> fmt::Result {
+    display_tree_with(f, roots, counts, &TreeStyle::UNICODE, label, None)
+}
+
+/// Like [`display_tree`] but draws the tree with the characters of `style`, writing extra lines
+/// below every frame through `below`, if given.
+pub(crate) fn display_tree_with(
+    f: &mut dyn fmt::Write,
+    roots: &[Frame],
+    counts: bool,
+    style: &TreeStyle,
+    label: Label<'_>,
+    below: Option<Below<'_>>,
+) -> fmt::Result {
+    for (i, root) in roots.iter().enumerate() {
+        if i > 0 {
+            writeln!(f)?;
+        }
+        f.write_str(style.root)?;
+        label(f, root)?;
+        if let Some(below) = below {
+            below(f, root, &root.continuation("  ", style))?;
+        }
+        root.display_children(f, "  ", counts, style, label, below)?;
+    }
+    Ok(())
+}
+
+/// Renders `trace` in the folded stack format understood by flamegraph tools like `inferno`.
+///
+/// Every branch of the trace becomes one line with the symbols from the root down to the leaf
+/// separated by `;`, followed by a count of 1. The frames of the waker that recorded the branch
+/// are left out since they end every single branch.
+pub fn fold(trace: &Trace) -> String {
+    fn fold_frame<'a>(frame: &'a Frame, path: &mut Vec<&'a str>, folded: &mut String) {
+        path.push(frame.symbol().unwrap_or("??"));
+        let mut children = frame
+            .children
+            .iter()
+            .filter(|child| !child.is_waker())
+            .peekable();
+        if children.peek().is_none() {
+            folded.push_str(&path.join(";"));
+            folded.push_str(" 1\n");
+        }
+        for child in children {
+            fold_frame(child, path, folded);
+        }
+        path.pop();
+    }
+
+    let mut folded = String::new();
+    for root in trace.roots() {
+        fold_frame(root, &mut Vec::new(), &mut folded);
+    }
+    folded
+}
+
+/// Renders `trace` as a Graphviz digraph, e.g. for `dot -Tsvg`.
+///
+/// Every frame becomes a node labeled with its symbol and source location, with an edge to each of
+/// the frames it called. Edges that stand for a different number of branches than their parent
+/// are labeled with that number, like the `(×N)` annotations of [`aggregate`](crate::aggregate).
+pub fn to_dot(trace: &Trace) -> String {
+    fn escape(label: &str) -> String {
+        label.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    fn node(frame: &Frame, next: &mut usize, dot: &mut String) -> usize {
+        let id = *next;
+        *next += 1;
+        let mut location = String::new();
+        let _ = frame.display_location(&mut location);
+        let symbol = escape(frame.symbol().unwrap_or("??"));
+        if location.is_empty() {
+            let _ = writeln!(dot, "    n{id} [label=\"{symbol}\"];");
+        } else {
+            let location = escape(&location);
+            let _ = writeln!(dot, "    n{id} [label=\"{symbol}\\n{location}\"];");
+        }
+        for child in &frame.children {
+            let child_id = node(child, next, dot);
+            if child.branches > 1 && child.branches != frame.branches {
+                let _ = writeln!(
+                    dot,
+                    "    n{id} -> n{child_id} [label=\"×{}\"];",
+                    child.branches
+                );
+            } else {
+                let _ = writeln!(dot, "    n{id} -> n{child_id};");
+            }
+        }
+        id
+    }
+
+    let mut dot = String::from("digraph trace {\n    node [shape=box];\n");
+    let mut next = 0;
+    for root in trace.roots() {
+        node(root, &mut next, &mut dot);
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Returns the branches of `trace`, i.e. its paths from a root down to a leaf, in the order in which
+/// they are rendered.
+///
+/// ```rust
+/// # async fn check(trace: tasktrace::Trace) {
+/// let blocked_on_lock = tasktrace::branches(&trace).any(|branch| {
+///     branch
+///         .frames()
+///         .any(|frame| frame.symbol().is_some_and(|symbol| symbol.contains("lock")))
+/// });
+/// # let _ = blocked_on_lock;
+/// # }
+/// ```
+///
+/// Identical leaves, e.g. of a `join_all` over equal futures, share a single branch.
+pub fn branches(trace: &Trace) -> impl Iterator<Item = Branch<'_>> {
+    fn collect<'a>(frame: &'a Frame, path: &mut Vec<&'a Frame>, branches: &mut Vec<Branch<'a>>) {
+        path.push(frame);
+        if frame.children.is_empty() {
+            branches.push(Branch {
+                frames: path.clone(),
+            });
+        }
+        for child in &frame.children {
+            collect(child, path, branches);
+        }
+        path.pop();
+    }
+
+    let mut branches = Vec::new();
+    for root in trace.roots() {
+        collect(root, &mut Vec::new(), &mut branches);
+    }
+    branches.into_iter()
+}
+
+/// A path through a [`Trace`] from one of its roots down to a leaf, see [`branches`].
+#[derive(Debug, Clone)]
+pub struct Branch<'a> {
+    frames: Vec<&'a Frame>,
+}
+
+impl<'a> Branch<'a> {
+    /// The frames of the branch, starting from the root.
+    pub fn frames(&self) -> impl DoubleEndedIterator<Item = &'a Frame> + '_ {
+        self.frames.iter().copied()
+    }
+
+    /// The innermost frame of the branch.
+    pub fn leaf(&self) -> &'a Frame {
+        self.frames[self.frames.len() - 1]
+    }
+
+    /// A hash of the symbols and source locations of the frames of the branch, which tells the
+    /// same wait point apart across captures, e.g. to follow one branch of a task over time. It is
+    /// the same for equal paths of different traces of the same binary, but not across builds.
+    pub fn id(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for frame in &self.frames {
+            (frame.symbol(), frame.file(), frame.line(), frame.col()).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+/// The function and source location that branches of a trace wait at, see
+/// [`Trace::leaf_histogram`].
+#[derive(Debug, Clone)]
+pub struct LeafFingerprint(Frame);
+
+impl LeafFingerprint {
+    /// The demangled name of the function.
+    pub fn symbol(&self) -> Option<&str> {
+        self.0.symbol()
+    }
+
+    /// The source file of the frame.
+    pub fn file(&self) -> Option<&Path> {
+        self.0.file()
+    }
+
+    /// The line number in the source file.
+    pub fn line(&self) -> Option<u32> {
+        self.0.line()
+    }
+
+    /// The column number in the source file.
+    pub fn col(&self) -> Option<u32> {
+        self.0.col()
+    }
+
+    fn key(&self) -> (Option<&str>, Option<&Path>, Option<u32>, Option<u32>) {
+        (self.symbol(), self.file(), self.line(), self.col())
+    }
+}
+
+impl PartialEq for LeafFingerprint {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for LeafFingerprint {}
+
+impl Hash for LeafFingerprint {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+/// Renders the symbol and the source location, like a frame of the rendered trace.
+impl fmt::Display for LeafFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol().unwrap_or("??"))?;
+        if self.file().is_some() {
+            write!(f, " at ")?;
+            self.0.display_location(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `trace` to `w` the way its `Display` implementation renders it, one piece at a time
+/// instead of building the whole rendering in memory first.
+///
+/// The rendering takes many small writes, so writers like a `TcpStream` or a `File` are best
+/// wrapped in a `BufWriter`.
+pub fn write_trace<W: io::Write + ?Sized>(w: &mut W, trace: &Trace) -> io::Result<()> {
+    write!(w, "{trace}")
+}
+
+/// Like [`write_trace`] but for [`fmt::Write`] implementations.
+pub fn write_trace_fmt<W: fmt::Write + ?Sized>(w: &mut W, trace: &Trace) -> fmt::Result {
+    write!(w, "{trace}")
+}
+
+/// Returns the number of leaves that registered the waker during the captured poll, i.e. roughly
+/// how many things the task is waiting on.
+///
+/// Identical leaves, e.g. of a `join_all` over equal futures, are counted individually, and so are
+/// the ones left out because of [`CaptureLimits::max_leaves`]. The count comes from the captured
+/// backtraces and does not require symbolizing the trace, except for deserialized traces that only
+/// know their distinct branches.
+pub fn leaf_count(trace: &Trace) -> usize {
+    fn leaves(frames: &[Frame]) -> usize {
+        frames
+            .iter()
+            .map(|frame| match frame.children() {
+                [] => 1,
+                children => leaves(children),
+            })
+            .sum()
+    }
+
+    if trace.backtraces.is_empty() {
+        leaves(trace.roots())
+    } else {
+        trace.backtraces.len() + trace.omitted_leaves
+    }
+}
+
+/// The name of the `tracing` span the current thread is in, if any.
+#[cfg(feature = "tracing")]
+fn current_span() -> Option<&'static str> {
+    tracing::Span::current()
+        .metadata()
+        // With tokio_unstable, tokio runs every task in a span of its own that says nothing about
+        // the leaf
+        .filter(|metadata| !metadata.target().starts_with("tokio::"))
+        .map(|metadata| metadata.name())
+}
+
+#[cfg(not(feature = "tracing"))]
+fn current_span() -> Option<&'static str> {
+    None
+}
+
+/// A frame of a recorded backtrace, symbolized when the trace is first inspected.
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+type StackFrame = BacktraceFrame;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+type StackFrame = crate::js::StackFrame;
+
+/// Walks the stack from the caller of [`Trace::leaf`] up to the roots of `captures`, returning
+/// the frames in between, innermost first, along with where the root of every capture is among
+/// them, innermost capture first. Returns `None` if the leaf was already recorded further down
+/// the stack.
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn walk_leaf(captures: &[Capture]) -> Option<(Vec<StackFrame>, Vec<usize>)> {
+    // The backtraces are only recorded once the walk is complete, since it may turn out
+    // that they are not needed at all
+    let mut frames = Vec::new();
+    let mut ends = Vec::with_capacity(captures.len());
+    let mut caller = None;
+    let mut above_leaf = false;
+    let mut nested = false;
+    backtrace::trace(|frame| {
+        let addr = frame.symbol_address();
+        if !above_leaf {
+            above_leaf = ptr::eq(addr, Trace::leaf as *const c_void);
+            return true;
+        }
+        match caller {
+            None => caller = Some(addr),
+            // The waker of an inner capture wraps the waker of an outer one and calls into
+            // it, after having recorded this very leaf for both of them
+            Some(caller) if ends.is_empty() && ptr::eq(addr, caller) => {
+                nested = true;
+                return false;
+            }
+            Some(_) => {}
+        }
+        // The roots are reached from the innermost one outwards
+        if ptr::eq(addr, captures[captures.len() - 1 - ends.len()].root_addr) {
+            ends.push(frames.len());
+            return ends.len() < captures.len();
+        }
+        frames.push(frame.clone().into());
+        true
+    });
+    (!nested).then_some((frames, ends))
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn walk_leaf(captures: &[Capture]) -> Option<(Vec<StackFrame>, Vec<usize>)> {
+    crate::js::walk_leaf(captures.len())
+}
+
+/// Returns whether the leaf was already recorded further down the stack, like [`walk_leaf`] does
+/// but without keeping the frames, for captures whose backends walk the stack themselves.
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn is_nested_leaf(captures: &[Capture]) -> bool {
+    let root = captures[captures.len() - 1].root_addr;
+    let mut caller = None;
+    let mut above_leaf = false;
+    let mut nested = false;
+    backtrace::trace(|frame| {
+        let addr = frame.symbol_address();
+        if !above_leaf {
+            above_leaf = ptr::eq(addr, Trace::leaf as *const c_void);
+            return true;
+        }
+        match caller {
+            None => caller = Some(addr),
+            Some(caller) => nested = ptr::eq(addr, caller),
+        }
+        // Only the frames below the innermost root can repeat the caller
+        !nested && !ptr::eq(addr, root)
+    });
+    nested
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn is_nested_leaf(captures: &[Capture]) -> bool {
+    crate::js::walk_leaf(captures.len()).is_none()
+}
+
+/// Feeds the instruction pointer of `frame` to `hasher`, see [`Trace::fingerprints`].
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn hash_frame(frame: &StackFrame, hasher: &mut impl Hasher) {
+    (frame.ip() as usize).hash(hasher);
+}
+
+// The JavaScript stacks have no instruction pointers, only the locations they resolve to
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn hash_frame(frame: &StackFrame, hasher: &mut impl Hasher) {
+    (&frame.symbol, &frame.file, frame.line, frame.col).hash(hasher);
+}
+
+/// Walks the stack from the caller of [`Trace::here`] up to its root, innermost frame first.
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn walk_here() -> Vec<StackFrame> {
+    let mut frames = Vec::new();
+    let mut above_here = false;
+    backtrace::trace(|frame| {
+        if above_here {
+            frames.push(frame.clone().into());
+        } else {
+            above_here = ptr::eq(frame.symbol_address(), Trace::here as *const c_void);
+        }
+        true
+    });
+    frames
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn walk_here() -> Vec<StackFrame> {
+    crate::js::walk_here()
+}
+
+/// Symbolizes the frames of a leaf, returning them from the root down to the leaf. With `mangled`
+/// the frames also keep the symbols they had in the binary, see [`use_mangled`]. The frames that
+/// come after `deadline` are left as bare addresses.
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn symbolize(frames: &[StackFrame], mangled: bool, deadline: Option<Instant>) -> Vec<Frame> {
+    // The last symbol is the function of the frame, the ones before it were inlined into it
+    let symbols_of = |frame: &BacktraceFrame| -> Vec<Frame> {
+        let symbols = frame.symbols();
+        symbols
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, symbol)| Frame {
+                inlined: i + 1 < symbols.len(),
+                ..Frame::new(symbol, mangled)
+            })
+            .collect()
+    };
+    let Some(deadline) = deadline else {
+        let mut backtrace = backtrace::Backtrace::from(frames.to_vec());
+        backtrace.resolve();
+        return backtrace
+            .frames()
+            .iter()
+            .rev()
+            .flat_map(symbols_of)
+            .collect();
+    };
+    // One frame at a time, to notice the deadline in between
+    frames
+        .iter()
+        .rev()
+        .flat_map(|frame| {
+            if Instant::now() >= deadline {
+                return vec![Frame::unsymbolized(frame.ip() as usize)];
+            }
+            let mut backtrace = backtrace::Backtrace::from(vec![frame.clone()]);
+            backtrace.resolve();
+            symbols_of(&backtrace.frames()[0])
+        })
+        .collect()
+}
+
+// The JavaScript stacks only have the names the engine shows
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn symbolize(frames: &[StackFrame], _mangled: bool, _deadline: Option<Instant>) -> Vec<Frame> {
+    frames.iter().rev().map(Frame::from_js).collect()
+}
+
+/// Symbolizes the frames of a leaf, returning them from the root down to the leaf.
+fn symbolize_leaf(frames: &LeafFrames, mangled: bool, deadline: Option<Instant>) -> Vec<Frame> {
+    match frames {
+        LeafFrames::Walked(frames) => symbolize(frames, mangled, deadline),
+        LeafFrames::Custom(backend, ips) => resolve_ips(&*backend.0, ips, deadline),
+    }
+}
+
+/// Symbolizes the instruction pointers of a stack, innermost first, through `backend`, returning
+/// the frames from the root down. The instruction pointers that come after `deadline` are left as
+/// bare addresses.
+fn resolve_ips(
+    backend: &dyn CaptureBackend,
+    ips: &[usize],
+    deadline: Option<Instant>,
+) -> Vec<Frame> {
+    ips.iter()
+        .rev()
+        .flat_map(|&ip| {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return vec![Frame::unsymbolized(ip)];
+            }
+            // Inlined functions come first
+            let frames = backend.resolve(ip);
+            let len = frames.len();
+            frames
+                .into_iter()
+                .enumerate()
+                .rev()
+                .map(move |(i, frame)| Frame {
+                    inlined: i + 1 < len,
+                    ..Frame::from_resolved(frame)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Symbolizes `backtraces` and merges them into a tree, keeping the branches in the order in which
+/// their leaves were reached.
+fn resolve(backtraces: &[Leaf], mangled: bool, deadline: Option<Instant>) -> Vec<Frame> {
+    let mut roots = Vec::new();
+    for leaf in backtraces {
+        let mut frames = symbolize_leaf(&leaf.frames, mangled, deadline);
+        // The polls of labelled futures are reached in the order their labels were pushed
+        let mut labels = leaf.labels.iter();
+        for frame in &mut frames {
+            if frame.symbol().is_some_and(crate::label::is_labelled_poll) {
+                if let Some(label) = labels.next() {
+                    *frame = Frame::placeholder(label.as_str().to_owned(), 0, Vec::new());
+                }
+            }
+        }
+        // The span and the resource go to the innermost frame that remains after the internal ones
+        // are stripped, so that it is shown next to the leaf either way
+        if let Some(frame) = frames.iter_mut().rev().find(|frame| !frame.is_internal()) {
+            frame.span = leaf.span.map(str::to_owned);
+            frame.resource = leaf.resource.map(str::to_owned);
+            frame.elapsed = Some(leaf.elapsed);
+        }
+        if leaf.omitted_frames > 0 {
+            let label = format!("… ({} frames omitted)", leaf.omitted_frames);
+            frames.push(Frame::placeholder(label, 0, Vec::new()));
+        }
+
+        let mut level: &mut Vec<Frame> = &mut roots;
+        for frame in frames {
+            let i = match level.iter().position(|other| other.same_location(&frame)) {
+                Some(i) => {
+                    // Identical leaves add up the time spent in them
+                    if let Some(elapsed) = frame.elapsed {
+                        *level[i].elapsed.get_or_insert(Duration::ZERO) += elapsed;
+                    }
+                    i
+                }
+                None => {
+                    level.push(frame);
+                    level.len() - 1
+                }
+            };
+            level[i].branches += 1;
+            level = &mut level[i].children;
+        }
+    }
+    roots
+}
+
+/// Adds `child` below the deepest frame of `roots` that `path`, a branch from the root of a stack
+/// down, still goes through, comparing frames by function. `child` becomes a root if `path`
+/// doesn't go through any.
+pub(crate) fn graft(roots: &mut Vec<Frame>, path: &[&Frame], child: Frame) {
+    // The stack may start further up, e.g. in the runtime that polls the task of the roots
+    let Some(start) = path
+        .iter()
+        .position(|frame| roots.iter().any(|root| root.same_function(frame)))
+    else {
+        roots.push(child);
+        return;
+    };
+    let mut level = roots;
+    for frame in &path[start..] {
+        let Some(i) = level.iter().position(|other| other.same_function(frame)) else {
+            break;
+        };
+        level[i].branches += child.branches;
+        level = &mut level[i].children;
+    }
+    level.push(child);
+}
+
+/// Returns whether any of `frames` or their descendants is only known by its address.
+fn has_unsymbolized(frames: &[Frame]) -> bool {
+    frames
+        .iter()
+        .any(|frame| frame.is_unsymbolized() || has_unsymbolized(&frame.children))
+}
+
+/// Removes the frames of this crate from `frames`. Their children take their place.
+fn strip_internal(frames: Vec<Frame>) -> Vec<Frame> {
+    let mut stripped = Vec::with_capacity(frames.len());
+    for mut frame in frames {
+        let children = strip_internal(std::mem::take(&mut frame.children));
+        if frame.is_internal() {
+            stripped.extend(children);
+        } else {
+            frame.children = children;
+            stripped.push(frame);
+        }
+    }
+    stripped
+}
+
+/// Replaces the symbols of `frames` by the ones they had in the binary, where they were kept.
+fn use_mangled(frames: &mut [Frame]) {
+    for frame in frames {
+        if let Some(mangled) = frame.mangled.take() {
+            frame.symbol = Some(mangled);
+        }
+        use_mangled(&mut frame.children);
+    }
+}
+
+/// Removes the frames of inlined functions from `frames`. Their children take their place, below
+/// the frame of the function they were inlined into.
+fn strip_inlined(frames: Vec<Frame>) -> Vec<Frame> {
+    let mut stripped = Vec::with_capacity(frames.len());
+    for mut frame in frames {
+        let children = strip_inlined(std::mem::take(&mut frame.children));
+        if frame.inlined {
+            stripped.extend(children);
+        } else {
+            frame.children = children;
+            stripped.push(frame);
+        }
+    }
+    stripped
+}
+
+/// Replaces the frames of `frames` that are deeper than `depth` with a frame that says how many
+/// were omitted.
+fn truncate(frames: &mut Vec<Frame>, depth: usize) {
+    if depth > 0 {
+        for frame in frames {
+            truncate(&mut frame.children, depth - 1);
+        }
+    } else if !frames.is_empty() {
+        let omitted: usize = frames.iter().map(Frame::len).sum();
+        let branches = frames.iter().map(|frame| frame.branches).sum();
+        *frames = vec![Frame::placeholder(
+            format!("… ({omitted} frames omitted)"),
+            branches,
+            Vec::new(),
+        )];
+    }
+}
+
+/// A frame of a [`Trace`] along with the frames it called.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Frame {
+    /// The address of the function, which tells apart instances of a generic function that share
+    /// their source location.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    addr: Option<usize>,
+    symbol: Option<String>,
+    file: Option<PathBuf>,
+    line: Option<u32>,
+    col: Option<u32>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    span: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    resource: Option<String>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    elapsed: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    age: Option<usize>,
+    /// The number of captured branches that go through this frame. Identical branches, e.g. of
+    /// a `join_all` over equal futures, end up as a single path through the tree.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    branches: usize,
+    /// Whether the function was inlined into the frame above, see
+    /// [`TraceOptions::expand_inlined`](crate::TraceOptions::expand_inlined).
+    #[cfg_attr(feature = "serde", serde(skip))]
+    inlined: bool,
+    /// The symbol as found in the binary, only kept until it replaces the demangled one, see
+    /// [`TraceOptions::demangle`](crate::TraceOptions::demangle).
+    #[cfg_attr(feature = "serde", serde(skip))]
+    mangled: Option<String>,
+    children: Vec<Frame>,
+}
+
+/// Where the function of a [`Frame`] comes from, see [`Frame::origin`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameOrigin {
+    /// The application itself.
+    User,
+    /// An async runtime, e.g. tokio.
+    Runtime,
+    /// The standard library.
+    Std,
+    /// Any other crate from the cargo registry.
+    Dependency,
+}
+
+impl fmt::Display for FrameOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FrameOrigin::User => "user",
+            FrameOrigin::Runtime => "runtime",
+            FrameOrigin::Std => "std",
+            FrameOrigin::Dependency => "dependency",
+        })
+    }
+}
+
+impl Frame {
+    #[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+    fn new(symbol: &BacktraceSymbol, mangled: bool) -> Self {
+        let name = symbol.name();
+        Frame {
+            addr: symbol.addr().map(|addr| addr as usize),
+            symbol: name.as_ref().map(|name| format!("{name:#}")),
+            file: symbol.filename().map(Path::to_path_buf),
+            line: symbol.lineno(),
+            col: symbol.colno(),
+            span: None,
+            resource: None,
+            elapsed: None,
+            age: None,
+            branches: 0,
+            inlined: false,
+            mangled: name
+                .filter(|_| mangled)
+                .map(|name| String::from_utf8_lossy(name.as_bytes()).into_owned()),
+            children: Vec::new(),
+        }
+    }
+
+    fn from_resolved(frame: ResolvedFrame) -> Self {
+        Frame {
+            addr: None,
+            symbol: frame.symbol,
+            file: frame.file,
+            line: frame.line,
+            col: frame.col,
+            span: None,
+            resource: None,
+            elapsed: None,
+            age: None,
+            branches: 0,
+            inlined: false,
+            mangled: None,
+            children: Vec::new(),
+        }
+    }
+
+    #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+    fn from_js(frame: &crate::js::StackFrame) -> Self {
+        Frame {
+            addr: None,
+            symbol: frame.symbol.clone(),
+            file: frame.file.as_ref().map(PathBuf::from),
+            line: frame.line,
+            col: frame.col,
+            span: None,
+            resource: None,
+            elapsed: None,
+            age: None,
+            branches: 0,
+            inlined: false,
+            mangled: None,
+            children: Vec::new(),
+        }
+    }
+
+    /// The demangled name of the function, without its hash, unless
+    /// [`TraceOptions::demangle`](crate::TraceOptions::demangle) is turned off.
+    pub fn symbol(&self) -> Option<&str> {
+        self.symbol.as_deref()
+    }
+
+    /// The source file of the frame.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    /// The line number within [`Frame::file`].
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+
+    /// The column number within [`Frame::line`].
+    pub fn col(&self) -> Option<u32> {
+        self.col
+    }
+
+    /// The name of the `tracing` span that was entered when the leaf below this frame registered
+    /// the waker. Only recorded with the `tracing` feature, and only for the innermost frame of
+    /// every branch.
+    pub fn span(&self) -> Option<&str> {
+        self.span.as_deref()
+    }
+
+    /// The resource that the leaf below this frame waits on, as described by the innermost
+    /// [`waiting_on`](crate::waiting_on) it was polled in. Only recorded for the innermost frame of
+    /// every branch.
+    pub fn resource(&self) -> Option<&str> {
+        self.resource.as_deref()
+    }
+
+    /// The time the poll spent in the branch that ends at this frame, from the previous leaf that
+    /// registered the waker, or the start of the poll, up to the leaf below this frame. Shows
+    /// which branch of a `join!` makes its polls slow, since the joined futures are polled one
+    /// after the other. Only known for the innermost frame of every branch, and the sum over all
+    /// of its leaves if identical leaves share the branch.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.elapsed
+    }
+
+    /// The number of consecutive polls of the task, up to the one the trace was captured from,
+    /// whose traces had this frame at the same place of the tree, 1 if it just appeared. Only
+    /// known for the traces of tasks created with
+    /// [`traced_with_frame_ages`](crate::traced_with_frame_ages).
+    pub fn age(&self) -> Option<usize> {
+        self.age
+    }
+
+    pub(crate) fn set_age(&mut self, age: usize) {
+        self.age = Some(age);
+    }
+
+    /// Where the function of the frame comes from, judging by its crate and its source file.
+    ///
+    /// Frames of `std`, `core` and `alloc` or with sources under `/rustc/` belong to the standard
+    /// library, frames of tokio, async-std, smol and their building blocks to a runtime, and the
+    /// other frames with sources in the cargo registry to a dependency. Everything else, including
+    /// frames without a known source file, is attributed to the application.
+    pub fn origin(&self) -> FrameOrigin {
+        const STD: &[&str] = &["std", "core", "alloc"];
+        const RUNTIMES: &[&str] = &[
+            "tokio",
+            "async_std",
+            "smol",
+            "async_executor",
+            "async_io",
+            "async_task",
+            "futures_executor",
+        ];
+        let name = self.crate_name();
+        let file = self.file();
+        if name.is_some_and(|name| STD.contains(&name))
+            || file.is_some_and(|file| file.starts_with("/rustc") || file.starts_with("<std>"))
+        {
+            FrameOrigin::Std
+        } else if name.is_some_and(|name| RUNTIMES.contains(&name)) {
+            FrameOrigin::Runtime
+        } else if file.is_some_and(crate::render::is_dependency) {
+            FrameOrigin::Dependency
+        } else {
+            FrameOrigin::User
+        }
+    }
+
+    /// The number of captured branches that go through this frame.
+    pub(crate) fn branches(&self) -> usize {
+        self.branches
+    }
+
+    /// The frames called by this one. There is more than one child where the task polls several
+    /// futures that each registered its waker, e.g. in a `join!`.
+    pub fn children(&self) -> &[Frame] {
+        &self.children
+    }
+
+    /// A frame that wasn't symbolized, of which only the instruction pointer `ip` is known.
+    fn unsymbolized(ip: usize) -> Self {
+        Frame {
+            addr: Some(ip),
+            symbol: None,
+            ..Frame::placeholder(String::new(), 0, Vec::new())
+        }
+    }
+
+    /// Returns whether only the address of the frame is known, see [`Frame::unsymbolized`].
+    fn is_unsymbolized(&self) -> bool {
+        self.symbol.is_none() && self.file.is_none() && self.addr.is_some()
+    }
+
+    /// A frame standing in for frames that were left out, which called `children`.
+    pub(crate) fn placeholder(label: String, branches: usize, children: Vec<Frame>) -> Self {
+        Frame {
+            addr: None,
+            symbol: Some(label),
+            file: None,
+            line: None,
+            col: None,
+            span: None,
+            resource: None,
+            elapsed: None,
+            age: None,
+            branches,
+            inlined: false,
+            mangled: None,
+            children,
+        }
+    }
+
+    /// Returns whether both subtrees consist of the same functions at the same places, as far as
+    /// `precision` tells them apart.
+    pub(crate) fn same_shape(&self, other: &Frame, precision: Precision) -> bool {
+        precision.same_point(self, other)
+            && self.span == other.span
+            && self.resource == other.resource
+            && self.children.len() == other.children.len()
+            && self
+                .children
+                .iter()
+                .zip(&other.children)
+                .all(|(child, other)| child.same_shape(other, precision))
+    }
+
+    /// Adds the branches of `other`, a subtree of the same shape, to the ones of this subtree.
+    pub(crate) fn absorb(&mut self, other: &Frame) {
+        self.branches += other.branches;
+        for (child, other) in self.children.iter_mut().zip(&other.children) {
+            child.absorb(other);
+        }
+    }
+
+    /// The number of frames in the subtree rooted at this frame, including itself.
+    fn len(&self) -> usize {
+        1 + self.children.iter().map(Frame::len).sum::<usize>()
+    }
+
+    /// A copy of this frame that called `children` instead.
+    pub(crate) fn with_children(&self, children: Vec<Frame>) -> Self {
+        Frame {
+            addr: self.addr,
+            symbol: self.symbol.clone(),
+            file: self.file.clone(),
+            line: self.line,
+            col: self.col,
+            span: self.span.clone(),
+            resource: self.resource.clone(),
+            elapsed: self.elapsed,
+            age: self.age,
+            branches: self.branches,
+            inlined: self.inlined,
+            mangled: self.mangled.clone(),
+            children,
+        }
+    }
+
+    /// A copy of this frame with the given symbol and source location in place of its own.
+    pub(crate) fn with_parts(
+        mut self,
+        symbol: Option<String>,
+        file: Option<PathBuf>,
+        line: Option<u32>,
+        col: Option<u32>,
+    ) -> Self {
+        self.symbol = symbol;
+        self.file = file;
+        self.line = line;
+        self.col = col;
+        self
+    }
+
+    /// A copy of this frame with the given span and resource in place of its own.
+    pub(crate) fn with_annotations(
+        mut self,
+        span: Option<String>,
+        resource: Option<String>,
+    ) -> Self {
+        self.span = span;
+        self.resource = resource;
+        self
+    }
+
+    pub(crate) fn children_mut(&mut self) -> &mut [Frame] {
+        &mut self.children
+    }
+
+    /// Adds `child` after the other children of the frame.
+    pub(crate) fn push_child(&mut self, child: Frame) {
+        self.children.push(child);
+    }
+
+    /// A copy of this frame with `symbol` in place of its own.
+    pub(crate) fn with_symbol(mut self, symbol: String) -> Self {
+        self.symbol = Some(symbol);
+        self
+    }
+
+    /// A copy of this frame with `file` as its source file.
+    pub(crate) fn with_file(mut self, file: PathBuf) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    /// A copy of this frame without its source location.
+    pub(crate) fn without_location(mut self) -> Self {
+        self.file = None;
+        self.line = None;
+        self.col = None;
+        self
+    }
+
+    /// The crate the function belongs to. For trait methods this is the crate of the implementing
+    /// type, e.g. `tokio` for `<tokio::future::maybe_done::MaybeDone<Fut> as Future>::poll`.
+    pub(crate) fn crate_name(&self) -> Option<&str> {
+        let symbol = self.symbol()?.trim_start_matches('<');
+        let end = symbol
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(symbol.len());
+        Some(&symbol[..end])
+    }
+
+    /// Returns whether this is a frame of the waker that records the traces.
+    fn is_waker(&self) -> bool {
+        matches!(
+            self.symbol(),
+            Some("tasktrace::clone_raw" | "tasktrace::wake_by_ref_raw")
+        )
+    }
+
+    /// Returns whether this is the poll of a `futures::future::Shared`, which polls the shared
+    /// future with a waker of its own so that its frames never show up below this one.
+    pub(crate) fn is_shared_poll(&self) -> bool {
+        self.symbol().is_some_and(|symbol| {
+            symbol.starts_with("<futures_util::future::future::shared::Shared<")
+                && symbol.ends_with(">::poll")
+        })
+    }
+
+    /// Returns whether this is one of the frames of this crate that wrap every trace, i.e. the
+    /// poll that started the capture or the waker.
+    fn is_internal(&self) -> bool {
+        self.is_waker()
+            || self.symbol().is_some_and(|symbol| {
+                matches!(
+                    symbol,
+                    "tasktrace::capture::{{closure}}"
+                        | "tasktrace::capture_next::{{closure}}"
+                        | "tasktrace::io::capture_io::{{closure}}"
+                ) || crate::label::is_combinator_poll(symbol)
+                    || (symbol
+                        .trim_start_matches('<')
+                        .starts_with("tasktrace::io::TracedIo<")
+                        && symbol.ends_with("{{closure}}"))
+            })
+    }
+
+    /// Returns whether both frames are in the same function, wherever in it they are. Compares
+    /// names only, since the frames of separate captures may resolve to different addresses.
+    fn same_function(&self, other: &Frame) -> bool {
+        self.symbol == other.symbol && self.file == other.file
+    }
+
+    /// Returns whether both frames refer to the same function at the same location, regardless of
+    /// what they called.
+    fn same_location(&self, other: &Frame) -> bool {
+        self.addr == other.addr
+            && self.symbol == other.symbol
+            && self.file == other.file
+            && self.line == other.line
+            && self.col == other.col
+            && self.span == other.span
+            && self.resource == other.resource
+    }
+
+    /// Renders the source location of the frame as `file:line:col`, or nothing if it is unknown.
+    pub(crate) fn display_location(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}", file.display())?;
+            if let Some(line) = self.line {
+                write!(f, ":{line}")?;
+                if let Some(col) = self.col {
+                    write!(f, ":{col}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the span, the resource and the age of the frame as
+    /// ` [span: name] [waiting on: resource] (3 polls)`, leaving out the ones it doesn't have, and
+    /// marks the polls of shared futures with ` [shared]`.
+    pub(crate) fn display_annotations(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        if let Some(span) = &self.span {
+            write!(f, " [span: {span}]")?;
+        }
+        if let Some(resource) = &self.resource {
+            write!(f, " [waiting on: {resource}]")?;
+        }
+        if self.is_shared_poll() {
+            f.write_str(" [shared]")?;
+        }
+        match self.age {
+            Some(1) => f.write_str(" (new)"),
+            Some(age) => write!(f, " ({age} polls)"),
+            None => Ok(()),
+        }
+    }
+
+    fn display_children(
+        &self,
+        f: &mut dyn fmt::Write,
+        prefix: &str,
+        counts: bool,
+        style: &TreeStyle,
+        label: Label<'_>,
+        below: Option<Below<'_>>,
+    ) -> fmt::Result {
+        for (i, child) in self.children.iter().enumerate() {
+            let (branch, indent) = if i == self.children.len() - 1 {
+                (style.last_child, style.last_indent)
+            } else {
+                (style.child, style.indent)
+            };
+            write!(f, "\n{prefix}{branch} ")?;
+            label(f, child)?;
+            if counts && child.branches > 1 && child.branches != self.branches {
+                write!(f, " ({}{})", style.times, child.branches)?;
+            }
+            let prefix = format!("{prefix}{indent}");
+            if let Some(below) = below {
+                below(f, child, &child.continuation(&prefix, style))?;
+            }
+            child.display_children(f, &prefix, counts, style, label, below)?;
+        }
+        Ok(())
+    }
+
+    /// The prefix of the lines below the frame, given the prefix of the lines of its children.
+    fn continuation(&self, prefix: &str, style: &TreeStyle) -> String {
+        if self.children.is_empty() {
+            format!("{prefix}{}", style.last_indent)
+        } else {
+            format!("{prefix}{}", style.indent)
+        }
+    }
+}
+
+/// Renders the frame itself, without its children, as `symbol at file:line:col`.
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(symbol) = &self.symbol {
+            f.write_str(symbol)?;
+        } else if self.is_unsymbolized() {
+            write!(f, "{:#x}", self.addr.unwrap_or_default())?;
+        }
+        if self.file.is_some() {
+            write!(f, " at ")?;
+            self.display_location(f)?;
+        }
+        self.display_annotations(f)
+    }
+}
+
+/// Serializes the trace as an object with its symbolized `roots` and, if known, the `name` of the
+/// task, the `poll_duration` and the `busy_ratio`. The number of `truncated_leaves`, see
+/// [`Trace::is_truncated`], is only written if there are any.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Trace {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut trace = serializer.serialize_struct("Trace", 5)?;
+        match &self.name {
+            Some(name) => trace.serialize_field("name", name)?,
+            None => trace.skip_field("name")?,
+        }
+        match &self.poll_duration {
+            Some(poll_duration) => trace.serialize_field("poll_duration", poll_duration)?,
+            None => trace.skip_field("poll_duration")?,
+        }
+        match &self.busy_ratio {
+            Some(busy_ratio) => trace.serialize_field("busy_ratio", busy_ratio)?,
+            None => trace.skip_field("busy_ratio")?,
+        }
+        match self.truncated_leaves {
+            0 => trace.skip_field("truncated_leaves")?,
+            truncated_leaves => trace.serialize_field("truncated_leaves", &truncated_leaves)?,
+        }
+        trace.serialize_field("roots", self.roots())?;
+        trace.end()
+    }
+}
+
+/// A deserialized trace only holds the symbolized frames, along with the name, the poll duration,
+/// the busy ratio and whether it is truncated.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Trace {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Serialized {
+            #[serde(default)]
+            name: Option<String>,
+            #[serde(default)]
+            poll_duration: Option<Duration>,
+            #[serde(default)]
+            busy_ratio: Option<f64>,
+            #[serde(default)]
+            truncated_leaves: usize,
+            roots: Vec<Frame>,
+        }
+
+        let serialized = Serialized::deserialize(deserializer)?;
+        let mut trace = Trace::from_roots(serialized.roots);
+        trace.name = serialized.name;
+        trace.poll_duration = serialized.poll_duration;
+        trace.busy_ratio = serialized.busy_ratio;
+        trace.truncated_leaves = serialized.truncated_leaves;
+        Ok(trace)
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_walk() {
+        let ((), trace) = Trace::root(
+            || {
+                // Stands for an unwinder that gives up before reaching the root
+                CAPTURES.with(|captures| {
+                    captures.borrow_mut().last_mut().unwrap().root_addr = ptr::dangling();
+                });
+                Trace::leaf();
+            },
+            &CaptureLimits::default(),
+        );
+        assert!(trace.is_truncated());
+        assert_eq!(leaf_count(&trace), 1);
+        let roots = trace.roots();
+        assert_eq!(
+            roots.last().unwrap().symbol(),
+            Some("⚠ (1 branch cut short by the unwinder)")
+        );
+        // The walk went on up to the end of the stack
+        assert!(branches(&trace)
+            .next()
+            .unwrap()
+            .frames()
+            .any(|frame| frame.symbol() == Some("tasktrace::trace::tests::truncated_walk")));
+
+        let ((), trace) = Trace::root(Trace::leaf, &CaptureLimits::default());
+        assert!(!trace.is_truncated());
+    }
+}
@@ -0,0 +1,133 @@
+//! Notifying about the moves of a task from one await point to another.
+//!
+//! Comparing traces requested every now and then misses the moves that happen in between, and
+//! costs a capture per request whether the task moved or not. A task created through
+//! [`traced_with_transition_callback`] captures every poll instead and compares the
+//! [fingerprints](Trace::fingerprints) of its leaves with the ones of its previous poll, which
+//! needs no symbolization, so that the callback sees every transition of its state machine.
+
+use std::future::Future;
+
+use crate::{traced, Trace, TraceHandle, TracedTask};
+
+/// Like [`traced`] but captures every poll of the task and calls `callback` from within the poll
+/// whenever the task is left parked at different leaves than after its previous poll, including
+/// after its first poll. Polls that leave the task where it was don't call it, and neither does
+/// the poll that completes the task.
+///
+/// Every poll pays the cost of a capture, but not of symbolizing it unless `callback` inspects
+/// the traces. The callback runs inside the poll of the task, so it should return quickly.
+pub fn traced_with_transition_callback<F: Future>(
+    fut: F,
+    callback: impl Fn(&LeafTransition) + Send + 'static,
+) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.transitions = Some(Transitions {
+        callback: Box::new(callback),
+        last: None,
+    });
+    (task, handle)
+}
+
+/// A move of a task to other leaves, see [`traced_with_transition_callback`].
+pub struct LeafTransition {
+    poll: usize,
+    from: Option<Trace>,
+    to: Trace,
+}
+
+impl LeafTransition {
+    /// The number of the poll that moved the task, counting from 1 for its first poll.
+    pub fn poll(&self) -> usize {
+        self.poll
+    }
+
+    /// The trace of the previous poll that moved the task, which shows where it was parked until
+    /// this poll. `None` for the first poll.
+    pub fn from(&self) -> Option<&Trace> {
+        self.from.as_ref()
+    }
+
+    /// The trace of the poll, which shows where the task is parked now.
+    pub fn to(&self) -> &Trace {
+        &self.to
+    }
+}
+
+/// The callback of a task created with [`traced_with_transition_callback`], along with where it
+/// was last parked.
+pub(crate) struct Transitions {
+    callback: Box<dyn Fn(&LeafTransition) + Send>,
+    /// The fingerprints and the trace of the last poll that moved the task.
+    last: Option<(Vec<u64>, Trace)>,
+}
+
+impl Transitions {
+    /// Calls the callback if `trace`, captured during the `poll`th poll of the task which left it
+    /// pending, shows it at other leaves than the previous one.
+    pub(crate) fn observe(&mut self, poll: usize, trace: &Trace) {
+        let fingerprints = trace.fingerprints();
+        if self
+            .last
+            .as_ref()
+            .is_some_and(|(last, _)| *last == fingerprints)
+        {
+            return;
+        }
+        let transition = LeafTransition {
+            poll,
+            from: self.last.take().map(|(_, trace)| trace),
+            to: trace.clone(),
+        };
+        (self.callback)(&transition);
+        self.last = Some((fingerprints, transition.to));
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::test_util::{pending, TestExecutor};
+
+    #[test]
+    fn transitions() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = futures_channel::oneshot::channel::<()>();
+        let (fut, _trace_handle) = traced_with_transition_callback(
+            async {
+                let _ = rx.await;
+                pending().await;
+            },
+            {
+                let seen = Arc::clone(&seen);
+                move |transition| {
+                    seen.lock().unwrap().push((
+                        transition.poll(),
+                        transition.from().map(Trace::to_string),
+                        transition.to().to_string(),
+                    ));
+                }
+            },
+        );
+        let mut executor = TestExecutor::new(fut);
+
+        // Parked at the receiver, twice
+        assert!(executor.poll_once().is_pending());
+        assert!(executor.poll_once().is_pending());
+        // And then at `pending`
+        tx.send(()).unwrap();
+        assert!(executor.poll_once().is_pending());
+        assert!(executor.poll_once().is_pending());
+
+        let seen = seen.lock().unwrap();
+        let [(1, None, first), (3, Some(from), to)] = &seen[..] else {
+            panic!("unexpected transitions {seen:?}");
+        };
+        assert_eq!(from, first);
+        assert!(first.contains("futures_channel::oneshot::Receiver"));
+        assert!(!first.contains("test_util::pending"));
+        assert!(to.contains("test_util::pending"));
+    }
+}
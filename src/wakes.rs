@@ -0,0 +1,111 @@
+//! Recording who wakes a traced task.
+//!
+//! The trace of a task that keeps getting polled without making progress shows where it parks,
+//! but not what keeps waking it. Tasks created through [`traced_with_wake_tracing`] are polled
+//! with a waker that records the backtrace of every `wake` call, so that
+//! [`TraceHandle::last_wake_trace`] can point at the component behind the wakeups.
+
+use std::future::Future;
+use std::sync::{Arc, Weak};
+use std::task::{Wake, Waker};
+
+use crate::{traced, TaskState, Trace, TraceHandle, TracedTask};
+
+/// Like [`traced`] but also records a backtrace of the call site every time the task is woken,
+/// see [`TraceHandle::last_wake_trace`].
+///
+/// Every wake pays for walking the stack of the thread that wakes the task, so this is meant for
+/// investigating tasks that wake up too often rather than for every task.
+pub fn traced_with_wake_tracing<F: Future>(fut: F) -> (TracedTask<F>, TraceHandle) {
+    let (mut task, handle) = traced(fut);
+    task.wakes = Some(WakeTracing { recorder: None });
+    (task, handle)
+}
+
+impl TraceHandle {
+    /// The backtrace of the most recent call that woke a task created with
+    /// [`traced_with_wake_tracing`], from the root of the waking thread down to the `wake` call.
+    /// `None` if the task was never woken or doesn't record its wakes.
+    ///
+    /// Only wakes through the waker the task was polled with are recorded, i.e. the ones of its
+    /// own futures. The wakes of the trace requests themselves are left out.
+    pub fn last_wake_trace(&self) -> Option<Trace> {
+        self.state.last_wake.lock().unwrap().clone()
+    }
+}
+
+/// The state of a task created with [`traced_with_wake_tracing`].
+pub(crate) struct WakeTracing {
+    /// The waker the future was last polled with, reused for as long as the executor's waker
+    /// doesn't change.
+    recorder: Option<Arc<WakeRecorder>>,
+}
+
+impl WakeTracing {
+    /// Returns a waker that records its wakes for `state` and passes them on to `waker`.
+    pub(crate) fn waker(&mut self, waker: &Waker, state: &Arc<TaskState>) -> Waker {
+        match &self.recorder {
+            Some(recorder) if recorder.inner.will_wake(waker) => {}
+            _ => {
+                self.recorder = Some(Arc::new(WakeRecorder {
+                    inner: waker.clone(),
+                    state: Arc::downgrade(state),
+                }))
+            }
+        }
+        Waker::from(Arc::clone(self.recorder.as_ref().unwrap()))
+    }
+}
+
+struct WakeRecorder {
+    inner: Waker,
+    state: Weak<TaskState>,
+}
+
+impl WakeRecorder {
+    fn record(&self) {
+        if let Some(state) = self.state.upgrade() {
+            *state.last_wake.lock().unwrap() = Some(Trace::here());
+        }
+    }
+}
+
+impl Wake for WakeRecorder {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.record();
+        self.inner.wake_by_ref();
+    }
+}
+
+#[cfg(all(test, feature = "enabled"))]
+mod tests {
+    use futures::channel::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn last_wake_trace() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let (fut, trace_handle) = traced_with_wake_tracing(rx);
+        let task = tokio::spawn(fut);
+        tokio::task::yield_now().await;
+        // Trace requests wake the task as well, but aren't recorded
+        trace_handle.backtrace().await.unwrap();
+        assert!(trace_handle.last_wake_trace().is_none());
+
+        notify(tx);
+        task.await.unwrap().unwrap();
+        let trace = trace_handle.last_wake_trace().unwrap().to_string();
+        assert!(trace.contains("tasktrace::wakes::tests::notify"));
+        assert!(trace.contains("futures_channel::oneshot::Sender<T>::send"));
+    }
+
+    #[inline(never)]
+    fn notify(tx: oneshot::Sender<()>) {
+        tx.send(()).unwrap();
+    }
+}
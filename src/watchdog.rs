@@ -0,0 +1,188 @@
+//! Detection of polls that block the executor.
+//!
+//! A future that runs blocking code between two `.await`s holds on to its executor thread for as
+//! long as that code runs. Its waker is registered only once the blocking part is over, so the
+//! traces never show it. [`watchdog`] times every poll instead and reports the ones that take
+//! longer than a threshold.
+
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use pin_project_lite::pin_project;
+
+use crate::time::{Clock, SystemClock};
+
+/// Calls `callback` after every poll of `fut` that takes longer than `threshold`.
+///
+/// ```rust
+/// # async fn work() {}
+/// # async fn example() {
+/// let fut = tasktrace::watchdog(work(), std::time::Duration::from_millis(10), |slow| {
+///     eprintln!("{slow}");
+/// });
+/// fut.await;
+/// # }
+/// ```
+///
+/// The callback runs on the thread that polled the future, right after the slow poll, so it
+/// should return quickly. There is no portable way to capture the stack of a thread while it is
+/// blocked, so the report can only tell which future was slow, not where it blocked. Wrapping the
+/// suspect parts of the future in watchdogs of their own narrows it down, and on Linux
+/// `TraceHandle::backtrace_with_thread_fallback` shows the stack of a traced task's thread while
+/// it blocks.
+#[track_caller]
+pub fn watchdog<F: Future>(
+    fut: F,
+    threshold: Duration,
+    callback: impl Fn(&SlowPoll) + Send + 'static,
+) -> Watchdog<F> {
+    Watchdog {
+        fut,
+        threshold,
+        callback: Box::new(callback),
+        location: Location::caller(),
+        polls: 0,
+        clock: Arc::new(SystemClock),
+    }
+}
+
+pin_project! {
+    /// The future returned by [`watchdog`].
+    #[must_use = "futures do nothing unless polled"]
+    pub struct Watchdog<F> {
+        #[pin]
+        fut: F,
+        threshold: Duration,
+        callback: Box<dyn Fn(&SlowPoll) + Send>,
+        location: &'static Location<'static>,
+        polls: usize,
+        clock: Arc<dyn Clock>,
+    }
+}
+
+impl<F> Watchdog<F> {
+    /// Times the polls with `clock` instead of the clock of the platform, see [`Clock`].
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+impl<F: Future> Future for Watchdog<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        *this.polls += 1;
+        let start = this.clock.now();
+        let result = this.fut.poll(cx);
+        let duration = this.clock.now().duration_since(start);
+        if duration > *this.threshold {
+            (this.callback)(&SlowPoll {
+                duration,
+                location: this.location,
+                poll: *this.polls,
+            });
+        }
+        result
+    }
+}
+
+/// A poll that took longer than the threshold of its [`watchdog`].
+#[derive(Debug, Clone)]
+pub struct SlowPoll {
+    duration: Duration,
+    location: &'static Location<'static>,
+    poll: usize,
+}
+
+impl SlowPoll {
+    /// How long the poll took.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Where the watchdog of the future was created.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// The number of the poll, counting from 1 for the first poll of the future.
+    pub fn poll(&self) -> usize {
+        self.poll
+    }
+}
+
+/// Renders the poll as `poll 3 of the future at src/main.rs:10:5 took 12.3ms`.
+impl std::fmt::Display for SlowPoll {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "poll {} of the future at {} took {:.1?}",
+            self.poll, self.location, self.duration
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_poll() {
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&slow);
+        let fut = watchdog(
+            async {
+                tokio::task::yield_now().await;
+                std::thread::sleep(Duration::from_millis(30));
+            },
+            Duration::from_millis(20),
+            move |slow| reported.lock().unwrap().push(slow.clone()),
+        );
+        fut.await;
+
+        let slow = slow.lock().unwrap();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].poll(), 2);
+        assert!(slow[0].duration() >= Duration::from_millis(30));
+        assert_eq!(slow[0].location().file(), file!());
+        assert!(slow[0]
+            .to_string()
+            .starts_with("poll 2 of the future at src/watchdog.rs:"));
+    }
+
+    #[test]
+    fn mock_clock() {
+        let clock = crate::test_util::MockClock::new();
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&slow);
+        let ticking = clock.clone();
+        let mut polls = [10, 30, 20].into_iter();
+        let fut = watchdog(
+            std::future::poll_fn(move |cx| match polls.next() {
+                Some(millis) => {
+                    ticking.advance(Duration::from_millis(millis));
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                None => Poll::Ready(()),
+            }),
+            Duration::from_millis(20),
+            move |slow| reported.lock().unwrap().push(slow.clone()),
+        )
+        .with_clock(clock);
+        futures::executor::block_on(fut);
+
+        let slow = slow.lock().unwrap();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].poll(), 2);
+        assert_eq!(slow[0].duration(), Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,44 @@
+//! Toggling tracing is process global so this lives in its own test binary.
+
+#[cfg(feature = "enabled")]
+#[tokio::test]
+async fn set_enabled() {
+    let (fut, trace_handle) = tasktrace::traced(pending());
+    tokio::spawn(fut);
+
+    tasktrace::set_enabled(false);
+    assert!(!tasktrace::is_enabled());
+    assert!(matches!(
+        trace_handle.backtrace().await,
+        Err(tasktrace::TraceError::Disabled)
+    ));
+
+    tasktrace::set_enabled(true);
+    assert!(tasktrace::is_enabled());
+    let trace = trace_handle.backtrace().await.unwrap();
+    assert!(trace.to_string().contains("enabled::pending"));
+}
+
+#[cfg(not(feature = "enabled"))]
+#[tokio::test]
+async fn compiled_out() {
+    let (fut, trace_handle) = tasktrace::traced(pending());
+    tokio::spawn(fut);
+
+    // Enabling tracing at runtime has no effect without the feature
+    tasktrace::set_enabled(true);
+    assert!(!tasktrace::is_enabled());
+    assert!(matches!(
+        trace_handle.backtrace().await,
+        Err(tasktrace::TraceError::Disabled)
+    ));
+}
+
+async fn pending() {
+    let mut waker = None;
+    std::future::poll_fn(|cx| {
+        waker = Some(cx.waker().clone());
+        std::task::Poll::Pending
+    })
+    .await
+}
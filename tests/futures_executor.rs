@@ -0,0 +1,44 @@
+//! Tracing tasks that run on the executors of the `futures` crate.
+#![cfg(feature = "enabled")]
+
+use futures::channel::oneshot;
+use futures::executor::{block_on, LocalPool};
+use futures::task::LocalSpawnExt;
+
+#[test]
+fn local_pool() {
+    let mut pool = LocalPool::new();
+    let (_tx, rx) = oneshot::channel::<()>();
+    let (fut, trace_handle) = tasktrace::traced(async {
+        let _ = wait(rx).await;
+    });
+    pool.spawner().spawn_local(fut).unwrap();
+
+    let trace = pool.run_until(trace_handle.backtrace()).unwrap();
+    assert!(trace
+        .to_string()
+        .contains("futures_executor::wait::{{closure}}"));
+    assert!(trace
+        .to_string()
+        .contains("<futures_channel::oneshot::Receiver<T> as core::future::future::Future>::poll"));
+}
+
+#[test]
+fn block_on_from_another_thread() {
+    let (tx, rx) = oneshot::channel::<()>();
+    let (fut, trace_handle) = tasktrace::traced(wait(rx));
+    let runner = std::thread::spawn(move || block_on(fut));
+
+    let trace = trace_handle
+        .backtrace_blocking(std::time::Duration::from_secs(10))
+        .unwrap();
+    assert!(trace
+        .to_string()
+        .contains("futures_executor::wait::{{closure}}"));
+    tx.send(()).unwrap();
+    runner.join().unwrap().unwrap();
+}
+
+async fn wait(rx: oneshot::Receiver<()>) -> Result<(), oneshot::Canceled> {
+    rx.await
+}
@@ -0,0 +1,126 @@
+//! Tests of the `#[traced]` and `#[instrument]` attributes.
+#![cfg(all(feature = "macros", feature = "enabled"))]
+
+#[tasktrace::traced]
+async fn double(value: u32) -> u32 {
+    pending().await;
+    value * 2
+}
+
+#[tasktrace::traced]
+async fn first<T: Clone>(items: &[T]) -> Option<T> {
+    pending().await;
+    items.first().cloned()
+}
+
+struct Counter {
+    step: u32,
+}
+
+impl Counter {
+    #[tasktrace::traced]
+    async fn add(&self, value: &u32) -> u32 {
+        pending().await;
+        value + self.step
+    }
+}
+
+#[tokio::test]
+async fn free_function() {
+    let (fut, trace_handle) = double(21);
+    let task = tokio::spawn(fut);
+    let trace = trace_handle.backtrace().await.unwrap();
+    assert!(trace.to_string().contains("macros::double"));
+    assert_eq!(task.await.unwrap(), 42);
+}
+
+#[tokio::test]
+async fn method() {
+    let counter = Counter { step: 1 };
+    let value = 41;
+    let (fut, trace_handle) = counter.add(&value);
+    let (trace, result) = tokio::join!(trace_handle.backtrace(), fut);
+    assert!(trace.unwrap().to_string().contains("macros::Counter::add"));
+    assert_eq!(result, 42);
+}
+
+#[tokio::test]
+async fn generic_borrowing() {
+    let items = vec![String::from("a"), String::from("b")];
+    let (fut, trace_handle) = first(&items);
+    let (trace, result) = tokio::join!(trace_handle.backtrace(), fut);
+    assert!(trace.unwrap().to_string().contains("macros::first"));
+    assert_eq!(result.as_deref(), Some("a"));
+}
+
+#[tasktrace::traced]
+async fn wait_for(rx: futures::channel::oneshot::Receiver<()>) {
+    let _ = rx.await;
+}
+
+#[cfg(feature = "registry")]
+#[tokio::test]
+async fn registered() {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    let (fut, _trace_handle) = wait_for(rx);
+    let task = tokio::spawn(fut);
+    let dump = tasktrace::registry::dump_all().await;
+    let (_, trace) = dump
+        .iter()
+        .find(|(name, _)| name == "macros::wait_for")
+        .unwrap();
+    assert!(trace
+        .as_ref()
+        .unwrap()
+        .to_string()
+        .contains("macros::wait_for"));
+
+    tx.send(()).unwrap();
+    task.await.unwrap();
+    let dump = tasktrace::registry::dump_all().await;
+    assert!(dump.iter().all(|(name, _)| name != "macros::wait_for"));
+}
+
+#[tasktrace::instrument]
+#[tasktrace::traced]
+async fn fetch(value: u32) -> u32 {
+    ready().await;
+    let inner = async { pending().await };
+    inner.await;
+    value
+}
+
+#[tokio::test]
+async fn instrumented() {
+    let (fut, trace_handle) = fetch(1);
+    let (trace, result) = tokio::join!(trace_handle.backtrace(), fut);
+    let trace = trace.unwrap().to_string();
+    assert!(!trace.contains("macros::fetch await #0"), "{trace}");
+    assert!(trace.contains("macros::fetch await #1"), "{trace}");
+    assert!(trace.contains("macros::fetch await #2"), "{trace}");
+    let outer = trace.find("await #2").unwrap();
+    assert!(outer < trace.find("await #1").unwrap(), "{trace}");
+    assert_eq!(result, 1);
+}
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
+
+async fn ready() {}
+
+/// Registers the waker once and completes when polled again.
+async fn pending() {
+    let mut polled = false;
+    std::future::poll_fn(|cx| {
+        if polled {
+            return std::task::Poll::Ready(());
+        }
+        polled = true;
+        cx.waker().wake_by_ref();
+        std::task::Poll::Pending
+    })
+    .await
+}
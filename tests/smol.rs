@@ -0,0 +1,61 @@
+//! Tracing tasks that run on smol instead of tokio.
+#![cfg(feature = "enabled")]
+
+use std::time::Duration;
+
+#[test]
+fn spawned_task() {
+    smol::block_on(async {
+        let (fut, trace_handle) = tasktrace::traced(async {
+            futures::join!(timer(), channel());
+        });
+        let _task = smol::spawn(fut);
+
+        let trace = trace_handle.backtrace().await.unwrap().to_string();
+        assert!(trace.contains("smol::timer"));
+        assert!(trace.contains("<async_io::Timer as core::future::future::Future>::poll"));
+        assert!(trace.contains("smol::channel"));
+        assert!(trace.contains("event_listener::TaskRef::into_task"));
+    });
+}
+
+#[test]
+fn local_executor() {
+    let local = smol::LocalExecutor::new();
+    let (fut, trace_handle) = tasktrace::traced(timer());
+    let _task = local.spawn(fut);
+
+    let trace = smol::block_on(local.run(trace_handle.backtrace())).unwrap();
+    assert!(trace.to_string().contains("smol::timer"));
+}
+
+#[test]
+fn wake_by_ref_leaf() {
+    smol::block_on(async {
+        // yield_now wakes the task by reference without ever cloning the waker
+        let (fut, trace_handle) = tasktrace::traced(async {
+            loop {
+                smol::future::yield_now().await;
+            }
+        });
+        let _task = smol::spawn(fut);
+
+        let options = tasktrace::TraceOptions::new().keep_internal_frames();
+        let trace = trace_handle
+            .backtrace_with(options)
+            .await
+            .unwrap()
+            .to_string();
+        assert!(trace.contains("YieldNow"));
+        assert!(trace.contains("tasktrace::wake_by_ref_raw"));
+    });
+}
+
+async fn timer() {
+    smol::Timer::after(Duration::from_secs(3600)).await;
+}
+
+async fn channel() {
+    let (_tx, rx) = smol::channel::bounded::<()>(1);
+    let _ = rx.recv().await;
+}
@@ -0,0 +1,6 @@
+#[tasktrace::traced]
+async fn impl_trait_arg(value: impl std::fmt::Debug) {
+    println!("{value:?}");
+}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[tasktrace::traced]
+fn not_async() {}
+
+fn main() {}